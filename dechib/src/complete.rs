@@ -0,0 +1,254 @@
+//! Tab completion for the REPL. Keywords always complete, table names complete where SQL expects
+//! a table and column names come from the tables the statement mentions, all from the catalog as
+//! it is when the prompt is shown.
+use dechib_core::types::ROWID_COLUMN;
+use dechib_core::Instance;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+use std::collections::BTreeMap;
+
+const KEYWORDS: &[&str] = &[
+    "ADD",
+    "ALTER",
+    "AND",
+    "AS",
+    "ASC",
+    "BIGINT",
+    "BOOLEAN",
+    "BY",
+    "BYTEA",
+    "CHECK",
+    "COLUMN",
+    "CONSTRAINT",
+    "CREATE",
+    "DECIMAL",
+    "DEFAULT",
+    "DELETE",
+    "DESC",
+    "DROP",
+    "EXISTS",
+    "FOREIGN",
+    "FROM",
+    "IF",
+    "IN",
+    "INDEX",
+    "INSERT",
+    "INT",
+    "INTEGER",
+    "INTO",
+    "IS",
+    "KEY",
+    "LIKE",
+    "LIMIT",
+    "NOT",
+    "NULL",
+    "ON",
+    "OR",
+    "ORDER",
+    "PRIMARY",
+    "REFERENCES",
+    "REINDEX",
+    "RENAME",
+    "RETURNING",
+    "SELECT",
+    "SET",
+    "TABLE",
+    "TEXT",
+    "TIMESTAMP",
+    "TO",
+    "UNIQUE",
+    "UPDATE",
+    "VALUES",
+    "WHERE",
+    "WITH",
+];
+
+/// Keywords a table name follows.
+const TABLE_KEYWORDS: &[&str] = &[
+    "FROM",
+    "INTO",
+    "UPDATE",
+    "TABLE",
+    "JOIN",
+    "REFERENCES",
+    "ON",
+];
+
+const META_COMMANDS: &[&str] = &["\\?", "\\d", "\\mode", "\\null", "\\q", "\\width", "\\x"];
+
+const MODES: &[&str] = &["csv", "expanded", "json", "table"];
+
+/// Columns of every table, without the hidden rowid.
+pub type Tables = BTreeMap<String, Vec<String>>;
+
+#[derive(Default)]
+pub struct SqlHelper {
+    tables: Tables,
+    /// Lines of the statement entered before the one being edited
+    pending: String,
+}
+
+impl SqlHelper {
+    /// Picks up tables created or altered since the last prompt.
+    pub fn refresh(&mut self, instance: &Instance, pending: &str) {
+        self.tables = match instance.tables() {
+            Ok(tables) => tables
+                .into_iter()
+                .map(|(name, columns)| {
+                    let columns = columns.into_keys().filter(|x| x != ROWID_COLUMN);
+                    (name, columns.collect())
+                })
+                .collect(),
+            // Completion is a convenience, the statement will report what's wrong
+            Err(_) => Tables::new(),
+        };
+        self.pending = pending.to_string();
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | '\\')
+}
+
+/// Where the word being completed starts.
+fn word_start(line: &str) -> usize {
+    line.char_indices()
+        .rev()
+        .find(|(_, c)| !is_word(*c))
+        .map_or(0, |(i, c)| i + c.len_utf8())
+}
+
+/// Candidates for the word ending at the end of `line`, `pending` holds the statement's earlier
+/// lines.
+pub fn candidates(tables: &Tables, pending: &str, line: &str) -> Vec<String> {
+    let start = word_start(line);
+    let word = &line[start..];
+    let before = line[..start].trim_end();
+    let previous = before
+        .rsplit(|c: char| c.is_whitespace() || c == '(' || c == ',')
+        .next()
+        .unwrap_or_default();
+    let matching = |candidates: &mut dyn Iterator<Item = String>| {
+        let word = word.to_lowercase();
+        let mut res = candidates
+            .filter(|x| x.to_lowercase().starts_with(&word))
+            .collect::<Vec<_>>();
+        res.sort();
+        res.dedup();
+        res
+    };
+    let table_names = || tables.keys().cloned();
+
+    if pending.is_empty() && before.is_empty() && word.starts_with('\\') {
+        return matching(&mut META_COMMANDS.iter().map(|x| x.to_string()));
+    }
+    if pending.is_empty() && before == previous && previous.starts_with('\\') {
+        return match previous {
+            "\\mode" => matching(&mut MODES.iter().map(|x| x.to_string())),
+            "\\d" => matching(&mut table_names()),
+            _ => vec![],
+        };
+    }
+    if TABLE_KEYWORDS.contains(&previous.to_uppercase().as_str()) {
+        return matching(&mut table_names());
+    }
+    if let Some((table, _)) = word.split_once('.') {
+        let Some(columns) = tables.get(table) else {
+            return vec![];
+        };
+        return matching(&mut columns.iter().map(|x| format!("{}.{}", table, x)));
+    }
+
+    // Keywords are completed in the case they're being typed in
+    let lowercase = word.chars().any(|c| c.is_lowercase());
+    let keywords = KEYWORDS.iter().map(|x| {
+        if lowercase {
+            x.to_lowercase()
+        } else {
+            x.to_string()
+        }
+    });
+    let statement = format!("{} {}", pending, line);
+    let mentioned = statement
+        .split(|c: char| !is_word(c))
+        .filter_map(|x| tables.get_key_value(x))
+        .collect::<BTreeMap<_, _>>();
+    // Columns usually come before the table they're from, so until one is named any will do
+    let columns = if mentioned.is_empty() {
+        tables.values().flatten().cloned().collect::<Vec<_>>()
+    } else {
+        mentioned.values().copied().flatten().cloned().collect()
+    };
+    matching(&mut keywords.chain(columns).chain(table_names()))
+}
+
+impl Completer for SqlHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let candidates = candidates(&self.tables, &self.pending, line);
+        Ok((word_start(line), candidates))
+    }
+}
+
+impl Hinter for SqlHelper {
+    type Hint = String;
+}
+
+impl Highlighter for SqlHelper {}
+
+impl Validator for SqlHelper {}
+
+impl Helper for SqlHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables() -> Tables {
+        BTreeMap::from([
+            (
+                "users".to_string(),
+                vec!["id".to_string(), "email".to_string()],
+            ),
+            (
+                "posts".to_string(),
+                vec!["id".to_string(), "author".to_string()],
+            ),
+        ])
+    }
+
+    #[test]
+    fn completions() {
+        let tables = tables();
+        let complete = |pending: &str, line: &str| candidates(&tables, pending, line);
+
+        assert_eq!(complete("", "SEL"), ["SELECT"]);
+        assert_eq!(complete("", "sel"), ["select"]);
+        assert_eq!(complete("", "SELECT * FROM u"), ["users"]);
+        assert_eq!(complete("", "insert into "), ["posts", "users"]);
+        // Only columns of the tables the statement mentions
+        assert_eq!(complete("", "SELECT * FROM users WHERE em"), ["email"]);
+        assert!(complete("SELECT * FROM posts", "WHERE em").is_empty());
+        assert_eq!(complete("SELECT * FROM posts", "WHERE au"), ["author"]);
+        // Until a table is named any column will do
+        assert_eq!(complete("", "SELECT au"), ["author"]);
+        assert_eq!(complete("", "SELECT users.e"), ["users.email"]);
+        assert!(complete("", "SELECT missing.e").is_empty());
+
+        assert_eq!(complete("", "\\m"), ["\\mode"]);
+        assert_eq!(complete("", "\\mode j"), ["json"]);
+        assert_eq!(complete("", "\\d p"), ["posts"]);
+        // A backslash inside a statement isn't a meta-command
+        assert!(complete("SELECT", "\\m").is_empty());
+    }
+}
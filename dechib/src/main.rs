@@ -1,3 +1,149 @@
-fn main() {
-    println!("Hello, world!");
+use anyhow::Context;
+use dechib_api::api::launch_server;
+use dechib_core::config::Config;
+use dechib_core::dump::DumpFormat;
+use dechib_core::migrate::load_migrations;
+use dechib_core::schema::{describe_table, schema_diff};
+use dechib_core::{setup_logging, setup_logging_with, Instance};
+use output::{render_result, OutputMode, OutputOptions};
+use std::io::Read;
+use std::process::ExitCode;
+use std::{env, fmt};
+
+mod complete;
+mod output;
+mod repl;
+
+const USAGE: &str = "Usage: dechib [--config <path>] [--output <table|csv|json|expanded>] \
+                     [-c <sql> | -f <file> | migrate <dir> [--down <version>] | \
+                     schema-diff <source db> | load-dump <mysql|postgres> <file> | \
+                     describe <table> | repl]";
+
+/// Exit code when a statement or command failed.
+const EXIT_FAILURE: u8 = 1;
+/// Exit code when the arguments couldn't be understood, nothing was run.
+const EXIT_USAGE: u8 = 2;
+
+#[derive(Debug)]
+struct Usage;
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(USAGE)
+    }
+}
+
+impl std::error::Error for Usage {}
+
+/// Removes `flag` and the value after it from the arguments.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> anyhow::Result<Option<String>> {
+    let Some(i) = args.iter().position(|x| x == flag) else {
+        return Ok(None);
+    };
+    let value = args.get(i + 1).ok_or(Usage)?.clone();
+    args.drain(i..=i + 1);
+    Ok(Some(value))
+}
+
+/// Runs a script from `-c` or `-f` and prints what its last statement returned.
+fn run_script(instance: &mut Instance, sql: &str, opts: &OutputOptions) -> anyhow::Result<()> {
+    let result = instance.execute(sql)?;
+    for warning in &result.warnings {
+        eprintln!("WARNING: {}", warning);
+    }
+    print!("{}", render_result(&result, opts));
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(env::args().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) if err.is::<Usage>() => {
+            eprintln!("{}", err);
+            ExitCode::from(EXIT_USAGE)
+        }
+        Err(err) => {
+            eprintln!("ERROR: {:#}", err);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+fn run(mut args: Vec<String>) -> anyhow::Result<()> {
+    let config = match take_flag(&mut args, "--config")? {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    let opts = OutputOptions {
+        mode: match take_flag(&mut args, "--output")? {
+            Some(mode) => OutputMode::parse(&mode).map_err(|_| Usage)?,
+            None => OutputMode::Table,
+        },
+        ..Default::default()
+    };
+
+    // Only the server logs everything by default, commands print their results to stdout and
+    // would be buried
+    if args.is_empty() {
+        setup_logging();
+    } else {
+        setup_logging_with("warn");
+    }
+
+    let mut instance = Instance::new_with_config(&config);
+    match args.as_slice() {
+        [] => launch_server(instance, &config.server),
+        [command, dir] if command == "migrate" => {
+            for version in instance.migrate_up(&load_migrations(dir)?)? {
+                println!("Applied {}", version);
+            }
+            Ok(())
+        }
+        [command, dir, flag, target] if command == "migrate" && flag == "--down" => {
+            let target = target.parse().context("Invalid version")?;
+            for version in instance.migrate_down(&load_migrations(dir)?, target)? {
+                println!("Reverted {}", version);
+            }
+            Ok(())
+        }
+        [command, source] if command == "schema-diff" => {
+            let source = Instance::new_with_path(source);
+            for statement in schema_diff(&source, &instance)? {
+                println!("{};", statement);
+            }
+            Ok(())
+        }
+        [command, table] if command == "describe" || command == "\\d" => {
+            print!("{}", describe_table(&instance, table)?);
+            Ok(())
+        }
+        [flag, sql] if flag == "-c" => run_script(&mut instance, sql, &opts),
+        [flag, path] if flag == "-f" => {
+            let mut sql = String::new();
+            if path == "-" {
+                std::io::stdin().read_to_string(&mut sql)?;
+            } else {
+                sql = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path))?;
+            }
+            run_script(&mut instance, &sql, &opts)
+        }
+        [command] if command == "repl" => repl::Repl::new(instance, opts).run(),
+        [command, format, path] if command == "load-dump" => {
+            let format = match format.as_str() {
+                "mysql" => DumpFormat::MySql,
+                "postgres" => DumpFormat::Postgres,
+                _ => return Err(Usage.into()),
+            };
+            let dump = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path))?;
+            let summary = instance.load_dump(&dump, format)?;
+            println!(
+                "Created {} tables, inserted {} rows, skipped {} statements",
+                summary.tables, summary.rows, summary.skipped
+            );
+            Ok(())
+        }
+        _ => Err(Usage.into()),
+    }
 }
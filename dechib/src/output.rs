@@ -0,0 +1,369 @@
+//! Rendering query results in the REPL. Like psql, rows can be shown as an aligned table, as CSV,
+//! as JSON or expanded with one line per column.
+use dechib_core::types::{QueryResult, Record, Value};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    Table,
+    Csv,
+    Json,
+    Expanded,
+}
+
+impl OutputMode {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        match name.to_lowercase().as_str() {
+            "table" | "aligned" => Ok(Self::Table),
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "expanded" => Ok(Self::Expanded),
+            _ => anyhow::bail!(
+                "Unknown output mode {}, expected table, csv, json or expanded",
+                name
+            ),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Table => "table",
+            Self::Csv => "csv",
+            Self::Json => "json",
+            Self::Expanded => "expanded",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputOptions {
+    pub mode: OutputMode,
+    /// Shown for NULL in every mode but JSON, which has its own null
+    pub null: String,
+    /// Longer values are cut short in the table and expanded modes, CSV and JSON are left whole
+    /// so they can be read back
+    pub max_width: Option<usize>,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            mode: OutputMode::Table,
+            null: String::new(),
+            max_width: None,
+        }
+    }
+}
+
+/// Columns in the order they first appear in the rows.
+fn columns(rows: &[Record]) -> Vec<&String> {
+    let mut res = vec![];
+    for row in rows {
+        for column in row.columns.keys() {
+            if !res.contains(&column) {
+                res.push(column);
+            }
+        }
+    }
+    res
+}
+
+/// A value as text, without the quotes SQL would put around it.
+fn text(value: Option<&Value>, null: &str) -> String {
+    match value {
+        Some(Value::Text(s)) => s.clone(),
+        Some(Value::Boolean(b)) => b.to_string(),
+        Some(Value::Number(n)) => n.to_string(),
+        Some(Value::Bytes(bytes)) => {
+            let mut res = "\\x".to_string();
+            for byte in bytes {
+                write!(res, "{:02x}", byte).unwrap();
+            }
+            res
+        }
+        Some(Value::Null) | None => null.to_string(),
+    }
+}
+
+fn truncate(cell: String, max_width: Option<usize>) -> String {
+    match max_width {
+        Some(width) if cell.chars().count() > width => {
+            let mut res = cell
+                .chars()
+                .take(width.saturating_sub(1))
+                .collect::<String>();
+            res.push('…');
+            res
+        }
+        _ => cell,
+    }
+}
+
+fn cell(row: &Record, column: &str, opts: &OutputOptions) -> String {
+    let value = row.columns.get(column).map(|x| x.as_ref());
+    truncate(text(value, &opts.null), opts.max_width)
+}
+
+fn width(s: &str) -> usize {
+    s.chars().count()
+}
+
+fn pad(s: &str, width: usize) -> String {
+    format!("{}{}", s, " ".repeat(width.saturating_sub(self::width(s))))
+}
+
+fn table(rows: &[Record], opts: &OutputOptions) -> String {
+    let columns = columns(rows);
+    let cells = rows
+        .iter()
+        .map(|row| columns.iter().map(|x| cell(row, x, opts)).collect())
+        .collect::<Vec<Vec<_>>>();
+    let mut widths = columns.iter().map(|x| width(x)).collect::<Vec<_>>();
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(self::width(cell));
+        }
+    }
+    let line = |cells: &[&str]| {
+        let cells = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!(" {} ", pad(cell, *width)))
+            .collect::<Vec<_>>();
+        format!("{}\n", cells.join("|").trim_end())
+    };
+
+    let mut res = String::new();
+    if !columns.is_empty() {
+        let header = columns.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        res.push_str(&line(&header));
+        let rule = widths.iter().map(|x| "-".repeat(x + 2)).collect::<Vec<_>>();
+        res.push_str(&format!("{}\n", rule.join("+")));
+    }
+    for row in &cells {
+        let row = row.iter().map(String::as_str).collect::<Vec<_>>();
+        res.push_str(&line(&row));
+    }
+    let plural = if rows.len() == 1 { "" } else { "s" };
+    res.push_str(&format!("({} row{})\n", rows.len(), plural));
+    res
+}
+
+fn expanded(rows: &[Record], opts: &OutputOptions) -> String {
+    let columns = columns(rows);
+    let width = columns.iter().map(|x| width(x)).max().unwrap_or_default();
+    let mut res = String::new();
+    for (i, row) in rows.iter().enumerate() {
+        res.push_str(&format!("-[ RECORD {} ]\n", i + 1));
+        for column in &columns {
+            let cell = cell(row, column, opts);
+            let line = format!("{} | {}", pad(column, width), cell);
+            res.push_str(line.trim_end());
+            res.push('\n');
+        }
+    }
+    if rows.is_empty() {
+        res.push_str("(0 rows)\n");
+    }
+    res
+}
+
+/// Quotes a field when it holds anything CSV gives a meaning to, RFC 4180 style.
+fn csv_field(field: &str) -> String {
+    let special = |c| matches!(c, ',' | '"' | '\n' | '\r');
+    if field.contains(special) || field.trim() != field {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn csv(rows: &[Record], opts: &OutputOptions) -> String {
+    let columns = columns(rows);
+    let mut res = String::new();
+    let line = |fields: Vec<String>| {
+        let fields = fields.iter().map(|x| csv_field(x)).collect::<Vec<_>>();
+        format!("{}\n", fields.join(","))
+    };
+    if !columns.is_empty() {
+        res.push_str(&line(columns.iter().map(|x| x.to_string()).collect()));
+    }
+    for row in rows {
+        let value =
+            |column: &&String| text(row.columns.get(*column).map(|x| x.as_ref()), &opts.null);
+        res.push_str(&line(columns.iter().map(value).collect()));
+    }
+    res
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => write!(res, "\\u{:04x}", c as u32).unwrap(),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Text(_) | Value::Bytes(_) => json_string(&text(Some(value), "")),
+    }
+}
+
+/// One object per row, on a line of its own so large results can be read a row at a time.
+fn json(rows: &[Record]) -> String {
+    let rows = rows
+        .iter()
+        .map(|row| {
+            let fields = row
+                .columns
+                .iter()
+                .map(|(column, value)| format!("{}:{}", json_string(column), json_value(value)))
+                .collect::<Vec<_>>();
+            format!("{{{}}}", fields.join(","))
+        })
+        .collect::<Vec<_>>();
+    if rows.is_empty() {
+        return "[]\n".to_string();
+    }
+    format!("[\n{}\n]\n", rows.join(",\n"))
+}
+
+pub fn render(rows: &[Record], opts: &OutputOptions) -> String {
+    match opts.mode {
+        OutputMode::Table => table(rows, opts),
+        OutputMode::Csv => csv(rows, opts),
+        OutputMode::Json => json(rows),
+        OutputMode::Expanded => expanded(rows, opts),
+    }
+}
+
+/// Rows when the statement returned any, otherwise how many it changed.
+pub fn render_result(result: &QueryResult, opts: &OutputOptions) -> String {
+    if result.rows.is_empty() && result.rows_affected > 0 {
+        format!("{} rows affected\n", result.rows_affected)
+    } else {
+        render(&result.rows, opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn rows() -> Vec<Record> {
+        let row = |id: i32, name: Value| Record {
+            columns: BTreeMap::from([
+                ("id".to_string(), Value::Number(id.into()).into()),
+                ("name".to_string(), name.into()),
+            ]),
+        };
+        vec![
+            row(1, Value::Text("Ann".to_string())),
+            row(2, Value::Null),
+            row(10, Value::Text("Bob, \"the builder\"".to_string())),
+        ]
+    }
+
+    fn options(mode: OutputMode) -> OutputOptions {
+        OutputOptions {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn table() {
+        let mut opts = options(OutputMode::Table);
+        opts.null = "NULL".to_string();
+        assert_eq!(
+            render(&rows(), &opts),
+            [
+                " id | name",
+                "----+--------------------",
+                " 1  | Ann",
+                " 2  | NULL",
+                " 10 | Bob, \"the builder\"",
+                "(3 rows)",
+                "",
+            ]
+            .join("\n")
+        );
+        opts.max_width = Some(5);
+        assert!(render(&rows(), &opts).contains(" 10 | Bob,…\n"));
+        assert_eq!(render(&[], &opts), "(0 rows)\n");
+    }
+
+    #[test]
+    fn expanded() {
+        let mut opts = options(OutputMode::Expanded);
+        opts.max_width = Some(3);
+        let rows = rows();
+        assert_eq!(
+            render(&rows[..2], &opts),
+            [
+                "-[ RECORD 1 ]",
+                "id   | 1",
+                "name | Ann",
+                "-[ RECORD 2 ]",
+                "id   | 2",
+                "name |",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn csv() {
+        let mut opts = options(OutputMode::Csv);
+        // Values are left whole so the output can be loaded back
+        opts.max_width = Some(3);
+        assert_eq!(
+            render(&rows(), &opts),
+            "id,name\n1,Ann\n2,\n10,\"Bob, \"\"the builder\"\"\"\n"
+        );
+        assert_eq!(csv_field(" padded"), "\" padded\"");
+    }
+
+    #[test]
+    fn json() {
+        let opts = options(OutputMode::Json);
+        assert_eq!(
+            render(&rows(), &opts),
+            [
+                "[",
+                "{\"id\":1,\"name\":\"Ann\"},",
+                "{\"id\":2,\"name\":null},",
+                "{\"id\":10,\"name\":\"Bob, \\\"the builder\\\"\"}",
+                "]",
+                "",
+            ]
+            .join("\n")
+        );
+        assert_eq!(render(&[], &opts), "[]\n");
+        assert_eq!(json_string("a\nb\u{1}"), "\"a\\nb\\u0001\"");
+        let bytes = Value::Bytes(vec![0, 255]);
+        assert_eq!(json_value(&bytes), "\"\\\\x00ff\"");
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(OutputMode::parse("CSV").unwrap(), OutputMode::Csv);
+        assert_eq!(OutputMode::parse("aligned").unwrap(), OutputMode::Table);
+        assert!(OutputMode::parse("html").is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! An interactive SQL prompt. Statements are buffered until a line ends with `;`, lines starting
+//! with a backslash are meta-commands that change how results are shown. Tab completes keywords,
+//! tables and columns, and history is kept between sessions.
+use crate::complete::SqlHelper;
+use crate::output::{render_result, OutputMode, OutputOptions};
+use dechib_core::schema::describe_table;
+use dechib_core::Instance;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+use std::env;
+use std::path::PathBuf;
+
+const HELP: &str = "\\mode [table|csv|json|expanded]  show or set the output mode
+\\x                                toggle expanded output
+\\null [text]                      show NULL as text, empty by default
+\\width [n|off]                    cut values longer than n characters
+\\d <table>                        describe a table
+\\q                                quit
+";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Meta {
+    Mode(Option<OutputMode>),
+    ToggleExpanded,
+    Null(String),
+    Width(Option<usize>),
+    Describe(String),
+    Help,
+    Quit,
+}
+
+fn parse_meta(line: &str) -> anyhow::Result<Meta> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let args = words.collect::<Vec<_>>();
+    let meta = match (command, args.as_slice()) {
+        ("\\mode", []) => Meta::Mode(None),
+        ("\\mode", [mode]) => Meta::Mode(Some(OutputMode::parse(mode)?)),
+        ("\\x", []) => Meta::ToggleExpanded,
+        ("\\null", _) => Meta::Null(args.join(" ")),
+        ("\\width", [] | ["off"]) => Meta::Width(None),
+        ("\\width", [width]) => match width.parse() {
+            Ok(width) if width > 0 => Meta::Width(Some(width)),
+            _ => anyhow::bail!("Invalid width {}, expected a positive number or off", width),
+        },
+        ("\\d", [table]) => Meta::Describe(table.to_string()),
+        ("\\?", []) => Meta::Help,
+        ("\\q", []) => Meta::Quit,
+        _ => anyhow::bail!("Invalid command {}, try \\? for help", line.trim()),
+    };
+    Ok(meta)
+}
+
+pub struct Repl {
+    instance: Instance,
+    opts: OutputOptions,
+    /// Expanded output is a toggle, this is the mode it goes back to
+    previous_mode: OutputMode,
+}
+
+impl Repl {
+    pub fn new(instance: Instance, opts: OutputOptions) -> Self {
+        Self {
+            instance,
+            previous_mode: opts.mode,
+            opts,
+        }
+    }
+
+    /// Handles a meta-command, returning false once the REPL should stop.
+    fn meta(&mut self, line: &str) -> anyhow::Result<bool> {
+        match parse_meta(line)? {
+            Meta::Mode(None) => println!("Output mode is {}", self.opts.mode.name()),
+            Meta::Mode(Some(mode)) => self.opts.mode = mode,
+            Meta::ToggleExpanded if self.opts.mode == OutputMode::Expanded => {
+                self.opts.mode = self.previous_mode;
+            }
+            Meta::ToggleExpanded => {
+                self.previous_mode = self.opts.mode;
+                self.opts.mode = OutputMode::Expanded;
+            }
+            Meta::Null(null) => self.opts.null = null,
+            Meta::Width(width) => self.opts.max_width = width,
+            Meta::Describe(table) => print!("{}", describe_table(&self.instance, &table)?),
+            Meta::Help => print!("{}", HELP),
+            Meta::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn execute(&mut self, sql: &str) -> anyhow::Result<()> {
+        let result = self.instance.execute(sql)?;
+        for warning in &result.warnings {
+            eprintln!("WARNING: {}", warning);
+        }
+        print!("{}", render_result(&result, &self.opts));
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        let mut editor = Editor::<SqlHelper, DefaultHistory>::new()?;
+        editor.set_helper(Some(SqlHelper::default()));
+        let history = history_path();
+        if let Some(path) = &history {
+            // There's nothing to load the first time
+            let _ = editor.load_history(path);
+        }
+        let mut buffer = String::new();
+        loop {
+            if let Some(helper) = editor.helper_mut() {
+                helper.refresh(&self.instance, &buffer);
+            }
+            let prompt = if buffer.is_empty() {
+                "dechib> "
+            } else {
+                "   ...> "
+            };
+            let line = match editor.readline(prompt) {
+                Ok(line) => line,
+                // Ctrl-C drops the statement being typed, like psql
+                Err(ReadlineError::Interrupted) => {
+                    buffer.clear();
+                    continue;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(err) => return Err(err.into()),
+            };
+            let trimmed = line.trim();
+            if !trimmed.is_empty() {
+                editor.add_history_entry(trimmed)?;
+            }
+            if buffer.is_empty() && trimmed.starts_with('\\') {
+                match self.meta(trimmed) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(err) => eprintln!("ERROR: {:#}", err),
+                }
+                continue;
+            }
+            buffer.push_str(&line);
+            buffer.push('\n');
+            if trimmed.ends_with(';') {
+                if let Err(err) = self.execute(&buffer) {
+                    eprintln!("ERROR: {:#}", err);
+                }
+                buffer.clear();
+            }
+        }
+        if let Some(path) = &history {
+            editor.save_history(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where history is kept between sessions, `DECHIB_HISTORY` or `~/.dechib_history`.
+fn history_path() -> Option<PathBuf> {
+    match env::var_os("DECHIB_HISTORY") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".dechib_history")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meta_commands() {
+        assert_eq!(
+            parse_meta("\\mode csv").unwrap(),
+            Meta::Mode(Some(OutputMode::Csv))
+        );
+        assert_eq!(parse_meta("\\mode").unwrap(), Meta::Mode(None));
+        assert!(parse_meta("\\mode html").is_err());
+        assert_eq!(parse_meta("\\null").unwrap(), Meta::Null(String::new()));
+        assert_eq!(
+            parse_meta("\\null (null)").unwrap(),
+            Meta::Null("(null)".to_string())
+        );
+        assert_eq!(parse_meta("\\width 20").unwrap(), Meta::Width(Some(20)));
+        assert_eq!(parse_meta("\\width off").unwrap(), Meta::Width(None));
+        assert!(parse_meta("\\width 0").is_err());
+        assert_eq!(
+            parse_meta("\\d users").unwrap(),
+            Meta::Describe("users".to_string())
+        );
+        assert!(parse_meta("\\bogus").is_err());
+    }
+}
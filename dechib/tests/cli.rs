@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// A fresh working directory, the database is created in it.
+struct WorkDir(PathBuf);
+
+impl WorkDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("dechib_cli_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_dechib"))
+            .current_dir(&self.0)
+            // Logging everything must still leave stdout to the result
+            .env("DECHIB_LOG", "trace")
+            .args(args)
+            .output()
+            .unwrap()
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn run_sql_from_the_command_line() {
+    let dir = WorkDir::new("run_sql");
+    let output = dir.run(&[
+        "--output",
+        "csv",
+        "-c",
+        "CREATE TABLE t (id INT PRIMARY KEY, name TEXT);
+         INSERT INTO t (id, name) VALUES (1, 'a'), (2, NULL);
+         SELECT * FROM t;",
+    ]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "id,name\n1,a\n2,\n"
+    );
+    assert!(!output.stderr.is_empty());
+
+    let output = dir.run(&["--output", "json", "-c", "SELECT * FROM t WHERE id = 1"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[\n{\"id\":1,\"name\":\"a\"}\n]\n"
+    );
+}
+
+#[test]
+fn exit_codes() {
+    let dir = WorkDir::new("exit_codes");
+    let output = dir.run(&["-c", "SELECT * FROM missing"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+
+    let output = dir.run(&["--output", "html", "-c", "SELECT 1"]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = dir.run(&["-f"]);
+    assert_eq!(output.status.code(), Some(2));
+}
@@ -1,35 +1,122 @@
-use dechib_core::Instance;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use dechib_core::config::ServerConfig;
+use dechib_core::types::QueryResult;
+use dechib_core::{Instance, Session};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
 
-pub fn launch_server(instance: Instance) -> anyhow::Result<()> {
+pub fn launch_server(instance: Instance, config: &ServerConfig) -> anyhow::Result<()> {
     let rt = Runtime::new()?;
+    let instance = Arc::new(Mutex::new(instance));
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    let limit = config.statement_timeout_ms.map(Duration::from_millis);
 
     rt.block_on(async {
-        let listener = TcpListener::bind("0.0.0.0:8080").await?;
+        let listener = TcpListener::bind(&config.bind).await?;
         loop {
             let (mut socket, _) = listener.accept().await?;
+            // Refuse rather than queue so a flood of clients can't pile up sockets
+            let Ok(permit) = connections.clone().try_acquire_owned() else {
+                let _ = socket.write_all(b"ERROR: too many connections\n").await;
+                continue;
+            };
+            let instance = instance.clone();
             tokio::spawn(async move {
-                let mut queue = String::new();
-                loop {
-                    let mut temp = String::new();
-                    socket.read_to_string(&mut temp);
-                    queue.extend(vec![temp]);
-                    let commands = queue.split("\n").collect::<Vec<&str>>();
-                    if !commands.is_empty() {
-                        let len = if queue.ends_with("\n") {
-                            commands.len()
-                        } else {
-                            commands.len() - 1
-                        };
-                        for command in commands.iter().take(len) {
-                            //instance.execute(command);
-                        }
-                        // instance.execute(
-                    }
-                }
+                let _ = handle_connection(socket, instance, limit).await;
+                drop(permit);
             });
         }
     })
 }
+
+/// Each line received is a query, each query gets a single line response. Rows and warnings are
+/// appended to the `OK` so clients that only check the prefix keep working. Statements run for
+/// at most `limit`, or less if the connection sets a lower `statement_timeout`.
+async fn handle_connection(
+    socket: TcpStream,
+    instance: Arc<Mutex<Instance>>,
+    limit: Option<Duration>,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    // Every connection shares the instance, so the settings this one made with SET are swapped
+    // in around each statement
+    let mut session = Session {
+        statement_timeout: limit,
+        ..Default::default()
+    };
+    while let Some(command) = lines.next_line().await? {
+        // Rows aren't Send so the result can't be held over the await
+        let response = {
+            let mut instance = instance.lock().unwrap();
+            instance.set_session(Session {
+                statement_timeout: capped(session.statement_timeout, limit),
+                ..session
+            });
+            let response = match instance.execute(&command) {
+                Ok(result) => format_result(&result),
+                Err(e) => format!("ERROR: {}\n", e),
+            };
+            session = instance.session();
+            response
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// The connection's own timeout, kept within the server's limit.
+fn capped(timeout: Option<Duration>, limit: Option<Duration>) -> Option<Duration> {
+    match (timeout, limit) {
+        (Some(timeout), Some(limit)) => Some(timeout.min(limit)),
+        (timeout, limit) => timeout.or(limit),
+    }
+}
+
+fn format_result(result: &QueryResult) -> String {
+    let mut response = "OK".to_string();
+    if !result.rows.is_empty() {
+        let rows = result
+            .rows
+            .iter()
+            .map(|row| {
+                let columns = row
+                    .columns
+                    .iter()
+                    .map(|(name, value)| format!("{} = {}", name, value))
+                    .collect::<Vec<_>>();
+                format!("({})", columns.join(", "))
+            })
+            .collect::<Vec<_>>();
+        response.push_str(&format!(" ROWS: {}", rows.join(", ")));
+    }
+    if !result.warnings.is_empty() {
+        let warnings = result
+            .warnings
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        response.push_str(&format!(" WARNINGS: {}", warnings.join("; ")));
+    }
+    response.push('\n');
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statement_timeout_capped() {
+        let secs = |n| Some(Duration::from_secs(n));
+        assert_eq!(capped(secs(5), secs(30)), secs(5));
+        assert_eq!(capped(secs(60), secs(30)), secs(30));
+        // Turning the timeout off only goes as far as the server allows
+        assert_eq!(capped(None, secs(30)), secs(30));
+        assert_eq!(capped(secs(5), None), secs(5));
+        assert_eq!(capped(None, None), None);
+    }
+}
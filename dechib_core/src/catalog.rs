@@ -0,0 +1,220 @@
+//! Moving a database's schema to another engine as JSON, separately from its rows. Tables,
+//! constraints, indexes and sequences are exported as they're stored along with a format version,
+//! so an engine can tell whether it understands a catalog before trying to load it.
+use crate::schema::dependency_order;
+use crate::types::*;
+use crate::Instance;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Version of the catalog format, bumped whenever a catalog written now couldn't be read by an
+/// engine from before.
+pub const CATALOG_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Catalog {
+    pub version: u32,
+    pub tables: Vec<TableCatalog>,
+    /// Next value of every sequence used by a `nextval` default
+    pub sequences: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableCatalog {
+    pub name: String,
+    /// Columns without the hidden rowid, which the importing engine adds back when needed
+    pub columns: ColumnDescriptors,
+    /// Primary key columns in key order
+    pub primary_key: Vec<String>,
+    pub constraints: Vec<Constraint>,
+    pub indexes: Vec<Index>,
+    pub ttl_column: Option<String>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Catalog {
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// The version is read on its own first so a catalog from a newer engine fails with that
+    /// rather than with whatever it changed.
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+        let Versioned { version } = serde_json::from_str(json).context("Invalid catalog")?;
+        if version > CATALOG_FORMAT_VERSION {
+            anyhow::bail!(
+                "Catalog format version {} is newer than the supported version {}",
+                version,
+                CATALOG_FORMAT_VERSION
+            );
+        }
+        serde_json::from_str(json).context("Invalid catalog")
+    }
+}
+
+impl Instance {
+    /// The definition of every table along with the sequences, without any rows.
+    pub fn export_catalog(&self) -> anyhow::Result<Catalog> {
+        let mut tables = vec![];
+        for (name, mut columns) in self.storage.tables()? {
+            columns.remove(ROWID_COLUMN);
+            let primary_key = self
+                .storage
+                .primary_key(&name)?
+                .into_iter()
+                .filter(|x| x != ROWID_COLUMN)
+                .collect();
+            tables.push(TableCatalog {
+                constraints: self.storage.constraints(&name)?,
+                indexes: self.storage.indexes(&name)?,
+                ttl_column: self.storage.ttl_column(&name)?,
+                max_bytes: self.storage.quota(&name)?,
+                name,
+                columns,
+                primary_key,
+            });
+        }
+        Ok(Catalog {
+            version: CATALOG_FORMAT_VERSION,
+            tables,
+            sequences: self.storage.sequences(),
+        })
+    }
+
+    /// Creates the tables and indexes of a catalog and carries its sequences on from where they
+    /// were. None of the tables can exist yet. Everything is checked against the schema before
+    /// the first table is created, so a catalog that doesn't fit leaves the database as it was.
+    pub fn import_catalog(&mut self, catalog: &Catalog) -> anyhow::Result<()> {
+        if catalog.version > CATALOG_FORMAT_VERSION {
+            anyhow::bail!(
+                "Catalog format version {} is newer than the supported version {}",
+                catalog.version,
+                CATALOG_FORMAT_VERSION
+            );
+        }
+        let tables = catalog
+            .tables
+            .iter()
+            .map(|x| (&x.name, x))
+            .collect::<BTreeMap<_, _>>();
+        let columns = tables
+            .iter()
+            .map(|(name, table)| (*name, &table.columns))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut commands = vec![];
+        // Named foreign keys can refer to tables created after theirs, so they're added once
+        // every table exists
+        let mut foreign_keys = vec![];
+        for name in dependency_order(&columns, &BTreeSet::new()) {
+            let table = tables[name];
+            let (named, constraints): (Vec<_>, Vec<_>) = table
+                .constraints
+                .iter()
+                .cloned()
+                .partition(|x| matches!(x.kind, ConstraintKind::ForeignKey { .. }));
+            foreign_keys.extend(named.into_iter().map(|constraint| {
+                Command::AlterTable(AlterTableOptions {
+                    name: name.clone(),
+                    operation: AlterOperation::AddConstraint(constraint),
+                })
+            }));
+            commands.push(Command::CreateTable(CreateTableOptions {
+                name: name.clone(),
+                columns: table.columns.clone(),
+                ttl_column: table.ttl_column.clone(),
+                max_bytes: table.max_bytes,
+                constraints,
+                primary_key: table.primary_key.clone(),
+            }));
+        }
+        commands.extend(foreign_keys);
+        for table in &catalog.tables {
+            commands.extend(table.indexes.iter().map(|index| {
+                Command::CreateIndex(CreateIndexOptions {
+                    table: table.name.clone(),
+                    index: index.clone(),
+                    if_not_exists: false,
+                })
+            }));
+        }
+
+        self.storage.validate(&commands)?;
+        self.run(&commands)?;
+        for (name, next) in &catalog.sequences {
+            self.storage.restore_sequence(name, *next)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn round_trip() {
+        let source_dir = tempdir().unwrap();
+        let mut source = Instance::new_with_path(source_dir.path());
+        source
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, email TEXT UNIQUE NOT NULL, \
+                     number INT DEFAULT nextval('handles'));
+                 CREATE TABLE posts (id INT PRIMARY KEY, author INT REFERENCES users(id), \
+                     editor INT, slug TEXT, expires TIMESTAMP, CHECK (id > 0)) \
+                     WITH (ttl_column = 'expires', max_bytes = 1048576);
+                 CREATE TABLE notes (body TEXT);
+                 ALTER TABLE posts ADD CONSTRAINT edited_by FOREIGN KEY (editor) \
+                     REFERENCES posts(id);
+                 CREATE INDEX by_slug ON posts (lower(slug)) WHERE author = 1;
+                 INSERT INTO users (id, email) VALUES (1, 'a@example.com'), (2, 'b@example.com');",
+            )
+            .unwrap();
+
+        let catalog = source.export_catalog().unwrap();
+        assert_eq!(catalog.version, CATALOG_FORMAT_VERSION);
+        assert_eq!(
+            catalog.sequences,
+            BTreeMap::from([("handles".to_string(), 3)])
+        );
+        let json = catalog.to_json().unwrap();
+        assert_eq!(Catalog::from_json(&json).unwrap(), catalog);
+
+        let target_dir = tempdir().unwrap();
+        let mut target = Instance::new_with_path(target_dir.path());
+        target
+            .import_catalog(&Catalog::from_json(&json).unwrap())
+            .unwrap();
+        assert_eq!(target.export_catalog().unwrap(), catalog);
+        // Only the schema came across
+        let rows = target.execute("SELECT * FROM users").unwrap().rows;
+        assert!(rows.is_empty());
+        target
+            .execute("INSERT INTO users (id, email) VALUES (3, 'c@example.com')")
+            .unwrap();
+        let rows = target.execute("SELECT * FROM users").unwrap().rows;
+        assert_eq!(*rows[0].columns["number"], Value::Number(3.into()));
+        assert!(target
+            .execute("INSERT INTO posts (id, author) VALUES (1, 9)")
+            .is_err());
+
+        // Importing again would recreate tables that are already there
+        assert!(target.import_catalog(&catalog).is_err());
+        assert_eq!(target.execute("SELECT * FROM users").unwrap().rows.len(), 1);
+
+        let newer = json.replacen(
+            &format!("\"version\": {}", CATALOG_FORMAT_VERSION),
+            &format!("\"version\": {}", CATALOG_FORMAT_VERSION + 1),
+            1,
+        );
+        let err = Catalog::from_json(&newer).unwrap_err();
+        assert!(err.to_string().contains("newer"));
+        assert!(Catalog::from_json("{}").is_err());
+    }
+}
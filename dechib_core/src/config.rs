@@ -0,0 +1,170 @@
+use anyhow::Context;
+use rocksdb::{BlockBasedOptions, Options, SliceTransform};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Top level configuration shared by the embedded engine and the server binary. Every field has a
+/// default so a config file only has to mention the settings it wants to change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub storage: StorageConfig,
+    pub server: ServerConfig,
+}
+
+impl Config {
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::from_toml(&contents)
+    }
+
+    pub fn from_toml(contents: &str) -> anyhow::Result<Self> {
+        toml::from_str(contents).context("Invalid config file")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Directory the rocksdb database lives in.
+    pub path: PathBuf,
+    pub max_open_files: i32,
+    pub write_buffer_size: usize,
+    pub max_background_jobs: i32,
+    /// Sync the WAL to disk before a write is acknowledged. Slower but survives power loss.
+    pub sync_writes: bool,
+    /// Most columns a table can declare.
+    pub max_columns: usize,
+    /// Largest a row can be once encoded.
+    pub max_row_bytes: usize,
+    /// Largest a key can be, including its namespace prefix.
+    pub max_key_bytes: usize,
+    /// Large writes are split into batches of about this size so a single statement can't
+    /// stall every other writer. Rows are checked before the first batch is written, but a
+    /// statement split over several batches isn't atomic if writing one of them fails.
+    pub max_batch_bytes: usize,
+    /// How far ahead scans hinted as sequential read. Large reads keep a spinning disk streaming
+    /// instead of seeking back and forth between tables.
+    pub scan_readahead_bytes: usize,
+    /// Auto increment and sequence values reserved in storage at a time. Storage is only written
+    /// when a block runs out, and a crash or restart skips at most the rest of a block.
+    pub counter_block_size: usize,
+    /// Percentages of a table's quota that are reported to the quota hook as writes pass them.
+    pub quota_thresholds: Vec<u8>,
+    /// Keep a bloom filter of key prefixes, see [`crate::keys::key_prefix`], in every file and
+    /// memtable so lookups in an index or by primary key skip the files that can't hold them.
+    pub prefix_bloom_filters: bool,
+    /// Size of the bloom filters, more bits make false positives rarer.
+    pub bloom_bits_per_key: u32,
+}
+
+impl StorageConfig {
+    pub fn with_path(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn db_options(&self) -> Options {
+        let mut opts = Options::default();
+        opts.set_max_open_files(self.max_open_files);
+        opts.set_write_buffer_size(self.write_buffer_size);
+        opts.set_max_background_jobs(self.max_background_jobs);
+        opts.set_compaction_filter("dechib_ttl", crate::ttl::compaction_filter);
+        if self.prefix_bloom_filters {
+            opts.set_prefix_extractor(SliceTransform::create(
+                "dechib_key_prefix",
+                crate::keys::key_prefix,
+                Some(crate::keys::has_key_prefix),
+            ));
+            opts.set_memtable_prefix_bloom_ratio(0.1);
+            let mut table = BlockBasedOptions::default();
+            table.set_bloom_filter(f64::from(self.bloom_bits_per_key), false);
+            opts.set_block_based_table_factory(&table);
+        }
+        opts
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        // These match the rocksdb defaults apart from the path and the bloom filters
+        Self {
+            path: PathBuf::from("_dechib_db"),
+            max_open_files: -1,
+            write_buffer_size: 64 << 20,
+            max_background_jobs: 2,
+            sync_writes: false,
+            max_columns: 1000,
+            max_row_bytes: 16 << 20,
+            max_key_bytes: 8 << 10,
+            max_batch_bytes: 4 << 20,
+            scan_readahead_bytes: 2 << 20,
+            counter_block_size: 1000,
+            quota_thresholds: vec![80, 90],
+            prefix_bloom_filters: true,
+            bloom_bits_per_key: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind: String,
+    /// Connections beyond this are refused until an existing client disconnects.
+    pub max_connections: usize,
+    /// Longest a statement from any one connection can run, in milliseconds. A client's own
+    /// `SET statement_timeout` can lower it for its connection but not lift it.
+    pub statement_timeout_ms: Option<u64>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:8080".to_string(),
+            max_connections: 100,
+            statement_timeout_ms: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_config_uses_defaults() {
+        let config = Config::from_toml(
+            r#"
+            [storage]
+            path = "/var/lib/dechib"
+            sync_writes = true
+
+            [server]
+            bind = "127.0.0.1:5432"
+            statement_timeout_ms = 30000
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.storage.path, PathBuf::from("/var/lib/dechib"));
+        assert!(config.storage.sync_writes);
+        assert_eq!(
+            config.storage.write_buffer_size,
+            StorageConfig::default().write_buffer_size
+        );
+        assert_eq!(config.server.bind, "127.0.0.1:5432");
+        assert_eq!(config.server.statement_timeout_ms, Some(30000));
+
+        assert_eq!(Config::from_toml("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn unknown_types_rejected() {
+        assert!(Config::from_toml("[storage]\nmax_open_files = \"lots\"").is_err());
+    }
+}
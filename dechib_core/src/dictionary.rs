@@ -0,0 +1,192 @@
+//! Dictionary encoding for low cardinality text columns. A table created with
+//! `WITH (dictionary = 'status')` keeps every distinct value of `status` once in its dictionary,
+//! stored under `m/dictionary`, and rows hold the value's position in it instead of the text.
+//!
+//! Codes are stored as numbers, which a text column can't otherwise hold, so a row is decoded by
+//! replacing the numbers in its dictionary columns. The dictionary only ever grows: a value no
+//! row uses any more keeps its code, so codes already in rows never change meaning.
+use crate::types::{ColumnDescriptors, Record, Value};
+use anyhow::Context;
+use bigdecimal::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::rc::Rc;
+
+/// Per table system state holding the dictionary.
+pub const DICTIONARY_KEY: &str = "dictionary";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Stored {
+    /// Values of each column, a value's code being its position
+    values: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Dictionary {
+    stored: Stored,
+    /// Columns the table encodes
+    columns: BTreeSet<String>,
+    codes: HashMap<String, HashMap<String, usize>>,
+}
+
+impl Dictionary {
+    /// Loads the dictionary of a table from its stored bytes, if it has any yet.
+    pub fn new(metadata: &ColumnDescriptors, stored: Option<&[u8]>) -> anyhow::Result<Self> {
+        let stored: Stored = match stored {
+            Some(bytes) => postcard::from_bytes(bytes)?,
+            None => Stored::default(),
+        };
+        let codes = stored
+            .values
+            .iter()
+            .map(|(column, values)| {
+                let codes = values
+                    .iter()
+                    .enumerate()
+                    .map(|(code, value)| (value.clone(), code))
+                    .collect();
+                (column.clone(), codes)
+            })
+            .collect();
+        Ok(Self {
+            stored,
+            columns: metadata
+                .iter()
+                .filter(|(_, desc)| desc.dictionary)
+                .map(|(column, _)| column.clone())
+                .collect(),
+            codes,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(postcard::to_allocvec(&self.stored)?)
+    }
+
+    /// Replaces the values of a row's dictionary columns with their codes, adding values that
+    /// aren't in the dictionary yet. Returns whether any were added, in which case the dictionary
+    /// has to be written before the row is.
+    pub fn encode(&mut self, record: &mut Record) -> bool {
+        let mut added = false;
+        for column in &self.columns {
+            let Some(Value::Text(text)) = record.columns.get(column).map(|x| x.as_ref()) else {
+                continue;
+            };
+            let codes = self.codes.entry(column.clone()).or_default();
+            let code = match codes.get(text) {
+                Some(code) => *code,
+                None => {
+                    let values = self.stored.values.entry(column.clone()).or_default();
+                    values.push(text.clone());
+                    codes.insert(text.clone(), values.len() - 1);
+                    added = true;
+                    values.len() - 1
+                }
+            };
+            record
+                .columns
+                .insert(column.clone(), Rc::new(Value::Number((code as u64).into())));
+        }
+        added
+    }
+
+    /// Replaces the codes in a row's dictionary columns with the values they stand for.
+    pub fn decode(&self, record: &mut Record) -> anyhow::Result<()> {
+        for column in &self.columns {
+            let Some(Value::Number(code)) = record.columns.get(column).map(|x| x.as_ref()) else {
+                continue;
+            };
+            let value = code
+                .to_usize()
+                .and_then(|code| self.stored.values.get(column)?.get(code))
+                .with_context(|| format!("Unknown dictionary code {} in {}", code, column))?;
+            record
+                .columns
+                .insert(column.clone(), Rc::new(Value::Text(value.clone())));
+        }
+        Ok(())
+    }
+
+    pub fn rename_column(&mut self, column: &str, to: &str) {
+        if let Some(values) = self.stored.values.remove(column) {
+            self.stored.values.insert(to.to_string(), values);
+        }
+        if let Some(codes) = self.codes.remove(column) {
+            self.codes.insert(to.to_string(), codes);
+        }
+        if self.columns.remove(column) {
+            self.columns.insert(to.to_string());
+        }
+    }
+
+    pub fn drop_column(&mut self, column: &str) {
+        self.stored.values.remove(column);
+        self.codes.remove(column);
+        self.columns.remove(column);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ColumnDescriptor;
+    use sqlparser::ast::DataType;
+
+    fn record(status: &str) -> Record {
+        Record {
+            columns: BTreeMap::from([
+                (
+                    "status".to_string(),
+                    Rc::new(Value::Text(status.to_string())),
+                ),
+                ("note".to_string(), Rc::new(Value::Text(status.to_string()))),
+            ]),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let metadata = ColumnDescriptors::from([
+            (
+                "status".to_string(),
+                ColumnDescriptor {
+                    datatype: DataType::Text,
+                    dictionary: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "note".to_string(),
+                ColumnDescriptor {
+                    datatype: DataType::Text,
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let mut dictionary = Dictionary::new(&metadata, None).unwrap();
+        let mut open = record("open");
+        assert!(dictionary.encode(&mut open));
+        assert_eq!(*open.columns["status"], Value::Number(0.into()));
+        assert_eq!(*open.columns["note"], Value::Text("open".to_string()));
+        let mut closed = record("closed");
+        assert!(dictionary.encode(&mut closed));
+        let mut again = record("open");
+        assert!(!dictionary.encode(&mut again));
+        assert_eq!(again, open);
+
+        // Codes survive being stored
+        let bytes = dictionary.to_bytes().unwrap();
+        let dictionary = Dictionary::new(&metadata, Some(&bytes)).unwrap();
+        dictionary.decode(&mut closed).unwrap();
+        assert_eq!(closed, record("closed"));
+        let mut unknown = record("open");
+        unknown
+            .columns
+            .insert("status".to_string(), Rc::new(Value::Number(5.into())));
+        assert!(dictionary.decode(&mut unknown).is_err());
+    }
+}
@@ -0,0 +1,571 @@
+//! Loading the plain SQL dumps written by `mysqldump` and `pg_dump`. Dumps are full of statements
+//! dechib has no use for (session settings, locks, sequences, ownership...) so only table
+//! definitions, inserts, `COPY` blocks and key constraints are kept and everything else is skipped.
+use crate::timezone::{self, TimeZone};
+use crate::types::*;
+use crate::Instance;
+use anyhow::Context;
+use sqlparser::ast::{
+    AlterTableOperation, ColumnOption, DataType, Expr, Ident, ObjectName, Statement,
+    TableConstraint,
+};
+use sqlparser::dialect::{Dialect, MySqlDialect, PostgreSqlDialect};
+use sqlparser::keywords::Keyword;
+use sqlparser::parser::{IsOptional, Parser};
+use std::collections::HashMap;
+use std::rc::Rc;
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    MySql,
+    Postgres,
+}
+
+impl DumpFormat {
+    fn dialect(&self) -> Box<dyn Dialect> {
+        match self {
+            Self::MySql => Box::new(MySqlDialect {}),
+            Self::Postgres => Box::new(PostgreSqlDialect {}),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DumpSummary {
+    pub tables: usize,
+    pub rows: usize,
+    /// Statements that were ignored
+    pub skipped: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Chunk<'a> {
+    Sql(&'a str),
+    /// A `COPY ... FROM stdin` statement along with the lines of data following it
+    Copy(&'a str, Vec<&'a str>),
+}
+
+enum Item {
+    Statement(Statement),
+    Copy(ObjectName, Vec<Ident>, Vec<String>),
+}
+
+/// Splits a dump into statements. Only quoting and comments need to be understood to find the
+/// semicolons, except `COPY` which is followed by lines of data until a `\.` line.
+fn split_statements(dump: &str, format: DumpFormat) -> anyhow::Result<Vec<Chunk<'_>>> {
+    let bytes = dump.as_bytes();
+    let mut res = vec![];
+    // Start of the current statement, skipping the whitespace and comments before it
+    let mut start = None;
+    let mut i = 0;
+    let find = |from: usize, pattern: &str| {
+        dump[from.min(dump.len())..]
+            .find(pattern)
+            .map(|x| x + from)
+            .unwrap_or(dump.len())
+    };
+    while i < bytes.len() {
+        let comment = matches!(
+            (bytes[i], bytes.get(i + 1)),
+            (b'-', Some(b'-')) | (b'/', Some(b'*'))
+        );
+        if start.is_none() && !comment && !bytes[i].is_ascii_whitespace() {
+            start = Some(i);
+        }
+        match bytes[i] {
+            quote @ (b'\'' | b'"' | b'`') => {
+                i += 1;
+                while i < bytes.len() {
+                    if bytes[i] == b'\\' && format == DumpFormat::MySql {
+                        i += 2;
+                    } else if bytes[i] == quote && bytes.get(i + 1) == Some(&quote) {
+                        i += 2;
+                    } else if bytes[i] == quote {
+                        break;
+                    } else {
+                        i += 1;
+                    }
+                }
+                i += 1;
+            }
+            b'$' if format == DumpFormat::Postgres => {
+                // Dollar quoted function bodies, `$tag$ ... $tag$`
+                let tag_end = dump[i + 1..]
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .map(|x| x + i + 1);
+                match tag_end {
+                    Some(end) if bytes[end] == b'$' => {
+                        let tag = &dump[i..=end];
+                        i = find(end + 1, tag) + tag.len();
+                    }
+                    _ => i += 1,
+                }
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => i = find(i, "\n"),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => i = find(i + 2, "*/") + 2,
+            b';' => {
+                let sql = start.map(|x| dump[x..i].trim()).unwrap_or_default();
+                start = None;
+                i += 1;
+                let upper = sql.to_uppercase();
+                if upper.starts_with("COPY ") && upper.ends_with("FROM STDIN") {
+                    let mut rows = vec![];
+                    i = find(i, "\n") + 1;
+                    loop {
+                        if i >= dump.len() {
+                            anyhow::bail!("Data for {} isn't terminated", sql);
+                        }
+                        let end = find(i, "\n");
+                        let line = dump[i..end].trim_end_matches('\r');
+                        i = end + 1;
+                        if line == "\\." {
+                            break;
+                        }
+                        rows.push(line);
+                    }
+                    res.push(Chunk::Copy(sql, rows));
+                } else if !sql.is_empty() {
+                    res.push(Chunk::Sql(sql));
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    if let Some(start) = start {
+        res.push(Chunk::Sql(dump[start..].trim()));
+    }
+    Ok(res)
+}
+
+/// Parses `COPY <table> [(<columns>)] FROM stdin`.
+fn parse_copy(sql: &str, dialect: &dyn Dialect) -> anyhow::Result<(ObjectName, Vec<Ident>)> {
+    let mut parser = Parser::new(dialect).try_with_sql(sql)?;
+    parser.expect_keyword(Keyword::COPY)?;
+    let table = parser.parse_object_name(false)?;
+    let columns = parser.parse_parenthesized_column_list(IsOptional::Optional, false)?;
+    parser.expect_keyword(Keyword::FROM)?;
+    parser.expect_keyword(Keyword::STDIN)?;
+    Ok((table, columns))
+}
+
+fn unescape_copy(field: &str) -> String {
+    let mut res = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('b') => res.push('\u{8}'),
+            Some('f') => res.push('\u{c}'),
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            Some('t') => res.push('\t'),
+            Some('v') => res.push('\u{b}'),
+            Some(c) => res.push(c),
+            None => res.push('\\'),
+        }
+    }
+    res
+}
+
+/// Converts a field of `COPY` data, which is always text, to the column's type.
+fn copy_value(field: &str, desc: &ColumnDescriptor) -> anyhow::Result<Value> {
+    if field == "\\N" {
+        return Ok(Value::Null);
+    }
+    let text = unescape_copy(field);
+    let value = match &desc.datatype {
+        DataType::Bool | DataType::Boolean => Value::Boolean(matches!(text.as_str(), "t" | "true")),
+        DataType::Bytea => {
+            let hex = text
+                .strip_prefix("\\x")
+                .context("Only hex encoded bytea is supported")?;
+            Value::Bytes(hex::decode(hex)?)
+        }
+        // pg_dump writes zoned timestamps with their offset
+        ty if timezone::is_zoned(ty) => Value::Text(timezone::to_utc(&text, TimeZone::UTC)?),
+        ty if is_numeric_type(ty) => Value::Number(
+            text.parse()
+                .with_context(|| format!("Invalid number {}", text))?,
+        ),
+        _ => Value::Text(text),
+    };
+    Ok(value)
+}
+
+/// Dumps qualify names with the schema, `public.users`, but dechib has no schemas.
+fn unqualified(name: &ObjectName) -> ObjectName {
+    ObjectName(name.0.last().cloned().into_iter().collect())
+}
+
+/// Postgres serial columns default to the next value of a sequence, which the dump fills in anyway.
+fn is_sequence_default(option: &ColumnOption) -> bool {
+    matches!(option, ColumnOption::Default(Expr::Function(f))
+        if f.name.to_string().eq_ignore_ascii_case("nextval"))
+}
+
+fn is_supported_constraint(constraint: &TableConstraint) -> bool {
+    matches!(
+        constraint,
+        TableConstraint::PrimaryKey { .. } | TableConstraint::ForeignKey { .. }
+    )
+}
+
+impl Instance {
+    /// Loads a plain SQL dump. `pg_dump` adds primary and foreign keys with `ALTER TABLE` after the
+    /// data. Primary keys decide how rows are stored, so they're folded into the table definitions
+    /// before anything runs. Foreign keys are added once every table and row is in, since both
+    /// tools write tables in name order rather than foreign key order, and the rows aren't checked
+    /// against foreign keys while loading. A foreign key added that way can't have `ON DELETE` or
+    /// `ON UPDATE` actions, they're dropped with a warning.
+    pub fn load_dump(&mut self, dump: &str, format: DumpFormat) -> anyhow::Result<DumpSummary> {
+        self.storage.set_foreign_key_checks(false);
+        let res = self.load_statements(dump, format);
+        self.storage.set_foreign_key_checks(true);
+        res
+    }
+
+    fn load_statements(&mut self, dump: &str, format: DumpFormat) -> anyhow::Result<DumpSummary> {
+        let dialect = format.dialect();
+        let mut summary = DumpSummary::default();
+        let mut items = vec![];
+        let mut added_constraints: HashMap<String, Vec<TableConstraint>> = HashMap::new();
+        // Foreign keys to add after everything else, with the table they're on
+        let mut foreign_keys = vec![];
+
+        for chunk in split_statements(dump, format)? {
+            let sql = match chunk {
+                Chunk::Sql(sql) => sql,
+                Chunk::Copy(sql, rows) => {
+                    let (table, columns) = parse_copy(sql, dialect.as_ref())
+                        .with_context(|| format!("Invalid COPY: {}", sql))?;
+                    let rows = rows.into_iter().map(String::from).collect();
+                    items.push(Item::Copy(table, columns, rows));
+                    continue;
+                }
+            };
+            let statements = match Parser::parse_sql(dialect.as_ref(), sql) {
+                Ok(statements) => statements,
+                Err(e) => {
+                    warn!(%e, "Skipping statement that can't be parsed");
+                    summary.skipped += 1;
+                    continue;
+                }
+            };
+            for statement in statements {
+                match statement {
+                    Statement::CreateTable { .. } | Statement::Insert(_) => {
+                        items.push(Item::Statement(statement));
+                    }
+                    Statement::AlterTable {
+                        name, operations, ..
+                    } => {
+                        let name = unqualified(&name);
+                        let table = normalize_object_name(&name);
+                        for operation in operations {
+                            match operation {
+                                AlterTableOperation::AddConstraint(
+                                    c @ TableConstraint::ForeignKey { .. },
+                                ) => foreign_keys.push((name.clone(), c)),
+                                AlterTableOperation::AddConstraint(c)
+                                    if is_supported_constraint(&c) =>
+                                {
+                                    added_constraints.entry(table.clone()).or_default().push(c);
+                                }
+                                _ => summary.skipped += 1,
+                            }
+                        }
+                    }
+                    _ => summary.skipped += 1,
+                }
+            }
+        }
+
+        // MySQL inserts and COPY may leave out the column list, meaning every column in the order
+        // they were declared
+        let mut column_order: HashMap<String, Vec<Ident>> = HashMap::new();
+        for item in items {
+            match item {
+                Item::Statement(mut statement) => {
+                    match &mut statement {
+                        Statement::CreateTable {
+                            name,
+                            columns,
+                            constraints,
+                            ..
+                        } => {
+                            *name = unqualified(name);
+                            let table = normalize_object_name(name);
+                            constraints.retain(|c| {
+                                let keep = is_supported_constraint(c);
+                                if !keep {
+                                    warn!(table = %table, constraint = %c, "Skipping constraint");
+                                }
+                                keep
+                            });
+                            constraints
+                                .extend(added_constraints.remove(&table).unwrap_or_default());
+                            // A table can only refer to tables that are already there
+                            for constraint in std::mem::take(constraints) {
+                                let later = match &constraint {
+                                    TableConstraint::ForeignKey { foreign_table, .. } => {
+                                        let target =
+                                            normalize_object_name(&unqualified(foreign_table));
+                                        target != table
+                                            && self.storage.table_metadata(&target).is_err()
+                                    }
+                                    _ => false,
+                                };
+                                if later {
+                                    foreign_keys.push((name.clone(), constraint));
+                                } else {
+                                    constraints.push(constraint);
+                                }
+                            }
+                            for constraint in constraints.iter_mut() {
+                                if let TableConstraint::ForeignKey { foreign_table, .. } =
+                                    constraint
+                                {
+                                    *foreign_table = unqualified(foreign_table);
+                                }
+                            }
+                            for column in columns.iter_mut() {
+                                column.options.retain(|x| !is_sequence_default(&x.option));
+                                for option in column.options.iter_mut() {
+                                    if let ColumnOption::ForeignKey { foreign_table, .. } =
+                                        &mut option.option
+                                    {
+                                        *foreign_table = unqualified(foreign_table);
+                                    }
+                                }
+                            }
+                            column_order
+                                .insert(table, columns.iter().map(|x| x.name.clone()).collect());
+                        }
+                        Statement::Insert(insert) => {
+                            insert.table_name = unqualified(&insert.table_name);
+                            if insert.columns.is_empty() {
+                                let table = normalize_object_name(&insert.table_name);
+                                insert.columns =
+                                    column_order.get(&table).cloned().with_context(|| {
+                                        format!("INSERT into {} without a column list", table)
+                                    })?;
+                            }
+                        }
+                        _ => {}
+                    }
+                    let command = Command::try_from(&statement)?;
+                    match &command {
+                        Command::CreateTable(opts) => {
+                            info!(table = %opts.name, "Creating table from dump");
+                            summary.tables += 1;
+                        }
+                        Command::Insert(opts) => summary.rows += opts.values.len(),
+                        Command::CloneTable(_)
+                        | Command::AlterTable(_)
+                        | Command::DropTable(_)
+                        | Command::CreateIndex(_)
+                        | Command::DropIndex(_)
+                        | Command::Reindex(_)
+                        | Command::Update(_)
+                        | Command::Delete(_)
+                        | Command::Select(_)
+                        | Command::Set(_) => {}
+                    }
+                    self.run(&[command])?;
+                }
+                Item::Copy(table, columns, rows) => {
+                    let table = normalize_object_name(&unqualified(&table));
+                    let columns = if columns.is_empty() {
+                        column_order
+                            .get(&table)
+                            .cloned()
+                            .with_context(|| format!("COPY into {} without a column list", table))?
+                    } else {
+                        columns
+                    };
+                    let columns = columns.iter().map(normalize_ident).collect::<Vec<_>>();
+                    let metadata = self.storage.table_metadata(&table)?;
+                    let descriptors = columns
+                        .iter()
+                        .map(|x| {
+                            metadata
+                                .get(x)
+                                .with_context(|| format!("No column {} in {}", x, table))
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+
+                    let mut values = Vec::with_capacity(rows.len());
+                    for row in &rows {
+                        let fields = row.split('\t').collect::<Vec<_>>();
+                        if fields.len() != descriptors.len() {
+                            anyhow::bail!("COPY row for {} has the wrong number of fields", table);
+                        }
+                        let row = fields
+                            .into_iter()
+                            .zip(&descriptors)
+                            .map(|(field, desc)| copy_value(field, desc).map(Rc::new))
+                            .collect::<anyhow::Result<Vec<_>>>()
+                            .with_context(|| format!("Invalid COPY row for {}", table))?;
+                        values.push(row);
+                    }
+                    summary.rows += values.len();
+                    self.storage.insert_rows(&InsertOptions {
+                        table,
+                        columns,
+                        values,
+                        returning: None,
+                        upsert: false,
+                    })?;
+                }
+            }
+        }
+
+        for (table, mut constraint) in foreign_keys {
+            let actions = match &constraint {
+                TableConstraint::ForeignKey {
+                    on_delete,
+                    on_update,
+                    ..
+                } => on_delete.is_some() || on_update.is_some(),
+                _ => false,
+            };
+            if actions {
+                warn!(table = %table, constraint = %constraint, "Dropping foreign key actions");
+            }
+            if let TableConstraint::ForeignKey {
+                foreign_table,
+                on_delete,
+                on_update,
+                ..
+            } = &mut constraint
+            {
+                *foreign_table = unqualified(foreign_table);
+                *on_delete = None;
+                *on_update = None;
+            }
+            let statement = Statement::AlterTable {
+                name: table,
+                if_exists: false,
+                only: false,
+                operations: vec![AlterTableOperation::AddConstraint(constraint)],
+                location: None,
+            };
+            self.run(&[Command::try_from(&statement)?])?;
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use tracing_test::traced_test;
+
+    #[test]
+    fn splitting() {
+        let dump = "-- a; comment\nINSERT INTO t VALUES ('a;b', 'it''s');\n/* c; */\n\
+                    COPY t (a) FROM stdin;\nx\\ty\n\\.\nSELECT 1";
+        assert_eq!(
+            split_statements(dump, DumpFormat::Postgres).unwrap(),
+            vec![
+                Chunk::Sql("INSERT INTO t VALUES ('a;b', 'it''s')"),
+                Chunk::Copy("COPY t (a) FROM stdin", vec!["x\\ty"]),
+                Chunk::Sql("SELECT 1"),
+            ]
+        );
+        let dump = "INSERT INTO t VALUES ('a\\';b');";
+        assert_eq!(split_statements(dump, DumpFormat::MySql).unwrap().len(), 1);
+        assert!(split_statements("COPY t FROM stdin;\n1\n", DumpFormat::Postgres).is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn load_mysql_dump() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        let dump = r#"
+/*!40101 SET @OLD_CHARACTER_SET_CLIENT=@@CHARACTER_SET_CLIENT */;
+DROP TABLE IF EXISTS `users`;
+CREATE TABLE `users` (
+  `id` int NOT NULL,
+  `name` varchar(20) NOT NULL,
+  `balance` int DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  KEY `by_name` (`name`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+LOCK TABLES `users` WRITE;
+INSERT INTO `users` VALUES (1,'Daniel',-5),(2,'O\'Brien',NULL);
+UNLOCK TABLES;
+"#;
+        let summary = instance.load_dump(dump, DumpFormat::MySql).unwrap();
+        assert_eq!(summary.tables, 1);
+        assert_eq!(summary.rows, 2);
+        assert!(summary.skipped >= 3);
+        assert!(instance.storage.table_metadata("users").unwrap()["id"].primary_key);
+    }
+
+    #[test]
+    #[traced_test]
+    fn load_postgres_dump() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        let dump = r#"
+SET statement_timeout = 0;
+SELECT pg_catalog.set_config('search_path', '', false);
+CREATE TABLE public.posts (
+    id integer DEFAULT nextval('public.posts_id_seq'::regclass) NOT NULL,
+    author integer,
+    parent integer
+);
+CREATE TABLE public.users (
+    id integer NOT NULL,
+    name text,
+    active boolean
+);
+CREATE SEQUENCE public.users_id_seq AS integer START WITH 1;
+COPY public.posts (id, author, parent) FROM stdin;
+1	2	\N
+\.
+COPY public.users (id, name, active) FROM stdin;
+1	Daniel	t
+2	tab\there	\N
+\.
+ALTER TABLE ONLY public.posts
+    ADD CONSTRAINT posts_pkey PRIMARY KEY (id);
+ALTER TABLE ONLY public.users
+    ADD CONSTRAINT users_pkey PRIMARY KEY (id);
+ALTER TABLE ONLY public.posts
+    ADD CONSTRAINT posts_author_fkey FOREIGN KEY (author) REFERENCES public.users(id);
+ALTER TABLE ONLY public.posts
+    ADD CONSTRAINT posts_parent_fkey FOREIGN KEY (parent) REFERENCES public.posts(id) ON DELETE CASCADE;
+ALTER TABLE public.users OWNER TO postgres;
+"#;
+        let summary = instance.load_dump(dump, DumpFormat::Postgres).unwrap();
+        assert_eq!(summary.tables, 2);
+        assert_eq!(summary.rows, 3);
+
+        let users = instance.storage.table_metadata("users").unwrap();
+        assert!(users["id"].primary_key);
+        let posts = instance.storage.table_metadata("posts").unwrap();
+        assert!(posts["id"].primary_key);
+        assert!(posts["id"].default.is_none());
+        // Posts come first in the dump but can still refer to users, and the dump names every
+        // constraint so the foreign keys keep their names
+        let constraints = instance.storage.constraints("posts").unwrap();
+        assert_eq!(constraints.len(), 2);
+        assert_eq!(constraints[0].name, "posts_author_fkey");
+        assert_eq!(
+            constraints[0].kind,
+            ConstraintKind::ForeignKey {
+                column: "author".to_string(),
+                table: "users".to_string(),
+                referred: "id".to_string(),
+            }
+        );
+    }
+}
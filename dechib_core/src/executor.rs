@@ -0,0 +1,727 @@
+//! Runs queries against the storage engine. A `WHERE` clause that picks rows by primary key,
+//! including one that fixes every column of a composite key, fetches them directly. Otherwise the
+//! secondary index whose columns the clause narrows down the most is scanned, falling back to the
+//! whole table. However rows are found the clause is checked against each one, and without an
+//! `ORDER BY` they come back in the order they're stored, by primary key or by rowid for a table
+//! without one, so the access path a query takes never changes its results.
+use crate::expr;
+use crate::keys;
+use crate::storage_engine::{primary_key_column, StorageEngine};
+use crate::types::*;
+use sqlparser::ast::{BinaryOperator, DataType, Expr};
+use std::rc::Rc;
+use tracing::{debug, instrument};
+
+/// The primary key values a filter is limited to when it's `pk = <value>` or
+/// `pk IN (<values>)`.
+fn point_lookup(filter: &Expr, pk: &str) -> Option<Vec<Rc<Value>>> {
+    let (column, list) = match filter {
+        Expr::Nested(inner) => return point_lookup(inner, pk),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } => (left.as_ref(), std::slice::from_ref(right.as_ref())),
+        Expr::InList {
+            expr,
+            list,
+            negated: false,
+        } => (expr.as_ref(), list.as_slice()),
+        _ => return None,
+    };
+    if expr::column_name(column)? != pk {
+        return None;
+    }
+    let mut keys = vec![];
+    for item in list {
+        let Expr::Value(value) = item else {
+            return None;
+        };
+        let value = Rc::new(Value::try_from(value.clone()).ok()?);
+        // NULL never matches and a key only needs fetching once
+        if *value != Value::Null && !keys.contains(&value) {
+            keys.push(value);
+        }
+    }
+    Some(keys)
+}
+
+/// Values for every column of the primary key, in key order, when the `AND`s at the top of a
+/// filter fix each of them with an equality, so the one row it can match is fetched by key.
+fn key_values(
+    filter: &Expr,
+    metadata: &ColumnDescriptors,
+    primary_key: &[String],
+) -> Option<Vec<Value>> {
+    let mut found = vec![];
+    conditions(filter, metadata, &mut found);
+    primary_key
+        .iter()
+        .map(|column| {
+            found
+                .iter()
+                .find(|x| {
+                    x.op == BinaryOperator::Eq
+                        && x.value != Value::Null
+                        && expr::column_name(&x.expr).as_ref() == Some(column)
+                })
+                .map(|x| x.value.clone())
+        })
+        .collect()
+}
+
+/// A column, or an expression of one, compared with a literal, from the `AND`s at the top of a
+/// filter so every matching row satisfies it. The expression is [`expr::normalized`] to match
+/// the ones in indexes.
+struct Condition {
+    expr: Expr,
+    op: BinaryOperator,
+    value: Value,
+}
+
+/// Whether a literal compares with a column's values the same way their encodings sort.
+fn comparable(value: &Value, datatype: &DataType) -> bool {
+    match value {
+        Value::Number(_) => is_numeric_type(datatype),
+        Value::Text(_) => {
+            is_text_type(datatype)
+                || matches!(
+                    datatype,
+                    DataType::Uuid | DataType::Timestamp(..) | DataType::Datetime(_)
+                )
+        }
+        Value::Boolean(_) => matches!(datatype, DataType::Bool | DataType::Boolean),
+        _ => false,
+    }
+}
+
+fn conditions(filter: &Expr, metadata: &ColumnDescriptors, res: &mut Vec<Condition>) {
+    let (left, op, right) = match filter {
+        Expr::Nested(inner) => return conditions(inner, metadata, res),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            conditions(left, metadata, res);
+            return conditions(right, metadata, res);
+        }
+        Expr::BinaryOp { left, op, right } => (left.as_ref(), op, right.as_ref()),
+        _ => return,
+    };
+    // `5 < x` is `x > 5`
+    let (side, op, value) = match (left, right) {
+        (side, Expr::Value(value)) => (side, op.clone(), value),
+        (Expr::Value(value), side) => {
+            let op = match op {
+                BinaryOperator::Lt => BinaryOperator::Gt,
+                BinaryOperator::LtEq => BinaryOperator::GtEq,
+                BinaryOperator::Gt => BinaryOperator::Lt,
+                BinaryOperator::GtEq => BinaryOperator::LtEq,
+                op => op.clone(),
+            };
+            (side, op, value)
+        }
+        _ => return,
+    };
+    let Ok(value) = Value::try_from(value.clone()) else {
+        return;
+    };
+    // The only functions are lower and upper, which always give text
+    let usable = match expr::column_name(side) {
+        Some(column) => metadata
+            .get(&column)
+            .is_some_and(|x| comparable(&value, &x.datatype)),
+        None => {
+            matches!(side, Expr::Function(_))
+                && matches!(value, Value::Text(_))
+                && expr::check(side, metadata).is_ok()
+        }
+    };
+    if usable {
+        res.push(Condition {
+            expr: expr::normalized(side),
+            op,
+            value,
+        });
+    }
+}
+
+/// The range of an index holding every row that can satisfy the conditions, along with how well
+/// it narrows them down. Equalities on the leading columns fix a prefix and comparisons on the
+/// column after them bound a range inside it. A partial index narrows things down by only holding
+/// some of the rows.
+fn index_range(index: &Index, conditions: &[Condition]) -> (usize, Vec<u8>, Vec<u8>) {
+    let mut prefix = keys::index_prefix(&index.name);
+    let mut equalities = 0;
+    for indexed in &index.columns {
+        let Some(condition) = conditions
+            .iter()
+            .find(|x| x.expr == *indexed && x.op == BinaryOperator::Eq)
+        else {
+            break;
+        };
+        keys::encode_value(&condition.value, &mut prefix);
+        equalities += 1;
+    }
+    let (mut start, mut end) = (prefix.clone(), keys::prefix_end(&prefix));
+    let mut ranged = false;
+    if let Some(indexed) = index.columns.get(equalities) {
+        for condition in conditions.iter().filter(|x| x.expr == *indexed) {
+            let mut bound = prefix.clone();
+            keys::encode_value(&condition.value, &mut bound);
+            match condition.op {
+                BinaryOperator::Gt => start = start.max(keys::prefix_end(&bound)),
+                BinaryOperator::GtEq => start = start.max(bound),
+                BinaryOperator::Lt => end = end.min(bound),
+                BinaryOperator::LtEq => end = end.min(keys::prefix_end(&bound)),
+                _ => continue,
+            }
+            ranged = true;
+        }
+    }
+    let partial = index.predicate.is_some();
+    (
+        equalities * 2 + ranged as usize + partial as usize,
+        start,
+        end,
+    )
+}
+
+/// Rows found through the secondary index that narrows the filter down the most, `None` when no
+/// index helps. A partial index can only be used when the filter implies its predicate, otherwise
+/// it would be missing rows.
+fn index_lookup(
+    storage: &StorageEngine,
+    table: &str,
+    filter: &Expr,
+    metadata: &ColumnDescriptors,
+) -> anyhow::Result<Option<Vec<Record>>> {
+    let mut found = vec![];
+    conditions(filter, metadata, &mut found);
+    let indexes = storage.indexes(table)?;
+    let best = indexes
+        .iter()
+        .filter(|x| {
+            x.predicate
+                .as_ref()
+                .map_or(true, |predicate| expr::implies(filter, predicate))
+        })
+        .map(|x| (x, index_range(x, &found)))
+        .filter(|(_, (score, ..))| *score > 0)
+        .max_by_key(|(_, (score, ..))| *score);
+    let Some((index, (_, start, end))) = best else {
+        return Ok(None);
+    };
+    debug!("Scanning index {}", index.name);
+    Ok(Some(storage.index_scan(table, &start, &end)?))
+}
+
+#[instrument(skip_all, fields(table = %query.table))]
+pub fn select_rows(storage: &StorageEngine, query: &QueryOptions) -> anyhow::Result<Vec<Record>> {
+    let Some(filter) = &query.filter else {
+        return storage.scan_table_hinted(&query.table, query.scan);
+    };
+    let metadata = storage.table_metadata(&query.table)?;
+    expr::check(filter, &metadata)?;
+    let keys = primary_key_column(&metadata)
+        .ok()
+        .and_then(|pk| point_lookup(filter, pk));
+    let primary_key = storage.primary_key(&query.table)?;
+    let candidates = if let Some(mut keys) = keys {
+        debug!(keys = keys.len(), "Looking up rows by primary key");
+        // Rows are keyed by their encoded primary key, sorting by it gives the order a scan would
+        keys.sort_by_cached_key(|value| {
+            let mut key = vec![];
+            keys::encode_value(value, &mut key);
+            key
+        });
+        storage
+            .get_rows_by_pk(&query.table, &keys)?
+            .into_iter()
+            .flatten()
+            .collect()
+    } else if let Some(values) = key_values(filter, &metadata, &primary_key) {
+        debug!("Looking up a row by its primary key values");
+        storage
+            .get_row(&query.table, &values)?
+            .into_iter()
+            .collect()
+    } else {
+        match index_lookup(storage, &query.table, filter, &metadata)? {
+            Some(rows) => rows,
+            None => storage.scan_table_hinted(&query.table, query.scan)?,
+        }
+    };
+    let filter = expr::compile_predicate(filter)?;
+    let mut rows = vec![];
+    for row in candidates {
+        storage.check_deadline()?;
+        if filter(&row)? {
+            rows.push(row);
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys;
+    use crate::types::*;
+    use crate::Instance;
+    use std::rc::Rc;
+    use tempfile::tempdir;
+    use tracing_test::traced_test;
+
+    #[test]
+    #[traced_test]
+    fn select_star() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        instance
+            .execute("CREATE TABLE users (name TEXT NOT NULL, age INT)")
+            .unwrap();
+        instance
+            .execute("INSERT INTO users (name, age) VALUES ('Daniel', 30), ('Ben', NULL)")
+            .unwrap();
+
+        let result = instance.execute("SELECT * FROM Users").unwrap();
+        let names = result
+            .rows
+            .iter()
+            .map(|x| x.columns["name"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                Rc::new(Value::Text("Daniel".to_string())),
+                Rc::new(Value::Text("Ben".to_string()))
+            ]
+        );
+        // The hidden rowid isn't returned
+        assert_eq!(result.rows[0].columns.len(), 2);
+        assert_eq!(*result.rows[1].columns["age"], Value::Null);
+
+        let sequential = instance
+            .execute("SELECT * FROM users WITH (SEQUENTIAL)")
+            .unwrap();
+        assert_eq!(sequential.rows, result.rows);
+        assert!(instance
+            .execute("SELECT * FROM users WITH (NOLOCK)")
+            .is_err());
+
+        assert!(instance.execute("SELECT * FROM missing").is_err());
+        assert!(instance.execute("SELECT name FROM users").is_err());
+        assert!(instance
+            .execute("SELECT * FROM users JOIN users AS u ON true")
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn where_clause() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        instance
+            .execute("CREATE TABLE users (name TEXT NOT NULL, age INT)")
+            .unwrap();
+        instance
+            .execute(
+                "INSERT INTO users (name, age) VALUES ('Daniel', 30), ('Ben', NULL), ('Ann', 25)",
+            )
+            .unwrap();
+        let names = |instance: &mut Instance, sql: &str| {
+            instance
+                .execute(sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|x| x.columns["name"].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(
+                &mut instance,
+                "SELECT * FROM users WHERE age > 26 OR name = 'Ann'"
+            ),
+            vec!["'Daniel'", "'Ann'"]
+        );
+        assert_eq!(
+            names(&mut instance, "SELECT * FROM users WHERE NOT (age < 26)"),
+            vec!["'Daniel'"]
+        );
+        assert!(instance
+            .execute("SELECT * FROM users WHERE missing = 1")
+            .is_err());
+        assert!(instance
+            .execute("SELECT * FROM users WHERE name = 1")
+            .is_err());
+
+        let result = instance
+            .execute("DELETE FROM users WHERE age IS NULL")
+            .unwrap();
+        assert_eq!(result.rows_affected, 1);
+        assert_eq!(
+            names(&mut instance, "SELECT * FROM users"),
+            vec!["'Daniel'", "'Ann'"]
+        );
+        // Nothing is deleted when a row fails to compare
+        assert!(instance
+            .execute("DELETE FROM users WHERE age = 'x'")
+            .is_err());
+        assert!(instance
+            .validate("DELETE FROM users WHERE missing = 1")
+            .is_err());
+        let result = instance.execute("DELETE FROM users").unwrap();
+        assert_eq!(result.rows_affected, 2);
+        assert!(names(&mut instance, "SELECT * FROM users").is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn primary_key_lookups() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        instance
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);
+                 INSERT INTO users (id, name) VALUES (1, 'Daniel');
+                 CREATE TABLE posts (title TEXT);
+                 INSERT INTO posts (title) VALUES ('a'), ('b'), ('c');",
+            )
+            .unwrap();
+        let count = |instance: &mut Instance, sql: &str| instance.execute(sql).unwrap().rows.len();
+
+        assert_eq!(count(&mut instance, "SELECT * FROM users WHERE id = 1"), 1);
+        assert_eq!(
+            count(&mut instance, "SELECT * FROM users WHERE (id IN (2, 1, 1))"),
+            1
+        );
+        assert_eq!(count(&mut instance, "SELECT * FROM users WHERE id = 2"), 0);
+        assert_eq!(
+            count(&mut instance, "SELECT * FROM users WHERE id IN (NULL)"),
+            0
+        );
+
+        let rows = instance
+            .storage()
+            .get_rows_by_pk(
+                "posts",
+                &[
+                    Rc::new(Value::Number(3.into())),
+                    Rc::new(Value::Number(9.into())),
+                    Rc::new(Value::Number(1.into())),
+                ],
+            )
+            .unwrap();
+        let titles = rows
+            .iter()
+            .map(|x| x.as_ref().map(|x| x.columns["title"].to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            titles,
+            vec![Some("'c'".to_string()), None, Some("'a'".to_string())]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn composite_key_lookups() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        instance
+            .execute(
+                "CREATE TABLE stock (store INT, sku TEXT, count INT, PRIMARY KEY (store, sku));
+                 INSERT INTO stock (store, sku, count) VALUES (1, 'a', 5), (1, 'b', 0), \
+                 (2, 'a', 7);",
+            )
+            .unwrap();
+        let counts = |instance: &mut Instance, sql: &str| {
+            instance
+                .execute(sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|x| x.columns["count"].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            counts(
+                &mut instance,
+                "SELECT * FROM stock WHERE sku = 'a' AND store = 2"
+            ),
+            ["7"]
+        );
+        assert!(logs_contain("Looking up a row by its primary key values"));
+        // The rest of the filter is still checked
+        assert!(counts(
+            &mut instance,
+            "SELECT * FROM stock WHERE store = 1 AND sku = 'a' AND count > 5"
+        )
+        .is_empty());
+        assert!(counts(
+            &mut instance,
+            "SELECT * FROM stock WHERE store = 3 AND sku = 'a'"
+        )
+        .is_empty());
+        assert_eq!(
+            counts(&mut instance, "SELECT * FROM stock WHERE store = 1"),
+            ["5", "0"]
+        );
+
+        let storage = instance.storage();
+        let key =
+            |store: i32, sku: &str| [Value::Number(store.into()), Value::Text(sku.to_string())];
+        let row = storage.get_row("stock", &key(1, "b")).unwrap().unwrap();
+        assert_eq!(*row.columns["count"], Value::Number(0.into()));
+        assert_eq!(storage.get_row("stock", &key(2, "b")).unwrap(), None);
+        assert!(storage
+            .get_row("stock", &[Value::Number(1.into())])
+            .is_err());
+        assert!(storage.get_row("missing", &key(1, "a")).is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn index_lookups() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        instance
+            .execute(
+                "CREATE TABLE events (id INT PRIMARY KEY, a INT, b INT, kind TEXT);
+                 INSERT INTO events (id, a, b, kind) VALUES
+                     (1, 1, 3, 'x'), (2, 1, 5, 'y'), (3, 1, 7, 'x'), (4, 1, 9, 'y'),
+                     (5, 2, 6, 'x'), (6, 1, NULL, 'x'), (7, NULL, 8, 'y');
+                 CREATE INDEX ON events (a, b);",
+            )
+            .unwrap();
+        let ids = |instance: &mut Instance, sql: &str| {
+            let mut ids = instance
+                .execute(sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|x| x.columns["id"].to_string())
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids.join(",")
+        };
+
+        let cases = [
+            ("a = 1 AND b > 5", "3,4"),
+            ("a = 1 AND b >= 5", "2,3,4"),
+            ("(b < 7) AND 1 = a", "1,2"),
+            ("a = 1 AND b <= 7 AND b > 3", "2,3"),
+            ("a = 1 AND b > 7 AND b < 5", ""),
+            ("a = 1", "1,2,3,4,6"),
+            ("a = 1 AND b = 5", "2"),
+            ("a = 1 AND b > 4 AND kind = 'x'", "3"),
+            ("a > 1", "5"),
+            ("a = 1 AND b IS NULL", "6"),
+            // Conditions the index can't use are still checked
+            ("a = 1 OR b = 8", "1,2,3,4,6,7"),
+            ("b > 7", "4,7"),
+        ];
+        for (filter, expected) in cases {
+            let sql = format!("SELECT * FROM events WHERE {}", filter);
+            assert_eq!(ids(&mut instance, &sql), expected, "{}", filter);
+        }
+        assert!(logs_contain("Scanning index events_a_b_idx"));
+
+        // Entries follow the rows as they change
+        instance
+            .execute(
+                "UPDATE events SET b = 10 WHERE id = 1;
+                 UPDATE events SET id = 10 WHERE id = 3;
+                 DELETE FROM events WHERE id = 4;
+                 INSERT INTO events (id, a, b, kind) VALUES (8, 1, 6, 'z');",
+            )
+            .unwrap();
+        assert_eq!(
+            ids(&mut instance, "SELECT * FROM events WHERE a = 1 AND b > 5"),
+            "1,10,8"
+        );
+        assert_eq!(
+            ids(&mut instance, "SELECT * FROM events WHERE a = 1 AND b < 6"),
+            "2"
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn partial_index_lookups() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        instance
+            .execute(
+                "CREATE TABLE posts (id INT PRIMARY KEY, author INT, deleted BOOLEAN);
+                 INSERT INTO posts (id, author, deleted) VALUES
+                     (1, 1, false), (2, 1, true), (3, 2, false), (4, 1, NULL);
+                 CREATE INDEX live ON posts (author) WHERE deleted = false;",
+            )
+            .unwrap();
+        let ids = |instance: &mut Instance, sql: &str| {
+            let rows = instance.execute(sql).unwrap().rows;
+            let mut ids = rows
+                .iter()
+                .map(|x| x.columns["id"].to_string())
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids.join(",")
+        };
+
+        assert_eq!(
+            ids(
+                &mut instance,
+                "SELECT * FROM posts WHERE author = 1 AND deleted = false"
+            ),
+            "1"
+        );
+        assert!(logs_contain("Scanning index live"));
+        // Only the matching rows are indexed
+        let prefix = keys::index_prefix("live");
+        let indexed = instance
+            .storage()
+            .index_scan("posts", &prefix, &keys::prefix_end(&prefix))
+            .unwrap();
+        assert_eq!(indexed.len(), 2);
+        assert_eq!(
+            ids(&mut instance, "SELECT * FROM posts WHERE deleted = false"),
+            "1,3"
+        );
+        // Without the predicate the index would miss rows
+        assert_eq!(
+            ids(&mut instance, "SELECT * FROM posts WHERE author = 1"),
+            "1,2,4"
+        );
+
+        // Rows join and leave the index as they start and stop matching
+        instance
+            .execute(
+                "UPDATE posts SET deleted = true WHERE id = 1;
+                 UPDATE posts SET deleted = false WHERE id = 2;
+                 INSERT INTO posts (id, author, deleted) VALUES (5, 1, false), (6, 1, true);",
+            )
+            .unwrap();
+        let sql = "SELECT * FROM posts WHERE author = 1 AND deleted = false";
+        assert_eq!(ids(&mut instance, sql), "2,5");
+        instance.execute("DELETE FROM posts WHERE id = 5").unwrap();
+        assert_eq!(ids(&mut instance, sql), "2");
+    }
+
+    #[test]
+    #[traced_test]
+    fn expression_index_lookups() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        instance
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, email TEXT);
+                 INSERT INTO users (id, email) VALUES
+                     (1, 'Ann@Example.com'), (2, 'bob@example.com'), (3, 'ANN@example.com'),
+                     (4, NULL);
+                 CREATE INDEX ON users (LOWER(email));",
+            )
+            .unwrap();
+        let ids = |instance: &mut Instance, sql: &str| {
+            let rows = instance.execute(sql).unwrap().rows;
+            let mut ids = rows
+                .iter()
+                .map(|x| x.columns["id"].to_string())
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids.join(",")
+        };
+
+        assert_eq!(
+            ids(
+                &mut instance,
+                "SELECT * FROM users WHERE lower(email) = 'ann@example.com'"
+            ),
+            "1,3"
+        );
+        assert!(logs_contain("Scanning index users_lower_idx"));
+        // Entries hold the computed values
+        let mut prefix = keys::index_prefix("users_lower_idx");
+        keys::encode_value(&Value::Text("bob@example.com".into()), &mut prefix);
+        let indexed = instance
+            .storage()
+            .index_scan("users", &prefix, &keys::prefix_end(&prefix))
+            .unwrap();
+        assert_eq!(indexed.len(), 1);
+
+        // Entries follow the rows they were computed from
+        instance
+            .execute(
+                "UPDATE users SET email = 'Bob@Example.com' WHERE id = 3;
+                 INSERT INTO users (id, email) VALUES (5, 'ann@EXAMPLE.com');",
+            )
+            .unwrap();
+        let sql = "SELECT * FROM users WHERE 'ann@example.com' = lower(email)";
+        assert_eq!(ids(&mut instance, sql), "1,5");
+        assert_eq!(
+            ids(
+                &mut instance,
+                "SELECT * FROM users WHERE lower(email) >= 'b'"
+            ),
+            "2,3"
+        );
+
+        // Renaming the column keeps the index usable and dropping it drops the index
+        instance
+            .execute("ALTER TABLE users RENAME COLUMN email TO mail")
+            .unwrap();
+        let sql = "SELECT * FROM users WHERE lower(mail) = 'bob@example.com'";
+        assert_eq!(ids(&mut instance, sql), "2,3");
+        assert_eq!(
+            instance.storage().indexes("users").unwrap()[0].columns[0].to_string(),
+            "lower(\"mail\")"
+        );
+        instance
+            .execute("ALTER TABLE users DROP COLUMN mail")
+            .unwrap();
+        assert!(instance.storage().indexes("users").unwrap().is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn rows_in_key_order() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        instance
+            .execute(
+                "CREATE TABLE t (id INT PRIMARY KEY, tag TEXT);
+                 INSERT INTO t (id, tag) VALUES (3, 'a'), (-1, 'b'), (2, 'a'), (10, 'a');
+                 CREATE INDEX by_tag ON t (tag);
+                 CREATE TABLE log (tag TEXT, n INT);
+                 INSERT INTO log (tag, n) VALUES ('z', 1), ('a', 2), ('z', 3), ('a', 4);
+                 CREATE INDEX log_by_tag ON log (tag);",
+            )
+            .unwrap();
+        let column = |instance: &mut Instance, sql: &str, column: &str| {
+            instance
+                .execute(sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|x| x.columns[column].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Every access path gives the order a full scan does
+        let sql = "SELECT * FROM t WHERE id IN (10, 3, -1, 2)";
+        assert_eq!(column(&mut instance, sql, "id"), ["-1", "2", "3", "10"]);
+        assert!(logs_contain("Looking up rows by primary key"));
+        let sql = "SELECT * FROM t WHERE tag >= 'a'";
+        assert_eq!(column(&mut instance, sql, "id"), ["-1", "2", "3", "10"]);
+        assert!(logs_contain("Scanning index by_tag"));
+        let sql = "SELECT * FROM t WHERE tag = 'a' OR id > 0";
+        assert_eq!(column(&mut instance, sql, "id"), ["2", "3", "10"]);
+        // Without a primary key that's the order rows were inserted in
+        let sql = "SELECT * FROM log WHERE tag >= 'a'";
+        assert_eq!(column(&mut instance, sql, "n"), ["1", "2", "3", "4"]);
+        assert!(logs_contain("Scanning index log_by_tag"));
+    }
+}
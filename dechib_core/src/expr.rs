@@ -0,0 +1,627 @@
+//! Evaluating expressions against a row, which is how `WHERE` clauses filter rows. Comparisons
+//! follow SQL's three valued logic, comparing anything with NULL gives NULL and a row only
+//! matches a predicate that's true. Statements that evaluate an expression for every row they
+//! scan [`compile`] it first.
+//!
+//! The only arithmetic is adding an interval to a timestamp, see [`crate::interval`]. The only
+//! functions are `lower` and `upper`, which only depend on their argument so they can be indexed.
+use crate::interval::Interval;
+use crate::storage_engine::SYSTEM_PREFIX;
+use crate::types::*;
+use sqlparser::ast::{
+    BinaryOperator, Expr, Function, FunctionArg, FunctionArgExpr, FunctionArguments, Ident,
+    ObjectName, UnaryOperator,
+};
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+/// The column an expression refers to, if it's just a column.
+pub(crate) fn column_name(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Identifier(ident) => Some(normalize_ident(ident)),
+        // There's only ever one table so any qualifier can be ignored
+        Expr::CompoundIdentifier(idents) => idents.last().map(normalize_ident),
+        _ => None,
+    }
+}
+
+/// Calls `f` with every column reference in the expression.
+pub(crate) fn for_each_column(expr: &mut Expr, f: &mut impl FnMut(&mut Ident)) {
+    match expr {
+        Expr::Identifier(ident) => f(ident),
+        Expr::CompoundIdentifier(idents) => {
+            if let Some(ident) = idents.last_mut() {
+                f(ident);
+            }
+        }
+        Expr::Nested(inner)
+        | Expr::IsNull(inner)
+        | Expr::IsNotNull(inner)
+        | Expr::UnaryOp { expr: inner, .. } => for_each_column(inner, f),
+        Expr::InList { expr, list, .. } => {
+            for_each_column(expr, f);
+            list.iter_mut().for_each(|x| for_each_column(x, f));
+        }
+        Expr::BinaryOp { left, right, .. } => {
+            for_each_column(left, f);
+            for_each_column(right, f);
+        }
+        Expr::Function(function) => {
+            if let FunctionArguments::List(list) = &mut function.args {
+                for arg in &mut list.args {
+                    if let FunctionArg::Unnamed(FunctionArgExpr::Expr(arg)) = arg {
+                        for_each_column(arg, f);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The columns an expression refers to, in the order they first appear.
+pub(crate) fn columns(expr: &Expr) -> Vec<String> {
+    let mut res = vec![];
+    for_each_column(&mut expr.clone(), &mut |ident| {
+        let column = normalize_ident(ident);
+        if !res.contains(&column) {
+            res.push(column);
+        }
+    });
+    res
+}
+
+/// Points references to `column` at `to` instead.
+pub(crate) fn rename_column(expr: &mut Expr, column: &str, to: &str) {
+    for_each_column(expr, &mut |ident| {
+        if normalize_ident(ident) == column {
+            *ident = Ident::with_quote('"', to);
+        }
+    });
+}
+
+/// The expression with column references and a function's name normalized, so expressions
+/// compare equal however they were written.
+pub(crate) fn normalized(expr: &Expr) -> Expr {
+    let mut expr = expr.clone();
+    for_each_column(&mut expr, &mut |ident| {
+        *ident = Ident::with_quote('"', normalize_ident(ident));
+    });
+    if let Expr::Function(function) = &mut expr {
+        function.name = ObjectName(vec![Ident::new(normalize_object_name(&function.name))]);
+    }
+    expr
+}
+
+/// The conditions `AND`ed together at the top of an expression, [`normalized`].
+fn conjuncts(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Nested(inner) => conjuncts(inner),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            let mut res = conjuncts(left);
+            res.extend(conjuncts(right));
+            res
+        }
+        expr => vec![normalized(expr)],
+    }
+}
+
+/// Whether every row matching `filter` is sure to match `predicate`, which is taken to be the
+/// case when each condition of the predicate is also one of the filter's. A filter that implies
+/// the predicate some other way isn't recognized.
+pub(crate) fn implies(filter: &Expr, predicate: &Expr) -> bool {
+    let filter = conjuncts(filter);
+    conjuncts(predicate).iter().all(|x| filter.contains(x))
+}
+
+fn is_comparison(op: &BinaryOperator) -> bool {
+    matches!(
+        op,
+        BinaryOperator::Eq
+            | BinaryOperator::NotEq
+            | BinaryOperator::Lt
+            | BinaryOperator::LtEq
+            | BinaryOperator::Gt
+            | BinaryOperator::GtEq
+    )
+}
+
+type ScalarFunction = fn(&Value) -> anyhow::Result<Value>;
+
+fn text_function(value: &Value, f: impl Fn(&str) -> String) -> anyhow::Result<Value> {
+    match value {
+        Value::Null => Ok(Value::Null),
+        Value::Text(text) => Ok(Value::Text(f(text))),
+        v => anyhow::bail!("Expected text, got {}", v),
+    }
+}
+
+/// Splits a call to one of the functions into the function and its argument.
+fn scalar_call(function: &Function) -> anyhow::Result<(ScalarFunction, &Expr)> {
+    let name = normalize_object_name(&function.name);
+    let f: ScalarFunction = match name.as_str() {
+        "lower" => |x| text_function(x, str::to_lowercase),
+        "upper" => |x| text_function(x, str::to_uppercase),
+        _ => anyhow::bail!("Unknown function {}", name),
+    };
+    let args = match &function.args {
+        FunctionArguments::List(list) if function.over.is_none() => list.args.as_slice(),
+        _ => &[],
+    };
+    match args {
+        [FunctionArg::Unnamed(FunctionArgExpr::Expr(arg))] => Ok((f, arg)),
+        _ => anyhow::bail!("{} takes a single argument", name),
+    }
+}
+
+/// Splits `<timestamp> + <interval>`, `<interval> + <timestamp>` or `<timestamp> - <interval>`
+/// into the timestamp and the interval to add to it.
+fn interval_arithmetic<'a>(
+    left: &'a Expr,
+    op: &BinaryOperator,
+    right: &'a Expr,
+) -> anyhow::Result<(&'a Expr, Interval)> {
+    match (op, left, right) {
+        (BinaryOperator::Plus, timestamp, Expr::Interval(interval))
+        | (BinaryOperator::Plus, Expr::Interval(interval), timestamp) => {
+            Ok((timestamp, Interval::parse(interval)?))
+        }
+        (BinaryOperator::Minus, timestamp, Expr::Interval(interval)) => {
+            Ok((timestamp, Interval::parse(interval)?.negate()))
+        }
+        _ => anyhow::bail!("Unsupported expression: {} {} {}", left, op, right),
+    }
+}
+
+/// Checks the columns `expr` refers to exist and that it only uses supported syntax, so a bad
+/// expression fails before any rows are read.
+pub fn check(expr: &Expr, columns: &ColumnDescriptors) -> anyhow::Result<()> {
+    if let Some(column) = column_name(expr) {
+        if column.starts_with(SYSTEM_PREFIX) || !columns.contains_key(&column) {
+            anyhow::bail!("Column {} does not exist", column);
+        }
+        return Ok(());
+    }
+    match expr {
+        Expr::Value(value) => Value::try_from(value.clone()).map(|_| ()),
+        Expr::Nested(inner) | Expr::IsNull(inner) | Expr::IsNotNull(inner) => check(inner, columns),
+        Expr::InList { expr, list, .. } => {
+            check(expr, columns)?;
+            list.iter().try_for_each(|x| check(x, columns))
+        }
+        Expr::UnaryOp {
+            op: UnaryOperator::Not | UnaryOperator::Minus | UnaryOperator::Plus,
+            expr,
+        } => check(expr, columns),
+        Expr::BinaryOp { left, op, right }
+            if is_comparison(op) || matches!(op, BinaryOperator::And | BinaryOperator::Or) =>
+        {
+            check(left, columns)?;
+            check(right, columns)
+        }
+        Expr::BinaryOp {
+            left,
+            op: op @ (BinaryOperator::Plus | BinaryOperator::Minus),
+            right,
+        } => check(interval_arithmetic(left, op, right)?.0, columns),
+        Expr::Function(function) => check(scalar_call(function)?.1, columns),
+        e => anyhow::bail!("Unsupported expression: {}", e),
+    }
+}
+
+/// `None` for NULL, anything other than a boolean is an error.
+fn truth(value: &Value) -> anyhow::Result<Option<bool>> {
+    match value {
+        Value::Boolean(b) => Ok(Some(*b)),
+        Value::Null => Ok(None),
+        v => anyhow::bail!("{} is not a boolean", v),
+    }
+}
+
+fn compare(left: &Value, right: &Value) -> anyhow::Result<Option<Ordering>> {
+    let ordering = match (left, right) {
+        (Value::Null, _) | (_, Value::Null) => return Ok(None),
+        (Value::Number(a), Value::Number(b)) => a.cmp(b),
+        (Value::Text(a), Value::Text(b)) => a.cmp(b),
+        (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+        (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+        (a, b) => anyhow::bail!("Can't compare {} with {}", a, b),
+    };
+    Ok(Some(ordering))
+}
+
+fn in_list(
+    value: &Value,
+    list: impl Iterator<Item = anyhow::Result<Rc<Value>>>,
+    negated: bool,
+) -> anyhow::Result<Value> {
+    let mut res = Some(false);
+    for item in list {
+        match compare(value, &item?)? {
+            Some(Ordering::Equal) => {
+                res = Some(true);
+                break;
+            }
+            None => res = None,
+            Some(_) => {}
+        }
+    }
+    Ok(match res {
+        Some(found) => Value::Boolean(found != negated),
+        None => Value::Null,
+    })
+}
+
+fn unary(op: UnaryOperator, value: Rc<Value>) -> anyhow::Result<Rc<Value>> {
+    let value = match (op, value.as_ref()) {
+        (_, Value::Null) => Value::Null,
+        (UnaryOperator::Not, Value::Boolean(b)) => Value::Boolean(!b),
+        (UnaryOperator::Minus, Value::Number(n)) => Value::Number(-n),
+        (UnaryOperator::Plus, Value::Number(_)) => return Ok(value),
+        (op, v) => anyhow::bail!("Can't apply {} to {}", op, v),
+    };
+    Ok(Rc::new(value))
+}
+
+/// `AND` or `OR`
+fn logical(op: &BinaryOperator, left: &Value, right: &Value) -> anyhow::Result<Value> {
+    let res = match (op, truth(left)?, truth(right)?) {
+        (BinaryOperator::And, Some(false), _) | (BinaryOperator::And, _, Some(false)) => {
+            Some(false)
+        }
+        (BinaryOperator::Or, Some(true), _) | (BinaryOperator::Or, _, Some(true)) => Some(true),
+        (_, Some(a), Some(b)) => Some(a && b),
+        _ => None,
+    };
+    Ok(res.map_or(Value::Null, Value::Boolean))
+}
+
+fn comparison(op: &BinaryOperator, left: &Value, right: &Value) -> anyhow::Result<Value> {
+    Ok(match compare(left, right)? {
+        None => Value::Null,
+        Some(ordering) => Value::Boolean(match op {
+            BinaryOperator::Eq => ordering == Ordering::Equal,
+            BinaryOperator::NotEq => ordering != Ordering::Equal,
+            BinaryOperator::Lt => ordering == Ordering::Less,
+            BinaryOperator::LtEq => ordering != Ordering::Greater,
+            BinaryOperator::Gt => ordering == Ordering::Greater,
+            _ => ordering != Ordering::Less,
+        }),
+    })
+}
+
+/// Evaluates `expr` with column references taken from `record`. Columns missing from the record
+/// are NULL.
+pub fn evaluate(expr: &Expr, record: &Record) -> anyhow::Result<Rc<Value>> {
+    if let Some(column) = column_name(expr) {
+        return Ok(record
+            .columns
+            .get(&column)
+            .cloned()
+            .unwrap_or_else(|| Rc::new(Value::Null)));
+    }
+    let value = match expr {
+        Expr::Value(value) => Value::try_from(value.clone())?,
+        Expr::Nested(inner) => return evaluate(inner, record),
+        Expr::IsNull(inner) => Value::Boolean(*evaluate(inner, record)? == Value::Null),
+        Expr::IsNotNull(inner) => Value::Boolean(*evaluate(inner, record)? != Value::Null),
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let value = evaluate(expr, record)?;
+            in_list(&value, list.iter().map(|x| evaluate(x, record)), *negated)?
+        }
+        Expr::UnaryOp { op, expr } => return unary(*op, evaluate(expr, record)?),
+        Expr::BinaryOp {
+            left,
+            op: op @ (BinaryOperator::And | BinaryOperator::Or),
+            right,
+        } => logical(op, &evaluate(left, record)?, &evaluate(right, record)?)?,
+        Expr::BinaryOp { left, op, right } if is_comparison(op) => {
+            comparison(op, &evaluate(left, record)?, &evaluate(right, record)?)?
+        }
+        Expr::BinaryOp {
+            left,
+            op: op @ (BinaryOperator::Plus | BinaryOperator::Minus),
+            right,
+        } => {
+            let (timestamp, interval) = interval_arithmetic(left, op, right)?;
+            interval.add(&evaluate(timestamp, record)?)?
+        }
+        Expr::Function(function) => {
+            let (f, arg) = scalar_call(function)?;
+            f(&evaluate(arg, record)?)?
+        }
+        e => anyhow::bail!("Unsupported expression: {}", e),
+    };
+    Ok(Rc::new(value))
+}
+
+/// An expression turned into a closure by [`compile`].
+pub type Compiled = Box<dyn Fn(&Record) -> anyhow::Result<Rc<Value>>>;
+
+/// Turns an expression into a closure that evaluates it the same way [`evaluate`] does. Column
+/// names are normalized, literals converted and operators picked once rather than for every row,
+/// which is most of the work of filtering a large scan.
+pub fn compile(expr: &Expr) -> anyhow::Result<Compiled> {
+    if let Some(column) = column_name(expr) {
+        return Ok(Box::new(move |record| {
+            Ok(record
+                .columns
+                .get(&column)
+                .cloned()
+                .unwrap_or_else(|| Rc::new(Value::Null)))
+        }));
+    }
+    let compiled: Compiled = match expr {
+        Expr::Value(value) => {
+            let value = Rc::new(Value::try_from(value.clone())?);
+            Box::new(move |_| Ok(value.clone()))
+        }
+        Expr::Nested(inner) => return compile(inner),
+        Expr::IsNull(inner) => {
+            let inner = compile(inner)?;
+            Box::new(move |record| Ok(Rc::new(Value::Boolean(*inner(record)? == Value::Null))))
+        }
+        Expr::IsNotNull(inner) => {
+            let inner = compile(inner)?;
+            Box::new(move |record| Ok(Rc::new(Value::Boolean(*inner(record)? != Value::Null))))
+        }
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let expr = compile(expr)?;
+            let list = list
+                .iter()
+                .map(compile)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let negated = *negated;
+            Box::new(move |record| {
+                let value = expr(record)?;
+                let list = list.iter().map(|x| x(record));
+                Ok(Rc::new(in_list(&value, list, negated)?))
+            })
+        }
+        Expr::UnaryOp { op, expr } => {
+            let (op, expr) = (*op, compile(expr)?);
+            Box::new(move |record| unary(op, expr(record)?))
+        }
+        Expr::BinaryOp {
+            left,
+            op: op @ (BinaryOperator::And | BinaryOperator::Or),
+            right,
+        } => {
+            let (left, op, right) = (compile(left)?, op.clone(), compile(right)?);
+            Box::new(move |record| Ok(Rc::new(logical(&op, &left(record)?, &right(record)?)?)))
+        }
+        Expr::BinaryOp { left, op, right } if is_comparison(op) => {
+            let (left, op, right) = (compile(left)?, op.clone(), compile(right)?);
+            Box::new(move |record| Ok(Rc::new(comparison(&op, &left(record)?, &right(record)?)?)))
+        }
+        Expr::BinaryOp {
+            left,
+            op: op @ (BinaryOperator::Plus | BinaryOperator::Minus),
+            right,
+        } => {
+            let (timestamp, interval) = interval_arithmetic(left, op, right)?;
+            let timestamp = compile(timestamp)?;
+            Box::new(move |record| Ok(Rc::new(interval.add(&timestamp(record)?)?)))
+        }
+        Expr::Function(function) => {
+            let (f, arg) = scalar_call(function)?;
+            let arg = compile(arg)?;
+            Box::new(move |record| Ok(Rc::new(f(&arg(record)?)?)))
+        }
+        e => anyhow::bail!("Unsupported expression: {}", e),
+    };
+    Ok(compiled)
+}
+
+/// Compiles a `WHERE` predicate, the closure returns whether a row [`matches`] it.
+pub fn compile_predicate(
+    predicate: &Expr,
+) -> anyhow::Result<impl Fn(&Record) -> anyhow::Result<bool>> {
+    let compiled = compile(predicate)?;
+    Ok(move |record: &Record| Ok(truth(&compiled(record)?)? == Some(true)))
+}
+
+/// Whether the row matches a `WHERE` predicate, NULL doesn't match.
+pub fn matches(predicate: &Expr, record: &Record) -> anyhow::Result<bool> {
+    Ok(truth(&evaluate(predicate, record)?)? == Some(true))
+}
+
+/// Whether the row satisfies a `CHECK` constraint. Unlike a predicate, NULL passes.
+pub fn satisfies(check: &Expr, record: &Record) -> anyhow::Result<bool> {
+    Ok(truth(&evaluate(check, record)?)? != Some(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::ast::DataType;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+    use std::collections::BTreeMap;
+
+    fn parse(sql: &str) -> Expr {
+        Parser::new(&GenericDialect {})
+            .try_with_sql(sql)
+            .unwrap()
+            .parse_expr()
+            .unwrap()
+    }
+
+    fn row() -> Record {
+        let columns = [
+            ("name", Value::Text("Daniel".to_string())),
+            ("age", Value::Number(30.into())),
+            ("admin", Value::Boolean(false)),
+            ("email", Value::Null),
+        ];
+        Record {
+            columns: columns
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), Rc::new(v)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn predicates() {
+        let row = row();
+        let matching = [
+            "name = 'Daniel'",
+            "age >= 30 AND age < 31",
+            "NOT admin",
+            "email IS NULL",
+            "users.name <> 'Ben' OR email = 'x'",
+            "(age > -1)",
+            "email = 'x' OR TRUE",
+            "age IN (1, 30)",
+            "name NOT IN ('Ben')",
+            "lower(name) = 'daniel'",
+            "UPPER(users.name) = 'DANIEL'",
+        ];
+        for sql in matching {
+            assert!(matches(&parse(sql), &row).unwrap(), "{}", sql);
+            assert!(
+                compile_predicate(&parse(sql)).unwrap()(&row).unwrap(),
+                "{}",
+                sql
+            );
+        }
+        let not_matching = [
+            "name = 'Ben'",
+            "email = 'x'",
+            "NOT (email = 'x')",
+            "email = 'x' AND TRUE",
+            "missing IS NOT NULL",
+            "age IN (1, NULL)",
+            "age NOT IN (1, NULL)",
+            "email IN ('x')",
+            "lower(email) = 'x'",
+        ];
+        for sql in not_matching {
+            assert!(!matches(&parse(sql), &row).unwrap(), "{}", sql);
+            assert!(
+                !compile_predicate(&parse(sql)).unwrap()(&row).unwrap(),
+                "{}",
+                sql
+            );
+        }
+        assert_eq!(
+            *evaluate(&parse("email = 'x' AND FALSE"), &row).unwrap(),
+            Value::Boolean(false)
+        );
+        assert!(matches(&parse("name = 1"), &row).is_err());
+        assert!(compile_predicate(&parse("name = 1")).unwrap()(&row).is_err());
+        assert_eq!(
+            *compile(&parse("-age")).unwrap()(&row).unwrap(),
+            Value::Number((-30).into())
+        );
+        assert!(matches(&parse("age"), &row).is_err());
+        assert!(matches(&parse("lower(age) = 'x'"), &row).is_err());
+    }
+
+    #[test]
+    fn checking() {
+        let columns = BTreeMap::from([
+            (
+                "name".to_string(),
+                ColumnDescriptor {
+                    datatype: DataType::Text,
+                    ..Default::default()
+                },
+            ),
+            (ROWID_COLUMN.to_string(), ColumnDescriptor::rowid()),
+        ]);
+        assert!(check(&parse("name = 'a' OR name IS NULL"), &columns).is_ok());
+        assert!(check(&parse("missing = 1"), &columns).is_err());
+        assert!(check(&parse(&format!("{} = 1", ROWID_COLUMN)), &columns).is_err());
+        assert!(check(&parse("name LIKE 'a%'"), &columns).is_err());
+        assert!(check(&parse("'a' < name + INTERVAL '1 day'"), &columns).is_ok());
+        assert!(check(&parse("'a' < name + INTERVAL '1 fortnight'"), &columns).is_err());
+        assert!(check(&parse("INTERVAL '1 day' - name > 'a'"), &columns).is_err());
+        assert!(check(&parse("name + 1 > 'a'"), &columns).is_err());
+        assert!(check(&parse("lower(name) = 'a'"), &columns).is_ok());
+        assert!(check(&parse("lower(missing) = 'a'"), &columns).is_err());
+        assert!(check(&parse("lower(name, name) = 'a'"), &columns).is_err());
+        assert!(check(&parse("coalesce(name) = 'a'"), &columns).is_err());
+    }
+
+    #[test]
+    fn interval_arithmetic() {
+        let row = Record {
+            columns: BTreeMap::from([
+                (
+                    "joined".to_string(),
+                    Rc::new(Value::Text("2024-01-31 10:00:00".to_string())),
+                ),
+                ("quit".to_string(), Rc::new(Value::Null)),
+            ]),
+        };
+        let cases = [
+            ("joined + INTERVAL '1 month'", "'2024-02-29 10:00:00'"),
+            ("joined + INTERVAL '2 hours'", "'2024-01-31 12:00:00'"),
+            (
+                "(joined - INTERVAL '1 day') - INTERVAL '1 day'",
+                "'2024-01-29 10:00:00'",
+            ),
+            ("'2024-01-01' + INTERVAL '1' DAY", "'2024-01-02 00:00:00'"),
+            ("quit + INTERVAL '1 day'", "NULL"),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(evaluate(&parse(sql), &row).unwrap().to_string(), expected);
+            assert_eq!(
+                compile(&parse(sql)).unwrap()(&row).unwrap().to_string(),
+                expected
+            );
+        }
+        assert!(matches(&parse("'2024-02-01' < joined + INTERVAL '1 day'"), &row).unwrap());
+        assert!(evaluate(&parse("1 + INTERVAL '1 day'"), &row).is_err());
+    }
+
+    #[test]
+    fn check_constraints() {
+        let row = row();
+        assert!(satisfies(&parse("age > 18"), &row).unwrap());
+        assert!(!satisfies(&parse("age > 40"), &row).unwrap());
+        // Comparing with NULL gives NULL, which a check lets through
+        assert!(satisfies(&parse("email <> 'x'"), &row).unwrap());
+
+        let mut expr = parse("age > 18 AND (users.age < 100 OR name IN (email))");
+        assert_eq!(columns(&expr), ["age", "name", "email"]);
+        rename_column(&mut expr, "age", "Years");
+        assert_eq!(columns(&expr), ["Years", "name", "email"]);
+        assert_eq!(columns(&parse("lower(email) = name")), ["email", "name"]);
+    }
+
+    #[test]
+    fn implication() {
+        let predicate = parse("deleted = false AND age > 18");
+        assert!(implies(
+            &parse("name = 'x' AND (Age > 18 AND DELETED = false)"),
+            &predicate
+        ));
+        assert!(implies(
+            &parse("deleted = false"),
+            &parse("(deleted = false)")
+        ));
+        assert!(!implies(&parse("deleted = false"), &predicate));
+        assert!(!implies(&parse("deleted = false OR age > 18"), &predicate));
+        // Only conditions written the same way are recognized
+        assert!(!implies(&parse("deleted = false AND age > 20"), &predicate));
+        assert!(implies(
+            &parse("LOWER(Email) = 'x'"),
+            &parse("lower(email) = 'x'")
+        ));
+    }
+}
@@ -0,0 +1,144 @@
+//! Functions that can be called from `DEFAULT` clauses. A handful are built in and applications
+//! can register their own. Function names are case insensitive.
+use crate::types::Value;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub type Function = Box<dyn Fn(&[Value]) -> anyhow::Result<Value> + Send + Sync>;
+
+/// Calls to this aren't looked up in the registry, the argument names a sequence and every call
+/// returns its next value.
+pub const NEXTVAL: &str = "nextval";
+
+pub struct FunctionRegistry {
+    functions: BTreeMap<String, Function>,
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        let mut res = Self {
+            functions: BTreeMap::new(),
+        };
+        res.register("now", |_| Ok(current_timestamp()));
+        res.register("current_timestamp", |_| Ok(current_timestamp()));
+        res.register("gen_random_uuid", |_| {
+            Ok(Value::Text(Uuid::new_v4().to_string()))
+        });
+        res
+    }
+}
+
+impl FunctionRegistry {
+    /// Adds a function, replacing any existing function with the same name.
+    pub fn register(
+        &mut self,
+        name: &str,
+        function: impl Fn(&[Value]) -> anyhow::Result<Value> + Send + Sync + 'static,
+    ) {
+        self.functions
+            .insert(name.to_lowercase(), Box::new(function));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.functions.get(&name.to_lowercase())
+    }
+}
+
+/// The current time in UTC as `YYYY-MM-DD HH:MM:SS`.
+pub fn current_timestamp() -> Value {
+    Value::Text(format_timestamp(unix_now() as i64))
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+/// Formats seconds since the unix epoch as `YYYY-MM-DD HH:MM:SS`.
+pub(crate) fn format_timestamp(secs: i64) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let rem = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Year, month and day of a count of days since the unix epoch, using the civil calendar
+/// conversion from <http://howardhinnant.github.io/date_algorithms.html>.
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Days since the unix epoch of a date in the civil calendar, the inverse of
+/// [`civil_from_days`].
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+pub(crate) fn days_in_month(year: i64, month: i64) -> i64 {
+    let next = if month == 12 {
+        days_from_civil(year + 1, 1, 1)
+    } else {
+        days_from_civil(year, month + 1, 1)
+    };
+    next - days_from_civil(year, month, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1704067199), "2023-12-31 23:59:59");
+        assert_eq!(format_timestamp(-1), "1969-12-31 23:59:59");
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 2, 29) * 86400, 951782400);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(days_in_month(2000, 2), 29);
+        assert_eq!(days_in_month(1900, 2), 28);
+        assert_eq!(days_in_month(1999, 12), 31);
+    }
+
+    #[test]
+    fn registry() {
+        let mut registry = FunctionRegistry::default();
+        assert!(registry.get("NOW").is_some());
+        assert!(registry.get("double").is_none());
+        registry.register("Double", |args| match args {
+            [Value::Number(n)] => Ok(Value::Number(n + n)),
+            _ => anyhow::bail!("double takes a number"),
+        });
+        let double = registry.get("double").unwrap();
+        assert_eq!(
+            double(&[Value::Number(2.into())]).unwrap(),
+            Value::Number(4.into())
+        );
+    }
+}
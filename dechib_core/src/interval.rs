@@ -0,0 +1,226 @@
+//! `INTERVAL` literals and adding them to timestamps, `at + INTERVAL '1 day'`. Timestamps are text
+//! so adding an interval parses the timestamp, shifts it and formats it back in the
+//! `YYYY-MM-DD HH:MM:SS` form timestamps are stored in.
+//!
+//! Months and years don't have a fixed length so they're added to the calendar date, landing on
+//! the last day of the month when the day doesn't exist, like Postgres. Everything else is a
+//! fixed number of seconds.
+use crate::functions::{civil_from_days, days_from_civil, days_in_month, format_timestamp};
+use crate::timezone::{parse_timestamp, TimeZone};
+use crate::types::Value;
+use anyhow::Context;
+use sqlparser::ast::{self, Expr};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Interval {
+    months: i64,
+    seconds: i64,
+}
+
+impl Interval {
+    /// Parses `INTERVAL '<n> <unit> [<n> <unit>...]'`, where a unit can also be written as
+    /// `HH:MM[:SS]`, or `INTERVAL '<n>' <unit>`.
+    pub fn parse(interval: &ast::Interval) -> anyhow::Result<Self> {
+        if interval.last_field.is_some() {
+            anyhow::bail!("Interval ranges like YEAR TO MONTH are not supported");
+        }
+        let value = match interval.value.as_ref() {
+            Expr::Value(ast::Value::SingleQuotedString(s)) => s.clone(),
+            Expr::Value(ast::Value::Number(n, _)) => n.to_string(),
+            e => anyhow::bail!("Invalid interval {}", e),
+        };
+        let text = match &interval.leading_field {
+            Some(field) => format!("{} {}", value, field),
+            None => value,
+        };
+        Self::parse_text(&text).with_context(|| format!("Invalid interval '{}'", text))
+    }
+
+    fn parse_text(text: &str) -> anyhow::Result<Self> {
+        let mut res = Self::default();
+        let mut tokens = text.split_whitespace();
+        let mut empty = true;
+        while let Some(token) = tokens.next() {
+            empty = false;
+            if token.contains(':') {
+                res.seconds = res
+                    .seconds
+                    .checked_add(parse_time(token)?)
+                    .context("Interval out of range")?;
+                continue;
+            }
+            let n = token.parse::<i64>()?;
+            let unit = tokens.next().context("Missing unit")?.to_lowercase();
+            let (months, seconds) = match unit.trim_end_matches('s') {
+                "year" => (12, 0),
+                "mon" | "month" => (1, 0),
+                "week" => (0, 7 * 86400),
+                "day" => (0, 86400),
+                "hour" => (0, 3600),
+                "min" | "minute" => (0, 60),
+                "sec" | "second" => (0, 1),
+                _ => anyhow::bail!("Unknown unit {}", unit),
+            };
+            res.months = n
+                .checked_mul(months)
+                .and_then(|x| x.checked_add(res.months))
+                .context("Interval out of range")?;
+            res.seconds = n
+                .checked_mul(seconds)
+                .and_then(|x| x.checked_add(res.seconds))
+                .context("Interval out of range")?;
+        }
+        if empty {
+            anyhow::bail!("Interval is empty");
+        }
+        Ok(res)
+    }
+
+    pub fn negate(self) -> Self {
+        Self {
+            months: -self.months,
+            seconds: -self.seconds,
+        }
+    }
+
+    /// Shifts seconds since the unix epoch by the interval.
+    pub fn add_to(&self, secs: i64) -> anyhow::Result<i64> {
+        let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+        let months = (year * 12 + month - 1)
+            .checked_add(self.months)
+            .context("Timestamp out of range")?;
+        let (year, month) = (months.div_euclid(12), months.rem_euclid(12) + 1);
+        check_year(year)?;
+        let day = day.min(days_in_month(year, month));
+        let secs = (days_from_civil(year, month, day) * 86400 + secs.rem_euclid(86400))
+            .checked_add(self.seconds)
+            .context("Timestamp out of range")?;
+        check_year(civil_from_days(secs.div_euclid(86400)).0)?;
+        Ok(secs)
+    }
+
+    /// Adds the interval to a timestamp, NULL stays NULL.
+    pub fn add(&self, value: &Value) -> anyhow::Result<Value> {
+        match value {
+            Value::Null => Ok(Value::Null),
+            Value::Text(text) => {
+                let secs = parse_timestamp(text, TimeZone::UTC)?;
+                Ok(Value::Text(format_timestamp(self.add_to(secs)?)))
+            }
+            v => anyhow::bail!("Can't add an interval to {}", v),
+        }
+    }
+}
+
+/// Timestamps are written with four digit years.
+fn check_year(year: i64) -> anyhow::Result<()> {
+    if !(0..=9999).contains(&year) {
+        anyhow::bail!("Timestamp out of range");
+    }
+    Ok(())
+}
+
+/// `[-]HH:MM[:SS]` in seconds.
+fn parse_time(token: &str) -> anyhow::Result<i64> {
+    let (sign, time) = match token.strip_prefix('-') {
+        Some(time) => (-1, time),
+        None => (1, token),
+    };
+    let mut secs = 0i64;
+    let mut parts = 0;
+    for (part, scale) in time.split(':').zip([3600, 60, 1]) {
+        secs += part.parse::<u32>().map(i64::from)? * scale;
+        parts += 1;
+    }
+    if parts < 2 || time.split(':').count() > 3 {
+        anyhow::bail!("Invalid time {}", token);
+    }
+    Ok(sign * secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn interval(sql: &str) -> anyhow::Result<Interval> {
+        let expr = Parser::new(&GenericDialect {})
+            .try_with_sql(sql)?
+            .parse_expr()?;
+        let Expr::Interval(interval) = expr else {
+            panic!("{} isn't an interval", sql);
+        };
+        Interval::parse(&interval)
+    }
+
+    fn add(timestamp: &str, sql: &str) -> String {
+        let value = Value::Text(timestamp.to_string());
+        interval(sql).unwrap().add(&value).unwrap().to_string()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            interval("INTERVAL '1 day 2 hours'").unwrap(),
+            Interval {
+                months: 0,
+                seconds: 93600
+            }
+        );
+        assert_eq!(
+            interval("INTERVAL '1 year -2 months 01:30'").unwrap(),
+            Interval {
+                months: 10,
+                seconds: 5400
+            }
+        );
+        assert_eq!(
+            interval("INTERVAL '3' WEEK").unwrap(),
+            Interval {
+                months: 0,
+                seconds: 21 * 86400
+            }
+        );
+        assert!(interval("INTERVAL ''").is_err());
+        assert!(interval("INTERVAL '1 fortnight'").is_err());
+        assert!(interval("INTERVAL '1'").is_err());
+        assert!(interval("INTERVAL '1-2' YEAR TO MONTH").is_err());
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(
+            add("2024-02-28 23:00:00", "INTERVAL '2 hours'"),
+            "'2024-02-29 01:00:00'"
+        );
+        assert_eq!(
+            add("2024-01-31 12:00:00", "INTERVAL '1 month'"),
+            "'2024-02-29 12:00:00'"
+        );
+        assert_eq!(
+            add("2024-03-31 00:00:00", "INTERVAL '-1 month -1 day'"),
+            "'2024-02-28 00:00:00'"
+        );
+        // Offsets are applied, the result is in UTC
+        assert_eq!(
+            add("2024-01-01 00:00:00+02:00", "INTERVAL '1 day'"),
+            "'2024-01-01 22:00:00'"
+        );
+        assert_eq!(
+            interval("INTERVAL '1 day'")
+                .unwrap()
+                .add(&Value::Null)
+                .unwrap(),
+            Value::Null
+        );
+        assert!(interval("INTERVAL '1 day'")
+            .unwrap()
+            .add(&Value::Number(1.into()))
+            .is_err());
+        assert!(interval("INTERVAL '9000 years'")
+            .unwrap()
+            .add(&Value::Text("2024-01-01".to_string()))
+            .is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Layout of the keys inside a table's column family. Every key starts with a namespace tag so
+//! rows, index entries and per-table system state sort into separate ranges and can't collide:
+//!
+//! * `d/<pk>` - a row, keyed by its primary key values
+//! * `m/<name>` - system state for the table
+//! * `i/<column><value>` - an entry in a column's unique index, holding the primary key of the row
+//!   with that value. The column name is encoded like a value so where it ends is unambiguous.
+//! * `x/<index><values><pk>` - an entry in a secondary index, the row's values for the index's
+//!   columns followed by its primary key, also holding the primary key. The index name is encoded
+//!   the same way as unique index column names.
+//!
+//! Primary key values are written with [`encode_value`] so rows sort in key order.
+use crate::types::Value;
+use bigdecimal::num_bigint::Sign;
+use bigdecimal::{BigDecimal, ToPrimitive};
+
+pub const DATA_PREFIX: &[u8] = b"d/";
+pub const METADATA_PREFIX: &[u8] = b"m/";
+pub const INDEX_PREFIX: &[u8] = b"i/";
+pub const SECONDARY_INDEX_PREFIX: &[u8] = b"x/";
+
+/// Version of this layout, stored under `m/layout` in every table so older tables can be detected
+/// and migrated.
+pub const LAYOUT_VERSION: u8 = 4;
+pub const LAYOUT_KEY: &str = "layout";
+/// Named constraints of the table.
+pub const CONSTRAINTS_KEY: &str = "constraints";
+/// Primary key columns of the table in the order their values make up a row's key.
+pub const PRIMARY_KEY_KEY: &str = "primary_key";
+/// Values reserved by each auto increment column of the table.
+pub const AUTO_INCREMENT_KEY: &str = "auto_increment";
+/// Secondary indexes of the table.
+pub const INDEXES_KEY: &str = "indexes";
+/// Most bytes the table can take up.
+pub const QUOTA_KEY: &str = "quota";
+
+fn prefixed(prefix: &[u8], rest: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(prefix.len() + rest.len());
+    key.extend_from_slice(prefix);
+    key.extend_from_slice(rest);
+    key
+}
+
+pub fn data_key(pk: impl AsRef<[u8]>) -> Vec<u8> {
+    prefixed(DATA_PREFIX, pk.as_ref())
+}
+
+pub fn metadata_key(name: &str) -> Vec<u8> {
+    prefixed(METADATA_PREFIX, name.as_bytes())
+}
+
+/// Start of the range holding every entry of a secondary index.
+pub fn index_prefix(index: &str) -> Vec<u8> {
+    let mut key = SECONDARY_INDEX_PREFIX.to_vec();
+    encode_bytes(index.as_bytes(), &mut key);
+    key
+}
+
+/// Entry of a row in a secondary index. `values` are the row's values for the index's columns in
+/// order and `pk` is its primary key, which keeps rows with the same values apart.
+pub fn index_key<'a>(
+    index: &str,
+    values: impl IntoIterator<Item = &'a Value>,
+    pk: &[u8],
+) -> Vec<u8> {
+    let mut key = index_prefix(index);
+    for value in values {
+        encode_value(value, &mut key);
+    }
+    key.extend_from_slice(pk);
+    key
+}
+
+/// Start of the range holding a column's unique index.
+pub fn unique_prefix(column: &str) -> Vec<u8> {
+    let mut key = INDEX_PREFIX.to_vec();
+    encode_bytes(column.as_bytes(), &mut key);
+    key
+}
+
+pub fn unique_key(column: &str, value: &Value) -> Vec<u8> {
+    let mut key = unique_prefix(column);
+    encode_value(value, &mut key);
+    key
+}
+
+/// The first key after every key starting with `prefix`, for range deletes and iterator bounds.
+pub fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            break;
+        }
+    }
+    end
+}
+
+/// Part of a key that point lookups and short scans stay inside of, used as rocksdb's prefix
+/// extractor so prefix bloom filters can skip files without it. That's the namespace for rows and
+/// system state, and for index entries the namespace along with the index or column name, so a
+/// scan of one index doesn't touch files only holding other indexes. Keys outside the layout are
+/// left whole.
+pub fn key_prefix(key: &[u8]) -> &[u8] {
+    match namespaced_prefix_len(key) {
+        Some(len) => &key[..len],
+        None => key,
+    }
+}
+
+/// Whether [`key_prefix`] finds a prefix in the key, keys without one never skip a file.
+pub fn has_key_prefix(key: &[u8]) -> bool {
+    namespaced_prefix_len(key).is_some()
+}
+
+fn namespaced_prefix_len(key: &[u8]) -> Option<usize> {
+    let namespace = DATA_PREFIX.len();
+    match key.get(..namespace)? {
+        prefix if prefix == DATA_PREFIX || prefix == METADATA_PREFIX => Some(namespace),
+        prefix if prefix == INDEX_PREFIX || prefix == SECONDARY_INDEX_PREFIX => {
+            encoded_bytes_len(&key[namespace..]).map(|len| namespace + len)
+        }
+        _ => None,
+    }
+}
+
+/// Length of the bytes written by [`encode_bytes`] at the start of `key`, terminator included.
+fn encoded_bytes_len(key: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    loop {
+        match key.get(i..i + 2)? {
+            [0x00, 0x01] => return Some(i + 2),
+            // An escaped zero
+            [0x00, 0xff] => i += 2,
+            _ => i += 1,
+        }
+    }
+}
+
+/// Returns the primary key part of a row key, or `None` if the key isn't a row.
+pub fn strip_data_prefix(key: &[u8]) -> Option<&[u8]> {
+    key.strip_prefix(DATA_PREFIX)
+}
+
+/// Appends a value to a key so that encoded values compare bytewise the way the values do.
+/// Each encoding ends itself, so the values of a composite key can be appended one after another
+/// and still sort column by column.
+pub fn encode_value(value: &Value, key: &mut Vec<u8>) {
+    match value {
+        Value::Null => key.push(0x00),
+        Value::Boolean(b) => key.extend([0x01, *b as u8]),
+        Value::Number(n) => encode_number(n, key),
+        Value::Text(text) => {
+            key.push(0x05);
+            encode_bytes(text.as_bytes(), key);
+        }
+        Value::Bytes(bytes) => {
+            key.push(0x06);
+            encode_bytes(bytes, key);
+        }
+    }
+}
+
+/// Zero bytes are escaped so the terminator sorts before any continuation, making a prefix sort
+/// first.
+fn encode_bytes(bytes: &[u8], key: &mut Vec<u8>) {
+    for &b in bytes {
+        key.push(b);
+        if b == 0x00 {
+            key.push(0xff);
+        }
+    }
+    key.extend([0x00, 0x01]);
+}
+
+/// Numbers are written as sign, exponent, then digits, like scientific notation. Dropping trailing
+/// zeros first means `1` and `1.0` get the same key. Negative numbers have their exponent and
+/// digits inverted so larger magnitudes sort first.
+fn encode_number(n: &BigDecimal, key: &mut Vec<u8>) {
+    let (negative, digits, scale) = small_number_digits(n).unwrap_or_else(|| number_digits(n));
+    if digits == "0" {
+        key.push(0x03);
+        return;
+    }
+    // Position of the first digit relative to the decimal point
+    let exponent = digits.len() as i64 - scale;
+    let mut magnitude = ((exponent as u64) ^ (1 << 63)).to_be_bytes().to_vec();
+    // Digits are ASCII so the terminator sorts first
+    magnitude.extend(digits.as_bytes());
+    magnitude.push(0x00);
+    if negative {
+        key.push(0x02);
+        key.extend(magnitude.iter().map(|b| !b));
+    } else {
+        key.push(0x04);
+        key.extend(magnitude);
+    }
+}
+
+/// Sign, digits without trailing zeros and scale of a number.
+fn number_digits(n: &BigDecimal) -> (bool, String, i64) {
+    let (digits, scale) = n.normalized().as_bigint_and_exponent();
+    let negative = digits.sign() == Sign::Minus;
+    (negative, digits.magnitude().to_string(), scale)
+}
+
+/// [`number_digits`] for a number whose digits fit in an `i128`, which is nearly all of them.
+/// Dropping the zeros from an integer is a lot cheaper than normalizing a `BigDecimal`, which
+/// goes through its digits as a vector.
+fn small_number_digits(n: &BigDecimal) -> Option<(bool, String, i64)> {
+    let (digits, mut scale) = n.as_bigint_and_exponent();
+    let digits = digits.to_i128()?;
+    let mut magnitude = digits.unsigned_abs();
+    if magnitude == 0 {
+        return Some((false, "0".to_string(), 0));
+    }
+    while magnitude % 10 == 0 {
+        magnitude /= 10;
+        scale -= 1;
+    }
+    Some((digits < 0, magnitude.to_string(), scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespaces_dont_overlap() {
+        // A primary key that looks like another namespace is still just a row
+        let sneaky = data_key(b"m/layout");
+        assert_ne!(sneaky, metadata_key(LAYOUT_KEY));
+        assert_eq!(strip_data_prefix(&sneaky), Some(&b"m/layout"[..]));
+        assert_eq!(strip_data_prefix(&metadata_key(LAYOUT_KEY)), None);
+
+        let name = Value::Text("Daniel".to_string());
+        let entry = index_key("by_name", [&name], b"1");
+        assert!(entry.starts_with(&index_prefix("by_name")));
+        assert!(!entry.starts_with(&index_prefix("by")));
+        assert_eq!(strip_data_prefix(&entry), None);
+        // An index and a unique column with the same name don't share a range
+        assert!(!entry.starts_with(&unique_prefix("by_name")));
+        assert!(!index_key("a", [&name], b"1").starts_with(&index_prefix("a/b")));
+
+        // A column name that's a prefix of another still gets its own range
+        let unique = unique_key("name", &Value::Text("Daniel".to_string()));
+        assert!(unique.starts_with(&unique_prefix("name")));
+        assert!(!unique.starts_with(&unique_prefix("nam")));
+        assert!(unique.as_slice() < prefix_end(&unique_prefix("name")).as_slice());
+        assert_eq!(strip_data_prefix(&unique), None);
+        assert_eq!(prefix_end(b"a\xff"), b"b");
+    }
+
+    #[test]
+    fn key_prefixes() {
+        let name = Value::Text("Daniel".to_string());
+        let entry = index_key("by\0name", [&name], b"1");
+        assert_eq!(key_prefix(&entry), index_prefix("by\0name"));
+        assert_eq!(key_prefix(&index_prefix("by")), index_prefix("by"));
+        let unique = unique_key("name", &name);
+        assert_eq!(key_prefix(&unique), unique_prefix("name"));
+        assert_eq!(key_prefix(&data_key(b"\x00\x01")), DATA_PREFIX);
+        assert_eq!(key_prefix(&metadata_key(LAYOUT_KEY)), METADATA_PREFIX);
+
+        // Anything the layout doesn't account for is out of the extractor's domain
+        for key in [&b"i/"[..], b"x/unterminated", b"users", b""] {
+            assert!(!has_key_prefix(key));
+            assert_eq!(key_prefix(key), key);
+        }
+        assert!(has_key_prefix(&entry));
+    }
+
+    #[test]
+    fn values_sort_in_key_order() {
+        let encode = |values: &[Value]| {
+            let mut key = vec![];
+            for value in values {
+                encode_value(value, &mut key);
+            }
+            key
+        };
+        let number = |n: &str| Value::Number(n.parse().unwrap());
+        let text = |s: &str| Value::Text(s.to_string());
+
+        let numbers = [
+            "-1000", "-12.5", "-12", "-1.01", "-1", "-0.5", "0", "0.001", "0.5", "1", "1.01", "9",
+            "10", "12", "12.5", "1000",
+        ];
+        for pair in numbers.windows(2) {
+            let (a, b) = (encode(&[number(pair[0])]), encode(&[number(pair[1])]));
+            assert!(a < b, "{} < {}", pair[0], pair[1]);
+        }
+        assert_eq!(encode(&[number("1")]), encode(&[number("1.00")]));
+        assert_eq!(encode(&[number("0")]), encode(&[number("-0.0")]));
+
+        let texts = ["", "a", "a\0", "a\0b", "ab", "b"];
+        for pair in texts.windows(2) {
+            let (a, b) = (encode(&[text(pair[0])]), encode(&[text(pair[1])]));
+            assert!(a < b, "{:?} < {:?}", pair[0], pair[1]);
+        }
+        assert!(encode(&[Value::Boolean(false)]) < encode(&[Value::Boolean(true)]));
+
+        // A composite key sorts by its first column before looking at the second
+        assert!(encode(&[text("a"), number("9")]) < encode(&[text("a"), number("10")]));
+        assert!(encode(&[text("a"), number("10")]) < encode(&[text("ab"), number("1")]));
+    }
+
+    #[test]
+    fn small_numbers_encode_like_big_ones() {
+        let mut numbers = [
+            "0", "-0.00", "1.0", "100", "1e5", "-1e-5", "12.3400", "0.000001",
+        ]
+        .map(|n| n.parse::<BigDecimal>().unwrap())
+        .to_vec();
+        numbers.extend((-1000..1000).map(|n| BigDecimal::new((n * 7919).into(), n % 7)));
+        numbers.extend([i128::MIN, i128::MAX].map(BigDecimal::from));
+        for n in &numbers {
+            let small = small_number_digits(n).unwrap();
+            assert_eq!(small, number_digits(n), "{}", n);
+        }
+        // Anything bigger goes the long way
+        let (min, max) = (BigDecimal::from(i128::MIN), BigDecimal::from(i128::MAX));
+        assert!(small_number_digits(&(min - 1)).is_none());
+        assert!(small_number_digits(&(max + 1)).is_none());
+    }
+
+    #[test]
+    fn composite_index_entries() {
+        let number = |n: i64| Value::Number(n.into());
+        let entry = |a: i64, b: &Value, pk: &[u8]| index_key("ab", [&number(a), b], pk);
+
+        // Entries sort by the first column, then the second, then the primary key
+        assert!(entry(1, &number(9), b"2") < entry(1, &number(10), b"1"));
+        assert!(entry(1, &number(10), b"1") < entry(1, &number(10), b"2"));
+        assert!(entry(1, &number(10), b"9") < entry(2, &Value::Null, b"1"));
+        // NULLs sort first
+        assert!(entry(1, &Value::Null, b"9") < entry(1, &number(-5), b"1"));
+
+        // Every entry with a = 1 shares a prefix, so `a = 1 AND b > 5` is a range
+        let mut a = index_prefix("ab");
+        encode_value(&number(1), &mut a);
+        let mut five = a.clone();
+        encode_value(&number(5), &mut five);
+        let (start, end) = (prefix_end(&five), prefix_end(&a));
+        let in_range = |key: &[u8]| start.as_slice() <= key && key < end.as_slice();
+        assert!(in_range(&entry(1, &number(6), b"1")));
+        assert!(!in_range(&entry(1, &number(5), b"1")));
+        assert!(!in_range(&entry(1, &number(4), b"1")));
+        assert!(!in_range(&entry(2, &number(6), b"1")));
+    }
+}
@@ -1,24 +1,71 @@
-use crate::query_engine::QueryEngine;
-use crate::storage_engine::StorageEngine;
+use crate::config::Config;
+use crate::query_engine::{PreparedStatement, QueryEngine};
+use crate::storage_engine::{RecoveryOptions, RecoveryReport, StorageEngine};
+use crate::timezone::TimeZone;
 use crate::types::*;
+use sqlparser::ast::Expr;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
 use std::{env, path::Path};
 use tracing::{debug, instrument};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+pub mod catalog;
+pub mod config;
+pub mod dictionary;
+pub mod dump;
+pub mod executor;
+pub mod expr;
+pub mod functions;
+pub mod interval;
+pub mod keys;
+pub mod migrate;
 pub mod query_engine;
+pub mod schema;
+pub mod speculate;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_import;
 pub mod storage_engine;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod timezone;
+pub mod ttl;
 pub mod types;
 
+/// Settings changed with `SET`. They belong to whoever issued the `SET`, so a server sharing one
+/// instance between connections keeps a session per connection and swaps it in with
+/// [`Instance::set_session`] around every statement.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Session {
+    pub statement_timeout: Option<Duration>,
+    /// Time zone for timestamps without an offset
+    pub timezone: TimeZone,
+    pub safe_updates: bool,
+    pub safe_updates_limit: Option<usize>,
+}
+
 pub struct Instance {
     storage: StorageEngine,
     query: QueryEngine,
+    session: Session,
+    /// Set while [`Self::execute_forced`] runs
+    forced: bool,
 }
 
+// The server shares an instance between connection tasks, so nothing in it can hold an `Rc`
+const _: () = {
+    fn assert_send<T: Send>() {}
+    let _ = assert_send::<Instance>;
+};
+
 impl Instance {
     pub fn new_with_path(path: impl AsRef<Path>) -> Self {
         Self {
             storage: StorageEngine::new_with_path(path),
             query: QueryEngine::default(),
+            session: Session::default(),
+            forced: false,
         }
     }
 
@@ -26,27 +73,322 @@ impl Instance {
         Self {
             storage: StorageEngine::new(),
             query: QueryEngine::default(),
+            session: Session::default(),
+            forced: false,
         }
     }
 
-    #[instrument(skip_all)]
-    pub fn execute(&mut self, query: &str) -> anyhow::Result<()> {
+    pub fn new_with_config(config: &Config) -> Self {
+        Self {
+            storage: StorageEngine::new_with_config(config.storage.clone()),
+            query: QueryEngine::default(),
+            session: Session::default(),
+            forced: false,
+        }
+    }
+
+    /// Opens a damaged database, see [`StorageEngine::open_with_recovery`].
+    pub fn open_with_recovery(
+        config: &Config,
+        recovery: &RecoveryOptions,
+    ) -> anyhow::Result<(Self, RecoveryReport)> {
+        let (storage, report) =
+            StorageEngine::open_with_recovery(config.storage.clone(), recovery)?;
+        let instance = Self {
+            storage,
+            query: QueryEngine::default(),
+            session: Session::default(),
+            forced: false,
+        };
+        Ok((instance, report))
+    }
+
+    #[instrument(skip_all, fields(query = %query))]
+    pub fn execute(&mut self, query: &str) -> anyhow::Result<QueryResult> {
+        let statements = self.query.process_sql(query)?;
+        self.run(&statements)
+    }
+
+    /// Parses and checks the query against the current schema without executing it, useful for
+    /// checking migration scripts.
+    pub fn validate(&self, query: &str) -> anyhow::Result<()> {
+        let statements = self.query.process_sql(query)?;
+        self.storage.validate(&statements)
+    }
+
+    /// Makes a function available to `DEFAULT` clauses. Functions aren't stored so they need to
+    /// be registered every time the database is opened.
+    pub fn register_function(
+        &mut self,
+        name: &str,
+        function: impl Fn(&[Value]) -> anyhow::Result<Value> + Send + Sync + 'static,
+    ) {
+        self.storage.register_function(name, function);
+    }
+
+    /// Adds a constraint like `ALTER TABLE ... ADD CONSTRAINT`. If the constraint isn't
+    /// `validated` it's added `NOT VALID`, skipping the check of the rows already in the table so
+    /// that big tables can be checked later with [`Self::validate_constraint`].
+    pub fn add_constraint(&mut self, table: &str, constraint: Constraint) -> anyhow::Result<()> {
+        let command = Command::AlterTable(AlterTableOptions {
+            name: table.to_string(),
+            operation: AlterOperation::AddConstraint(constraint),
+        });
+        self.run(&[command])?;
+        Ok(())
+    }
+
+    pub fn validate_constraint(&mut self, table: &str, name: &str) -> anyhow::Result<()> {
+        self.storage.validate_constraint(table, name)
+    }
+
+    /// Limits how many bytes a table can take up, like `WITH (max_bytes = ...)` when creating it,
+    /// or lifts the limit with `None`. See [`StorageEngine::set_quota`].
+    pub fn set_quota(&mut self, table: &str, quota: Option<u64>) -> anyhow::Result<()> {
+        self.storage.set_quota(table, quota)
+    }
+
+    /// Every table with its columns, see [`StorageEngine::tables`].
+    pub fn tables(&self) -> anyhow::Result<BTreeMap<String, ColumnDescriptors>> {
+        self.storage.tables()
+    }
+
+    /// Estimated bytes a table takes up, what its quota is checked against.
+    pub fn table_usage(&self, table: &str) -> anyhow::Result<u64> {
+        self.storage.table_usage(table)
+    }
+
+    /// Called whenever a write takes a table past one of the configured
+    /// [`quota_thresholds`](config::StorageConfig::quota_thresholds) of its quota, for feeding
+    /// usage into metrics. Like functions the hook isn't stored and needs setting every time the
+    /// database is opened.
+    pub fn set_quota_hook(&mut self, hook: impl Fn(&QuotaUsage) + Send + Sync + 'static) {
+        self.storage.set_quota_hook(hook);
+    }
+
+    /// Bulk loads rows through SST ingestion, see [`StorageEngine::ingest_rows`].
+    pub fn ingest(&mut self, insert: &InsertOptions) -> anyhow::Result<()> {
+        let command = Command::Insert(insert.clone());
+        let (command, _) = self.normalize_timestamps(&command)?;
+        let Command::Insert(insert) = command.as_ref() else {
+            unreachable!()
+        };
+        self.storage.ingest_rows(insert)
+    }
+
+    /// Statements running longer than this fail with [`StatementTimeout`], like
+    /// `SET statement_timeout`. The limit is checked as rows are read and written so a statement
+    /// can go a little over.
+    pub fn set_statement_timeout(&mut self, timeout: Option<Duration>) {
+        self.session.statement_timeout = timeout;
+    }
+
+    pub fn statement_timeout(&self) -> Option<Duration> {
+        self.session.statement_timeout
+    }
+
+    /// The settings `SET` has changed, to be given back to [`Self::set_session`].
+    pub fn session(&self) -> Session {
+        self.session
+    }
+
+    /// Replaces every setting `SET` can change.
+    pub fn set_session(&mut self, session: Session) {
+        self.session = session;
+    }
+
+    /// Fails `UPDATE` and `DELETE` statements without a `WHERE` clause with [`UnsafeWrite`], like
+    /// `SET sql_safe_updates = ON`.
+    pub fn set_safe_updates(&mut self, on: bool) {
+        self.session.safe_updates = on;
+    }
+
+    /// Fails `UPDATE` and `DELETE` statements that would change more than `limit` rows with
+    /// [`UnsafeWrite`], like `SET safe_updates_limit`.
+    pub fn set_safe_updates_limit(&mut self, limit: Option<usize>) {
+        self.session.safe_updates_limit = limit;
+    }
+
+    /// Runs a query without the `sql_safe_updates` and `safe_updates_limit` checks, for going
+    /// ahead with a write that failed with [`UnsafeWrite`].
+    pub fn execute_forced(&mut self, query: &str) -> anyhow::Result<QueryResult> {
         let statements = self.query.process_sql(query)?;
-        for statement in &statements {
+        self.forced = true;
+        let res = self.run(&statements);
+        self.forced = false;
+        res
+    }
+
+    /// Fails with [`UnsafeWrite`] when an `UPDATE` or `DELETE` would break `sql_safe_updates` or
+    /// `safe_updates_limit`. Counting the rows means reading them twice, so there's only a cost
+    /// when there's a limit or a write without a `WHERE` clause to refuse.
+    fn check_safe_write(&self, table: &str, filter: Option<&Expr>) -> anyhow::Result<()> {
+        if self.forced || (!self.session.safe_updates && self.session.safe_updates_limit.is_none())
+        {
+            return Ok(());
+        }
+        // Only writes without a WHERE clause are refused, there are no rows to count
+        if filter.is_some() && self.session.safe_updates_limit.is_none() {
+            return Ok(());
+        }
+        let query = QueryOptions {
+            table: table.to_string(),
+            filter: filter.cloned(),
+            scan: ScanHint::Auto,
+        };
+        let rows = executor::select_rows(&self.storage, &query)?.len();
+        let limit = match self.session.safe_updates_limit {
+            _ if self.session.safe_updates && filter.is_none() => None,
+            Some(limit) if rows > limit => Some(limit),
+            _ => return Ok(()),
+        };
+        Err(UnsafeWrite {
+            table: table.to_string(),
+            rows,
+            limit,
+        }
+        .into())
+    }
+
+    /// Time zone for timestamps without an offset, like `SET TIME ZONE`.
+    pub fn set_timezone(&mut self, timezone: TimeZone) {
+        self.session.timezone = timezone;
+    }
+
+    /// Columns of the table a command reads or writes, empty for commands that don't touch rows.
+    fn command_columns(&self, command: &Command) -> ColumnDescriptors {
+        let table = match command {
+            Command::Insert(opts) => &opts.table,
+            Command::Update(opts) => &opts.table,
+            Command::Delete(opts) => &opts.table,
+            Command::Select(opts) => &opts.table,
+            _ => return ColumnDescriptors::new(),
+        };
+        self.storage.table_metadata(table).unwrap_or_default()
+    }
+
+    /// Converts the timestamps a command writes or compares against to how they're stored. Zoned
+    /// columns are converted from the session's time zone to UTC and the rest are only written out
+    /// in full, `YYYY-MM-DD HH:MM:SS`, so keys and indexes on them sort by time. Returns the zoned
+    /// columns, which rows read back are rendered in.
+    fn normalize_timestamps<'a>(
+        &self,
+        command: &'a Command,
+    ) -> anyhow::Result<(Cow<'a, Command>, BTreeSet<String>)> {
+        let columns = self.command_columns(command);
+        let zoned = timezone::zoned_columns(&columns);
+        let plain = timezone::plain_timestamp_columns(&columns);
+        if zoned.is_empty() && plain.is_empty() {
+            return Ok((Cow::Borrowed(command), zoned));
+        }
+        let mut command = command.clone();
+        timezone::command_to_utc(&mut command, &zoned, self.session.timezone)?;
+        timezone::command_to_utc(&mut command, &plain, TimeZone::UTC)?;
+        Ok((Cow::Owned(command), zoned))
+    }
+
+    pub fn prepare(&self, query: &str) -> anyhow::Result<PreparedStatement> {
+        self.query.prepare(query, &self.storage)
+    }
+
+    #[instrument(skip_all)]
+    pub fn execute_prepared(
+        &mut self,
+        statement: &PreparedStatement,
+        params: &[Value],
+    ) -> anyhow::Result<QueryResult> {
+        let statements = statement.bind(params)?;
+        self.run(&statements)
+    }
+
+    fn run(&mut self, statements: &[Command]) -> anyhow::Result<QueryResult> {
+        // Drop anything left behind by a statement that failed
+        self.storage.take_warnings();
+        let mut rows = vec![];
+        let mut rows_affected = 0;
+        for statement in statements {
             debug!("Running: {:?}", statement);
+            self.storage.start_statement(self.session.statement_timeout);
+            let (converted, zoned) = self.normalize_timestamps(statement)?;
+            let statement = converted.as_ref();
+            match statement {
+                Command::Update(opts) => {
+                    self.check_safe_write(&opts.table, opts.filter.as_ref())?
+                }
+                Command::Delete(opts) => {
+                    self.check_safe_write(&opts.table, opts.filter.as_ref())?
+                }
+                _ => {}
+            }
             match statement {
                 Command::CreateTable(opts) => {
                     self.storage.create_table(opts)?;
                 }
+                Command::CloneTable(opts) => {
+                    self.storage.clone_table(opts)?;
+                }
+                Command::AlterTable(opts) => {
+                    self.storage.alter_table(opts)?;
+                }
+                Command::DropTable(opts) => {
+                    self.storage.drop_table(opts)?;
+                }
+                Command::CreateIndex(opts) => {
+                    self.storage.create_index(opts)?;
+                }
+                Command::DropIndex(opts) => {
+                    self.storage.drop_index(opts)?;
+                }
+                Command::Reindex(reindex) => {
+                    self.storage.reindex(reindex)?;
+                }
                 Command::Insert(opts) => {
-                    self.storage.insert_rows(opts)?;
+                    let (count, returned) = self.storage.insert_rows(opts)?;
+                    rows_affected += count;
+                    if opts.returning.is_some() {
+                        rows = returned;
+                        timezone::render_rows(&mut rows, &zoned, self.session.timezone);
+                    }
+                }
+                Command::Update(opts) => {
+                    let (count, returned) = self.storage.update_rows(opts)?;
+                    rows_affected += count;
+                    if opts.returning.is_some() {
+                        rows = returned;
+                        timezone::render_rows(&mut rows, &zoned, self.session.timezone);
+                    }
+                }
+                Command::Delete(opts) => {
+                    let (count, returned) = self.storage.delete_rows(opts)?;
+                    rows_affected += count;
+                    if opts.returning.is_some() {
+                        rows = returned;
+                        timezone::render_rows(&mut rows, &zoned, self.session.timezone);
+                    }
+                }
+                Command::Select(opts) => {
+                    rows = executor::select_rows(&self.storage, opts)?;
+                    timezone::render_rows(&mut rows, &zoned, self.session.timezone);
+                }
+                Command::Set(Variable::StatementTimeout(timeout)) => {
+                    self.session.statement_timeout = *timeout;
+                }
+                Command::Set(Variable::TimeZone(timezone)) => {
+                    self.session.timezone = *timezone;
+                }
+                Command::Set(Variable::SafeUpdates(on)) => {
+                    self.session.safe_updates = *on;
                 }
-                Command::Select(_) => {
-                    anyhow::bail!("Currently don't support SELECT queries");
+                Command::Set(Variable::SafeUpdatesLimit(limit)) => {
+                    self.session.safe_updates_limit = *limit;
                 }
             }
         }
-        Ok(())
+        Ok(QueryResult {
+            warnings: self.storage.take_warnings(),
+            rows,
+            rows_affected,
+        })
     }
 
     #[cfg(test)]
@@ -56,22 +398,57 @@ impl Instance {
 }
 
 pub fn setup_logging() {
+    setup_logging_with("dechib=trace,desql=info");
+}
+
+/// Like [`setup_logging`] with `default_filter` used when `DECHIB_LOG` isn't set. Logs go to
+/// stderr so they never mix with results written to stdout.
+pub fn setup_logging_with(default_filter: &str) {
     let filter = match env::var("DECHIB_LOG") {
         Ok(s) => EnvFilter::new(s),
-        Err(_) => EnvFilter::new("dechib=trace,desql=info"),
+        Err(_) => EnvFilter::new(default_filter),
     };
 
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(filter)
-        .init();
+    let registry = tracing_subscriber::registry().with(fmt::layer().with_writer(std::io::stderr));
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel_layer());
+    registry.with(filter).init();
+}
+
+/// Exports spans over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Logging is set up before
+/// there's a tokio runtime so spans are exported synchronously with the blocking client.
+#[cfg(feature = "otel")]
+fn otel_layer<S>(
+) -> Option<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry::KeyValue;
+    use opentelemetry_sdk::{trace, Resource};
+
+    env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT")?;
+    let resource = Resource::new([KeyValue::new("service.name", "dechib")]);
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http())
+        .with_trace_config(trace::Config::default().with_resource(resource))
+        .install_simple()
+        .map_err(|e| eprintln!("Failed to set up OpenTelemetry export: {}", e))
+        .ok()?;
+    let tracer = provider.tracer("dechib");
+    opentelemetry::global::set_tracer_provider(provider);
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use sqlparser::ast::DataType;
+    use crate::config::StorageConfig;
+    use rocksdb::{Direction, IteratorMode};
+    use sqlparser::ast::{DataType, Ident};
     use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
     use tracing_test::traced_test;
     use uuid::Uuid;
 
@@ -131,4 +508,1627 @@ mod tests {
 
         let _engine = StorageEngine::new_with_path(&handle.path);
     }
+
+    #[test]
+    #[traced_test]
+    fn validate_only() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+
+        engine
+            .execute("CREATE TABLE users (id INTEGER UNSIGNED NOT NULL UNIQUE PRIMARY KEY, name TEXT NOT NULL);")
+            .unwrap();
+
+        engine
+            .validate(
+                "CREATE TABLE posts (id INT PRIMARY KEY, author INT, title TEXT NOT NULL, FOREIGN KEY (author) REFERENCES users(id));
+                 INSERT INTO posts (id, author, title) VALUES (1, 1, 'Hello');
+                 INSERT INTO users (id, name) VALUES (1, 'Daniel');",
+            )
+            .unwrap();
+        // Nothing should have been created
+        assert!(engine.storage.table_metadata("posts").is_err());
+
+        assert!(engine
+            .validate("INSERT INTO users (id, name) VALUES (1, true);")
+            .is_err());
+        assert!(engine
+            .validate("INSERT INTO users (id) VALUES (1);")
+            .is_err());
+        assert!(engine
+            .validate("CREATE TABLE users (id INT PRIMARY KEY);")
+            .is_err());
+        assert!(engine
+            .validate("CREATE TABLE posts (author INT, FOREIGN KEY (author) REFERENCES nope(id));")
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn prepared_insert() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+
+        engine
+            .execute("CREATE TABLE users (id INTEGER UNSIGNED NOT NULL UNIQUE PRIMARY KEY, name TEXT NOT NULL, admin BOOLEAN);")
+            .unwrap();
+
+        let prepared = engine
+            .prepare("INSERT INTO users (id, name, admin) VALUES ($1, $2, $3);")
+            .unwrap();
+        assert_eq!(
+            prepared.parameter_types().collect::<Vec<_>>(),
+            vec![
+                &DataType::UnsignedInteger(None),
+                &DataType::Text,
+                &DataType::Boolean
+            ]
+        );
+
+        let number = Value::Number(1u32.into());
+        let name = Value::Text("Daniel".to_string());
+
+        assert!(engine
+            .execute_prepared(&prepared, &[number.clone(), name.clone()])
+            .is_err());
+        assert!(engine
+            .execute_prepared(
+                &prepared,
+                &[name.clone(), name.clone(), Value::Boolean(true)]
+            )
+            .is_err());
+        engine
+            .execute_prepared(&prepared, &[number, name, Value::Null])
+            .unwrap();
+
+        let positional = engine
+            .prepare("INSERT INTO users (name, id) VALUES (?, ?);")
+            .unwrap();
+        assert_eq!(positional.parameters()[0].column, "name");
+        assert_eq!(positional.parameters()[1].column, "id");
+
+        assert!(engine
+            .prepare("INSERT INTO users (nope) VALUES ($1);")
+            .is_err());
+        assert!(engine
+            .prepare("INSERT INTO users (id, name) VALUES ($1, $3);")
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn warnings() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+
+        let res = engine
+            .execute("CREATE TABLE users (id INT PRIMARY KEY, name TEXT);")
+            .unwrap();
+        assert!(res.warnings.is_empty());
+
+        let res = engine.execute("CREATE TABLE events (name TEXT);").unwrap();
+        assert_eq!(
+            res.warnings,
+            vec![Warning::ImplicitRowid {
+                table: "events".to_string()
+            }]
+        );
+
+        // Warnings only belong to the query that raised them
+        let res = engine
+            .execute("INSERT INTO events (name) VALUES ('login');")
+            .unwrap();
+        assert!(res.warnings.is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn constraints() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute("CREATE TABLE users (id INT PRIMARY KEY, name TEXT);")
+            .unwrap();
+        engine
+            .execute("INSERT INTO users (id, name) VALUES (1, 'Daniel');")
+            .unwrap();
+        engine
+            .execute("CREATE TABLE posts (author INT, slug TEXT CONSTRAINT posts_slug UNIQUE);")
+            .unwrap();
+        engine
+            .execute("INSERT INTO posts (author, slug) VALUES (1, 'a'), (2, 'b'), (NULL, 'c');")
+            .unwrap();
+
+        // Rows already in the table are checked when a constraint is added
+        let err = engine
+            .execute(
+                "ALTER TABLE posts ADD CONSTRAINT posts_author FOREIGN KEY (author) \
+                 REFERENCES users(id);",
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("author = 2"), "{}", err);
+        engine
+            .execute("ALTER TABLE posts ADD UNIQUE (author);")
+            .unwrap();
+        assert!(engine
+            .execute("ALTER TABLE posts ADD CONSTRAINT posts_author_key UNIQUE (slug);")
+            .is_err());
+
+        // Unless they're added NOT VALID, then they're checked on request
+        let foreign_key = Constraint {
+            name: "posts_author_fkey".to_string(),
+            kind: ConstraintKind::ForeignKey {
+                column: "author".to_string(),
+                table: "users".to_string(),
+                referred: "id".to_string(),
+            },
+            validated: false,
+        };
+        engine.add_constraint("posts", foreign_key).unwrap();
+        assert!(engine
+            .validate_constraint("posts", "posts_author_fkey")
+            .is_err());
+        let mut unique_slug = Constraint {
+            name: "slug_once".to_string(),
+            kind: ConstraintKind::Unique {
+                column: "slug".to_string(),
+            },
+            validated: false,
+        };
+        engine.add_constraint("posts", unique_slug.clone()).unwrap();
+        engine.validate_constraint("posts", "slug_once").unwrap();
+        unique_slug.validated = true;
+        assert_eq!(
+            engine.storage().constraints("posts").unwrap().last(),
+            Some(&unique_slug)
+        );
+
+        engine
+            .execute("ALTER TABLE posts DROP CONSTRAINT posts_author_fkey;")
+            .unwrap();
+        assert!(engine
+            .execute("ALTER TABLE posts DROP CONSTRAINT posts_author_fkey;")
+            .is_err());
+        engine
+            .execute("ALTER TABLE posts DROP CONSTRAINT IF EXISTS posts_author_fkey;")
+            .unwrap();
+        let names = engine
+            .storage()
+            .constraints("posts")
+            .unwrap()
+            .into_iter()
+            .map(|x| x.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["posts_slug", "posts_author_key", "slug_once"]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn update() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine.register_function("current_timestamp", |_| {
+            Ok(Value::Text("2024-01-01 00:00:00".to_string()))
+        });
+        engine
+            .execute(
+                "CREATE TABLE posts (title TEXT NOT NULL, views INT, \
+                 updated_at TIMESTAMP ON UPDATE CURRENT_TIMESTAMP);
+                 INSERT INTO posts (title, views) VALUES ('a', 0), ('b', 0);",
+            )
+            .unwrap();
+        let column = |engine: &Instance, column: &str| {
+            engine
+                .storage
+                .scan_table("posts")
+                .unwrap()
+                .iter()
+                .map(|x| x.columns[column].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let result = engine
+            .execute("UPDATE posts SET views = 1 WHERE title = 'a'")
+            .unwrap();
+        assert_eq!(result.rows_affected, 1);
+        assert_eq!(column(&engine, "views"), vec!["1", "0"]);
+        assert_eq!(
+            column(&engine, "updated_at"),
+            vec!["'2024-01-01 00:00:00'", "NULL"]
+        );
+
+        // Assignments see the row before the update
+        engine
+            .execute("UPDATE posts SET views = NULL, title = 'c', updated_at = NULL")
+            .unwrap();
+        assert_eq!(column(&engine, "title"), vec!["'c'", "'c'"]);
+        assert_eq!(column(&engine, "updated_at"), vec!["NULL", "NULL"]);
+
+        // A bad row fails the whole update
+        engine
+            .execute("UPDATE posts SET views = 2 WHERE title = 'c'")
+            .unwrap();
+        assert!(engine.execute("UPDATE posts SET title = views").is_err());
+        assert!(engine.execute("UPDATE posts SET title = NULL").is_err());
+        assert_eq!(column(&engine, "title"), vec!["'c'", "'c'"]);
+        assert!(engine.validate("UPDATE posts SET missing = 1").is_err());
+        assert!(engine
+            .validate("UPDATE posts SET views = 1, views = 2")
+            .is_err());
+        assert!(engine
+            .validate(&format!("UPDATE posts SET {} = 1", ROWID_COLUMN))
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn drop_table() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);
+                 CREATE TABLE posts (author INT REFERENCES users(id), title TEXT);
+                 CREATE TABLE likes (user_id INT, CONSTRAINT likes_user FOREIGN KEY (user_id) REFERENCES users(id));
+                 INSERT INTO users (id, name) VALUES (1, 'Ben');
+                 INSERT INTO posts (author, title) VALUES (1, 'Hello');",
+            )
+            .unwrap();
+
+        assert!(engine.execute("DROP TABLE users").is_err());
+        assert!(engine.validate("DROP TABLE users, posts").is_err());
+        engine.validate("DROP TABLE users, posts, likes").unwrap();
+        assert!(engine
+            .validate("DROP TABLE posts; INSERT INTO posts (title) VALUES ('x')")
+            .is_err());
+        engine
+            .validate("DROP TABLE posts; CREATE TABLE posts (title TEXT)")
+            .unwrap();
+        assert!(engine.execute("DROP TABLE missing").is_err());
+        engine.execute("DROP TABLE IF EXISTS missing").unwrap();
+
+        engine.execute("DROP TABLE users CASCADE").unwrap();
+        assert!(engine.storage.table_metadata("users").is_err());
+        assert_eq!(
+            engine.storage.table_metadata("posts").unwrap()["author"].foreign_key,
+            None
+        );
+        assert!(engine.storage.constraints("likes").unwrap().is_empty());
+        assert_eq!(engine.storage.scan_table("posts").unwrap().len(), 1);
+
+        // The name can be used again and starts out empty
+        engine
+            .execute("CREATE TABLE users (id INT PRIMARY KEY, name TEXT)")
+            .unwrap();
+        assert!(engine.storage.scan_table("users").unwrap().is_empty());
+        engine.execute("DROP TABLE posts, likes").unwrap();
+        std::mem::drop(engine);
+        let engine = Instance::new_with_path(&handle.path);
+        assert_eq!(
+            engine.storage.tables().unwrap().keys().collect::<Vec<_>>(),
+            vec!["users"]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn add_column() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE users (name TEXT NOT NULL);
+                 INSERT INTO users (name) VALUES ('Daniel'), ('Ben');",
+            )
+            .unwrap();
+
+        engine
+            .execute("ALTER TABLE users ADD COLUMN city TEXT NOT NULL DEFAULT 'London'")
+            .unwrap();
+        engine
+            .execute("ALTER TABLE users ADD COLUMN age INT")
+            .unwrap();
+        let rows = engine.storage.scan_table("users").unwrap();
+        assert!(rows
+            .iter()
+            .all(|x| *x.columns["city"] == Value::Text("London".to_string())));
+        let result = engine
+            .execute("SELECT * FROM users WHERE age IS NULL")
+            .unwrap();
+        assert_eq!(result.rows.len(), 2);
+
+        let before = engine.storage.table_metadata("users").unwrap();
+        for sql in [
+            "ALTER TABLE users ADD COLUMN email TEXT NOT NULL",
+            "ALTER TABLE users ADD COLUMN city TEXT",
+            "ALTER TABLE users ADD COLUMN id INT PRIMARY KEY",
+            "ALTER TABLE users ADD COLUMN n INT DEFAULT 'x'",
+            "ALTER TABLE users ADD COLUMN code TEXT DEFAULT 'x' UNIQUE",
+            "ALTER TABLE users ADD COLUMN boss INT REFERENCES missing(id)",
+        ] {
+            assert!(engine.execute(sql).is_err(), "{}", sql);
+        }
+        assert_eq!(engine.storage.table_metadata("users").unwrap(), before);
+
+        engine
+            .validate(
+                "ALTER TABLE users ADD COLUMN score INT;
+                 INSERT INTO users (name, score) VALUES ('Ann', 1);",
+            )
+            .unwrap();
+        engine
+            .execute("INSERT INTO users (name, age) VALUES ('Ann', 30)")
+            .unwrap();
+        let rows = engine.storage.scan_table("users").unwrap();
+        assert_eq!(*rows[2].columns["city"], Value::Text("London".to_string()));
+    }
+
+    #[test]
+    #[traced_test]
+    fn drop_column() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, email TEXT, name TEXT);
+                 CREATE TABLE posts (author INT REFERENCES users(id), slug TEXT, title TEXT, \
+                 CONSTRAINT posts_slug UNIQUE (slug));
+                 INSERT INTO users (id) VALUES (1);
+                 INSERT INTO posts (author, slug, title) VALUES (1, 'a', 'A'), (1, 'b', 'B');",
+            )
+            .unwrap();
+
+        for sql in [
+            "ALTER TABLE users DROP COLUMN id",
+            "ALTER TABLE users DROP COLUMN missing",
+            &format!("ALTER TABLE posts DROP COLUMN {}", ROWID_COLUMN),
+        ] {
+            assert!(engine.execute(sql).is_err(), "{}", sql);
+        }
+        engine
+            .execute("ALTER TABLE users DROP COLUMN IF EXISTS missing")
+            .unwrap();
+        assert!(engine
+            .validate("ALTER TABLE posts DROP COLUMN slug; INSERT INTO posts (slug) VALUES ('c')")
+            .is_err());
+
+        engine
+            .execute("ALTER TABLE posts DROP COLUMN slug")
+            .unwrap();
+        assert!(!engine
+            .storage
+            .table_metadata("posts")
+            .unwrap()
+            .contains_key("slug"));
+        assert!(engine.storage.constraints("posts").unwrap().is_empty());
+        let rows = engine.storage.scan_table("posts").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|x| !x.columns.contains_key("slug")));
+
+        // Columns holding a foreign key can be dropped, only referred to columns are protected
+        engine
+            .execute("ALTER TABLE posts DROP COLUMN author")
+            .unwrap();
+        engine
+            .execute("ALTER TABLE users DROP COLUMN email")
+            .unwrap();
+        engine
+            .execute("ALTER TABLE posts ADD COLUMN slug TEXT")
+            .unwrap();
+        let result = engine
+            .execute("SELECT * FROM posts WHERE slug IS NULL")
+            .unwrap();
+        assert_eq!(result.rows.len(), 2);
+    }
+
+    #[test]
+    #[traced_test]
+    fn returning() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE posts (id INT DEFAULT nextval('post_ids'), title TEXT, \
+                 status TEXT DEFAULT 'draft')",
+            )
+            .unwrap();
+        let values = |result: &QueryResult, column: &str| {
+            result
+                .rows
+                .iter()
+                .map(|x| x.columns[column].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Generated values come back with the row
+        let result = engine
+            .execute("INSERT INTO posts (title) VALUES ('a'), ('b') RETURNING *")
+            .unwrap();
+        assert_eq!(result.rows_affected, 2);
+        assert_eq!(values(&result, "id"), vec!["1", "2"]);
+        assert_eq!(values(&result, "status"), vec!["'draft'", "'draft'"]);
+        assert!(result
+            .rows
+            .iter()
+            .all(|x| !x.columns.contains_key(ROWID_COLUMN)));
+
+        let result = engine
+            .execute("UPDATE posts SET status = 'live' WHERE id = 2 RETURNING id, status")
+            .unwrap();
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0].columns.len(), 2);
+        assert_eq!(values(&result, "status"), vec!["'live'"]);
+
+        // Deleted rows are returned as they were
+        let result = engine
+            .execute("DELETE FROM posts WHERE status = 'draft' RETURNING title")
+            .unwrap();
+        assert_eq!(values(&result, "title"), vec!["'a'"]);
+
+        // Without RETURNING a write doesn't replace the rows of an earlier SELECT
+        let result = engine
+            .execute("SELECT * FROM posts; INSERT INTO posts (title) VALUES ('c')")
+            .unwrap();
+        assert_eq!(values(&result, "title"), vec!["'b'"]);
+
+        for sql in [
+            "INSERT INTO posts (title) VALUES ('d') RETURNING missing",
+            &format!("DELETE FROM posts RETURNING {}", ROWID_COLUMN),
+            "UPDATE posts SET title = 'e' RETURNING title || 'x'",
+        ] {
+            assert!(engine.execute(sql).is_err(), "{}", sql);
+        }
+        assert_eq!(engine.storage.scan_table("posts").unwrap().len(), 2);
+    }
+
+    #[test]
+    #[traced_test]
+    fn rename() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);
+                 CREATE TABLE posts (author INT REFERENCES users(id), editor INT, slug TEXT, \
+                 CONSTRAINT posts_editor FOREIGN KEY (editor) REFERENCES users(id), \
+                 CONSTRAINT posts_slug UNIQUE (slug));
+                 INSERT INTO users (id, name) VALUES (1, 'Ben');
+                 INSERT INTO posts (author, slug) VALUES (1, 'a'), (1, 'b');",
+            )
+            .unwrap();
+
+        for sql in [
+            "ALTER TABLE users RENAME TO posts",
+            "ALTER TABLE missing RENAME TO other",
+            &format!(
+                "ALTER TABLE users RENAME TO {}x",
+                storage_engine::SYSTEM_PREFIX
+            ),
+            "ALTER TABLE users RENAME COLUMN missing TO other",
+            "ALTER TABLE users RENAME COLUMN id TO name",
+            &format!("ALTER TABLE posts RENAME COLUMN {} TO n", ROWID_COLUMN),
+        ] {
+            assert!(engine.execute(sql).is_err(), "{}", sql);
+        }
+        assert!(engine
+            .validate(
+                "ALTER TABLE posts RENAME TO articles; INSERT INTO articles (slug) VALUES ('c')"
+            )
+            .is_ok());
+        assert!(engine
+            .validate("ALTER TABLE posts RENAME TO articles; INSERT INTO posts (slug) VALUES ('c')")
+            .is_err());
+
+        engine
+            .execute("ALTER TABLE users RENAME TO people")
+            .unwrap();
+        assert!(engine.storage.table_metadata("users").is_err());
+        assert_eq!(engine.storage.scan_table("people").unwrap().len(), 1);
+        engine
+            .execute("ALTER TABLE people RENAME COLUMN id TO person_id")
+            .unwrap();
+        let posts = engine.storage.table_metadata("posts").unwrap();
+        assert_eq!(
+            posts["author"].foreign_key,
+            Some(("people".to_string(), "person_id".to_string()))
+        );
+        let constraints = engine.storage.constraints("posts").unwrap();
+        assert_eq!(
+            constraints[0].kind,
+            ConstraintKind::ForeignKey {
+                column: "editor".to_string(),
+                table: "people".to_string(),
+                referred: "person_id".to_string(),
+            }
+        );
+        // The foreign keys moved with the table so it's still protected
+        assert!(engine.execute("DROP TABLE people").is_err());
+
+        engine
+            .execute("ALTER TABLE posts RENAME COLUMN slug TO path")
+            .unwrap();
+        assert_eq!(
+            engine.storage.constraints("posts").unwrap()[1].kind,
+            ConstraintKind::Unique {
+                column: "path".to_string()
+            }
+        );
+        let result = engine
+            .execute("SELECT * FROM posts WHERE path = 'a'")
+            .unwrap();
+        assert_eq!(result.rows.len(), 1);
+        assert!(!result.rows[0].columns.contains_key("slug"));
+    }
+
+    #[test]
+    #[traced_test]
+    fn composite_primary_key() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE scores (player INT, game INT, points INT, PRIMARY KEY (game, player));
+                 INSERT INTO scores (player, game, points) VALUES (1, 1, 10), (2, 1, 20), (1, 2, 30);",
+            )
+            .unwrap();
+        assert_eq!(
+            engine.storage.primary_key("scores").unwrap(),
+            vec!["game".to_string(), "player".to_string()]
+        );
+        assert_eq!(engine.storage.scan_table("scores").unwrap().len(), 3);
+
+        engine
+            .execute("UPDATE scores SET player = 3 WHERE points = 30")
+            .unwrap();
+        let result = engine
+            .execute("SELECT * FROM scores WHERE player = 3")
+            .unwrap();
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(engine.storage.scan_table("scores").unwrap().len(), 3);
+
+        for sql in [
+            "CREATE TABLE t (a INT PRIMARY KEY, b INT, PRIMARY KEY (a, b))",
+            "CREATE TABLE t (a INT, PRIMARY KEY (a, missing))",
+            // One column of a composite key doesn't identify a row
+            "CREATE TABLE plays (game INT REFERENCES scores(game))",
+            "CREATE TABLE plays (game INT, player INT,
+             FOREIGN KEY (game, player) REFERENCES scores (game, player))",
+        ] {
+            assert!(engine.execute(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn rows_sorted_by_primary_key() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE t (n INT PRIMARY KEY);
+                 INSERT INTO t (n) VALUES (10), (-5), (2), (0), (-40);",
+            )
+            .unwrap();
+        let rows = engine.execute("SELECT * FROM t").unwrap().rows;
+        let values = rows
+            .iter()
+            .map(|x| x.columns["n"].to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec!["-40", "-5", "0", "2", "10"]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn duplicate_primary_key() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);
+                 INSERT INTO users (id, name) VALUES (1, 'Daniel');",
+            )
+            .unwrap();
+
+        let err = engine
+            .execute("INSERT INTO users (id, name) VALUES (1, 'Ben')")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Duplicate key (id) = (1) in users");
+        assert!(engine
+            .execute("INSERT INTO users (id, name) VALUES (2, 'Ben'), (2, 'Ann')")
+            .is_err());
+        assert!(engine
+            .execute("INSERT INTO users (id, name) VALUES (3, 'Ben') ON CONFLICT DO NOTHING")
+            .is_err());
+        let rows = engine.storage.scan_table("users").unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(*rows[0].columns["name"], Value::Text("Daniel".to_string()));
+
+        engine
+            .execute("REPLACE INTO users (id, name) VALUES (1, 'Ben'), (2, 'Ann')")
+            .unwrap();
+        let rows = engine.storage.scan_table("users").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(*rows[0].columns["name"], Value::Text("Ben".to_string()));
+    }
+
+    #[test]
+    #[traced_test]
+    fn unique_constraint() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, email TEXT UNIQUE, name TEXT);
+                 INSERT INTO users (id, email, name) VALUES (1, 'a@x', 'Daniel'), (2, 'b@x', 'Ben');",
+            )
+            .unwrap();
+
+        let err = engine
+            .execute("INSERT INTO users (id, email) VALUES (3, 'a@x')")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Duplicate value email = a@x in users violates unique constraint users_email_key"
+        );
+        assert!(engine
+            .execute("INSERT INTO users (id, email) VALUES (3, 'c@x'), (4, 'c@x')")
+            .is_err());
+        // Any number of rows can leave a unique column NULL
+        engine
+            .execute("INSERT INTO users (id) VALUES (3), (4)")
+            .unwrap();
+
+        assert!(engine
+            .execute("UPDATE users SET email = 'a@x' WHERE id = 2")
+            .is_err());
+        engine
+            .execute("UPDATE users SET email = 'c@x' WHERE id = 1")
+            .unwrap();
+        engine
+            .execute("INSERT INTO users (id, email) VALUES (5, 'a@x')")
+            .unwrap();
+        engine.execute("DELETE FROM users WHERE id = 5").unwrap();
+        engine
+            .execute("INSERT INTO users (id, email) VALUES (6, 'a@x')")
+            .unwrap();
+
+        engine
+            .execute("ALTER TABLE users ADD CONSTRAINT users_name UNIQUE (name)")
+            .unwrap();
+        assert!(engine
+            .execute("INSERT INTO users (id, name) VALUES (7, 'Ben')")
+            .is_err());
+        engine
+            .execute("ALTER TABLE users DROP CONSTRAINT users_name")
+            .unwrap();
+        engine
+            .execute("INSERT INTO users (id, name) VALUES (7, 'Ben')")
+            .unwrap();
+
+        engine
+            .execute("ALTER TABLE users RENAME COLUMN email TO address")
+            .unwrap();
+        assert!(engine
+            .execute("INSERT INTO users (id, address) VALUES (8, 'c@x')")
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn dictionary_encoding() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE tickets (id INT PRIMARY KEY, status TEXT, title TEXT) \
+                 WITH (dictionary = 'status');
+                 INSERT INTO tickets (id, status, title) VALUES (1, 'open', 'a'), (2, 'closed', 'b'), (3, 'open', 'c');
+                 UPDATE tickets SET status = 'stale' WHERE id = 2;",
+            )
+            .unwrap();
+        assert!(engine.storage.table_metadata("tickets").unwrap()["status"].dictionary);
+        let statuses = |engine: &Instance| {
+            engine
+                .storage
+                .scan_table("tickets")
+                .unwrap()
+                .into_iter()
+                .map(|x| (*x.columns["status"]).clone())
+                .collect::<Vec<_>>()
+        };
+        let text = |x: &str| Value::Text(x.to_string());
+        assert_eq!(
+            statuses(&engine),
+            vec![text("open"), text("stale"), text("open")]
+        );
+
+        engine
+            .execute("ALTER TABLE tickets RENAME COLUMN title TO summary")
+            .unwrap();
+        drop(engine);
+        let mut engine = Instance::new_with_path(&handle.path);
+        assert_eq!(
+            statuses(&engine),
+            vec![text("open"), text("stale"), text("open")]
+        );
+
+        assert!(engine
+            .execute("CREATE TABLE a (id INT PRIMARY KEY, n INT) WITH (dictionary = 'n')")
+            .is_err());
+        assert!(engine
+            .execute("CREATE TABLE b (id INT PRIMARY KEY, s TEXT UNIQUE) WITH (dictionary = 's')")
+            .is_err());
+        assert!(engine
+            .execute("ALTER TABLE tickets ADD CONSTRAINT tickets_status UNIQUE (status)")
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn foreign_keys_enforced() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);
+                 CREATE TABLE posts (id INT PRIMARY KEY, author INT REFERENCES users(id), \
+                 editor INT, CONSTRAINT posts_editor FOREIGN KEY (editor) REFERENCES users(id));
+                 ALTER TABLE users ADD COLUMN boss INT REFERENCES users(id);
+                 INSERT INTO users (id, name) VALUES (1, 'Daniel');",
+            )
+            .unwrap();
+
+        let err = engine
+            .execute("INSERT INTO posts (id, author) VALUES (1, 2)")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Constraint posts_author_fkey on posts failed, author = 2 has no matching users.id"
+        );
+        assert!(engine
+            .execute("INSERT INTO posts (id, editor) VALUES (1, 2)")
+            .is_err());
+        engine
+            .execute("INSERT INTO posts (id, author, editor) VALUES (1, 1, 1), (2, NULL, NULL)")
+            .unwrap();
+        // Rows can refer to rows inserted along with them
+        engine
+            .execute("INSERT INTO users (id, boss) VALUES (2, 3), (3, 1)")
+            .unwrap();
+        assert!(engine
+            .execute("INSERT INTO users (id, boss) VALUES (4, 5)")
+            .is_err());
+
+        assert!(engine
+            .execute("UPDATE posts SET author = 4 WHERE id = 1")
+            .is_err());
+        engine
+            .execute("UPDATE posts SET author = 2 WHERE id = 2")
+            .unwrap();
+        let rows = engine.storage.scan_table("posts").unwrap();
+        assert_eq!(*rows[1].columns["author"], Value::Number(2.into()));
+    }
+
+    #[test]
+    #[traced_test]
+    fn referential_actions() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);
+                 CREATE TABLE posts (id INT PRIMARY KEY, \
+                 author INT REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE, editor INT, \
+                 CONSTRAINT posts_editor FOREIGN KEY (editor) REFERENCES users(id) \
+                 ON DELETE SET NULL);
+                 CREATE TABLE comments (id INT PRIMARY KEY, \
+                 post INT REFERENCES posts(id) ON DELETE CASCADE);
+                 CREATE TABLE likes (id INT PRIMARY KEY, post INT REFERENCES posts(id));
+                 INSERT INTO users (id, name) VALUES (1, 'Daniel'), (2, 'Ben');
+                 INSERT INTO posts (id, author, editor) VALUES (1, 1, 2), (2, 2, 1);
+                 INSERT INTO comments (id, post) VALUES (1, 1), (2, 2);",
+            )
+            .unwrap();
+        let metadata = engine.storage.table_metadata("posts").unwrap();
+        assert_eq!(
+            schema::column_definition("author", &metadata["author"]),
+            "author INT REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE"
+        );
+
+        engine.execute("DELETE FROM users WHERE id = 1").unwrap();
+        let posts = engine.storage.scan_table("posts").unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(*posts[0].columns["id"], Value::Number(2.into()));
+        assert!(!posts[0].columns.contains_key("editor"));
+        let comments = engine.storage.scan_table("comments").unwrap();
+        assert_eq!(comments.len(), 1);
+        assert_eq!(*comments[0].columns["id"], Value::Number(2.into()));
+
+        engine
+            .execute("UPDATE users SET id = 3 WHERE id = 2")
+            .unwrap();
+        let posts = engine.storage.scan_table("posts").unwrap();
+        assert_eq!(*posts[0].columns["author"], Value::Number(3.into()));
+
+        // A foreign key without an action stops the whole delete
+        engine
+            .execute("INSERT INTO likes (id, post) VALUES (1, 2)")
+            .unwrap();
+        let err = engine
+            .execute("DELETE FROM users WHERE id = 3")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Constraint likes_post_fkey on likes failed, post = 2 still refers to posts.id"
+        );
+        assert_eq!(engine.storage.scan_table("users").unwrap().len(), 1);
+        assert_eq!(engine.storage.scan_table("comments").unwrap().len(), 1);
+        assert!(engine.execute("UPDATE posts SET id = 5").is_err());
+
+        assert!(engine
+            .execute(
+                "ALTER TABLE likes ADD CONSTRAINT likes_user FOREIGN KEY (id) \
+                 REFERENCES users(id) ON DELETE CASCADE"
+            )
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn check_constraints() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE products (id INT PRIMARY KEY, price INT CHECK (price > 0), \
+                 discount INT, CONSTRAINT sensible CHECK (discount < price))",
+            )
+            .unwrap();
+        engine
+            .execute("INSERT INTO products (id, price, discount) VALUES (1, 10, 5)")
+            .unwrap();
+        let err = engine
+            .execute("INSERT INTO products (id, price) VALUES (2, 0)")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Constraint products_price_check on products failed, CHECK (price > 0) is false"
+        );
+        let err = engine
+            .execute("INSERT INTO products (id, price, discount) VALUES (3, 10, 20)")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Constraint sensible on products failed, CHECK (discount < price) is false"
+        );
+        // NULL isn't false so it passes
+        engine
+            .execute("INSERT INTO products (id) VALUES (4)")
+            .unwrap();
+        assert!(engine
+            .execute("UPDATE products SET discount = 50 WHERE id = 1")
+            .is_err());
+
+        assert!(engine
+            .execute("ALTER TABLE products ADD CONSTRAINT small CHECK (price < 5)")
+            .is_err());
+        engine
+            .execute("ALTER TABLE products ADD CONSTRAINT cheap CHECK (price < 100)")
+            .unwrap();
+        assert!(engine
+            .execute("INSERT INTO products (id, price) VALUES (5, 200)")
+            .is_err());
+
+        engine
+            .execute("ALTER TABLE products RENAME COLUMN price TO cost")
+            .unwrap();
+        assert!(engine
+            .execute("INSERT INTO products (id, cost) VALUES (6, 0)")
+            .is_err());
+        engine
+            .execute("INSERT INTO products (id, cost, discount) VALUES (6, 1, 0)")
+            .unwrap();
+        // Dropping a column drops the checks that refer to it
+        engine
+            .execute("ALTER TABLE products DROP COLUMN discount")
+            .unwrap();
+        let constraints = engine.storage.constraints("products").unwrap();
+        assert_eq!(constraints.len(), 1);
+        assert_eq!(constraints[0].name, "cheap");
+
+        assert!(engine
+            .execute("CREATE TABLE t (a INT CHECK (a > b), b INT)")
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn explicit_null() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT NOT NULL, email TEXT);
+                 INSERT INTO users (id, name, email) VALUES (1, 'Daniel', NULL);",
+            )
+            .unwrap();
+        let err = engine
+            .execute("INSERT INTO users (id, name) VALUES (2, NULL)")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Column name is NOT NULL");
+        assert!(engine
+            .execute("INSERT INTO users (id, name) VALUES (NULL, 'Ben')")
+            .is_err());
+
+        assert!(engine.execute("UPDATE users SET name = NULL").is_err());
+        engine.execute("UPDATE users SET email = 'd@x'").unwrap();
+        engine.execute("UPDATE users SET email = NULL").unwrap();
+        let rows = engine
+            .execute("SELECT * FROM users WHERE email IS NULL")
+            .unwrap();
+        assert_eq!(rows.rows.len(), 1);
+    }
+
+    #[test]
+    #[traced_test]
+    fn identity_columns() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE tickets (id INT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY \
+                 (START WITH 10 INCREMENT BY 5 MAXVALUE 20), name TEXT);
+                 CREATE TABLE countdown (n INT GENERATED ALWAYS AS IDENTITY \
+                 (START WITH 3 INCREMENT BY -1 MINVALUE 1), note TEXT);",
+            )
+            .unwrap();
+        let metadata = engine.storage.table_metadata("tickets").unwrap();
+        assert_eq!(
+            schema::column_definition("id", &metadata["id"]),
+            "id INT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY \
+             (START WITH 10 INCREMENT BY 5 MAXVALUE 20)"
+        );
+
+        engine
+            .execute("INSERT INTO tickets (name) VALUES ('a'), ('b'), ('c')")
+            .unwrap();
+        let ids = engine
+            .storage
+            .scan_table("tickets")
+            .unwrap()
+            .into_iter()
+            .map(|x| x.columns["id"].to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["10", "15", "20"]);
+        let err = engine
+            .execute("INSERT INTO tickets (name) VALUES ('d')")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Identity column id has run out of values");
+
+        engine
+            .execute("INSERT INTO countdown (note) VALUES ('a'), ('b'), ('c')")
+            .unwrap();
+        let rows = engine
+            .execute("SELECT * FROM countdown WHERE n = 1")
+            .unwrap();
+        assert_eq!(*rows.rows[0].columns["note"], Value::Text("c".to_string()));
+        assert!(engine
+            .execute("INSERT INTO countdown (note) VALUES ('d')")
+            .is_err());
+
+        assert!(engine
+            .execute("CREATE TABLE bad (id INT GENERATED BY DEFAULT AS IDENTITY (INCREMENT BY 0))")
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn timezone_aware_timestamps() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE events (id INT PRIMARY KEY, at TIMESTAMP WITH TIME ZONE);
+                 SET TIME ZONE '+02:00';
+                 INSERT INTO events (id, at) VALUES (1, '2024-03-01 12:00:00'), \
+                 (2, '2024-03-01 09:30:00-05:00'), (3, '2024-03-01 11:00:00Z');",
+            )
+            .unwrap();
+        // Stored in UTC
+        let stored = engine
+            .storage
+            .scan_table("events")
+            .unwrap()
+            .into_iter()
+            .map(|x| x.columns["at"].to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stored,
+            [
+                "'2024-03-01 10:00:00'",
+                "'2024-03-01 14:30:00'",
+                "'2024-03-01 11:00:00'"
+            ]
+        );
+
+        let at = |engine: &mut Instance, sql: &str| {
+            engine
+                .execute(sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|x| x.columns["at"].to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            at(&mut engine, "SELECT * FROM events WHERE id = 1"),
+            ["'2024-03-01 12:00:00+02:00'"]
+        );
+        // Comparisons are between instants whatever offset they're written with
+        assert_eq!(
+            at(
+                &mut engine,
+                "SELECT * FROM events WHERE at > '2024-03-01 10:30:00+00:00'"
+            ),
+            ["'2024-03-01 16:30:00+02:00'", "'2024-03-01 13:00:00+02:00'"]
+        );
+        assert_eq!(
+            at(
+                &mut engine,
+                "SET timezone = 'UTC'; SELECT * FROM events WHERE at = '2024-03-01 12:00:00+02'"
+            ),
+            ["'2024-03-01 10:00:00+00:00'"]
+        );
+        engine.set_timezone(TimeZone::parse("-05:00").unwrap());
+        assert_eq!(
+            at(
+                &mut engine,
+                "UPDATE events SET at = '2024-03-02 00:00:00' WHERE id = 3 RETURNING *"
+            ),
+            ["'2024-03-02 00:00:00-05:00'"]
+        );
+
+        assert!(engine
+            .execute("INSERT INTO events (id, at) VALUES (4, 'tomorrow')")
+            .is_err());
+        assert!(engine.execute("SET TIME ZONE 'Mars/Olympus'").is_err());
+        engine.execute("SET TIME ZONE -8").unwrap();
+        assert_eq!(engine.session.timezone, TimeZone::parse("-08:00").unwrap());
+    }
+
+    #[test]
+    #[traced_test]
+    fn sessions() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute("CREATE TABLE events (id INT PRIMARY KEY, at TIMESTAMP WITH TIME ZONE)")
+            .unwrap();
+        let utc = engine.session();
+        engine
+            .execute("SET TIME ZONE '+02:00'; SET sql_safe_updates = 1")
+            .unwrap();
+        let zoned = engine.session();
+        assert!(zoned.safe_updates);
+
+        // Settings made in one session don't apply to another
+        engine.set_session(utc);
+        engine
+            .execute("INSERT INTO events (id, at) VALUES (1, '2024-03-01 12:00:00')")
+            .unwrap();
+        assert!(engine.execute("DELETE FROM events").is_ok());
+        engine.set_session(zoned);
+        engine
+            .execute("INSERT INTO events (id, at) VALUES (1, '2024-03-01 12:00:00')")
+            .unwrap();
+        assert!(engine.execute("DELETE FROM events").is_err());
+        let rows = engine.execute("SELECT * FROM events").unwrap().rows;
+        assert_eq!(
+            rows[0].columns["at"].to_string(),
+            "'2024-03-01 12:00:00+02:00'"
+        );
+        engine.set_session(utc);
+        let rows = engine.execute("SELECT * FROM events").unwrap().rows;
+        assert_eq!(
+            rows[0].columns["at"].to_string(),
+            "'2024-03-01 10:00:00+00:00'"
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn interval_arithmetic() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE invoices (id INT PRIMARY KEY, due TIMESTAMP);
+                 INSERT INTO invoices (id, due) VALUES (1, '2024-01-31 00:00:00'), \
+                 (2, '2024-03-15 00:00:00');
+                 UPDATE invoices SET due = due + INTERVAL '1 month' WHERE id = 1;",
+            )
+            .unwrap();
+        let ids = |engine: &mut Instance, sql: &str| {
+            engine
+                .execute(sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|x| x.columns["id"].to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(
+                &mut engine,
+                "SELECT * FROM invoices WHERE due = '2024-02-29 00:00:00'"
+            ),
+            ["1"]
+        );
+        assert_eq!(
+            ids(
+                &mut engine,
+                "SELECT * FROM invoices WHERE due > '2024-03-03' - INTERVAL '2 days'"
+            ),
+            ["2"]
+        );
+        assert!(engine
+            .execute("UPDATE invoices SET due = due + INTERVAL '1 eon'")
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn safe_updates() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE t (n INT);
+                 INSERT INTO t (n) VALUES (1), (2), (3), (4);
+                 SET sql_safe_updates = 1;",
+            )
+            .unwrap();
+        let err = engine.execute("DELETE FROM t").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UnsafeWrite>(),
+            Some(&UnsafeWrite {
+                table: "t".to_string(),
+                rows: 4,
+                limit: None
+            })
+        );
+        assert!(engine.execute("UPDATE t SET n = 0").is_err());
+        let res = engine.execute("DELETE FROM t WHERE n = 4").unwrap();
+        assert_eq!(res.rows_affected, 1);
+
+        engine.set_safe_updates_limit(Some(1));
+        let err = engine
+            .execute("UPDATE t SET n = 0 WHERE n < 3")
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<UnsafeWrite>().unwrap().rows, 2);
+        assert_eq!(
+            engine
+                .execute("SELECT * FROM t WHERE n = 0")
+                .unwrap()
+                .rows
+                .len(),
+            0
+        );
+        let res = engine
+            .execute_forced("UPDATE t SET n = 0 WHERE n < 3")
+            .unwrap();
+        assert_eq!(res.rows_affected, 2);
+        // Forcing only lasts for the one query
+        assert!(engine.execute("DELETE FROM t WHERE n = 0").is_err());
+
+        engine
+            .execute("SET sql_safe_updates = 0; SET safe_updates_limit = DEFAULT")
+            .unwrap();
+        assert_eq!(engine.execute("DELETE FROM t").unwrap().rows_affected, 3);
+        assert!(engine.execute("SET sql_safe_updates = 'maybe'").is_err());
+    }
+
+    #[test]
+    fn reindex() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE t (id INT PRIMARY KEY, a INT, b TEXT UNIQUE);
+                 INSERT INTO t (id, a, b) VALUES (1, 1, 'x'), (2, 2, 'y');
+                 CREATE INDEX by_a ON t (a);",
+            )
+            .unwrap();
+        let entries = |engine: &Instance, prefix: &[u8]| {
+            let db = engine.storage().handle();
+            let cf = db.cf_handle("t").unwrap();
+            db.iterator_cf(cf, IteratorMode::From(prefix, Direction::Forward))
+                .take_while(|x| x.as_ref().unwrap().0.starts_with(prefix))
+                .count()
+        };
+        let by_a = keys::index_prefix("by_a");
+
+        // A load replacing a row leaves the entries of the row it replaced behind
+        engine
+            .ingest(&InsertOptions {
+                table: "t".to_string(),
+                columns: vec!["id".to_string(), "a".to_string(), "b".to_string()],
+                values: vec![vec![
+                    Value::Number(1.into()).into(),
+                    Value::Number(3.into()).into(),
+                    Value::Text("z".to_string()).into(),
+                ]],
+                returning: None,
+                upsert: false,
+            })
+            .unwrap();
+        assert_eq!(entries(&engine, &by_a), 3);
+        assert_eq!(entries(&engine, keys::INDEX_PREFIX), 3);
+
+        engine.execute("REINDEX INDEX by_a").unwrap();
+        assert_eq!(entries(&engine, &by_a), 2);
+        assert_eq!(entries(&engine, keys::INDEX_PREFIX), 3);
+        engine.execute("REINDEX TABLE t").unwrap();
+        assert_eq!(entries(&engine, &by_a), 2);
+        assert_eq!(entries(&engine, keys::INDEX_PREFIX), 2);
+        let rows = engine.execute("SELECT * FROM t WHERE a = 3").unwrap().rows;
+        assert_eq!(rows.len(), 1);
+        engine
+            .execute("INSERT INTO t (id, a, b) VALUES (3, 1, 'x')")
+            .unwrap();
+        assert!(engine
+            .execute("INSERT INTO t (id, a, b) VALUES (4, 1, 'z')")
+            .is_err());
+
+        assert!(engine.execute("REINDEX INDEX missing").is_err());
+        assert!(engine.execute("REINDEX TABLE missing").is_err());
+        assert!(engine.validate("REINDEX INDEX missing").is_err());
+        engine
+            .validate("REINDEX INDEX by_a; REINDEX TABLE t")
+            .unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn secondary_indexes() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE t (id INT PRIMARY KEY, a INT, b TEXT);
+                 INSERT INTO t (id, a, b) VALUES (1, 1, 'x'), (2, 1, 'y'), (3, 2, 'x');
+                 CREATE INDEX by_ab ON t (a, b);
+                 CREATE TABLE u (n INT);",
+            )
+            .unwrap();
+        let index = |name: &str, columns: &[&str]| Index {
+            name: name.to_string(),
+            columns: columns
+                .iter()
+                .map(|x| expr::normalized(&Expr::Identifier(Ident::new(*x))))
+                .collect(),
+            predicate: None,
+        };
+        let ids = |engine: &mut Instance, sql: &str| {
+            let rows = engine.execute(sql).unwrap().rows;
+            rows.iter()
+                .map(|x| x.columns["id"].to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            engine.storage().indexes("t").unwrap(),
+            vec![index("by_ab", &["a", "b"])]
+        );
+
+        // Names are unique across tables
+        assert!(engine.execute("CREATE INDEX by_ab ON u (n)").is_err());
+        engine
+            .execute("CREATE INDEX IF NOT EXISTS by_ab ON u (n)")
+            .unwrap();
+        assert!(engine.storage().indexes("u").unwrap().is_empty());
+        assert!(engine.execute("CREATE INDEX ON t (missing)").is_err());
+        assert!(engine.execute("CREATE INDEX ON t (a, a)").is_err());
+        assert!(engine.execute("CREATE INDEX ON missing (a)").is_err());
+        assert!(engine
+            .validate("CREATE INDEX i ON t (a); CREATE INDEX i ON t (b)")
+            .is_err());
+        assert!(engine
+            .validate("DROP INDEX by_ab; DROP INDEX by_ab")
+            .is_err());
+        engine
+            .validate("CREATE INDEX i ON t (a); DROP INDEX i")
+            .unwrap();
+
+        // Renames carry the index along, the clone starts without it
+        engine
+            .execute(
+                "ALTER TABLE t RENAME COLUMN a TO c;
+                 ALTER TABLE t RENAME TO s;
+                 CREATE TABLE t CLONE s;",
+            )
+            .unwrap();
+        assert_eq!(
+            engine.storage().indexes("s").unwrap(),
+            vec![index("by_ab", &["c", "b"])]
+        );
+        assert!(engine.storage().indexes("t").unwrap().is_empty());
+        assert_eq!(
+            ids(&mut engine, "SELECT * FROM s WHERE c = 1 AND b > 'x'"),
+            vec!["2"]
+        );
+        assert_eq!(
+            ids(&mut engine, "SELECT * FROM t WHERE c = 1 AND b > 'x'"),
+            vec!["2"]
+        );
+
+        // Bulk loaded rows are indexed too
+        engine
+            .ingest(&InsertOptions {
+                table: "s".to_string(),
+                columns: vec!["id".to_string(), "c".to_string(), "b".to_string()],
+                values: vec![vec![
+                    Value::Number(4.into()).into(),
+                    Value::Number(1.into()).into(),
+                    Value::Text("z".to_string()).into(),
+                ]],
+                returning: None,
+                upsert: false,
+            })
+            .unwrap();
+        assert_eq!(
+            ids(&mut engine, "SELECT * FROM s WHERE c = 1 AND b > 'x'"),
+            vec!["2", "4"]
+        );
+
+        engine
+            .execute("ALTER TABLE s DROP COLUMN b; CREATE INDEX by_c ON s (c)")
+            .unwrap();
+        assert_eq!(
+            engine.storage().indexes("s").unwrap(),
+            vec![index("by_c", &["c"])]
+        );
+        engine.execute("DROP INDEX by_c").unwrap();
+        assert!(engine.storage().indexes("s").unwrap().is_empty());
+        assert!(engine.execute("DROP INDEX by_c").is_err());
+        engine.execute("DROP INDEX IF EXISTS by_c").unwrap();
+        assert_eq!(ids(&mut engine, "SELECT * FROM s WHERE c = 2"), vec!["3"]);
+
+        // A partial index's predicate follows its columns and goes with them
+        assert!(engine
+            .execute("CREATE INDEX bad ON s (c) WHERE missing = 1")
+            .is_err());
+        engine
+            .execute(
+                "ALTER TABLE s ADD COLUMN flag BOOLEAN;
+                 CREATE INDEX flagged ON s (c) WHERE flag = true;
+                 ALTER TABLE s RENAME COLUMN flag TO f;",
+            )
+            .unwrap();
+        let indexes = engine.storage().indexes("s").unwrap();
+        assert_eq!(
+            indexes[0].predicate.as_ref().unwrap().to_string(),
+            "\"f\" = true"
+        );
+        engine.execute("ALTER TABLE s DROP COLUMN f").unwrap();
+        assert!(engine.storage().indexes("s").unwrap().is_empty());
+    }
+
+    #[test]
+    fn table_quotas() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        let crossed = Arc::new(Mutex::new(vec![]));
+        let seen = crossed.clone();
+        engine.set_quota_hook(move |usage| {
+            seen.lock()
+                .unwrap()
+                .push((usage.table.clone(), usage.threshold))
+        });
+        engine
+            .execute(
+                "CREATE TABLE notes (id INT PRIMARY KEY, body TEXT) WITH (max_bytes = 65536);
+                 CREATE TABLE other (id INT PRIMARY KEY, body TEXT);",
+            )
+            .unwrap();
+        let body = "x".repeat(1000);
+        let insert = |table: &str, id: usize| {
+            format!(
+                "INSERT INTO {} (id, body) VALUES ({}, '{}')",
+                table, id, body
+            )
+        };
+
+        let mut inserted = 0;
+        let err = loop {
+            match engine.execute(&insert("notes", inserted)) {
+                Ok(_) => inserted += 1,
+                Err(e) => break e,
+            }
+            assert!(inserted < 1000, "Quota was never reached");
+        };
+        let err = err.downcast::<QuotaExceeded>().unwrap();
+        assert_eq!(err.table, "notes");
+        assert_eq!(err.quota, 65536);
+        assert!(err.used + err.adding > 65536);
+        assert!(inserted > 10);
+        // Each threshold is reported once, on the way past it
+        let notes = |threshold| ("notes".to_string(), threshold);
+        assert_eq!(*crossed.lock().unwrap(), vec![notes(80), notes(90)]);
+        // Other tables aren't limited
+        for id in 0..inserted + 10 {
+            engine.execute(&insert("other", id)).unwrap();
+        }
+
+        engine.set_quota("notes", Some(1 << 20)).unwrap();
+        engine.execute(&insert("notes", inserted)).unwrap();
+        // A load over the quota fails before any of it is written
+        let load = InsertOptions {
+            table: "notes".to_string(),
+            columns: vec!["id".to_string(), "body".to_string()],
+            values: (10_000..12_000)
+                .map(|id| {
+                    vec![
+                        Value::Number(id.into()).into(),
+                        Value::Text(body.clone()).into(),
+                    ]
+                })
+                .collect(),
+            returning: None,
+            upsert: false,
+        };
+        assert!(engine.ingest(&load).unwrap_err().is::<QuotaExceeded>());
+        let count =
+            |engine: &mut Instance| engine.execute("SELECT * FROM notes").unwrap().rows.len();
+        assert_eq!(count(&mut engine), inserted + 1);
+        engine.set_quota("notes", None).unwrap();
+        engine.ingest(&load).unwrap();
+        assert_eq!(count(&mut engine), inserted + 2001);
+        assert!(engine.set_quota("missing", Some(1)).is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn keys_sort_by_value() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine
+            .execute(
+                "CREATE TABLE readings (at TIMESTAMP, sensor DECIMAL, PRIMARY KEY (at, sensor));
+                 CREATE INDEX by_sensor ON readings (sensor);
+                 INSERT INTO readings (at, sensor) VALUES ('2024-01-05T10:00', 10), \
+                 ('2024-01-05 09:00:00', -2.5), ('2024-01-05', 9), ('2024-01-05', -10);",
+            )
+            .unwrap();
+        let column = |engine: &mut Instance, sql: &str, column: &str| {
+            engine
+                .execute(sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|x| x.columns[column].to_string())
+                .collect::<Vec<_>>()
+        };
+        // Timestamps are written out in full so they sort by time rather than as they were typed
+        assert_eq!(
+            column(&mut engine, "SELECT * FROM readings", "at"),
+            [
+                "'2024-01-05 00:00:00'",
+                "'2024-01-05 00:00:00'",
+                "'2024-01-05 09:00:00'",
+                "'2024-01-05 10:00:00'"
+            ]
+        );
+        assert_eq!(
+            column(&mut engine, "SELECT * FROM readings", "sensor"),
+            ["-10", "9", "-2.5", "10"]
+        );
+        assert_eq!(
+            column(
+                &mut engine,
+                "SELECT * FROM readings WHERE at = '2024-01-05'",
+                "sensor"
+            ),
+            ["-10", "9"]
+        );
+        assert_eq!(
+            column(
+                &mut engine,
+                "SELECT * FROM readings WHERE sensor < 9.5",
+                "sensor"
+            ),
+            ["-10", "-2.5", "9"]
+        );
+        assert!(engine
+            .execute("INSERT INTO readings (at, sensor) VALUES ('soon', 1)")
+            .is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn prefix_bloom_filters() {
+        let handle = TableHandle::new();
+        let config = |bloom: bool| Config {
+            storage: StorageConfig {
+                prefix_bloom_filters: bloom,
+                ..StorageConfig::with_path(&handle.path)
+            },
+            ..Default::default()
+        };
+        let ids = |engine: &mut Instance, sql: &str| {
+            engine
+                .execute(sql)
+                .unwrap()
+                .rows
+                .iter()
+                .map(|x| x.columns["id"].to_string())
+                .collect::<Vec<_>>()
+        };
+        let check = |engine: &mut Instance| {
+            assert_eq!(ids(engine, "SELECT * FROM t WHERE id = 150"), ["150"]);
+            assert!(ids(engine, "SELECT * FROM t WHERE id = 1000").is_empty());
+            assert_eq!(ids(engine, "SELECT * FROM t WHERE a = 7").len(), 20);
+            assert_eq!(ids(engine, "SELECT * FROM t WHERE b = 'b199'"), ["199"]);
+            assert_eq!(ids(engine, "SELECT * FROM t").len(), 200);
+        };
+
+        let mut engine = Instance::new_with_config(&config(true));
+        engine
+            .execute(
+                "CREATE TABLE t (id INT PRIMARY KEY, a INT, b TEXT UNIQUE);
+                 CREATE INDEX by_a ON t (a);",
+            )
+            .unwrap();
+        // Spread over several files so lookups have some to skip
+        for chunk in 0..4 {
+            let values = (chunk * 50..(chunk + 1) * 50)
+                .map(|id| format!("({}, {}, 'b{}')", id, id % 10, id))
+                .collect::<Vec<_>>();
+            engine
+                .execute(&format!(
+                    "INSERT INTO t (id, a, b) VALUES {}",
+                    values.join(", ")
+                ))
+                .unwrap();
+            let db = engine.storage().handle();
+            db.flush_cf(db.cf_handle("t").unwrap()).unwrap();
+        }
+        check(&mut engine);
+        drop(engine);
+
+        // Files written with the filters still read correctly without them
+        let mut engine = Instance::new_with_config(&config(false));
+        check(&mut engine);
+    }
+
+    #[test]
+    #[traced_test]
+    fn statement_timeout() {
+        let handle = TableHandle::new();
+        let mut engine = Instance::new_with_path(&handle.path);
+        engine.execute("CREATE TABLE t (n INT)").unwrap();
+        let values = (0..100).map(|x| format!("({})", x)).collect::<Vec<_>>();
+        engine
+            .execute(&format!("INSERT INTO t (n) VALUES {}", values.join(", ")))
+            .unwrap();
+
+        // Anything is over a nanosecond, so the scan stops at its first check
+        engine.set_statement_timeout(Some(Duration::from_nanos(1)));
+        let err = engine.execute("SELECT * FROM t").unwrap_err();
+        let timeout = err.downcast_ref::<StatementTimeout>().unwrap();
+        assert_eq!(timeout.rows, 64);
+        assert!(engine.execute("DELETE FROM t").is_err());
+
+        engine.execute("SET statement_timeout = 0").unwrap();
+        assert_eq!(engine.execute("SELECT * FROM t").unwrap().rows.len(), 100);
+        engine.execute("SET statement_timeout = '5s'").unwrap();
+        assert_eq!(
+            engine.session.statement_timeout,
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(engine.execute("SELECT * FROM t").unwrap().rows.len(), 100);
+        assert!(engine
+            .execute("SET statement_timeout = '5 fortnights'")
+            .is_err());
+        assert!(engine.execute("SET search_path = 'x'").is_err());
+    }
 }
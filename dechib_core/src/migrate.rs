@@ -0,0 +1,237 @@
+//! Versioned schema migrations. A migration directory holds files named
+//! `<version>_<name>.up.sql`, each optionally paired with a `<version>_<name>.down.sql` that undoes
+//! it. Applied versions are recorded in a system table so every migration only runs once.
+//!
+//! Migrators can't run at the same time: rocksdb locks the database directory for as long as an
+//! instance has it open, so a second process fails to open it rather than interleaving.
+use crate::keys;
+use crate::Instance;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use tracing::info;
+
+const MIGRATIONS_CF: &str = "__dechib_migrations__";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Migration {
+    pub version: u64,
+    pub name: String,
+    pub up: String,
+    pub down: Option<String>,
+}
+
+/// Reads every migration in `dir`, ordered by version.
+pub fn load_migrations(dir: impl AsRef<Path>) -> anyhow::Result<Vec<Migration>> {
+    let dir = dir.as_ref();
+    let mut ups = BTreeMap::new();
+    let mut downs = BTreeMap::new();
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|x| x.to_str()) else {
+            continue;
+        };
+        let (stem, found) = if let Some(stem) = file_name.strip_suffix(".up.sql") {
+            (stem, &mut ups)
+        } else if let Some(stem) = file_name.strip_suffix(".down.sql") {
+            (stem, &mut downs)
+        } else {
+            continue;
+        };
+        let (version, name) = stem.split_once('_').unwrap_or((stem, ""));
+        let version: u64 = version
+            .parse()
+            .with_context(|| format!("Migration {} doesn't start with a version", file_name))?;
+        let sql = std::fs::read_to_string(&path)?;
+        if found.insert(version, (name.to_string(), sql)).is_some() {
+            anyhow::bail!("Multiple migrations with version {}", version);
+        }
+    }
+
+    let mut migrations = Vec::with_capacity(ups.len());
+    for (version, (name, up)) in ups {
+        let down = match downs.remove(&version) {
+            Some((down_name, sql)) if down_name == name => Some(sql),
+            Some((down_name, _)) => anyhow::bail!(
+                "Down migration {}_{} doesn't match {}_{}",
+                version,
+                down_name,
+                version,
+                name
+            ),
+            None => None,
+        };
+        migrations.push(Migration {
+            version,
+            name,
+            up,
+            down,
+        });
+    }
+    if let Some(version) = downs.keys().next() {
+        anyhow::bail!("Down migration {} has no up migration", version);
+    }
+    Ok(migrations)
+}
+
+fn version_key(version: u64) -> Vec<u8> {
+    keys::data_key(version.to_be_bytes())
+}
+
+impl Instance {
+    /// Versions of every applied migration along with their names.
+    pub fn applied_migrations(&self) -> anyhow::Result<BTreeMap<u64, String>> {
+        let mut res = BTreeMap::new();
+        for (key, value) in self.storage.system_scan(MIGRATIONS_CF, keys::DATA_PREFIX)? {
+            let version = keys::strip_data_prefix(&key)
+                .and_then(|x| <[u8; 8]>::try_from(x).ok())
+                .map(u64::from_be_bytes)
+                .context("Invalid migration record")?;
+            res.insert(version, String::from_utf8(value.into_vec())?);
+        }
+        Ok(res)
+    }
+
+    /// Applies every migration that hasn't been applied yet in version order. Returns the
+    /// versions that were applied.
+    ///
+    /// DDL isn't transactional, so if a migration fails part way through whatever it already did
+    /// stays done and it isn't recorded as applied.
+    pub fn migrate_up(&mut self, migrations: &[Migration]) -> anyhow::Result<Vec<u64>> {
+        let applied = self.applied_migrations()?;
+        let mut res = vec![];
+        for migration in migrations {
+            match applied.get(&migration.version) {
+                Some(name) if *name == migration.name => continue,
+                Some(name) => anyhow::bail!(
+                    "Migration {} was applied as {} but is now called {}",
+                    migration.version,
+                    name,
+                    migration.name
+                ),
+                None => {}
+            }
+            info!(version = migration.version, name = %migration.name, "Applying migration");
+            self.execute(&migration.up).with_context(|| {
+                format!("Migration {}_{} failed", migration.version, migration.name)
+            })?;
+            self.storage.system_put(
+                MIGRATIONS_CF,
+                &version_key(migration.version),
+                migration.name.as_bytes(),
+            )?;
+            res.push(migration.version);
+        }
+        Ok(res)
+    }
+
+    /// Reverts every applied migration newer than `target`, newest first. Returns the versions
+    /// that were reverted.
+    pub fn migrate_down(
+        &mut self,
+        migrations: &[Migration],
+        target: u64,
+    ) -> anyhow::Result<Vec<u64>> {
+        let applied = self.applied_migrations()?;
+        let mut res = vec![];
+        for (version, name) in applied
+            .range((Bound::Excluded(target), Bound::Unbounded))
+            .rev()
+        {
+            let down = migrations
+                .iter()
+                .find(|x| x.version == *version)
+                .and_then(|x| x.down.as_ref())
+                .with_context(|| format!("No down migration for {}_{}", version, name))?;
+            info!(version, name = %name, "Reverting migration");
+            self.execute(down)
+                .with_context(|| format!("Reverting {}_{} failed", version, name))?;
+            self.storage
+                .system_delete(MIGRATIONS_CF, &version_key(*version))?;
+            res.push(*version);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, StorageConfig};
+    use crate::storage_engine::RecoveryOptions;
+    use tempfile::tempdir;
+    use tracing_test::traced_test;
+
+    #[test]
+    #[traced_test]
+    fn apply_and_revert() {
+        let db_dir = tempdir().unwrap();
+        let migration_dir = tempdir().unwrap();
+        let write = |name: &str, sql: &str| {
+            std::fs::write(migration_dir.path().join(name), sql).unwrap();
+        };
+        write(
+            "0001_users.up.sql",
+            "CREATE TABLE users (id INT PRIMARY KEY, name TEXT NOT NULL);",
+        );
+        write(
+            "0002_seed.up.sql",
+            "INSERT INTO users (id, name) VALUES (1, 'Daniel');",
+        );
+        write(
+            "0002_seed.down.sql",
+            "CREATE TABLE seed_reverted (id INT PRIMARY KEY);",
+        );
+        write("README.md", "not a migration");
+
+        let migrations = load_migrations(migration_dir.path()).unwrap();
+        assert_eq!(migrations.len(), 2);
+        assert_eq!(migrations[0].name, "users");
+        assert!(migrations[0].down.is_none());
+        assert!(migrations[1].down.is_some());
+
+        let mut instance = Instance::new_with_path(db_dir.path());
+        assert_eq!(instance.migrate_up(&migrations).unwrap(), vec![1, 2]);
+        assert_eq!(instance.migrate_up(&migrations).unwrap(), Vec::<u64>::new());
+        assert_eq!(
+            instance.applied_migrations().unwrap(),
+            BTreeMap::from([(1, "users".to_string()), (2, "seed".to_string())])
+        );
+
+        assert_eq!(instance.migrate_down(&migrations, 1).unwrap(), vec![2]);
+        assert!(instance.storage.table_metadata("seed_reverted").is_ok());
+        assert_eq!(instance.applied_migrations().unwrap().len(), 1);
+
+        // No down migration for the first one
+        assert!(instance.migrate_down(&migrations, 0).is_err());
+        assert_eq!(instance.applied_migrations().unwrap().len(), 1);
+    }
+
+    #[test]
+    #[traced_test]
+    fn one_migrator_at_a_time() {
+        let db_dir = tempdir().unwrap();
+        let _instance = Instance::new_with_path(db_dir.path());
+        // A second migrator can't open the database while the first has it
+        let config = Config {
+            storage: StorageConfig::with_path(db_dir.path()),
+            ..Default::default()
+        };
+        assert!(Instance::open_with_recovery(&config, &RecoveryOptions::default()).is_err());
+    }
+
+    #[test]
+    fn mismatched_files() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("0001_a.up.sql"), "").unwrap();
+        std::fs::write(dir.path().join("0001_b.down.sql"), "").unwrap();
+        assert!(load_migrations(dir.path()).is_err());
+
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("first.up.sql"), "").unwrap();
+        assert!(load_migrations(dir.path()).is_err());
+    }
+}
@@ -1,7 +1,11 @@
+use crate::storage_engine::StorageEngine;
 use crate::types::*;
-use sqlparser::ast::Statement;
+use anyhow::Context;
+use sqlparser::ast::{self, DataType, Expr, SetExpr, Statement};
 use sqlparser::dialect::GenericDialect;
+use sqlparser::keywords::Keyword;
 use sqlparser::parser::Parser;
+use sqlparser::tokenizer::Token;
 use std::collections::BTreeMap;
 use tracing::debug;
 
@@ -9,18 +13,95 @@ use tracing::debug;
 pub struct QueryEngine;
 
 impl QueryEngine {
+    /// Like [`Parser::parse_sql`] but statements sqlparser doesn't know are picked out first.
     pub fn process_sql(&self, sql: &str) -> anyhow::Result<Vec<Command>> {
         let dialect = GenericDialect {};
-        let parsed = Parser::parse_sql(&dialect, sql)?;
-        debug!(ast=?parsed, "parsed sql query");
-        let mut res = Vec::with_capacity(parsed.len());
-
-        for statement in &parsed {
-            res.push(Command::try_from(statement)?);
+        let mut parser = Parser::new(&dialect).try_with_sql(sql)?;
+        let mut res = vec![];
+        loop {
+            while parser.consume_token(&Token::SemiColon) {}
+            if parser.peek_token().token == Token::EOF {
+                break;
+            }
+            if let Some(reindex) = parse_reindex(&mut parser)? {
+                res.push(Command::Reindex(reindex));
+            } else {
+                let statement = parser.parse_statement()?;
+                debug!(ast=?statement, "parsed sql query");
+                res.push(Command::try_from(&statement)?);
+            }
+            let next = parser.peek_token().token;
+            if next != Token::SemiColon && next != Token::EOF {
+                anyhow::bail!("Expected end of statement, found {}", next);
+            }
         }
         Ok(res)
     }
 
+    /// Parses a statement containing `$n` or `?` placeholders and works out the type each
+    /// parameter must have from the metadata of the columns they're bound to.
+    pub fn prepare(&self, sql: &str, storage: &StorageEngine) -> anyhow::Result<PreparedStatement> {
+        let dialect = GenericDialect {};
+        let statements = Parser::parse_sql(&dialect, sql)?;
+        debug!(ast=?statements, "parsed prepared statement");
+
+        let mut parameters: BTreeMap<usize, Parameter> = BTreeMap::new();
+        for statement in &statements {
+            let Some((table, columns, rows)) = insert_values(statement) else {
+                // Nothing to infer, but make sure the statement is one we can run
+                Command::try_from(statement)?;
+                continue;
+            };
+            let metadata = storage.table_metadata(table)?;
+            let mut indexer = PlaceholderIndexer::default();
+            for row in rows {
+                for (i, expr) in row.iter().enumerate() {
+                    let Expr::Value(ast::Value::Placeholder(p)) = expr else {
+                        continue;
+                    };
+                    let index = indexer.index(p)?;
+                    let column = normalize_ident(
+                        columns
+                            .get(i)
+                            .context("More values than columns in insert")?,
+                    );
+                    let descriptor = metadata
+                        .get(&column)
+                        .with_context(|| format!("Column {} not present in table", column))?
+                        .clone();
+                    if let Some(existing) = parameters.get(&index) {
+                        if existing.descriptor.datatype != descriptor.datatype {
+                            anyhow::bail!(
+                                "Parameter ${} used as both {} and {}",
+                                index + 1,
+                                existing.descriptor.datatype,
+                                descriptor.datatype
+                            );
+                        }
+                    } else {
+                        parameters.insert(index, Parameter { column, descriptor });
+                    }
+                }
+            }
+        }
+
+        let parameters = parameters
+            .into_iter()
+            .enumerate()
+            .map(|(expected, (index, param))| {
+                if expected != index {
+                    anyhow::bail!("Parameter ${} is never used", expected + 1);
+                }
+                Ok(param)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(PreparedStatement {
+            statements,
+            parameters,
+        })
+    }
+
     pub fn create_execution_plan(&self, query: &str) -> anyhow::Result<()> {
         let dialect = GenericDialect {};
         let parsed = Parser::parse_sql(&dialect, query)?;
@@ -29,6 +110,133 @@ impl QueryEngine {
     }
 }
 
+/// `REINDEX { INDEX | TABLE } <name>`, `None` when the next statement is something else.
+fn parse_reindex(parser: &mut Parser) -> anyhow::Result<Option<Reindex>> {
+    match parser.peek_token().token {
+        Token::Word(word)
+            if word.quote_style.is_none() && word.value.eq_ignore_ascii_case("reindex") =>
+        {
+            parser.next_token();
+        }
+        _ => return Ok(None),
+    }
+    let index = parser.parse_keyword(Keyword::INDEX);
+    if !index {
+        parser.expect_keyword(Keyword::TABLE)?;
+    }
+    let name = normalize_object_name(&parser.parse_object_name(false)?);
+    Ok(Some(if index {
+        Reindex::Index(name)
+    } else {
+        Reindex::Table(name)
+    }))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parameter {
+    /// Column the parameter is inserted into, this is where the type comes from
+    pub column: String,
+    pub descriptor: ColumnDescriptor,
+}
+
+#[derive(Clone, Debug)]
+pub struct PreparedStatement {
+    statements: Vec<Statement>,
+    parameters: Vec<Parameter>,
+}
+
+impl PreparedStatement {
+    pub fn parameters(&self) -> &[Parameter] {
+        &self.parameters
+    }
+
+    pub fn parameter_types(&self) -> impl Iterator<Item = &DataType> + '_ {
+        self.parameters.iter().map(|x| &x.descriptor.datatype)
+    }
+
+    /// Checks the values against the inferred parameter types and substitutes them into the
+    /// statement ready for execution.
+    pub fn bind(&self, params: &[Value]) -> anyhow::Result<Vec<Command>> {
+        if params.len() != self.parameters.len() {
+            anyhow::bail!(
+                "Expected {} parameters, got {}",
+                self.parameters.len(),
+                params.len()
+            );
+        }
+        for (i, (param, value)) in self.parameters.iter().zip(params).enumerate() {
+            if !param.descriptor.value_matches_type(value) {
+                anyhow::bail!(
+                    "Parameter ${} for column {} must be {}, got {:?}",
+                    i + 1,
+                    param.column,
+                    param.descriptor.datatype,
+                    value
+                );
+            }
+        }
+
+        let mut res = Vec::with_capacity(self.statements.len());
+        for statement in &self.statements {
+            let mut statement = statement.clone();
+            if let Some(rows) = insert_values_mut(&mut statement) {
+                let mut indexer = PlaceholderIndexer::default();
+                for expr in rows.iter_mut().flatten() {
+                    if let Expr::Value(ast::Value::Placeholder(p)) = expr {
+                        let index = indexer.index(p)?;
+                        *expr = Expr::Value(ast::Value::from(&params[index]));
+                    }
+                }
+            }
+            res.push(Command::try_from(&statement)?);
+        }
+        Ok(res)
+    }
+}
+
+/// Placeholders are either numbered `$1` or positional `?`, this maps both to a zero based index.
+#[derive(Default)]
+struct PlaceholderIndexer {
+    next: usize,
+}
+
+impl PlaceholderIndexer {
+    fn index(&mut self, placeholder: &str) -> anyhow::Result<usize> {
+        if placeholder == "?" {
+            self.next += 1;
+            return Ok(self.next - 1);
+        }
+        match placeholder.strip_prefix('$').map(str::parse::<usize>) {
+            Some(Ok(n)) if n > 0 => Ok(n - 1),
+            _ => anyhow::bail!("Invalid placeholder: {}", placeholder),
+        }
+    }
+}
+
+fn insert_values(statement: &Statement) -> Option<(String, &[ast::Ident], &[Vec<Expr>])> {
+    let Statement::Insert(insert) = statement else {
+        return None;
+    };
+    match insert.source.as_ref()?.body.as_ref() {
+        SetExpr::Values(v) => Some((
+            normalize_object_name(&insert.table_name),
+            insert.columns.as_slice(),
+            v.rows.as_slice(),
+        )),
+        _ => None,
+    }
+}
+
+fn insert_values_mut(statement: &mut Statement) -> Option<&mut Vec<Vec<Expr>>> {
+    let Statement::Insert(insert) = statement else {
+        return None;
+    };
+    match insert.source.as_mut()?.body.as_mut() {
+        SetExpr::Values(v) => Some(&mut v.rows),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -42,4 +250,174 @@ mod tests {
             .process_sql("INSERT INTO Persons (FirstName, FirstName) VALUES ('Daniel', 'Daniel');");
         assert!(res.is_err(), "{:?} should be error", res);
     }
+
+    #[test]
+    #[traced_test]
+    fn identifier_case_folding() {
+        let engine = QueryEngine::default();
+        let res = engine
+            .process_sql(
+                r#"CREATE TABLE Users ("Name" TEXT, Age INT, "ID" INT, PRIMARY KEY ("ID"));"#,
+            )
+            .unwrap();
+        let Command::CreateTable(opts) = &res[0] else {
+            panic!("Expected create table: {:?}", res);
+        };
+        assert_eq!(opts.name, "users");
+        assert_eq!(
+            opts.columns.keys().collect::<Vec<_>>(),
+            vec!["ID", "Name", "age"]
+        );
+        assert!(opts.columns["ID"].primary_key);
+
+        let res = engine
+            .process_sql(r#"INSERT INTO USERS ("Name", AGE) VALUES ('Daniel', 31);"#)
+            .unwrap();
+        let Command::Insert(opts) = &res[0] else {
+            panic!("Expected insert: {:?}", res);
+        };
+        assert_eq!(opts.table, "users");
+        assert_eq!(opts.columns, vec!["Name", "age"]);
+
+        // Quoting a lowercase name is the same column as the unquoted one
+        let res = engine.process_sql(r#"INSERT INTO users ("age", AGE) VALUES (1, 2);"#);
+        assert!(res.is_err(), "{:?} should be error", res);
+        let res = engine.process_sql(r#"INSERT INTO users ("Age", AGE) VALUES (1, 2);"#);
+        assert!(res.is_ok(), "{:?} should be ok", res);
+    }
+
+    #[test]
+    #[traced_test]
+    fn clone_table() {
+        let engine = QueryEngine::default();
+        let res = engine
+            .process_sql("CREATE TABLE Fixture CLONE users;")
+            .unwrap();
+        let Command::CloneTable(opts) = &res[0] else {
+            panic!("Expected clone table: {:?}", res);
+        };
+        assert_eq!(opts.name, "fixture");
+        assert_eq!(opts.source, "users");
+    }
+
+    #[test]
+    #[traced_test]
+    fn create_index() {
+        let engine = QueryEngine::default();
+        let res = engine
+            .process_sql("CREATE INDEX ON Users (Last, first); CREATE INDEX by_age ON users (age)")
+            .unwrap();
+        let Command::CreateIndex(opts) = &res[0] else {
+            panic!("Expected create index: {:?}", res);
+        };
+        assert_eq!(opts.table, "users");
+        assert_eq!(opts.index.name, "users_last_first_idx");
+        let columns = opts.index.columns.iter().map(|x| x.to_string());
+        assert_eq!(columns.collect::<Vec<_>>(), vec!["\"last\"", "\"first\""]);
+        let Command::CreateIndex(opts) = &res[1] else {
+            panic!("Expected create index: {:?}", res);
+        };
+        assert_eq!(opts.index.name, "by_age");
+        assert!(opts.index.predicate.is_none());
+
+        let res = engine
+            .process_sql("CREATE INDEX live ON posts (author) WHERE deleted = false")
+            .unwrap();
+        let Command::CreateIndex(opts) = &res[0] else {
+            panic!("Expected create index: {:?}", res);
+        };
+        assert_eq!(
+            opts.index.predicate.as_ref().map(|x| x.to_string()),
+            Some("deleted = false".to_string())
+        );
+
+        let res = engine
+            .process_sql("CREATE INDEX ON users (LOWER(Email), id)")
+            .unwrap();
+        let Command::CreateIndex(opts) = &res[0] else {
+            panic!("Expected create index: {:?}", res);
+        };
+        assert_eq!(opts.index.name, "users_lower_id_idx");
+        assert_eq!(opts.index.columns[0].to_string(), "lower(\"email\")");
+
+        let res = engine
+            .process_sql("REINDEX INDEX By_Age; reindex table Users;")
+            .unwrap();
+        let [Command::Reindex(Reindex::Index(index)), Command::Reindex(Reindex::Table(table))] =
+            res.as_slice()
+        else {
+            panic!("Expected reindex: {:?}", res);
+        };
+        assert_eq!((index.as_str(), table.as_str()), ("by_age", "users"));
+        for sql in ["REINDEX users", "REINDEX TABLE users users"] {
+            assert!(engine.process_sql(sql).is_err(), "{}", sql);
+        }
+
+        let res = engine
+            .process_sql("DROP INDEX IF EXISTS by_age, By_Name")
+            .unwrap();
+        let Command::DropIndex(opts) = &res[0] else {
+            panic!("Expected drop index: {:?}", res);
+        };
+        assert_eq!(opts.names, vec!["by_age", "by_name"]);
+        assert!(opts.if_exists);
+
+        for sql in [
+            "CREATE UNIQUE INDEX ON users (age)",
+            "CREATE INDEX ON users (age DESC)",
+            "CREATE INDEX ON users (age + 1)",
+        ] {
+            assert!(engine.process_sql(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn on_update_column() {
+        let engine = QueryEngine::default();
+        let res = engine
+            .process_sql(
+                "CREATE TABLE posts (id INT PRIMARY KEY, \
+                 updated_at TIMESTAMP DEFAULT now() ON UPDATE CURRENT_TIMESTAMP);",
+            )
+            .unwrap();
+        let Command::CreateTable(opts) = &res[0] else {
+            panic!("Expected create table: {:?}", res);
+        };
+        let updated_at = &opts.columns["updated_at"];
+        assert_eq!(
+            updated_at.on_update.as_ref().map(|x| x.to_string()),
+            Some("CURRENT_TIMESTAMP".to_string())
+        );
+        assert!(opts.columns["id"].on_update.is_none());
+    }
+
+    #[test]
+    #[traced_test]
+    fn set_statement_timeout() {
+        let engine = QueryEngine::default();
+        let cases = [
+            ("100", Some(100)),
+            ("'250ms'", Some(250)),
+            ("'1.5s'", Some(1500)),
+            ("'2 min'", Some(120_000)),
+            ("0", None),
+            ("DEFAULT", None),
+        ];
+        for (value, expected) in cases {
+            let res = engine
+                .process_sql(&format!("SET statement_timeout = {}", value))
+                .unwrap();
+            let Command::Set(Variable::StatementTimeout(timeout)) = &res[0] else {
+                panic!("Expected set: {:?}", res);
+            };
+            assert_eq!(
+                *timeout,
+                expected.map(std::time::Duration::from_millis),
+                "{}",
+                value
+            );
+        }
+        assert!(engine.process_sql("SET statement_timeout = '-1s'").is_err());
+    }
 }
@@ -0,0 +1,832 @@
+//! Turning catalog metadata back into SQL, and comparing the catalogs of two databases.
+use crate::expr::for_each_column;
+use crate::types::*;
+use crate::Instance;
+use sqlparser::ast::Expr;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Quotes an identifier unless it would come back unchanged after case folding.
+pub fn quote_ident(name: &str) -> String {
+    let bare = name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && !name.is_empty();
+    if bare {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+pub fn column_definition(name: &str, desc: &ColumnDescriptor) -> String {
+    let mut def = format!("{} {}", quote_ident(name), desc.datatype);
+    if desc.not_null {
+        def.push_str(" NOT NULL");
+    }
+    column_constraints(&mut def, desc, true);
+    def
+}
+
+fn column_constraints(def: &mut String, desc: &ColumnDescriptor, primary_key: bool) {
+    if desc.primary_key && primary_key {
+        def.push_str(" PRIMARY KEY");
+    } else if desc.unique {
+        def.push_str(" UNIQUE");
+    }
+    if desc.auto_increment && desc.identity != Identity::default() {
+        def.push_str(&format!(" {}", desc.identity));
+    } else if desc.auto_increment {
+        def.push_str(" AUTO_INCREMENT");
+    }
+    if let Some(default) = &desc.default {
+        def.push_str(&format!(" DEFAULT {}", default));
+    }
+    if let Some(on_update) = &desc.on_update {
+        def.push_str(&format!(" ON UPDATE {}", on_update));
+    }
+    if let Some(check) = &desc.check {
+        def.push_str(&format!(" CHECK ({})", check));
+    }
+    if let Some((table, column)) = &desc.foreign_key {
+        def.push_str(&format!(
+            " REFERENCES {}({})",
+            quote_ident(table),
+            quote_ident(column)
+        ));
+        let actions = desc.foreign_key_actions;
+        if actions.on_delete != ReferentialAction::NoAction {
+            def.push_str(&format!(" ON DELETE {}", actions.on_delete));
+        }
+        if actions.on_update != ReferentialAction::NoAction {
+            def.push_str(&format!(" ON UPDATE {}", actions.on_update));
+        }
+    }
+}
+
+fn visible_columns(
+    columns: &ColumnDescriptors,
+) -> impl Iterator<Item = (&String, &ColumnDescriptor)> {
+    columns
+        .iter()
+        .filter(|(name, _)| name.as_str() != ROWID_COLUMN)
+}
+
+/// The table as `CREATE TABLE` would make it, with its named constraints and `WITH` options. A
+/// composite primary key is declared after the columns, in key order.
+pub fn create_table_sql(table: &CreateTableOptions) -> String {
+    let primary_key = &table.primary_key;
+    let mut columns = visible_columns(&table.columns)
+        .map(|(name, desc)| {
+            if primary_key.len() < 2 {
+                return column_definition(name, desc);
+            }
+            let mut def = format!("{} {}", quote_ident(name), desc.datatype);
+            if desc.not_null || desc.primary_key {
+                def.push_str(" NOT NULL");
+            }
+            column_constraints(&mut def, desc, false);
+            def
+        })
+        .collect::<Vec<_>>();
+    if primary_key.len() > 1 {
+        let key = primary_key
+            .iter()
+            .map(|x| quote_ident(x))
+            .collect::<Vec<_>>();
+        columns.push(format!("PRIMARY KEY ({})", key.join(", ")));
+    }
+    columns.extend(table.constraints.iter().map(declared_constraint));
+    let mut res = format!(
+        "CREATE TABLE {} ({})",
+        quote_ident(&table.name),
+        columns.join(", ")
+    );
+    let options = with_options(table);
+    if !options.is_empty() {
+        res.push_str(&format!(" WITH ({})", options.join(", ")));
+    }
+    res
+}
+
+/// `ttl_column`, `max_bytes` and `dictionary` as they're given in `CREATE TABLE ... WITH`.
+fn with_options(table: &CreateTableOptions) -> Vec<String> {
+    let mut res = vec![];
+    if let Some(column) = &table.ttl_column {
+        res.push(format!("ttl_column = '{}'", column.replace('\'', "''")));
+    }
+    if let Some(bytes) = table.max_bytes {
+        res.push(format!("max_bytes = {}", bytes));
+    }
+    let dictionary = visible_columns(&table.columns)
+        .filter(|(_, desc)| desc.dictionary)
+        .map(|(name, _)| name.replace('\'', "''"))
+        .collect::<Vec<_>>();
+    if !dictionary.is_empty() {
+        res.push(format!("dictionary = '{}'", dictionary.join(", ")));
+    }
+    res
+}
+
+/// Everything needed to create the table again: its columns, named constraints, primary key
+/// order and `WITH` options. Indexes are created separately.
+pub fn table_definition(instance: &Instance, table: &str) -> anyhow::Result<CreateTableOptions> {
+    Ok(CreateTableOptions {
+        name: table.to_string(),
+        columns: instance.storage.table_metadata(table)?,
+        ttl_column: instance.storage.ttl_column(table)?,
+        max_bytes: instance.storage.quota(table)?,
+        constraints: instance.storage.constraints(table)?,
+        primary_key: instance.storage.primary_key(table)?,
+    })
+}
+
+/// `CREATE INDEX <name> ON <table> (<columns>) [WHERE <predicate>]`
+pub fn create_index_sql(table: &str, index: &Index) -> String {
+    let columns = index.columns.iter().map(index_expr).collect::<Vec<_>>();
+    let mut res = format!(
+        "CREATE INDEX {} ON {} ({})",
+        quote_ident(&index.name),
+        quote_ident(table),
+        columns.join(", ")
+    );
+    if let Some(predicate) = &index.predicate {
+        res.push_str(&format!(" WHERE {}", index_expr(predicate)));
+    }
+    res
+}
+
+/// Everything the catalog knows about a table, displayed like psql's `\d`. Constraints declared
+/// without a name are given the name Postgres would have generated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableDescription {
+    pub name: String,
+    pub columns: Vec<(String, ColumnDescriptor)>,
+    /// Primary key as (constraint name, columns in key order)
+    pub primary_key: Option<(String, Vec<String>)>,
+    /// Unique, foreign key and check constraints
+    pub constraints: Vec<Constraint>,
+    /// Indexes created with `CREATE INDEX`
+    pub indexes: Vec<Index>,
+    /// Foreign keys of other tables pointing at this one, as (table, foreign key)
+    pub referenced_by: Vec<(String, Constraint)>,
+    pub ttl_column: Option<String>,
+    /// Most bytes the table can take up
+    pub max_bytes: Option<u64>,
+}
+
+/// Constraints declared on the columns of a table followed by its named constraints.
+fn table_constraints(
+    instance: &Instance,
+    table: &str,
+    columns: &ColumnDescriptors,
+) -> anyhow::Result<Vec<Constraint>> {
+    let mut res = vec![];
+    for (column, desc) in visible_columns(columns) {
+        let mut kinds = vec![];
+        if desc.unique && !desc.primary_key {
+            kinds.push(ConstraintKind::Unique {
+                column: column.clone(),
+            });
+        }
+        if let Some((target, referred)) = &desc.foreign_key {
+            kinds.push(ConstraintKind::ForeignKey {
+                column: column.clone(),
+                table: target.clone(),
+                referred: referred.clone(),
+            });
+        }
+        if let Some(expr) = &desc.check {
+            kinds.push(ConstraintKind::Check {
+                column: column.clone(),
+                expr: expr.clone(),
+            });
+        }
+        res.extend(kinds.into_iter().map(|kind| Constraint {
+            name: kind.default_name(table),
+            kind,
+            validated: true,
+        }));
+    }
+    res.extend(instance.storage.constraints(table)?);
+    Ok(res)
+}
+
+pub fn describe_table(instance: &Instance, table: &str) -> anyhow::Result<TableDescription> {
+    let metadata = instance.storage.table_metadata(table)?;
+    let columns = visible_columns(&metadata)
+        .map(|(name, desc)| (name.clone(), desc.clone()))
+        .collect::<Vec<_>>();
+    let primary_key = Some(instance.storage.primary_key(table)?)
+        .filter(|x| !x.is_empty() && x.as_slice() != [ROWID_COLUMN])
+        .map(|columns| (format!("{}_pkey", table), columns));
+    let mut referenced_by = vec![];
+    for (other, other_columns) in instance.storage.tables()? {
+        for constraint in table_constraints(instance, &other, &other_columns)? {
+            if matches!(&constraint.kind, ConstraintKind::ForeignKey { table: target, .. } if target == table)
+            {
+                referenced_by.push((other.clone(), constraint));
+            }
+        }
+    }
+    Ok(TableDescription {
+        name: table.to_string(),
+        constraints: table_constraints(instance, table, &metadata)?,
+        columns,
+        primary_key,
+        indexes: instance.storage.indexes(table)?,
+        referenced_by,
+        ttl_column: instance.storage.ttl_column(table)?,
+        max_bytes: instance.storage.quota(table)?,
+    })
+}
+
+/// An indexed expression or index predicate without the quotes normalizing put on its columns.
+fn index_expr(expr: &Expr) -> String {
+    let mut expr = expr.clone();
+    for_each_column(&mut expr, &mut |ident| {
+        if quote_ident(&ident.value) == ident.value {
+            ident.quote_style = None;
+        }
+    });
+    expr.to_string()
+}
+
+/// The index as psql lists it, `"name" INDEX (<columns>) [WHERE <predicate>]`.
+pub fn index_definition(index: &Index) -> String {
+    let columns = index.columns.iter().map(index_expr).collect::<Vec<_>>();
+    let mut res = format!("\"{}\" INDEX ({})", index.name, columns.join(", "));
+    if let Some(predicate) = &index.predicate {
+        res.push_str(&format!(" WHERE {}", index_expr(predicate)));
+    }
+    res
+}
+
+/// The constraint as it's declared, whether or not the rows were checked against it.
+fn declared_constraint(constraint: &Constraint) -> String {
+    constraint_definition(&Constraint {
+        validated: true,
+        ..constraint.clone()
+    })
+}
+
+/// The constraint as it would be written in a `CREATE TABLE`.
+pub fn constraint_definition(constraint: &Constraint) -> String {
+    let definition = match &constraint.kind {
+        ConstraintKind::Unique { column } => format!("UNIQUE ({})", quote_ident(column)),
+        ConstraintKind::ForeignKey {
+            column,
+            table,
+            referred,
+        } => format!(
+            "FOREIGN KEY ({}) REFERENCES {}({})",
+            quote_ident(column),
+            quote_ident(table),
+            quote_ident(referred)
+        ),
+        ConstraintKind::Check { expr, .. } => format!("CHECK ({})", expr),
+    };
+    let mut res = format!(
+        "CONSTRAINT {} {}",
+        quote_ident(&constraint.name),
+        definition
+    );
+    if !constraint.validated {
+        res.push_str(" NOT VALID");
+    }
+    res
+}
+
+impl fmt::Display for TableDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = ["Column", "Type", "Nullable", "Default"];
+        let rows = self
+            .columns
+            .iter()
+            .map(|(name, desc)| {
+                let default = match &desc.default {
+                    Some(default) => default.to_string(),
+                    None if desc.auto_increment => "auto_increment".to_string(),
+                    None => String::new(),
+                };
+                let not_null = desc.not_null || desc.primary_key;
+                let nullable = if not_null { "not null" } else { "" };
+                [
+                    name.clone(),
+                    desc.datatype.to_string(),
+                    nullable.to_string(),
+                    default,
+                ]
+            })
+            .collect::<Vec<_>>();
+        let mut widths = header.map(str::len);
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        let line = |cells: &[&str]| {
+            let cells = cells
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!(" {:width$} ", cell, width = width))
+                .collect::<Vec<_>>();
+            cells.join("|").trim_end().to_string()
+        };
+
+        writeln!(f, "Table \"{}\"", self.name)?;
+        writeln!(f, "{}", line(&header))?;
+        let rule = widths.map(|x| "-".repeat(x + 2));
+        writeln!(f, "{}", rule.join("+"))?;
+        for row in &rows {
+            let cells = row.iter().map(String::as_str).collect::<Vec<_>>();
+            writeln!(f, "{}", line(&cells))?;
+        }
+
+        let (unique, rest): (Vec<_>, Vec<_>) = self
+            .constraints
+            .iter()
+            .partition(|x| matches!(x.kind, ConstraintKind::Unique { .. }));
+        let (checks, foreign_keys): (Vec<_>, Vec<_>) = rest
+            .into_iter()
+            .partition(|x| matches!(x.kind, ConstraintKind::Check { .. }));
+        if self.primary_key.is_some() || !unique.is_empty() || !self.indexes.is_empty() {
+            writeln!(f, "Indexes:")?;
+        }
+        if let Some((name, columns)) = &self.primary_key {
+            let columns = columns.iter().map(|x| quote_ident(x)).collect::<Vec<_>>();
+            writeln!(f, "    \"{}\" PRIMARY KEY ({})", name, columns.join(", "))?;
+        }
+        for constraint in unique {
+            let column = quote_ident(constraint.kind.column());
+            writeln!(f, "    \"{}\" UNIQUE ({})", constraint.name, column)?;
+        }
+        for index in &self.indexes {
+            writeln!(f, "    {}", index_definition(index))?;
+        }
+        if !checks.is_empty() {
+            writeln!(f, "Check constraints:")?;
+        }
+        for constraint in checks {
+            let ConstraintKind::Check { expr, .. } = &constraint.kind else {
+                continue;
+            };
+            writeln!(f, "    \"{}\" CHECK ({})", constraint.name, expr)?;
+        }
+        if !foreign_keys.is_empty() {
+            writeln!(f, "Foreign-key constraints:")?;
+        }
+        for constraint in foreign_keys {
+            writeln!(f, "    {}", constraint_definition(constraint))?;
+        }
+        if !self.referenced_by.is_empty() {
+            writeln!(f, "Referenced by:")?;
+        }
+        for (table, constraint) in &self.referenced_by {
+            let definition = constraint_definition(constraint);
+            writeln!(f, "    TABLE {} {}", quote_ident(table), definition)?;
+        }
+        if let Some(column) = &self.ttl_column {
+            writeln!(f, "Rows expire at: {}", quote_ident(column))?;
+        }
+        if let Some(bytes) = self.max_bytes {
+            writeln!(f, "Size quota: {} bytes", bytes)?;
+        }
+        let dictionary = self
+            .columns
+            .iter()
+            .filter(|(_, desc)| desc.dictionary)
+            .map(|(name, _)| quote_ident(name))
+            .collect::<Vec<_>>();
+        if !dictionary.is_empty() {
+            writeln!(f, "Dictionary encoded: {}", dictionary.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Orders tables so that any table referenced by a foreign key comes before the tables referring
+/// to it. `existing` are tables that are already there.
+pub(crate) fn dependency_order<'a>(
+    tables: &BTreeMap<&'a String, &'a ColumnDescriptors>,
+    existing: &BTreeSet<&String>,
+) -> Vec<&'a String> {
+    let mut done = BTreeSet::new();
+    let mut res = vec![];
+    while res.len() < tables.len() {
+        let before = res.len();
+        for (name, columns) in tables {
+            if done.contains(name) {
+                continue;
+            }
+            let ready =
+                columns
+                    .values()
+                    .filter_map(|x| x.foreign_key.as_ref())
+                    .all(|(table, _)| {
+                        table == *name
+                            || existing.contains(table)
+                            || done.contains(table)
+                            || !tables.contains_key(table)
+                    });
+            if ready {
+                done.insert(*name);
+                res.push(*name);
+            }
+        }
+        if res.len() == before {
+            // Cycle, we can't do better than just listing the rest
+            res.extend(tables.keys().copied().filter(|x| !done.contains(x)));
+            break;
+        }
+    }
+    res
+}
+
+/// Differences between two columns that `ALTER TABLE` can't make in place.
+fn column_changes(source: &ColumnDescriptor, target: &ColumnDescriptor) -> Vec<&'static str> {
+    let mut res = vec![];
+    if source.datatype != target.datatype {
+        res.push("type");
+    }
+    if source.not_null != target.not_null {
+        res.push("NOT NULL");
+    }
+    if source.default != target.default {
+        res.push("DEFAULT");
+    }
+    if source.on_update != target.on_update {
+        res.push("ON UPDATE");
+    }
+    if source.primary_key != target.primary_key
+        || (source.unique || source.primary_key) != (target.unique || target.primary_key)
+        || source.auto_increment != target.auto_increment
+        || source.identity != target.identity
+        || source.foreign_key != target.foreign_key
+        || source.foreign_key_actions != target.foreign_key_actions
+        || source.check != target.check
+    {
+        res.push("key constraints");
+    }
+    if source.dictionary != target.dictionary {
+        res.push("dictionary encoding");
+    }
+    res
+}
+
+/// Named constraints are the same if they have the same name and definition, whether or not the
+/// rows were checked against them.
+fn same_constraint(a: &Constraint, b: &Constraint) -> bool {
+    a.name == b.name && a.kind == b.kind
+}
+
+/// The statements that would make the schema of `target` match `source`. New tables are created
+/// first in foreign key order, with their named foreign keys added once every table exists. Then
+/// existing tables are altered, dropping the constraints and indexes that go before their columns
+/// would take them along, and removed tables are dropped last. Changes `ALTER TABLE` can't make,
+/// to a column's type, nullability, default or keys or to a table's `WITH` options, are emitted
+/// as comments for a human to handle.
+pub fn schema_diff(source: &Instance, target: &Instance) -> anyhow::Result<Vec<String>> {
+    let source_tables = source.storage.tables()?;
+    let target_tables = target.storage.tables()?;
+    let existing = target_tables.keys().collect::<BTreeSet<_>>();
+    let mut res = vec![];
+
+    let created = source_tables
+        .iter()
+        .filter(|(name, _)| !target_tables.contains_key(*name))
+        .collect::<BTreeMap<_, _>>();
+    let mut foreign_keys = vec![];
+    let mut indexes = vec![];
+    for name in dependency_order(&created, &existing) {
+        let mut definition = table_definition(source, name)?;
+        // Named foreign keys can refer to tables created after theirs
+        let (named, constraints): (Vec<_>, Vec<_>) = definition
+            .constraints
+            .into_iter()
+            .partition(|x| matches!(x.kind, ConstraintKind::ForeignKey { .. }));
+        definition.constraints = constraints;
+        res.push(create_table_sql(&definition));
+        foreign_keys.extend(named.iter().map(|constraint| {
+            format!(
+                "ALTER TABLE {} ADD {}",
+                quote_ident(name),
+                declared_constraint(constraint)
+            )
+        }));
+        for index in source.storage.indexes(name)? {
+            indexes.push(create_index_sql(name, &index));
+        }
+    }
+    res.extend(foreign_keys);
+    res.extend(indexes);
+
+    for (name, source_columns) in &source_tables {
+        let Some(target_columns) = target_tables.get(name) else {
+            continue;
+        };
+        let table = quote_ident(name);
+        let wanted = table_definition(source, name)?;
+        let current = table_definition(target, name)?;
+        let source_indexes = source.storage.indexes(name)?;
+        let target_indexes = target.storage.indexes(name)?;
+
+        for index in &target_indexes {
+            if !source_indexes.contains(index) {
+                res.push(format!("DROP INDEX {}", quote_ident(&index.name)));
+            }
+        }
+        for constraint in &current.constraints {
+            if !wanted
+                .constraints
+                .iter()
+                .any(|x| same_constraint(x, constraint))
+            {
+                res.push(format!(
+                    "ALTER TABLE {} DROP CONSTRAINT {}",
+                    table,
+                    quote_ident(&constraint.name)
+                ));
+            }
+        }
+        for (column, desc) in visible_columns(source_columns) {
+            if !target_columns.contains_key(column) {
+                res.push(format!(
+                    "ALTER TABLE {} ADD COLUMN {}",
+                    table,
+                    column_definition(column, desc)
+                ));
+            }
+        }
+        for (column, _) in visible_columns(target_columns) {
+            if !source_columns.contains_key(column) {
+                res.push(format!(
+                    "ALTER TABLE {} DROP COLUMN {}",
+                    table,
+                    quote_ident(column)
+                ));
+            }
+        }
+        for (column, desc) in visible_columns(source_columns) {
+            let Some(current) = target_columns.get(column) else {
+                continue;
+            };
+            let changes = column_changes(desc, current);
+            if !changes.is_empty() {
+                let verb = if changes.len() == 1 {
+                    "differs"
+                } else {
+                    "differ"
+                };
+                res.push(format!(
+                    "-- {}.{} {} {}, wanted: {}",
+                    table,
+                    quote_ident(column),
+                    changes.join(", "),
+                    verb,
+                    column_definition(column, desc)
+                ));
+            }
+        }
+        if (&wanted.ttl_column, wanted.max_bytes) != (&current.ttl_column, current.max_bytes) {
+            let options = with_options(&wanted);
+            let options = if options.is_empty() {
+                "no WITH options".to_string()
+            } else {
+                format!("WITH ({})", options.join(", "))
+            };
+            res.push(format!(
+                "-- {} WITH options differ, wanted: {}",
+                table, options
+            ));
+        }
+        for constraint in &wanted.constraints {
+            if !current
+                .constraints
+                .iter()
+                .any(|x| same_constraint(x, constraint))
+            {
+                res.push(format!(
+                    "ALTER TABLE {} ADD {}",
+                    table,
+                    declared_constraint(constraint)
+                ));
+            }
+        }
+        for index in &source_indexes {
+            if !target_indexes.contains(index) {
+                res.push(create_index_sql(name, index));
+            }
+        }
+    }
+
+    // Drop tables that reference others before the tables they reference
+    let dropped = target_tables
+        .iter()
+        .filter(|(name, _)| !source_tables.contains_key(*name))
+        .collect::<BTreeMap<_, _>>();
+    for name in dependency_order(&dropped, &BTreeSet::new())
+        .into_iter()
+        .rev()
+    {
+        res.push(format!("DROP TABLE {}", quote_ident(name)));
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use tracing_test::traced_test;
+
+    #[test]
+    fn identifier_quoting() {
+        assert_eq!(quote_ident("users"), "users");
+        assert_eq!(quote_ident("Users"), "\"Users\"");
+        assert_eq!(quote_ident("1st"), "\"1st\"");
+        assert_eq!(quote_ident("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[test]
+    #[traced_test]
+    fn describe() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path());
+        instance
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, email TEXT UNIQUE NOT NULL, name TEXT);
+                 CREATE INDEX by_name ON users (name);
+                 CREATE INDEX named_lower ON users (lower(email)) WHERE name IS NOT NULL;",
+            )
+            .unwrap();
+        instance
+            .execute(
+                "CREATE TABLE posts (id INT PRIMARY KEY, author INT REFERENCES users(id), \
+                 slug TEXT, expires TIMESTAMP, CONSTRAINT one_slug UNIQUE (slug)) \
+                 WITH (ttl_column = 'expires', max_bytes = 1048576);",
+            )
+            .unwrap();
+
+        let users = describe_table(&instance, "users").unwrap();
+        assert_eq!(
+            users.to_string(),
+            [
+                "Table \"users\"",
+                " Column | Type | Nullable | Default",
+                "--------+------+----------+---------",
+                " email  | TEXT | not null |",
+                " id     | INT  | not null |",
+                " name   | TEXT |          |",
+                "Indexes:",
+                "    \"users_pkey\" PRIMARY KEY (id)",
+                "    \"users_email_key\" UNIQUE (email)",
+                "    \"by_name\" INDEX (name)",
+                "    \"named_lower\" INDEX (lower(email)) WHERE name IS NOT NULL",
+                "Referenced by:",
+                "    TABLE posts CONSTRAINT posts_author_fkey FOREIGN KEY (author) \
+                 REFERENCES users(id)",
+                "",
+            ]
+            .join("\n")
+        );
+
+        let posts = describe_table(&instance, "posts").unwrap();
+        let names = posts
+            .constraints
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["posts_author_fkey", "one_slug"]);
+        assert!(posts
+            .to_string()
+            .contains("    \"one_slug\" UNIQUE (slug)\n"));
+        assert_eq!(posts.ttl_column.as_deref(), Some("expires"));
+        assert_eq!(posts.max_bytes, Some(1048576));
+        assert!(posts.to_string().ends_with("Size quota: 1048576 bytes\n"));
+        assert!(describe_table(&instance, "missing").is_err());
+
+        instance
+            .execute("CREATE TABLE pairs (a INT, b INT, v TEXT, PRIMARY KEY (b, a));")
+            .unwrap();
+        let pairs = describe_table(&instance, "pairs").unwrap();
+        assert!(pairs
+            .to_string()
+            .contains("    \"pairs_pkey\" PRIMARY KEY (b, a)\n"));
+        let pairs = table_definition(&instance, "pairs").unwrap();
+        assert_eq!(
+            create_table_sql(&pairs),
+            "CREATE TABLE pairs (a INT NOT NULL, b INT NOT NULL, v TEXT, PRIMARY KEY (b, a))"
+        );
+        let posts = table_definition(&instance, "posts").unwrap();
+        assert_eq!(
+            create_table_sql(&posts),
+            "CREATE TABLE posts (author INT REFERENCES users(id), expires TIMESTAMP, \
+             id INT PRIMARY KEY, slug TEXT, CONSTRAINT one_slug UNIQUE (slug)) \
+             WITH (ttl_column = 'expires', max_bytes = 1048576)"
+        );
+        assert_eq!(
+            create_index_sql("users", &users.indexes[1]),
+            "CREATE INDEX named_lower ON users (lower(email)) WHERE name IS NOT NULL"
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn diff_catalogs() {
+        let source_dir = tempdir().unwrap();
+        let target_dir = tempdir().unwrap();
+        let mut source = Instance::new_with_path(source_dir.path());
+        let mut target = Instance::new_with_path(target_dir.path());
+
+        source
+            .execute("CREATE TABLE users (id INT PRIMARY KEY, name TEXT NOT NULL, email TEXT);")
+            .unwrap();
+        source
+            .execute("CREATE TABLE posts (id INT PRIMARY KEY, author INT REFERENCES users(id));")
+            .unwrap();
+        source
+            .execute("CREATE TABLE comments (id INT PRIMARY KEY, post INT REFERENCES posts(id));")
+            .unwrap();
+        target
+            .execute("CREATE TABLE users (id INT PRIMARY KEY, name VARCHAR(10), age INT);")
+            .unwrap();
+        target
+            .execute("CREATE TABLE old (id INT PRIMARY KEY);")
+            .unwrap();
+
+        let diff = schema_diff(&source, &target).unwrap();
+        assert_eq!(
+            diff,
+            vec![
+                "CREATE TABLE posts (author INT REFERENCES users(id), id INT PRIMARY KEY)",
+                "CREATE TABLE comments (id INT PRIMARY KEY, post INT REFERENCES posts(id))",
+                "ALTER TABLE users ADD COLUMN email TEXT",
+                "ALTER TABLE users DROP COLUMN age",
+                "-- users.name type, NOT NULL differ, wanted: name TEXT NOT NULL",
+                "DROP TABLE old",
+            ]
+        );
+
+        assert!(schema_diff(&source, &source).unwrap().is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn diff_applied() {
+        let source_dir = tempdir().unwrap();
+        let target_dir = tempdir().unwrap();
+        let mut source = Instance::new_with_path(source_dir.path());
+        let mut target = Instance::new_with_path(target_dir.path());
+
+        source
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, email TEXT, name TEXT, \
+                 CONSTRAINT one_email UNIQUE (email));
+                 CREATE INDEX by_name ON users (name);
+                 CREATE INDEX by_email ON users (lower(email)) WHERE name IS NOT NULL;",
+            )
+            .unwrap();
+        // comments sorts before posts, which its named foreign key refers to
+        source
+            .execute(
+                "CREATE TABLE posts (id INT PRIMARY KEY, author INT, slug TEXT, \
+                 expires TIMESTAMP, \
+                 CONSTRAINT by_author FOREIGN KEY (author) REFERENCES users(id), \
+                 CONSTRAINT positive_id CHECK (id > 0)) \
+                 WITH (ttl_column = 'expires', max_bytes = 1048576, dictionary = 'slug');
+                 CREATE INDEX by_slug ON posts (slug);
+                 CREATE TABLE comments (id INT PRIMARY KEY, post INT, \
+                 CONSTRAINT on_post FOREIGN KEY (post) REFERENCES posts(id));",
+            )
+            .unwrap();
+        target
+            .execute(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, age INT, \
+                 CONSTRAINT adult CHECK (age > 17));
+                 CREATE INDEX by_name ON users (name, id);
+                 CREATE INDEX by_age ON users (age);
+                 CREATE TABLE old (id INT PRIMARY KEY);",
+            )
+            .unwrap();
+
+        let diff = schema_diff(&source, &target).unwrap();
+        assert!(diff.iter().all(|x| !x.starts_with("--")), "{:?}", diff);
+        for statement in &diff {
+            target.execute(statement).unwrap();
+        }
+        assert!(schema_diff(&source, &target).unwrap().is_empty());
+        assert_eq!(source.tables().unwrap(), target.tables().unwrap());
+        for table in source.tables().unwrap().keys() {
+            let [wanted, got] = [&source, &target].map(|instance| {
+                let mut description = describe_table(instance, table).unwrap();
+                description.constraints.sort_by(|a, b| a.name.cmp(&b.name));
+                description.indexes.sort_by(|a, b| a.name.cmp(&b.name));
+                description
+            });
+            assert_eq!(wanted, got);
+        }
+    }
+}
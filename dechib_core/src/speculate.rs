@@ -0,0 +1,153 @@
+//! Speculative execution. A closure runs against a copy of the database and its writes only
+//! replace the real database if it asks for them to be committed, which suits validating a batch
+//! of changes or trying out a migration.
+use crate::config::StorageConfig;
+use crate::query_engine::QueryEngine;
+use crate::storage_engine::StorageEngine;
+use crate::Instance;
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// What a speculative closure wants done with its writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome<T> {
+    Commit(T),
+    Rollback(T),
+}
+
+/// A new path next to `path`, so it's on the same filesystem and checkpoints can hard link.
+fn sibling(path: &Path, label: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}-{}", label, Uuid::new_v4()));
+    PathBuf::from(name)
+}
+
+impl Instance {
+    /// Runs `f` against a copy of the database and keeps its writes only if it returns
+    /// [`Outcome::Commit`], errors roll back as well. The copy starts out as a checkpoint so it
+    /// shares files with the database rather than copying them. Committing closes and reopens the
+    /// database, if the process dies part way through the previous database is left next to it
+    /// in a `.replaced-*` directory.
+    pub fn speculate<T>(
+        &mut self,
+        f: impl FnOnce(&mut Instance) -> anyhow::Result<Outcome<T>>,
+    ) -> anyhow::Result<T> {
+        let config = self.storage.config().clone();
+        let copy_path = sibling(&config.path, "speculative");
+        self.storage.checkpoint(&copy_path)?;
+        let mut copy = Instance {
+            storage: StorageEngine::new_with_config(StorageConfig {
+                path: copy_path.clone(),
+                ..config
+            }),
+            query: QueryEngine::default(),
+            session: self.session,
+            forced: self.forced,
+        };
+        self.storage.swap_functions(&mut copy.storage);
+        let res = f(&mut copy);
+        self.storage.swap_functions(&mut copy.storage);
+
+        let res = match res {
+            Ok(Outcome::Commit(value)) => self.commit_copy(copy.storage).map(|_| value),
+            Ok(Outcome::Rollback(value)) => {
+                drop(copy);
+                Ok(value)
+            }
+            Err(e) => {
+                drop(copy);
+                Err(e)
+            }
+        };
+        if let Err(e) = std::fs::remove_dir_all(&copy_path) {
+            warn!(path = %copy_path.display(), "Failed to remove speculative copy: {}", e);
+        }
+        res
+    }
+
+    fn commit_copy(&mut self, copy: StorageEngine) -> anyhow::Result<()> {
+        let config = self.storage.config().clone();
+        let replaced = sibling(&config.path, "replaced");
+        // An open database can't be moved, so the copy stands in while the real one is closed and
+        // moved aside, then a checkpoint of the copy takes its place.
+        drop(std::mem::replace(&mut self.storage, copy));
+        let res = std::fs::rename(&config.path, &replaced)
+            .context("Failed to move the database aside")
+            .and_then(|_| {
+                self.storage.checkpoint(&config.path).map_err(|e| {
+                    if let Err(e) = std::fs::rename(&replaced, &config.path) {
+                        warn!(path = %replaced.display(), "Failed to restore the database: {}", e);
+                    }
+                    e
+                })
+            });
+        let mut reopened = StorageEngine::new_with_config(config);
+        self.storage.swap_functions(&mut reopened);
+        drop(std::mem::replace(&mut self.storage, reopened));
+        res?;
+        info!("Committed speculative writes");
+        if let Err(e) = std::fs::remove_dir_all(&replaced) {
+            warn!(path = %replaced.display(), "Failed to remove the replaced database: {}", e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Value;
+    use tempfile::tempdir;
+
+    #[test]
+    fn rollback_and_commit() {
+        let dir = tempdir().unwrap();
+        let mut instance = Instance::new_with_path(dir.path().join("db"));
+        instance.register_function("answer", |_| Ok(Value::Number(42.into())));
+        instance
+            .execute("CREATE TABLE t (n INT DEFAULT answer(), name TEXT)")
+            .unwrap();
+        instance
+            .execute("INSERT INTO t (name) VALUES ('a')")
+            .unwrap();
+        let count = |instance: &Instance| instance.storage.scan_table("t").unwrap().len();
+
+        let res = instance
+            .speculate(|copy| {
+                copy.execute("INSERT INTO t (name) VALUES ('b')")?;
+                assert_eq!(count(copy), 2);
+                Ok(Outcome::Rollback("checked"))
+            })
+            .unwrap();
+        assert_eq!(res, "checked");
+        assert_eq!(count(&instance), 1);
+
+        let res = instance.speculate(|copy| {
+            copy.execute("INSERT INTO t (name) VALUES ('b')")?;
+            copy.execute("INSERT INTO missing (name) VALUES ('c')")?;
+            Ok(Outcome::Commit(()))
+        });
+        assert!(res.is_err());
+        assert_eq!(count(&instance), 1);
+
+        instance
+            .speculate(|copy| {
+                copy.execute("INSERT INTO t (name) VALUES ('b')")?;
+                Ok(Outcome::Commit(()))
+            })
+            .unwrap();
+        let rows = instance.storage.scan_table("t").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(*rows[1].columns["n"], Value::Number(42.into()));
+
+        // Functions came back with the reopened database and nothing was left behind
+        instance
+            .execute("INSERT INTO t (name) VALUES ('c')")
+            .unwrap();
+        assert_eq!(count(&instance), 3);
+        let entries = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(entries, 1);
+    }
+}
@@ -0,0 +1,405 @@
+//! Importing the tables of a SQLite database. SQLite column types are free text, so they're mapped
+//! to dechib types using SQLite's own type affinity rules.
+use crate::functions::format_timestamp;
+use crate::schema::dependency_order;
+use crate::types::*;
+use crate::Instance;
+use anyhow::Context;
+use bigdecimal::{BigDecimal, FromPrimitive};
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use sqlparser::ast::{DataType, ExactNumberInfo, TimezoneInfo};
+use sqlparser::dialect::SQLiteDialect;
+use sqlparser::parser::Parser;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::rc::Rc;
+use tracing::info;
+
+/// Rows written per batch when loading a table.
+const BATCH_SIZE: usize = 1000;
+
+fn sqlite_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Maps a declared column type following the affinity rules in section 3.1 of the SQLite
+/// datatype docs. Booleans and timestamps aren't SQLite types but are common enough to keep. A
+/// `DATE` is imported as a timestamp at midnight and a `TIME` as text, dechib has neither type.
+fn map_type(declared: &str) -> DataType {
+    let declared = declared.to_uppercase();
+    if declared.contains("BOOL") {
+        DataType::Boolean
+    } else if declared.contains("INT") {
+        DataType::BigInt(None)
+    } else if declared.contains("CHAR") || declared.contains("CLOB") || declared.contains("TEXT") {
+        DataType::Text
+    } else if declared.contains("BLOB") || declared.is_empty() {
+        DataType::Bytea
+    } else if declared.contains("REAL") || declared.contains("FLOA") || declared.contains("DOUB") {
+        DataType::Double
+    } else if declared.contains("DATETIME") {
+        DataType::Datetime(None)
+    } else if declared.contains("TIMESTAMP") || declared.contains("DATE") {
+        DataType::Timestamp(None, TimezoneInfo::None)
+    } else if declared.contains("TIME") {
+        DataType::Text
+    } else {
+        DataType::Numeric(ExactNumberInfo::None)
+    }
+}
+
+fn map_value(value: ValueRef<'_>, datatype: &DataType) -> anyhow::Result<Value> {
+    let value = match (value, datatype) {
+        (ValueRef::Null, _) => Value::Null,
+        // SQLite stores booleans as 0 and 1
+        (ValueRef::Integer(i), DataType::Boolean) => Value::Boolean(i != 0),
+        // Dates kept as numbers are seconds since the unix epoch
+        (ValueRef::Integer(i), DataType::Timestamp(..) | DataType::Datetime(_)) => {
+            Value::Text(format_timestamp(i))
+        }
+        (ValueRef::Integer(i), _) => Value::Number(i.into()),
+        (ValueRef::Real(f), _) => Value::Number(
+            BigDecimal::from_f64(f).with_context(|| format!("Can't import {} as a number", f))?,
+        ),
+        // SQLite doesn't check that text is UTF-8, so text in a BLOB column is kept byte for byte
+        // as a way to bring over text in other encodings
+        (ValueRef::Text(s), DataType::Bytea) => Value::Bytes(s.to_vec()),
+        (ValueRef::Text(s), _) => {
+            Value::Text(String::from_utf8(s.to_vec()).context(
+                "Text isn't valid UTF-8, declare the column as BLOB to import it as bytes",
+            )?)
+        }
+        (ValueRef::Blob(b), _) => Value::Bytes(b.to_vec()),
+    };
+    Ok(value)
+}
+
+struct SqliteTable {
+    columns: ColumnDescriptors,
+    /// Primary key columns in key order
+    primary_key: Vec<String>,
+    /// Column names in the SQLite table, paired with the name they're imported as
+    names: Vec<(String, String)>,
+    /// Foreign keys as (column, table, referred column), the referred column being `None` when
+    /// it's the primary key of the other table
+    foreign_keys: Vec<(String, String, Option<String>)>,
+}
+
+fn read_table(conn: &Connection, table: &str) -> anyhow::Result<SqliteTable> {
+    let mut columns = ColumnDescriptors::new();
+    let mut names = vec![];
+    let mut primary_key = vec![];
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", sqlite_ident(table)))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(1)?;
+        let declared: String = row.get(2)?;
+        let default = row
+            .get::<_, Option<String>>(4)?
+            .map(|sql| {
+                Parser::new(&SQLiteDialect {})
+                    .try_with_sql(&sql)?
+                    .parse_expr()
+                    .with_context(|| format!("Unsupported default for {}.{}: {}", table, name, sql))
+            })
+            .transpose()?;
+        // Position of the column in the primary key, starting from 1
+        let position = row.get::<_, i64>(5)?;
+        let datatype = map_type(&declared);
+        columns.insert(
+            name.to_lowercase(),
+            ColumnDescriptor {
+                datatype,
+                not_null: row.get::<_, bool>(3)? || position > 0,
+                primary_key: position > 0,
+                default,
+                ..Default::default()
+            },
+        );
+        if position > 0 {
+            primary_key.push((position, name.to_lowercase()));
+        }
+        names.push((name.clone(), name.to_lowercase()));
+    }
+    primary_key.sort();
+    let primary_key = primary_key
+        .into_iter()
+        .map(|(_, column)| column)
+        .collect::<Vec<_>>();
+    if let [column] = primary_key.as_slice() {
+        let desc = columns.get_mut(column).unwrap();
+        desc.unique = true;
+        // SQLite lets an integer primary key be left out, picking the next rowid
+        desc.auto_increment = desc.datatype == DataType::BigInt(None);
+    }
+
+    let mut foreign_keys = vec![];
+    let mut stmt = conn.prepare(&format!("PRAGMA foreign_key_list({})", sqlite_ident(table)))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        if row.get::<_, i64>(1)? > 0 {
+            anyhow::bail!(
+                "{} has a composite foreign key which isn't supported",
+                table
+            );
+        }
+        let target: String = row.get(2)?;
+        let column: String = row.get(3)?;
+        let referred: Option<String> = row.get(4)?;
+        foreign_keys.push((
+            column.to_lowercase(),
+            target.to_lowercase(),
+            referred.map(|x| x.to_lowercase()),
+        ));
+    }
+
+    Ok(SqliteTable {
+        columns,
+        primary_key,
+        names,
+        foreign_keys,
+    })
+}
+
+impl Instance {
+    /// Creates every table of the SQLite database at `path` and copies its rows over, returning
+    /// how many rows each table got. Identifiers are folded to lowercase since SQLite treats them
+    /// case insensitively. Tables are created in foreign key order, but an import that fails part
+    /// way keeps the tables and rows it already wrote. SQLite doesn't enforce foreign keys unless
+    /// asked to, so rows aren't checked against them.
+    pub fn import_sqlite(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<BTreeMap<String, usize>> {
+        self.storage.set_foreign_key_checks(false);
+        let res = self.import_tables(path.as_ref());
+        self.storage.set_foreign_key_checks(true);
+        res
+    }
+
+    fn import_tables(&mut self, path: &Path) -> anyhow::Result<BTreeMap<String, usize>> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        let mut stmt = conn.prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+        )?;
+        let names = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut tables = BTreeMap::new();
+        for name in names {
+            let table = read_table(&conn, &name).with_context(|| format!("Reading {}", name))?;
+            let folded = name.to_lowercase();
+            if tables.contains_key(&folded) {
+                anyhow::bail!("Multiple tables are named {} ignoring case", folded);
+            }
+            tables.insert(folded, (name, table));
+        }
+
+        // Foreign keys to an implicit column point at the primary key
+        let primary_keys = tables
+            .iter()
+            .map(|(name, (_, table))| {
+                let pk = table
+                    .columns
+                    .iter()
+                    .find(|(_, desc)| desc.primary_key)
+                    .map(|(column, _)| column.clone());
+                (name.clone(), pk)
+            })
+            .collect::<BTreeMap<_, _>>();
+        for (name, (_, table)) in tables.iter_mut() {
+            for (column, target, referred) in &table.foreign_keys {
+                let referred = match referred {
+                    Some(referred) => referred.clone(),
+                    None => primary_keys
+                        .get(target)
+                        .cloned()
+                        .flatten()
+                        .with_context(|| {
+                            format!(
+                                "{}.{} references {} which has no primary key",
+                                name, column, target
+                            )
+                        })?,
+                };
+                if let Some(desc) = table.columns.get_mut(column) {
+                    desc.foreign_key = Some((target.clone(), referred));
+                }
+            }
+        }
+
+        let columns = tables
+            .iter()
+            .map(|(name, (_, table))| (name, &table.columns))
+            .collect::<BTreeMap<_, _>>();
+        let order = dependency_order(&columns, &BTreeSet::new())
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut res = BTreeMap::new();
+        for name in order {
+            let (source, table) = &tables[&name];
+            info!(table = %name, "Importing table from SQLite");
+            self.storage.create_table(&CreateTableOptions {
+                name: name.clone(),
+                columns: table.columns.clone(),
+                ttl_column: None,
+                max_bytes: None,
+                constraints: vec![],
+                primary_key: table.primary_key.clone(),
+            })?;
+            let count = self
+                .copy_rows(&conn, source, &name, table)
+                .with_context(|| format!("Importing rows of {}", name))?;
+            res.insert(name, count);
+        }
+        Ok(res)
+    }
+
+    fn copy_rows(
+        &mut self,
+        conn: &Connection,
+        source: &str,
+        name: &str,
+        table: &SqliteTable,
+    ) -> anyhow::Result<usize> {
+        let select = table
+            .names
+            .iter()
+            .map(|(column, _)| sqlite_ident(column))
+            .collect::<Vec<_>>();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM {}",
+            select.join(", "),
+            sqlite_ident(source)
+        ))?;
+        let columns = table
+            .names
+            .iter()
+            .map(|(_, column)| column.clone())
+            .collect::<Vec<_>>();
+        let datatypes = columns
+            .iter()
+            .map(|column| &table.columns[column].datatype)
+            .collect::<Vec<_>>();
+
+        let mut batch = InsertOptions {
+            table: name.to_string(),
+            columns,
+            values: vec![],
+            returning: None,
+            upsert: false,
+        };
+        let mut count = 0;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let mut values = Vec::with_capacity(datatypes.len());
+            for (i, datatype) in datatypes.iter().enumerate() {
+                let value = map_value(row.get_ref(i)?, datatype)
+                    .with_context(|| format!("Importing {}.{}", name, batch.columns[i]))?;
+                values.push(Rc::new(value));
+            }
+            batch.values.push(values);
+            if batch.values.len() == BATCH_SIZE {
+                self.storage.insert_rows(&batch)?;
+                count += batch.values.len();
+                batch.values.clear();
+            }
+        }
+        if !batch.values.is_empty() {
+            self.storage.insert_rows(&batch)?;
+            count += batch.values.len();
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use tracing_test::traced_test;
+
+    #[test]
+    fn type_affinity() {
+        assert_eq!(map_type("INTEGER"), DataType::BigInt(None));
+        assert_eq!(map_type("varchar(20)"), DataType::Text);
+        assert_eq!(map_type(""), DataType::Bytea);
+        assert_eq!(map_type("DOUBLE PRECISION"), DataType::Double);
+        assert_eq!(map_type("BOOLEAN"), DataType::Boolean);
+        assert_eq!(map_type("DATETIME"), DataType::Datetime(None));
+        assert_eq!(
+            map_type("date"),
+            DataType::Timestamp(None, TimezoneInfo::None)
+        );
+        assert_eq!(map_type("TIME"), DataType::Text);
+        assert_eq!(map_type("MONEY"), DataType::Numeric(ExactNumberInfo::None));
+    }
+
+    #[test]
+    fn text_encoding() {
+        let latin1 = ValueRef::Text(b"caf\xe9");
+        assert!(map_value(latin1, &DataType::Text).is_err());
+        assert_eq!(
+            map_value(latin1, &DataType::Bytea).unwrap(),
+            Value::Bytes(b"caf\xe9".to_vec())
+        );
+        assert_eq!(
+            map_value(ValueRef::Text("café".as_bytes()), &DataType::Text).unwrap(),
+            Value::Text("café".to_string())
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn import_tables() {
+        let dir = tempdir().unwrap();
+        let sqlite_path = dir.path().join("source.sqlite");
+        let conn = Connection::open(&sqlite_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE Users (id INTEGER PRIMARY KEY, name TEXT NOT NULL, active BOOLEAN DEFAULT 1);
+             CREATE TABLE posts (id INTEGER PRIMARY KEY, author INTEGER REFERENCES users, score REAL, body BLOB, posted DATETIME);
+             INSERT INTO users (name, active) VALUES ('Daniel', 1), ('Ben', 0);
+             INSERT INTO posts (author, score, body, posted) VALUES (1, 4.5, x'0102', '2024-01-05 10:00:00'), (2, NULL, NULL, 86400);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let mut instance = Instance::new_with_path(dir.path().join("db"));
+        let counts = instance.import_sqlite(&sqlite_path).unwrap();
+        assert_eq!(
+            counts,
+            BTreeMap::from([("posts".to_string(), 2), ("users".to_string(), 2)])
+        );
+
+        let users = instance.storage.table_metadata("users").unwrap();
+        assert!(users["id"].primary_key);
+        assert!(users["name"].not_null);
+        assert_eq!(users["active"].datatype, DataType::Boolean);
+        let posts = instance.storage.table_metadata("posts").unwrap();
+        assert_eq!(
+            posts["author"].foreign_key,
+            Some(("users".to_string(), "id".to_string()))
+        );
+        assert_eq!(posts["body"].datatype, DataType::Bytea);
+        assert_eq!(posts["posted"].datatype, DataType::Datetime(None));
+
+        let result = instance.execute("SELECT posted FROM posts").unwrap();
+        let posted = result
+            .rows
+            .iter()
+            .map(|x| x.columns["posted"].as_ref().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            posted,
+            [
+                Value::Text("2024-01-05 10:00:00".to_string()),
+                Value::Text("1970-01-02 00:00:00".to_string())
+            ]
+        );
+    }
+}
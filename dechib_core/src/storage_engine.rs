@@ -1,39 +1,411 @@
+use crate::config::StorageConfig;
+use crate::dictionary::{Dictionary, DICTIONARY_KEY};
+use crate::expr;
+use crate::functions::{unix_now, Function, FunctionRegistry, NEXTVAL};
+use crate::keys;
+use crate::timezone;
+use crate::ttl::{self, EXPIRES_COLUMN, TTL_KEY};
 use crate::types::*;
 use anyhow::Context;
-use bigdecimal::{BigDecimal, FromPrimitive};
+use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
 use postcard::{from_bytes, to_allocvec};
-use rocksdb::{Options, WriteBatch, DB};
-use sqlparser::ast::Expr;
-use std::collections::BTreeMap;
-use std::path::Path;
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{
+    ColumnFamily, Direction, IngestExternalFileOptions, IteratorMode, ReadOptions, SstFileWriter,
+    WriteBatch, WriteOptions, DB, DEFAULT_COLUMN_FAMILY_NAME,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sqlparser::ast::{DataType, Expr, FunctionArg, FunctionArgExpr, FunctionArguments};
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use tracing::debug;
+use std::time::{Duration, Instant};
+use tracing::{debug, instrument, warn, Span};
+use uuid::Uuid;
 
-const TABLE_METADATA_KEY: &'static str = "__metadata__";
+/// Names starting with this are reserved for column families the engine uses internally.
+pub const SYSTEM_PREFIX: &str = "__dechib";
+/// Table metadata lives here keyed by table name, keeping system state out of the table column
+/// families so no user row can ever collide with it.
+const CATALOG_CF: &str = "__dechib_catalog__";
+/// Values reserved by every sequence used by a `nextval` default, keyed by sequence name.
+const SEQUENCES_CF: &str = "__dechib_sequences__";
+/// Older databases kept the metadata inside each table under this key.
+const LEGACY_METADATA_KEY: &str = "__metadata__";
+/// Format of the catalog entries, stored in the catalog under a reserved name so it can't clash
+/// with a table.
+const CATALOG_VERSION: u8 = 6;
+/// Last catalog version that changed the format of the entries. Version 6 left them as they were
+/// and started storing timestamps in full.
+const COLUMNS_VERSION: u8 = 5;
+const CATALOG_VERSION_KEY: &str = "__dechib_version__";
+/// Keys fetched per `multi_get` when checking foreign keys.
+const LOOKUP_BATCH: usize = 1024;
 
 pub struct StorageEngine {
     db: DB,
-    auto_incs: BTreeMap<Entry, AtomicUsize>,
+    auto_incs: BTreeMap<Entry, Counter>,
+    sequences: BTreeMap<String, Counter>,
+    functions: FunctionRegistry,
+    config: StorageConfig,
+    write_counters: WriteCounters,
+    warnings: Vec<Warning>,
+    deadline: Option<Deadline>,
+    /// Whether written rows have to refer to rows that exist through their foreign keys
+    foreign_key_checks: bool,
+    quota_hook: Option<QuotaHook>,
 }
 
-pub enum Action<'a> {
-    Increment(&'a AtomicUsize),
-    ApplyConstant(Rc<Value>),
+/// Called when a write takes a table past one of the thresholds of its quota.
+pub type QuotaHook = Box<dyn Fn(&QuotaUsage) + Send + Sync>;
+
+/// A table's quota along with how much of it was used when a write started.
+struct Quota {
+    limit: u64,
+    used: u64,
+}
+
+impl Quota {
+    fn check(&self, table: &str, adding: usize) -> anyhow::Result<()> {
+        let adding = adding as u64;
+        if self.used + adding > self.limit {
+            return Err(QuotaExceeded {
+                table: table.to_string(),
+                used: self.used,
+                adding,
+                quota: self.limit,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// An auto increment column or a sequence. Values are reserved in storage a block at a time so
+/// the counter isn't written on every insert.
+#[derive(Debug, Default)]
+struct Counter {
+    next: AtomicUsize,
+    /// Values below this are reserved in storage
+    reserved: AtomicUsize,
+}
+
+impl Counter {
+    fn new(next: usize) -> Self {
+        Self {
+            next: AtomicUsize::new(next),
+            reserved: AtomicUsize::new(0),
+        }
+    }
+
+    /// Carries on from the end of the block reserved before a restart, whatever of it was used.
+    fn restored(reserved: usize) -> Self {
+        Self {
+            next: AtomicUsize::new(reserved),
+            reserved: AtomicUsize::new(reserved),
+        }
+    }
+
+    /// The limit to store so `count` more values are reserved, `None` if they already are.
+    fn reservation(&self, count: usize, block: usize) -> Option<usize> {
+        let needed = self.next.load(Ordering::SeqCst) + count;
+        (needed > self.reserved.load(Ordering::SeqCst)).then_some(needed + block)
+    }
+}
+
+/// How often, in rows, statements check whether they've run out of time.
+const DEADLINE_CHECK_ROWS: usize = 64;
+
+/// Limit on how long the running statement has, checked cooperatively as rows are read.
+struct Deadline {
+    started: Instant,
+    timeout: Duration,
+    rows: Cell<usize>,
+}
+
+#[derive(Debug, Default)]
+struct WriteCounters {
+    batches: AtomicUsize,
+    bytes: AtomicUsize,
+    largest_batch: AtomicUsize,
+    stalls: AtomicUsize,
+}
+
+/// Writes made since the engine was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    pub batches: usize,
+    pub bytes: usize,
+    pub largest_batch: usize,
+    /// Writes after which rocksdb was delaying or stopping writes
+    pub stalls: usize,
+}
+
+/// How hard [`StorageEngine::open_with_recovery`] tries to open a damaged database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryOptions {
+    /// Fail on any corruption rocksdb notices while opening, its default. Turning this off lets
+    /// a database with damaged files open with whatever is still readable.
+    pub paranoid_checks: bool,
+    /// Run rocksdb's repair before opening
+    pub repair: bool,
+}
+
+impl Default for RecoveryOptions {
+    fn default() -> Self {
+        Self {
+            paranoid_checks: true,
+            repair: false,
+        }
+    }
+}
+
+/// What [`StorageEngine::open_with_recovery`] gave up to open the database.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Files the repair couldn't salvage, moved under `lost/` in the database directory
+    pub lost_files: Vec<PathBuf>,
+    /// Rows deleted from each table because they could no longer be decoded
+    pub dropped_rows: BTreeMap<String, usize>,
+}
+
+/// How a value is generated for a column an insert leaves out.
+pub enum DefaultProvider<'a> {
+    Constant(Rc<Value>),
+    /// A sequence
+    Counter(&'a Counter),
+    /// An auto increment column, whose counter numbers its values
+    Identity {
+        counter: &'a Counter,
+        identity: Identity,
+        column: String,
+    },
+    Function(&'a Function, Vec<Value>),
+}
+
+impl DefaultProvider<'_> {
+    fn generate(&self) -> anyhow::Result<Rc<Value>> {
+        let value = match self {
+            Self::Constant(value) => return Ok(value.clone()),
+            Self::Counter(counter) => {
+                let value = counter.next.fetch_add(1, Ordering::SeqCst);
+                Value::Number(BigDecimal::from_usize(value).unwrap())
+            }
+            Self::Identity {
+                counter,
+                identity,
+                column,
+            } => {
+                let n = counter.next.fetch_add(1, Ordering::SeqCst);
+                Value::Number(identity.value(column, n)?.into())
+            }
+            Self::Function(function, args) => function(args.as_slice())?,
+        };
+        Ok(Rc::new(value))
+    }
+}
+
+/// Splits a default that calls a function into the function name and its arguments, which have to
+/// be constants. Returns `None` if the default isn't a function call.
+fn default_call(expr: &Expr) -> anyhow::Result<Option<(String, Vec<Value>)>> {
+    let Expr::Function(function) = expr else {
+        return Ok(None);
+    };
+    let args = match &function.args {
+        FunctionArguments::None => vec![],
+        FunctionArguments::List(list) => list
+            .args
+            .iter()
+            .map(|arg| match arg {
+                FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::Value(val))) => {
+                    Value::try_from(val.clone())
+                }
+                arg => anyhow::bail!("Unsupported argument {} in default", arg),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        FunctionArguments::Subquery(_) => anyhow::bail!("Defaults can't use subqueries"),
+    };
+    Ok(Some((normalize_object_name(&function.name), args)))
 }
 
-fn generate_pk_name(record: &Record, metadata: &ColumnDescriptors) -> String {
-    let mut name = String::new();
-    for key in metadata
+fn sequence_name(args: &[Value]) -> anyhow::Result<&str> {
+    match args {
+        [Value::Text(name)] => Ok(name),
+        _ => anyhow::bail!("{} takes the name of a sequence", NEXTVAL),
+    }
+}
+
+/// Checks a default can be generated, either a constant or a call to a known function.
+fn check_default(expr: &Expr, functions: &FunctionRegistry) -> anyhow::Result<()> {
+    if let Expr::Value(val) = expr {
+        Value::try_from(val.clone())?;
+        return Ok(());
+    }
+    let (name, args) =
+        default_call(expr)?.with_context(|| format!("Unsupported default expression: {}", expr))?;
+    if name == NEXTVAL {
+        sequence_name(&args)?;
+    } else if functions.get(&name).is_none() {
+        anyhow::bail!("No function {} for default", name);
+    }
+    Ok(())
+}
+
+/// The column rows are keyed by, the hidden rowid for tables without a primary key.
+pub(crate) fn primary_key_column(metadata: &ColumnDescriptors) -> anyhow::Result<&str> {
+    let mut columns = metadata
         .iter()
-        .filter(|(_, desc)| !desc.primary_key)
-        .map(|(k, _)| k)
-    {
-        name.push_str(&format!("{}/", key));
+        .filter(|(_, desc)| desc.primary_key)
+        .map(|(column, _)| column.as_str());
+    match (columns.next(), columns.next()) {
+        (Some(column), None) => Ok(column),
+        (None, _) => anyhow::bail!("Table has no primary key"),
+        _ => anyhow::bail!("Rows of a composite primary key are looked up with get_row"),
+    }
+}
+
+/// Columns of a table kept unique by an index, each with the name of the constraint reported when
+/// a value is taken. A single column primary key is already unique through the row keys.
+fn unique_columns(
+    table: &str,
+    metadata: &ColumnDescriptors,
+    constraints: &[Constraint],
+    primary_key: &[String],
+) -> BTreeMap<String, String> {
+    let mut columns = BTreeMap::new();
+    for (column, desc) in metadata {
+        if desc.unique && primary_key != std::slice::from_ref(column) {
+            let kind = ConstraintKind::Unique {
+                column: column.clone(),
+            };
+            columns.insert(column.clone(), kind.default_name(table));
+        }
+    }
+    for constraint in constraints {
+        if let ConstraintKind::Unique { column } = &constraint.kind {
+            columns
+                .entry(column.clone())
+                .or_insert_with(|| constraint.name.clone());
+        }
+    }
+    columns
+}
+
+/// The value a row has in an indexed column. NULLs aren't indexed, any number of rows can have
+/// them.
+fn indexed_value<'a>(record: &'a Record, column: &str) -> Option<&'a Value> {
+    match record.columns.get(column).map(|x| x.as_ref()) {
+        None | Some(Value::Null) => None,
+        value => value,
+    }
+}
+
+fn unique_violation(table: &str, column: &str, constraint: &str, value: &Value) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Duplicate value {} = {} in {} violates unique constraint {}",
+        column,
+        value,
+        table,
+        constraint
+    )
+}
+
+/// Adds an entry to the unique index of `column` for every row with a value in it.
+fn index_rows(
+    db: &DB,
+    handle: &ColumnFamily,
+    column: &str,
+    batch: &mut WriteBatch,
+) -> anyhow::Result<()> {
+    let start = IteratorMode::From(keys::DATA_PREFIX, Direction::Forward);
+    for entry in db.iterator_cf(handle, start) {
+        let (key, value) = entry?;
+        let Some(pk) = keys::strip_data_prefix(&key) else {
+            break;
+        };
+        let record: Record = from_bytes(&value)?;
+        if let Some(value) = indexed_value(&record, column) {
+            batch.put_cf(handle, keys::unique_key(column, value), pk);
+        }
+    }
+    Ok(())
+}
+
+/// Entry of a row in a secondary index, `key` is where the row is stored. Missing columns are
+/// indexed as NULL. `None` when the index is partial and the row doesn't match its predicate.
+fn secondary_entry(index: &Index, record: &Record, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    if let Some(predicate) = &index.predicate {
+        if !expr::matches(predicate, record)? {
+            return Ok(None);
+        }
+    }
+    let values = index
+        .columns
+        .iter()
+        .map(|x| expr::evaluate(x, record))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let pk = keys::strip_data_prefix(key).unwrap();
+    Ok(Some(keys::index_key(
+        &index.name,
+        values.iter().map(|x| x.as_ref()),
+        pk,
+    )))
+}
+
+/// Values reserved by each auto increment column of a table, empty for tables from before they
+/// were stored.
+fn read_auto_increments(db: &DB, handle: &ColumnFamily) -> anyhow::Result<BTreeMap<String, u64>> {
+    match db.get_cf(handle, keys::metadata_key(keys::AUTO_INCREMENT_KEY))? {
+        Some(bytes) => Ok(from_bytes(&bytes)?),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// Primary key columns of a table in key order. Tables created before the order was stored use
+/// the columns marked as the primary key.
+fn read_primary_key(
+    db: &DB,
+    handle: &ColumnFamily,
+    metadata: &ColumnDescriptors,
+) -> anyhow::Result<Vec<String>> {
+    match db.get_cf(handle, keys::metadata_key(keys::PRIMARY_KEY_KEY))? {
+        Some(bytes) => Ok(from_bytes(&bytes)?),
+        None => Ok(metadata
+            .iter()
+            .filter(|(_, desc)| desc.primary_key)
+            .map(|(column, _)| column.clone())
+            .collect()),
+    }
+}
+
+/// The key of a row, made of its primary key values in key order so rows sort by primary key.
+fn generate_pk_name(record: &Record, primary_key: &[String]) -> anyhow::Result<Vec<u8>> {
+    if let [column] = primary_key {
+        if column == ROWID_COLUMN {
+            let rowid = match record.columns.get(ROWID_COLUMN).map(|x| x.as_ref()) {
+                Some(Value::Number(n)) => n.to_u64().context("Invalid rowid")?,
+                _ => anyhow::bail!("Row is missing its rowid"),
+            };
+            // Big endian so rows sort in the order they were inserted
+            return Ok(rowid.to_be_bytes().to_vec());
+        }
+    }
+    if primary_key.is_empty() {
+        anyhow::bail!("Table has no primary key");
+    }
+    let mut key = vec![];
+    for column in primary_key {
+        match record.columns.get(column).map(|x| x.as_ref()) {
+            None | Some(Value::Null) => {
+                anyhow::bail!("Primary key column {} can't be NULL", column)
+            }
+            Some(value) => keys::encode_value(value, &mut key),
+        }
     }
-    name.pop();
-    assert!(!name.is_empty());
-    name
+    Ok(key)
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, Ord, PartialOrd)]
@@ -44,20 +416,334 @@ pub struct Entry {
 
 impl StorageEngine {
     pub fn new() -> Self {
-        Self::new_with_path("_dechib_db")
+        Self::new_with_config(StorageConfig::default())
     }
 
     pub fn new_with_path(path: impl AsRef<Path>) -> Self {
-        let mut opts = Options::default();
+        Self::new_with_config(StorageConfig::with_path(path))
+    }
+
+    pub fn new_with_config(config: StorageConfig) -> Self {
+        let mut opts = config.db_options();
         opts.create_if_missing(true);
-        let db = match DB::list_cf(&opts, path.as_ref()) {
-            Ok(cf) => DB::open_cf(&opts, path, &cf).expect("Failed to load storage"),
-            Err(_) => DB::open(&opts, path).expect("Failed to create storage"),
+        let mut engine = Self::open(config, opts).expect("Failed to open storage");
+        engine
+            .restore_counters()
+            .expect("Failed to restore counters");
+        engine
+    }
+
+    /// Opens a damaged database as a last resort. A repair has rocksdb salvage what it can from
+    /// the files on disk, setting aside anything it can't read under `lost/` in the database
+    /// directory, then rows that no longer decode are deleted. The report says what was lost.
+    pub fn open_with_recovery(
+        config: StorageConfig,
+        recovery: &RecoveryOptions,
+    ) -> anyhow::Result<(Self, RecoveryReport)> {
+        let mut opts = config.db_options();
+        opts.set_paranoid_checks(recovery.paranoid_checks);
+        let mut report = RecoveryReport::default();
+        if recovery.repair {
+            let lost = config.path.join("lost");
+            let before = lost_files(&lost)?;
+            warn!(path = %config.path.display(), "Repairing storage");
+            DB::repair(&opts, &config.path)
+                .with_context(|| format!("Failed to repair {}", config.path.display()))?;
+            report.lost_files = lost_files(&lost)?
+                .into_iter()
+                .filter(|x| !before.contains(x))
+                .collect();
+        }
+        let mut engine = Self::open(config, opts)?;
+        if recovery.repair {
+            // Counters are restored from the rows, so anything unreadable has to go first
+            report.dropped_rows = engine.drop_undecodable_rows()?;
+        }
+        engine.restore_counters()?;
+        Ok((engine, report))
+    }
+
+    fn open(config: StorageConfig, opts: rocksdb::Options) -> anyhow::Result<Self> {
+        let path = &config.path;
+        let mut db = match DB::list_cf(&opts, path) {
+            Ok(cf) => DB::open_cf(&opts, path, &cf).context("Failed to load storage")?,
+            Err(_) => DB::open(&opts, path).context("Failed to create storage")?,
         };
-        Self {
+        for cf in [CATALOG_CF, SEQUENCES_CF] {
+            if db.cf_handle(cf).is_none() {
+                db.create_cf(cf, &config.db_options())
+                    .context("Failed to create system column family")?;
+            }
+        }
+        migrate_legacy_metadata(&db, &opts, path).context("Failed to migrate table metadata")?;
+        let version = migrate_catalog(&db).context("Failed to migrate catalog")?;
+        migrate_key_layout(&db, &opts, path).context("Failed to migrate key layout")?;
+        let mut engine = Self {
             db,
             auto_incs: BTreeMap::new(),
+            sequences: BTreeMap::new(),
+            functions: FunctionRegistry::default(),
+            config,
+            write_counters: WriteCounters::default(),
+            warnings: vec![],
+            deadline: None,
+            foreign_key_checks: true,
+            quota_hook: None,
+        };
+        engine
+            .migrate_timestamps(version)
+            .context("Failed to migrate timestamps")?;
+        Ok(engine)
+    }
+
+    /// Before catalog version 6 timestamps were only written out in full by `Instance`, so rows
+    /// imported, copied or loaded straight into storage could hold them as they were given. Those
+    /// don't sort by time, rewrite them the way they're stored now, moving rows keyed by them and
+    /// rebuilding the indexes of every table that changed.
+    fn migrate_timestamps(&mut self, version: u8) -> anyhow::Result<()> {
+        if version >= CATALOG_VERSION {
+            return Ok(());
+        }
+        for (table, metadata) in self.tables()? {
+            let mut columns = timezone::zoned_columns(&metadata);
+            columns.extend(timezone::plain_timestamp_columns(&metadata));
+            if columns.is_empty() {
+                continue;
+            }
+            debug!("Migrating timestamps of {}", table);
+            let ttl_column = self.ttl_column(&table)?;
+            let primary_key = self.key_columns(&table, &metadata)?;
+            let dictionary = self.dictionary(&table, &metadata)?;
+            let handle = self.db.cf_handle(&table).unwrap();
+            let mut batch = WriteBatch::default();
+            let mut moved = vec![];
+            let start = IteratorMode::From(keys::DATA_PREFIX, Direction::Forward);
+            for entry in self.db.iterator_cf(handle, start) {
+                let (key, value) = entry?;
+                if keys::strip_data_prefix(&key).is_none() {
+                    break;
+                }
+                let mut record: Record = from_bytes(&value)?;
+                let mut changed = false;
+                for column in &columns {
+                    let Some(value) = record.columns.get(column).cloned() else {
+                        continue;
+                    };
+                    match timezone::stored_value(&metadata[column], value.clone()) {
+                        Ok(stored) if stored != value => {
+                            record.columns.insert(column.clone(), stored);
+                            changed = true;
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!(%table, "Leaving {} = {} as it is: {}", column, value, e),
+                    }
+                }
+                if !changed {
+                    continue;
+                }
+                if let Some(expires) = ttl_column.as_ref().and_then(|x| record.columns.get(x)) {
+                    let expires = expires.clone();
+                    record.columns.insert(EXPIRES_COLUMN.to_string(), expires);
+                }
+                // Keys are made from the values as they were inserted, before dictionary encoding
+                let mut decoded = record.clone();
+                dictionary.decode(&mut decoded)?;
+                let new_key = keys::data_key(generate_pk_name(&decoded, &primary_key)?);
+                if *key != *new_key {
+                    batch.delete_cf(handle, &key);
+                }
+                moved.push((new_key, to_allocvec(&record)?));
+            }
+            if moved.is_empty() {
+                continue;
+            }
+            // Puts go after every delete so a row moving onto another row's old key isn't deleted
+            for (key, row) in moved {
+                batch.put_cf(handle, key, row);
+            }
+            self.db.write(batch)?;
+            if let Err(e) = self.reindex(&Reindex::Table(table.clone())) {
+                warn!(%table, "Indexes of {} couldn't be rebuilt: {}", table, e);
+            }
+        }
+        self.db
+            .put_cf(self.catalog(), CATALOG_VERSION_KEY, [CATALOG_VERSION])?;
+        Ok(())
+    }
+
+    fn restore_counters(&mut self) -> anyhow::Result<()> {
+        self.restore_auto_increments()
+            .context("Failed to restore auto increment counters")?;
+        self.restore_sequences()
+            .context("Failed to restore sequences")
+    }
+
+    /// Deletes rows that can't be decoded any more, returning how many went from each table.
+    fn drop_undecodable_rows(&self) -> anyhow::Result<BTreeMap<String, usize>> {
+        let mut dropped = BTreeMap::new();
+        for table in self.tables()?.into_keys() {
+            let handle = self
+                .db
+                .cf_handle(&table)
+                .with_context(|| format!("No column family for {}", table))?;
+            let mut batch = WriteBatch::default();
+            let start = IteratorMode::From(keys::DATA_PREFIX, Direction::Forward);
+            for row in self.db.iterator_cf(handle, start) {
+                let (key, value) = row?;
+                if keys::strip_data_prefix(&key).is_none() {
+                    break;
+                }
+                if from_bytes::<Record>(&value).is_err() {
+                    batch.delete_cf(handle, key);
+                }
+            }
+            if !batch.is_empty() {
+                warn!(%table, rows = batch.len(), "Dropping rows that can't be decoded");
+                dropped.insert(table, batch.len());
+                self.write(batch)?;
+            }
+        }
+        Ok(dropped)
+    }
+
+    fn restore_sequences(&mut self) -> anyhow::Result<()> {
+        for (key, value) in self.system_scan(SEQUENCES_CF, keys::DATA_PREFIX)? {
+            let name = keys::strip_data_prefix(&key).context("Invalid sequence key")?;
+            let reserved = <[u8; 8]>::try_from(value.as_ref())
+                .map(u64::from_be_bytes)
+                .context("Invalid sequence value")?;
+            self.sequences.insert(
+                String::from_utf8(name.to_vec())?,
+                Counter::restored(reserved as usize),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn register_function(
+        &mut self,
+        name: &str,
+        function: impl Fn(&[Value]) -> anyhow::Result<Value> + Send + Sync + 'static,
+    ) {
+        self.functions.register(name, function);
+    }
+
+    pub fn set_quota_hook(&mut self, hook: impl Fn(&QuotaUsage) + Send + Sync + 'static) {
+        self.quota_hook = Some(Box::new(hook));
+    }
+
+    fn default_provider(
+        &self,
+        table: &str,
+        column: &str,
+        desc: &ColumnDescriptor,
+    ) -> anyhow::Result<DefaultProvider<'_>> {
+        match &desc.default {
+            Some(expr) => self.expr_provider(expr),
+            None => {
+                let entry = Entry {
+                    table: table.to_string(),
+                    column: column.to_string(),
+                };
+                let auto_inc = self
+                    .auto_incs
+                    .get(&entry)
+                    .with_context(|| format!("No auto increment support for {}", column))?;
+                Ok(DefaultProvider::Identity {
+                    counter: auto_inc,
+                    identity: desc.identity,
+                    column: column.to_string(),
+                })
+            }
+        }
+    }
+
+    /// Provider for a default or `ON UPDATE` expression, a constant or a function call.
+    fn expr_provider(&self, expr: &Expr) -> anyhow::Result<DefaultProvider<'_>> {
+        if let Expr::Value(val) = expr {
+            return Ok(DefaultProvider::Constant(Rc::new(Value::try_from(
+                val.clone(),
+            )?)));
+        }
+        let (name, args) = default_call(expr)?
+            .with_context(|| format!("Unsupported default expression: {}", expr))?;
+        if name == NEXTVAL {
+            let sequence = sequence_name(&args)?;
+            let counter = self
+                .sequences
+                .get(sequence)
+                .with_context(|| format!("No sequence {}", sequence))?;
+            Ok(DefaultProvider::Counter(counter))
+        } else {
+            let function = self
+                .functions
+                .get(&name)
+                .with_context(|| format!("No function {} for default", name))?;
+            Ok(DefaultProvider::Function(function, args))
+        }
+    }
+
+    /// Counters carry on from the values they reserved. Tables from before counters were stored
+    /// carry on from the largest value in their rows instead.
+    fn restore_auto_increments(&mut self) -> anyhow::Result<()> {
+        for (table, metadata) in self.tables()? {
+            let handle = self
+                .db
+                .cf_handle(&table)
+                .with_context(|| format!("No column family for {}", table))?;
+            let reserved = read_auto_increments(&self.db, handle)?;
+            let mut columns = vec![];
+            for (column, desc) in &metadata {
+                if !desc.auto_increment {
+                    continue;
+                }
+                match reserved.get(column) {
+                    Some(reserved) => {
+                        let entry = Entry {
+                            table: table.clone(),
+                            column: column.clone(),
+                        };
+                        self.auto_incs
+                            .insert(entry, Counter::restored(*reserved as usize));
+                    }
+                    None => columns.push(column),
+                }
+            }
+            if columns.is_empty() {
+                continue;
+            }
+            let mut next = vec![1; columns.len()];
+            let start = IteratorMode::From(keys::DATA_PREFIX, Direction::Forward);
+            for row in self.db.iterator_cf(handle, start) {
+                let (key, value) = row?;
+                if keys::strip_data_prefix(&key).is_none() {
+                    break;
+                }
+                let record: Record = from_bytes(&value)?;
+                for (column, next) in columns.iter().zip(next.iter_mut()) {
+                    let value = record.columns.get(*column).map(|x| x.as_ref());
+                    if let Some(Value::Number(n)) = value {
+                        if let Some(n) = n.to_usize() {
+                            *next = (*next).max(n + 1);
+                        }
+                    }
+                }
+            }
+            for (column, next) in columns.into_iter().zip(next) {
+                let entry = Entry {
+                    table: table.clone(),
+                    column: column.clone(),
+                };
+                self.auto_incs.insert(entry, Counter::new(next));
+            }
         }
+        Ok(())
+    }
+
+    fn catalog(&self) -> &ColumnFamily {
+        self.db
+            .cf_handle(CATALOG_CF)
+            .expect("Catalog is created on open")
     }
 
     pub fn handle(&self) -> &DB {
@@ -68,237 +754,4288 @@ impl StorageEngine {
         &mut self.db
     }
 
-    fn validate_table_options(&self, create_table: &CreateTableOptions) -> anyhow::Result<()> {
-        for (column, props) in create_table
-            .columns
-            .iter()
-            .filter(|(_, x)| x.foreign_key.is_some())
-        {
-            if let Some((table, col)) = props.foreign_key.as_ref() {
-                let table_metadata = self.table_metadata(&table)?;
-                if let Some(desc) = table_metadata.get(col) {
-                    if !desc.primary_key {
-                        anyhow::bail!("Foreign key {}.{} must refer to a primary key", table, col);
-                    }
-                } else {
-                    anyhow::bail!("Column {} does not exist in {}", col, table);
-                }
+    pub fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+
+    /// Writes a consistent copy of the database to `path`, which mustn't exist yet. Files are hard
+    /// linked where possible so it's cheap when `path` is on the same filesystem.
+    pub(crate) fn checkpoint(&self, path: &Path) -> anyhow::Result<()> {
+        Checkpoint::new(&self.db)?
+            .create_checkpoint(path)
+            .with_context(|| format!("Failed to create checkpoint at {}", path.display()))
+    }
+
+    /// Registered functions aren't stored, so engines opened on a copy of the database borrow
+    /// them from the original.
+    pub(crate) fn swap_functions(&mut self, other: &mut StorageEngine) {
+        std::mem::swap(&mut self.functions, &mut other.functions);
+    }
+
+    /// Turns checking foreign keys on writes on or off, like MySQL's `foreign_key_checks`. Loads
+    /// that don't write tables in dependency order need them off.
+    pub(crate) fn set_foreign_key_checks(&mut self, on: bool) {
+        self.foreign_key_checks = on;
+    }
+
+    /// Starts timing a statement, which fails with [`StatementTimeout`] once it's run for longer
+    /// than `timeout`.
+    pub(crate) fn start_statement(&mut self, timeout: Option<Duration>) {
+        self.deadline = timeout.map(|timeout| Deadline {
+            started: Instant::now(),
+            timeout,
+            rows: Cell::new(0),
+        });
+    }
+
+    /// Fails if the running statement is out of time.
+    pub(crate) fn check_deadline(&self) -> anyhow::Result<()> {
+        let Some(deadline) = &self.deadline else {
+            return Ok(());
+        };
+        let elapsed = deadline.started.elapsed();
+        if elapsed > deadline.timeout {
+            return Err(StatementTimeout {
+                timeout: deadline.timeout,
+                elapsed,
+                rows: deadline.rows.get(),
             }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Counts a row read or written by the running statement, checking the deadline every so
+    /// often.
+    fn count_row(&self) -> anyhow::Result<()> {
+        let Some(deadline) = &self.deadline else {
+            return Ok(());
+        };
+        let rows = deadline.rows.get() + 1;
+        deadline.rows.set(rows);
+        if rows % DEADLINE_CHECK_ROWS == 0 {
+            self.check_deadline()?;
+        }
+        Ok(())
+    }
+
+    /// Read options for iterating a table's rows. Rows are decoded into owned records straight
+    /// away so pinning blocks buys nothing, and a scan reads a snapshot so it never tails.
+    fn scan_options(&self, hint: ScanHint) -> ReadOptions {
+        let mut opts = ReadOptions::default();
+        // Stops readahead running on into the table's system state
+        opts.set_iterate_upper_bound(keys::prefix_end(keys::DATA_PREFIX));
+        if hint == ScanHint::Sequential {
+            opts.set_readahead_size(self.config.scan_readahead_bytes);
+            // A one-off scan would otherwise evict the blocks everything else keeps reading
+            opts.fill_cache(false);
+        }
+        opts
+    }
+
+    /// Warnings raised since they were last taken.
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    pub fn write_stats(&self) -> WriteStats {
+        let counters = &self.write_counters;
+        WriteStats {
+            batches: counters.batches.load(Ordering::Relaxed),
+            bytes: counters.bytes.load(Ordering::Relaxed),
+            largest_batch: counters.largest_batch.load(Ordering::Relaxed),
+            stalls: counters.stalls.load(Ordering::Relaxed),
+        }
+    }
+
+    fn write(&self, batch: WriteBatch) -> anyhow::Result<()> {
+        let bytes = batch.size_in_bytes();
+        debug!(bytes, entries = batch.len(), "Writing batch");
+        let counters = &self.write_counters;
+        counters.batches.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        counters.largest_batch.fetch_max(bytes, Ordering::Relaxed);
+
+        let mut opts = WriteOptions::default();
+        opts.set_sync(self.config.sync_writes);
+        self.db.write_opt(batch, &opts)?;
+
+        let delayed = self
+            .db
+            .property_int_value("rocksdb.actual-delayed-write-rate")?
+            .unwrap_or_default();
+        let stopped = self
+            .db
+            .property_int_value("rocksdb.is-write-stopped")?
+            .unwrap_or_default();
+        if delayed > 0 || stopped > 0 {
+            warn!(delayed, stopped, "Writes are being stalled");
+            counters.stalls.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Writes the batch out once it's grown past the configured size, leaving an empty one.
+    fn write_if_full(&self, batch: &mut WriteBatch) -> anyhow::Result<()> {
+        if batch.size_in_bytes() >= self.config.max_batch_bytes {
+            self.write(std::mem::take(batch))?;
         }
         Ok(())
     }
 
+    #[instrument(skip_all, fields(table = %create_table.name))]
     pub fn create_table(&mut self, create_table: &CreateTableOptions) -> anyhow::Result<()> {
-        self.validate_table_options(create_table)?;
+        let columns = check_create_table(create_table, &self.config, |table| {
+            self.table_metadata(table)
+        })?;
+        let name = create_table.name.as_str();
+
         // So each table should be a column family so operations that operate on different tables
         // can happen concurrently (my current understanding)
-        let name = create_table.name.as_ref();
-        self.db.create_cf(name, &Options::default())?;
-        let handle = self.db.cf_handle(name).unwrap();
-
-        // TODO we should put in an implict primary key if there isn't one present (it just makes
-        // other things work nicer)
+        self.db.create_cf(name, &self.config.db_options())?;
 
-        self.db.put_cf(
-            &handle,
-            TABLE_METADATA_KEY,
-            to_allocvec(&create_table.columns)?,
-        )?;
+        let mut batch = WriteBatch::default();
+        let handle = self.db.cf_handle(name).unwrap();
+        batch.put_cf(self.catalog(), name, to_allocvec(&columns)?);
+        batch.put_cf(
+            handle,
+            keys::metadata_key(keys::LAYOUT_KEY),
+            [keys::LAYOUT_VERSION],
+        );
+        if let Some(column) = &create_table.ttl_column {
+            batch.put_cf(handle, keys::metadata_key(TTL_KEY), column);
+        }
+        if let Some(quota) = create_table.max_bytes {
+            batch.put_cf(
+                handle,
+                keys::metadata_key(keys::QUOTA_KEY),
+                to_allocvec(&quota)?,
+            );
+        }
+        if !create_table.primary_key.is_empty() {
+            batch.put_cf(
+                handle,
+                keys::metadata_key(keys::PRIMARY_KEY_KEY),
+                to_allocvec(&create_table.primary_key)?,
+            );
+        }
+        if !create_table.constraints.is_empty() {
+            batch.put_cf(
+                handle,
+                keys::metadata_key(keys::CONSTRAINTS_KEY),
+                to_allocvec(&create_table.constraints)?,
+            );
+        }
+        self.write(batch)?;
 
-        for (column, props) in create_table
-            .columns
-            .iter()
-            .filter(|(_, v)| v.auto_increment)
-        {
-            let initial = AtomicUsize::new(1);
+        for (column, props) in columns.iter().filter(|(_, v)| v.auto_increment) {
+            let initial = Counter::new(1);
             let entry = Entry {
                 table: name.to_string(),
                 column: column.to_string(),
             };
             self.auto_incs.insert(entry, initial);
         }
+        if columns.contains_key(ROWID_COLUMN) {
+            self.warnings.push(Warning::ImplicitRowid {
+                table: name.to_string(),
+            });
+        }
 
         Ok(())
     }
 
-    pub fn table_metadata(&self, name: impl AsRef<str>) -> anyhow::Result<ColumnDescriptors> {
-        let handle = self
-            .db
-            .cf_handle(name.as_ref())
-            .with_context(|| format!("No table {} exists", name.as_ref()))?;
-        let bytes = self
-            .db
-            .get_pinned_cf(&handle, TABLE_METADATA_KEY)?
-            .context("No metadata for table")?;
-        let res = from_bytes(&bytes)?;
-        Ok(res)
-    }
+    /// Creates a table with the same columns, constraints, primary key order, expiry column and
+    /// quota as the source, then copies its rows and unique index entries across along with its
+    /// auto increment counters. Secondary indexes aren't cloned, their names are unique across the
+    /// database. The rows are read from a snapshot of the source into an SST file that's ingested
+    /// into the clone, so the clone gets all of them or none. It's still a full copy rather than
+    /// an export and import of the source's files, which the rocksdb bindings don't expose.
+    #[instrument(skip_all, fields(table = %opts.name, source = %opts.source, rows, bytes))]
+    pub fn clone_table(&mut self, opts: &CloneTableOptions) -> anyhow::Result<()> {
+        let mut definition = clone_definition(opts, |table| self.table_metadata(table))?;
+        let handle = self.db.cf_handle(&opts.source).unwrap();
+        let primary_key = read_primary_key(&self.db, handle, &self.table_metadata(&opts.source)?)?;
+        definition.primary_key = primary_key
+            .into_iter()
+            .filter(|x| x != ROWID_COLUMN)
+            .collect();
+        definition.ttl_column = self.ttl_column(&opts.source)?;
+        definition.max_bytes = self.quota(&opts.source)?;
+        definition.constraints = self.constraints(&opts.source)?;
+        self.create_table(&definition)?;
 
-    pub fn insert_rows(&mut self, insert_op: &InsertOptions) -> anyhow::Result<()> {
-        // We should validate our metadata against our column data types!
-        let metadata = self.table_metadata(&insert_op.table)?;
+        for (entry, counter) in &self.auto_incs {
+            if entry.table != opts.source {
+                continue;
+            }
+            let entry = Entry {
+                table: opts.name.clone(),
+                column: entry.column.clone(),
+            };
+            if let Some(cloned) = self.auto_incs.get(&entry) {
+                let next = counter.next.load(Ordering::SeqCst);
+                cloned.next.store(next, Ordering::SeqCst);
+            }
+        }
 
-        // First lets just go over and make sure column names match etc
-        if let Some(bad_column) = insert_op
-            .columns
-            .iter()
-            .find(|x| !metadata.contains_key(x.as_str()))
-        {
-            anyhow::bail!("Column {} not present in table", bad_column);
+        let (rows, bytes) = self.copy_rows(&opts.source, &opts.name)?;
+        Span::current().record("rows", rows).record("bytes", bytes);
+        Ok(())
+    }
+
+    /// Every table with its columns and named constraints.
+    fn table_definitions(&self) -> anyhow::Result<TableDefinitions> {
+        let mut tables = BTreeMap::new();
+        for (name, columns) in self.tables()? {
+            let constraints = self.constraints(&name)?;
+            tables.insert(name, (columns, constraints));
         }
+        Ok(tables)
+    }
 
-        let mut value_actions = BTreeMap::new();
+    /// Drops tables and their rows. Foreign keys in other tables that refer to a dropped table
+    /// are an error, unless the drop cascades in which case the foreign keys are dropped too.
+    #[instrument(skip_all, fields(tables = ?drop_op.names))]
+    pub fn drop_table(&mut self, drop_op: &DropTableOptions) -> anyhow::Result<()> {
+        let mut tables = self.table_definitions()?;
+        let dropped = check_drop_table(drop_op, &tables)?;
 
-        for (column, desc) in metadata.iter() {
-            if desc.needs_value() {
-                // Now find missing columns that we need!
-                if !insert_op.columns.contains(column) {
-                    anyhow::bail!("Required column {} is missing", column)
-                }
-            } else if !insert_op.columns.contains(column) && desc.should_generate() {
-                if let Some(Expr::Value(val)) = &desc.default {
-                    value_actions.insert(
-                        column,
-                        Action::ApplyConstant(Rc::new(Value::try_from(val.clone())?)),
+        let mut batch = WriteBatch::default();
+        for reference in referencing_foreign_keys(&dropped, &tables) {
+            debug!(
+                table = %reference.table,
+                column = %reference.column,
+                "Dropping foreign key into {}",
+                reference.referred
+            );
+            let (columns, constraints) = tables.get_mut(&reference.table).unwrap();
+            let handle = self.db.cf_handle(&reference.table).unwrap();
+            match &reference.constraint {
+                Some(name) => {
+                    constraints.retain(|x| x.name != *name);
+                    batch.put_cf(
+                        handle,
+                        keys::metadata_key(keys::CONSTRAINTS_KEY),
+                        to_allocvec(constraints)?,
                     );
-                } else if desc.default.is_some() {
-                    anyhow::bail!("Unsupported default expression: {:?}", desc.default);
-                } else if desc.auto_increment {
-                    let entry = Entry {
-                        table: insert_op.table.to_string(),
-                        column: column.to_string(),
-                    };
-                    let auto_inc = self
-                        .auto_incs
-                        .get(&entry)
-                        .with_context(|| format!("No auto increment support for {}", column))?;
-                    value_actions.insert(column, Action::Increment(auto_inc));
-                } else {
-                    anyhow::bail!("Unsure how to generate value for {}", column);
+                }
+                None => {
+                    columns.get_mut(&reference.column).unwrap().foreign_key = None;
+                    batch.put_cf(self.catalog(), &reference.table, to_allocvec(columns)?);
                 }
             }
         }
+        for name in &dropped {
+            batch.delete_cf(self.catalog(), name);
+        }
+        // The catalog goes first, a column family left behind by a crash isn't a table
+        self.write(batch)?;
+        for name in &dropped {
+            self.db.drop_cf(name)?;
+        }
+        self.auto_incs
+            .retain(|entry, _| !dropped.contains(&entry.table));
+        Ok(())
+    }
+
+    /// Secondary indexes of a table, in the order they were created.
+    pub fn indexes(&self, table: &str) -> anyhow::Result<Vec<Index>> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        match self
+            .db
+            .get_cf(handle, keys::metadata_key(keys::INDEXES_KEY))?
+        {
+            Some(bytes) => Ok(from_bytes(&bytes)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn put_indexes(
+        &self,
+        table: &str,
+        indexes: &[Index],
+        batch: &mut WriteBatch,
+    ) -> anyhow::Result<()> {
+        let handle = self.db.cf_handle(table).unwrap();
+        batch.put_cf(
+            handle,
+            keys::metadata_key(keys::INDEXES_KEY),
+            to_allocvec(indexes)?,
+        );
+        Ok(())
+    }
+
+    /// The table every secondary index is on, keyed by index name.
+    fn index_tables(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut res = BTreeMap::new();
+        for table in self.tables()?.into_keys() {
+            for index in self.indexes(&table)? {
+                res.insert(index.name, table.clone());
+            }
+        }
+        Ok(res)
+    }
+
+    /// Creates a secondary index and adds an entry for every row already in the table. The index
+    /// is listed in the table's metadata after its entries are written, so it's never used
+    /// half built.
+    #[instrument(skip_all, fields(table = %opts.table, index = %opts.index.name))]
+    pub fn create_index(&mut self, opts: &CreateIndexOptions) -> anyhow::Result<()> {
+        let metadata = self.table_metadata(&opts.table)?;
+        if self.index_tables()?.contains_key(&opts.index.name) {
+            if opts.if_not_exists {
+                return Ok(());
+            }
+            anyhow::bail!("Index {} already exists", opts.index.name);
+        }
+        check_create_index(opts, &metadata)?;
+
+        let handle = self.db.cf_handle(&opts.table).unwrap();
+        let mut batch = WriteBatch::default();
+        for (key, record) in self.scan_rows(&opts.table)? {
+            let pk = keys::strip_data_prefix(&key).unwrap();
+            if let Some(entry) = secondary_entry(&opts.index, &record, &key)? {
+                batch.put_cf(handle, entry, pk);
+            }
+            self.write_if_full(&mut batch)?;
+        }
+        let mut indexes = self.indexes(&opts.table)?;
+        indexes.push(opts.index.clone());
+        self.put_indexes(&opts.table, &indexes, &mut batch)?;
+        self.write(batch)
+    }
+
+    /// Drops secondary indexes along with their entries, in a single batch.
+    #[instrument(skip_all, fields(indexes = ?opts.names))]
+    pub fn drop_index(&mut self, opts: &DropIndexOptions) -> anyhow::Result<()> {
+        let tables = self.index_tables()?;
+        let mut remaining = BTreeMap::new();
+        let mut batch = WriteBatch::default();
+        for name in &opts.names {
+            let Some(table) = tables.get(name) else {
+                if opts.if_exists {
+                    continue;
+                }
+                anyhow::bail!("No index {} exists", name);
+            };
+            if !remaining.contains_key(table) {
+                remaining.insert(table, self.indexes(table)?);
+            }
+            remaining
+                .get_mut(table)
+                .unwrap()
+                .retain(|x: &Index| x.name != *name);
+            let handle = self.db.cf_handle(table).unwrap();
+            let prefix = keys::index_prefix(name);
+            batch.delete_range_cf(handle, &prefix, keys::prefix_end(&prefix));
+        }
+        for (table, indexes) in remaining {
+            self.put_indexes(table, &indexes, &mut batch)?;
+        }
+        self.write(batch)
+    }
+
+    /// Rebuilds indexes from the rows of their table, dropping entries left behind by bulk loads
+    /// and expired rows or damaged some other way. Unique entries are checked against each other
+    /// before anything is written, so a table whose rows no longer satisfy a unique constraint
+    /// keeps the index it had.
+    #[instrument(skip_all, fields(reindex = ?reindex))]
+    pub fn reindex(&mut self, reindex: &Reindex) -> anyhow::Result<()> {
+        let (table, indexes, unique) = match reindex {
+            Reindex::Index(name) => {
+                let table = self
+                    .index_tables()?
+                    .remove(name)
+                    .with_context(|| format!("No index {} exists", name))?;
+                let mut indexes = self.indexes(&table)?;
+                indexes.retain(|x| x.name == *name);
+                (table, indexes, BTreeMap::new())
+            }
+            Reindex::Table(table) => {
+                let metadata = self.table_metadata(table)?;
+                let unique = self.unique_indexes(table, &metadata)?;
+                (table.clone(), self.indexes(table)?, unique)
+            }
+        };
+
+        let rows = self.scan_rows(&table)?;
+        let mut taken = HashSet::new();
+        for (_, record) in &rows {
+            for (column, name) in &unique {
+                let Some(value) = indexed_value(record, column) else {
+                    continue;
+                };
+                if !taken.insert(keys::unique_key(column, value)) {
+                    return Err(unique_violation(&table, column, name, value));
+                }
+            }
+        }
+
+        let handle = self.db.cf_handle(&table).unwrap();
+        let mut batch = WriteBatch::default();
+        for index in &indexes {
+            let prefix = keys::index_prefix(&index.name);
+            batch.delete_range_cf(handle, &prefix, keys::prefix_end(&prefix));
+        }
+        if let Reindex::Table(_) = reindex {
+            let prefix = keys::INDEX_PREFIX;
+            batch.delete_range_cf(handle, prefix, keys::prefix_end(prefix));
+        }
+        for (key, record) in &rows {
+            let pk = keys::strip_data_prefix(key).unwrap();
+            for index in &indexes {
+                if let Some(entry) = secondary_entry(index, record, key)? {
+                    batch.put_cf(handle, entry, pk);
+                }
+            }
+            for column in unique.keys() {
+                if let Some(value) = indexed_value(record, column) {
+                    batch.put_cf(handle, keys::unique_key(column, value), pk);
+                }
+            }
+            self.write_if_full(&mut batch)?;
+        }
+        self.write(batch)
+    }
+
+    #[instrument(skip_all, fields(table = %opts.name))]
+    pub fn alter_table(&mut self, opts: &AlterTableOptions) -> anyhow::Result<()> {
+        let metadata = self.table_metadata(&opts.name)?;
+        let mut constraints = self.constraints(&opts.name)?;
+        check_alter_table(
+            opts,
+            &metadata,
+            &constraints,
+            &self.config,
+            &self.functions,
+            |table| self.table_metadata(table),
+        )?;
+        let handle = self.db.cf_handle(&opts.name).unwrap();
+        // Index changes are written along with the constraints
+        let mut batch = WriteBatch::default();
+        match &opts.operation {
+            AlterOperation::AddConstraint(constraint) => {
+                if constraint.validated {
+                    self.check_constraint_rows(&opts.name, constraint)?;
+                }
+                constraints.push(constraint.clone());
+                if let ConstraintKind::Unique { column } = &constraint.kind {
+                    index_rows(&self.db, handle, column, &mut batch)?;
+                }
+            }
+            AlterOperation::DropConstraint { name, .. } => {
+                let before = self.unique_indexes(&opts.name, &metadata)?;
+                constraints.retain(|x| x.name != *name);
+                let primary_key = self.key_columns(&opts.name, &metadata)?;
+                let after = unique_columns(&opts.name, &metadata, &constraints, &primary_key);
+                for column in before.keys().filter(|x| !after.contains_key(*x)) {
+                    let prefix = keys::unique_prefix(column);
+                    batch.delete_range_cf(handle, &prefix, keys::prefix_end(&prefix));
+                }
+            }
+            AlterOperation::AddColumn {
+                column,
+                descriptor,
+                constraints: added,
+                ..
+            } => {
+                if metadata.contains_key(column) {
+                    // IF NOT EXISTS
+                    return Ok(());
+                }
+                constraints.extend(added.iter().cloned());
+                return self.add_column(&opts.name, metadata, column, descriptor, &constraints);
+            }
+            AlterOperation::DropColumn { column, .. } => {
+                if !metadata.contains_key(column) {
+                    // IF EXISTS
+                    return Ok(());
+                }
+                let ttl_column = self.ttl_column(&opts.name)?;
+                check_drop_column(
+                    &opts.name,
+                    column,
+                    &self.table_definitions()?,
+                    ttl_column.as_deref(),
+                )?;
+                constraints.retain(|x| !x.kind.refers_to(column));
+                return self.drop_column(&opts.name, metadata, column, &constraints);
+            }
+            AlterOperation::RenameTable { to } => return self.rename_table(&opts.name, to),
+            AlterOperation::RenameColumn { column, to } => {
+                return self.rename_column(&opts.name, column, to)
+            }
+        }
+        batch.put_cf(
+            handle,
+            keys::metadata_key(keys::CONSTRAINTS_KEY),
+            to_allocvec(&constraints)?,
+        );
+        self.write(batch)
+    }
+
+    /// Adds a column and fills it in on the existing rows from its default. The rows are written
+    /// in the same batch as the new metadata so the table is never seen half altered.
+    fn add_column(
+        &mut self,
+        table: &str,
+        mut metadata: ColumnDescriptors,
+        column: &str,
+        desc: &ColumnDescriptor,
+        constraints: &[Constraint],
+    ) -> anyhow::Result<()> {
+        let entry = Entry {
+            table: table.to_string(),
+            column: column.to_string(),
+        };
+        let sequences =
+            self.register_sequences(&BTreeMap::from([(column.to_string(), desc.clone())]))?;
+        if desc.auto_increment {
+            self.auto_incs.insert(entry.clone(), Counter::new(1));
+        }
+
+        metadata.insert(column.to_string(), desc.clone());
+        let res = self.backfill_column(table, &metadata, column, constraints, &sequences);
+        if res.is_err() {
+            self.auto_incs.remove(&entry);
+        }
+        res
+    }
+
+    /// Removes a column from the table's metadata and from every row in a single batch. Secondary
+    /// indexes using the column, in their columns or their predicate, are dropped with it.
+    fn drop_column(
+        &mut self,
+        table: &str,
+        mut metadata: ColumnDescriptors,
+        column: &str,
+        constraints: &[Constraint],
+    ) -> anyhow::Result<()> {
+        let dropped = metadata.remove(column);
+        let handle = self.db.cf_handle(table).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.put_cf(self.catalog(), table, to_allocvec(&metadata)?);
+        let mut dictionary = self.dictionary(table, &metadata)?;
+        if dropped.is_some_and(|x| x.dictionary) {
+            dictionary.drop_column(column);
+            batch.put_cf(
+                handle,
+                keys::metadata_key(DICTIONARY_KEY),
+                dictionary.to_bytes()?,
+            );
+        }
+        batch.put_cf(
+            handle,
+            keys::metadata_key(keys::CONSTRAINTS_KEY),
+            to_allocvec(constraints)?,
+        );
+        let prefix = keys::unique_prefix(column);
+        batch.delete_range_cf(handle, &prefix, keys::prefix_end(&prefix));
+        let (on_column, indexes): (Vec<_>, Vec<_>) =
+            self.indexes(table)?.into_iter().partition(|x| {
+                x.columns
+                    .iter()
+                    .chain(&x.predicate)
+                    .any(|x| expr::columns(x).iter().any(|x| x == column))
+            });
+        if !on_column.is_empty() {
+            for index in &on_column {
+                let prefix = keys::index_prefix(&index.name);
+                batch.delete_range_cf(handle, &prefix, keys::prefix_end(&prefix));
+            }
+            self.put_indexes(table, &indexes, &mut batch)?;
+        }
+        let mut reserved = read_auto_increments(&self.db, handle)?;
+        if reserved.remove(column).is_some() {
+            batch.put_cf(
+                handle,
+                keys::metadata_key(keys::AUTO_INCREMENT_KEY),
+                to_allocvec(&reserved)?,
+            );
+        }
+        for (key, mut record) in self.scan_rows(table)? {
+            if record.columns.remove(column).is_some() {
+                let row = self.encode_row(table, &mut dictionary, &record)?;
+                batch.put_cf(handle, key, row);
+            }
+        }
+        self.write(batch)?;
+        self.auto_incs.remove(&Entry {
+            table: table.to_string(),
+            column: column.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Moves a table to a new name. Column families can't be renamed in rocksdb, so the rows are
+    /// copied into a new one and the catalog switched over to it in one batch, along with foreign
+    /// keys that referred to the old name. The old column family is dropped last.
+    fn rename_table(&mut self, table: &str, to: &str) -> anyhow::Result<()> {
+        let mut tables = self.table_definitions()?;
+        let before = tables.clone();
+        rename_table_definitions(&mut tables, table, to);
+
+        self.db.create_cf(to, &self.config.db_options())?;
+        if let Err(e) = self.copy_rows(table, to) {
+            // Nothing refers to the copy until the catalog does
+            self.db.drop_cf(to)?;
+            return Err(e);
+        }
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(self.catalog(), table);
+        self.put_definitions(&mut batch, &before, &tables)?;
+        self.write(batch)?;
+        self.db.drop_cf(table)?;
+
+        let renamed = self
+            .auto_incs
+            .keys()
+            .filter(|x| x.table == table)
+            .cloned()
+            .collect::<Vec<_>>();
+        for entry in renamed {
+            let counter = self.auto_incs.remove(&entry).unwrap();
+            let entry = Entry {
+                table: to.to_string(),
+                column: entry.column,
+            };
+            self.auto_incs.insert(entry, counter);
+        }
+        Ok(())
+    }
+
+    /// Renames a column in the catalog and every row in a single batch, along with the table's
+    /// constraints, indexes and TTL column and foreign keys in other tables that refer to it.
+    fn rename_column(&mut self, table: &str, column: &str, to: &str) -> anyhow::Result<()> {
+        let mut tables = self.table_definitions()?;
+        let before = tables.clone();
+        rename_column_definitions(&mut tables, table, column, to);
+
+        let handle = self.db.cf_handle(table).unwrap();
+        let mut batch = WriteBatch::default();
+        self.put_definitions(&mut batch, &before, &tables)?;
+        let unique = self
+            .unique_indexes(table, &before[table].0)?
+            .contains_key(column);
+        let mut dictionary = self.dictionary(table, &before[table].0)?;
+        if before[table].0[column].dictionary {
+            dictionary.rename_column(column, to);
+            batch.put_cf(
+                handle,
+                keys::metadata_key(DICTIONARY_KEY),
+                dictionary.to_bytes()?,
+            );
+        }
+        if unique {
+            let prefix = keys::unique_prefix(column);
+            batch.delete_range_cf(handle, &prefix, keys::prefix_end(&prefix));
+        }
+        if self.ttl_column(table)?.as_deref() == Some(column) {
+            batch.put_cf(handle, keys::metadata_key(TTL_KEY), to);
+        }
+        // Entries hold values rather than column names so they stay as they are
+        let mut indexes = self.indexes(table)?;
+        let mut renamed = false;
+        for index in &mut indexes {
+            for indexed in index.columns.iter_mut().chain(&mut index.predicate) {
+                if expr::columns(indexed).iter().any(|x| x == column) {
+                    expr::rename_column(indexed, column, to);
+                    renamed = true;
+                }
+            }
+        }
+        if renamed {
+            self.put_indexes(table, &indexes, &mut batch)?;
+        }
+        let primary_key_key = keys::metadata_key(keys::PRIMARY_KEY_KEY);
+        if let Some(bytes) = self.db.get_cf(handle, &primary_key_key)? {
+            let mut primary_key: Vec<String> = from_bytes(&bytes)?;
+            if let Some(renamed) = primary_key.iter_mut().find(|x| *x == column) {
+                *renamed = to.to_string();
+                batch.put_cf(handle, &primary_key_key, to_allocvec(&primary_key)?);
+            }
+        }
+        let mut reserved = read_auto_increments(&self.db, handle)?;
+        if let Some(limit) = reserved.remove(column) {
+            reserved.insert(to.to_string(), limit);
+            batch.put_cf(
+                handle,
+                keys::metadata_key(keys::AUTO_INCREMENT_KEY),
+                to_allocvec(&reserved)?,
+            );
+        }
+        for (key, mut record) in self.scan_rows(table)? {
+            if let Some(value) = record.columns.remove(column) {
+                if unique && *value != Value::Null {
+                    let pk = keys::strip_data_prefix(&key).unwrap();
+                    batch.put_cf(handle, keys::unique_key(to, &value), pk);
+                }
+                record.columns.insert(to.to_string(), value);
+                let row = self.encode_row(table, &mut dictionary, &record)?;
+                batch.put_cf(handle, key, row);
+            }
+        }
+        self.write(batch)?;
+
+        let entry = Entry {
+            table: table.to_string(),
+            column: column.to_string(),
+        };
+        if let Some(counter) = self.auto_incs.remove(&entry) {
+            let entry = Entry {
+                table: table.to_string(),
+                column: to.to_string(),
+            };
+            self.auto_incs.insert(entry, counter);
+        }
+        Ok(())
+    }
+
+    /// Adds the catalog entry and constraints of every table in `after` whose definition differs
+    /// from `before` to the batch.
+    fn put_definitions(
+        &self,
+        batch: &mut WriteBatch,
+        before: &TableDefinitions,
+        after: &TableDefinitions,
+    ) -> anyhow::Result<()> {
+        for (name, definition) in after {
+            if before.get(name) == Some(definition) {
+                continue;
+            }
+            let (columns, constraints) = definition;
+            let handle = self
+                .db
+                .cf_handle(name)
+                .with_context(|| format!("No table {} exists", name))?;
+            batch.put_cf(self.catalog(), name, to_allocvec(columns)?);
+            batch.put_cf(
+                handle,
+                keys::metadata_key(keys::CONSTRAINTS_KEY),
+                to_allocvec(constraints)?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Copies the rows and unique index entries of one table into another from a snapshot of the
+    /// source, in a single ingested file. The target's metadata is left to [`Self::create_table`].
+    /// Returns the number of rows copied and their size.
+    fn copy_rows(&self, source: &str, target: &str) -> anyhow::Result<(usize, usize)> {
+        let source = self.db.cf_handle(source).unwrap();
+        let snapshot = self.db.snapshot();
+        let range = |prefix: &'static [u8]| {
+            let start = IteratorMode::From(prefix, Direction::Forward);
+            snapshot
+                .iterator_cf(source, start)
+                .take_while(move |entry| {
+                    entry
+                        .as_ref()
+                        .map_or(true, |(key, _)| key.starts_with(prefix))
+                })
+        };
+        // Rows sort before unique index entries, the order the file has to be written in
+        let mut entries = range(keys::DATA_PREFIX)
+            .chain(range(keys::INDEX_PREFIX))
+            .peekable();
+        // An SST file can't be empty
+        if entries.peek().is_none() {
+            return Ok((0, 0));
+        }
+        let (mut rows, mut bytes) = (0, 0);
+        self.ingest_file(target, |writer| {
+            for entry in entries {
+                let (key, value) = entry?;
+                if keys::strip_data_prefix(&key).is_some() {
+                    rows += 1;
+                    bytes += value.len();
+                }
+                writer.put(key, value)?;
+            }
+            Ok(())
+        })?;
+        Ok((rows, bytes))
+    }
+
+    fn backfill_column(
+        &self,
+        table: &str,
+        metadata: &ColumnDescriptors,
+        column: &str,
+        constraints: &[Constraint],
+        sequences: &[String],
+    ) -> anyhow::Result<()> {
+        let desc = &metadata[column];
+        let mut rows = self.scan_rows(table)?;
+        // Rows don't store NULLs so a column without a default needs nothing written
+        let backfill = !rows.is_empty() && desc.should_generate();
+        if backfill {
+            if desc.needs_value() {
+                anyhow::bail!(
+                    "Column {} is NOT NULL without a default but {} already has rows",
+                    column,
+                    table
+                );
+            }
+            self.reserve_counters(table, sequences, rows.len())?;
+            let provider = self.default_provider(table, column, desc)?;
+            for (_, record) in rows.iter_mut() {
+                let value = provider.generate()?;
+                if !desc.value_matches_type(&value) {
+                    anyhow::bail!("Default for {} doesn't match column type", column);
+                }
+                record.columns.insert(column.to_string(), value);
+            }
+        }
+
+        let mut checks = constraints
+            .iter()
+            .filter(|x| x.kind.refers_to(column))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut kinds = vec![];
+        if desc.unique {
+            kinds.push(ConstraintKind::Unique {
+                column: column.to_string(),
+            });
+        }
+        if let Some((target, referred)) = &desc.foreign_key {
+            kinds.push(ConstraintKind::ForeignKey {
+                column: column.to_string(),
+                table: target.clone(),
+                referred: referred.clone(),
+            });
+        }
+        if let Some(expr) = &desc.check {
+            kinds.push(ConstraintKind::Check {
+                column: column.to_string(),
+                expr: expr.clone(),
+            });
+        }
+        for kind in kinds {
+            checks.push(Constraint {
+                name: kind.default_name(table),
+                kind,
+                validated: true,
+            });
+        }
+        let records = rows.iter().map(|(_, x)| x.clone()).collect::<Vec<_>>();
+        for constraint in &checks {
+            self.check_rows(table, &records, constraint)?;
+        }
+
+        let handle = self.db.cf_handle(table).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.put_cf(self.catalog(), table, to_allocvec(metadata)?);
+        batch.put_cf(
+            handle,
+            keys::metadata_key(keys::CONSTRAINTS_KEY),
+            to_allocvec(constraints)?,
+        );
+        let unique = checks
+            .iter()
+            .any(|x| matches!(x.kind, ConstraintKind::Unique { .. }));
+        let mut dictionary = self.dictionary(table, metadata)?;
+        if backfill {
+            for (key, record) in &rows {
+                let row = self.encode_row(table, &mut dictionary, record)?;
+                self.check_row_size(table, key, &row)?;
+                if let Some(value) = indexed_value(record, column).filter(|_| unique) {
+                    let pk = keys::strip_data_prefix(key).unwrap();
+                    batch.put_cf(handle, keys::unique_key(column, value), pk);
+                }
+                batch.put_cf(handle, key, row);
+            }
+        }
+        self.write(batch)
+    }
+
+    /// Checks the rows already in the table against a constraint added `NOT VALID`, marking it
+    /// as validated if they all pass.
+    pub fn validate_constraint(&mut self, table: &str, name: &str) -> anyhow::Result<()> {
+        let mut constraints = self.constraints(table)?;
+        let constraint = constraints
+            .iter_mut()
+            .find(|x| x.name == name)
+            .with_context(|| format!("Constraint {} does not exist on {}", name, table))?;
+        if constraint.validated {
+            return Ok(());
+        }
+        self.check_constraint_rows(table, constraint)?;
+        constraint.validated = true;
+        self.put_constraints(table, &constraints)
+    }
+
+    /// Named constraints of a table, in the order they were added.
+    pub fn constraints(&self, table: &str) -> anyhow::Result<Vec<Constraint>> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        match self
+            .db
+            .get_cf(handle, keys::metadata_key(keys::CONSTRAINTS_KEY))?
+        {
+            Some(bytes) => Ok(from_bytes(&bytes)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn put_constraints(&mut self, table: &str, constraints: &[Constraint]) -> anyhow::Result<()> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            handle,
+            keys::metadata_key(keys::CONSTRAINTS_KEY),
+            to_allocvec(constraints)?,
+        );
+        self.write(batch)
+    }
+
+    /// Checks the rows already in a table satisfy a constraint that's being added.
+    fn check_constraint_rows(&self, table: &str, constraint: &Constraint) -> anyhow::Result<()> {
+        let rows = self.scan_table(table)?;
+        self.check_rows(table, &rows, constraint)
+    }
+
+    /// Checks `rows` of `table` satisfy the constraint.
+    fn check_rows(
+        &self,
+        table: &str,
+        rows: &[Record],
+        constraint: &Constraint,
+    ) -> anyhow::Result<()> {
+        match &constraint.kind {
+            ConstraintKind::Unique { column } => {
+                let mut seen = HashSet::new();
+                for value in non_null_values(rows, column) {
+                    if !seen.insert(value) {
+                        anyhow::bail!(
+                            "Constraint {} on {} failed, {} = {} appears more than once",
+                            constraint.name,
+                            table,
+                            column,
+                            value
+                        );
+                    }
+                }
+            }
+            ConstraintKind::ForeignKey {
+                column,
+                table: target,
+                referred,
+            } => {
+                let mut values = vec![];
+                let mut seen = HashSet::new();
+                for value in non_null_values(rows, column) {
+                    if seen.insert(value) {
+                        values.push(value.clone());
+                    }
+                }
+                let target_metadata = self.table_metadata(target)?;
+                let mut missing = None;
+                if primary_key_column(&target_metadata).ok() == Some(referred.as_str()) {
+                    for chunk in values.chunks(LOOKUP_BATCH) {
+                        let found = self.get_rows_by_pk(target, chunk)?;
+                        if let Some(i) = found.iter().position(Option::is_none) {
+                            missing = Some(chunk[i].clone());
+                            break;
+                        }
+                    }
+                } else {
+                    let keys = self
+                        .scan_table(target)?
+                        .into_iter()
+                        .filter_map(|mut row| row.columns.remove(referred))
+                        .collect::<HashSet<_>>();
+                    missing = values.into_iter().find(|x| !keys.contains(x));
+                }
+                if let Some(value) = missing {
+                    anyhow::bail!(
+                        "Constraint {} on {} failed, {} = {} has no matching {}.{}",
+                        constraint.name,
+                        table,
+                        column,
+                        value,
+                        target,
+                        referred
+                    );
+                }
+            }
+            ConstraintKind::Check { expr, .. } => {
+                for row in rows {
+                    if !expr::satisfies(expr, row)? {
+                        anyhow::bail!(
+                            "Constraint {} on {} failed, CHECK ({}) is false",
+                            constraint.name,
+                            table,
+                            expr
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The column holding the time rows of the table expire, if it has one.
+    pub fn ttl_column(&self, table: &str) -> anyhow::Result<Option<String>> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        match self.db.get_cf(handle, keys::metadata_key(TTL_KEY))? {
+            Some(column) => Ok(Some(String::from_utf8(column)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Most bytes the table can take up, if it's limited.
+    pub fn quota(&self, table: &str) -> anyhow::Result<Option<u64>> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        match self
+            .db
+            .get_cf(handle, keys::metadata_key(keys::QUOTA_KEY))?
+        {
+            Some(bytes) => Ok(Some(from_bytes(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Limits how many bytes a table can take up, or lifts the limit with `None`. Inserts that
+    /// would take the table over fail with [`QuotaExceeded`], rows already in the table are left
+    /// alone.
+    pub fn set_quota(&mut self, table: &str, quota: Option<u64>) -> anyhow::Result<()> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        let key = keys::metadata_key(keys::QUOTA_KEY);
+        let mut batch = WriteBatch::default();
+        match quota {
+            Some(quota) => batch.put_cf(handle, key, to_allocvec(&quota)?),
+            None => batch.delete_cf(handle, key),
+        }
+        self.write(batch)
+    }
+
+    /// Estimated bytes the table takes up, rocksdb's estimate of its live data plus its
+    /// memtables. Deleted rows keep counting until compaction gets to them.
+    pub fn table_usage(&self, table: &str) -> anyhow::Result<u64> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        let mut used = 0;
+        for property in [
+            "rocksdb.estimate-live-data-size",
+            "rocksdb.cur-size-all-mem-tables",
+        ] {
+            used += self
+                .db
+                .property_int_value_cf(handle, property)?
+                .unwrap_or_default();
+        }
+        Ok(used)
+    }
+
+    /// The table's quota and its usage now, `None` when it has no quota so unlimited tables
+    /// don't pay for reading the estimates.
+    fn quota_usage(&self, table: &str) -> anyhow::Result<Option<Quota>> {
+        let Some(limit) = self.quota(table)? else {
+            return Ok(None);
+        };
+        let used = self.table_usage(table)?;
+        Ok(Some(Quota { limit, used }))
+    }
+
+    /// Warns about and tells the quota hook of every threshold a write took the table past.
+    fn report_quota(&self, table: &str, before: Option<&Quota>) -> anyhow::Result<()> {
+        let Some(before) = before else {
+            return Ok(());
+        };
+        let used = self.table_usage(table)?;
+        for &threshold in &self.config.quota_thresholds {
+            let bytes = (before.limit as u128 * threshold as u128 / 100) as u64;
+            if before.used >= bytes || used < bytes {
+                continue;
+            }
+            warn!(
+                table,
+                used,
+                quota = before.limit,
+                "Table passed {}% of its quota",
+                threshold
+            );
+            if let Some(hook) = &self.quota_hook {
+                hook(&QuotaUsage {
+                    table: table.to_string(),
+                    used,
+                    quota: before.limit,
+                    threshold,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn table_metadata(&self, name: impl AsRef<str>) -> anyhow::Result<ColumnDescriptors> {
+        let name = name.as_ref();
+        if name.starts_with(SYSTEM_PREFIX) || self.db.cf_handle(name).is_none() {
+            anyhow::bail!("No table {} exists", name);
+        }
+        let bytes = self
+            .db
+            .get_pinned_cf(self.catalog(), name)?
+            .context("No metadata for table")?;
+        let res = from_bytes(&bytes)?;
+        Ok(res)
+    }
+
+    /// Primary key columns of a table in the order their values make up row keys.
+    pub fn primary_key(&self, table: &str) -> anyhow::Result<Vec<String>> {
+        let metadata = self.table_metadata(table)?;
+        self.key_columns(table, &metadata)
+    }
+
+    fn key_columns(
+        &self,
+        table: &str,
+        metadata: &ColumnDescriptors,
+    ) -> anyhow::Result<Vec<String>> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        read_primary_key(&self.db, handle, metadata)
+    }
+
+    /// Checks the commands would succeed against the current schema without changing anything.
+    /// Tables created by earlier commands are visible to later ones.
+    pub fn validate(&self, commands: &[Command]) -> anyhow::Result<()> {
+        let mut created: BTreeMap<String, ColumnDescriptors> = BTreeMap::new();
+        let mut constraints: BTreeMap<String, Vec<Constraint>> = BTreeMap::new();
+        let mut dropped: HashSet<String> = HashSet::new();
+        let mut indexes = self.index_tables()?.into_keys().collect::<HashSet<_>>();
+        // Every table as the commands so far have left them
+        let definitions = |created: &BTreeMap<String, ColumnDescriptors>,
+                           constraints: &BTreeMap<String, Vec<Constraint>>,
+                           dropped: &HashSet<String>| {
+            let mut tables = self.table_definitions()?;
+            tables.retain(|name, _| !dropped.contains(name));
+            for (name, columns) in created {
+                let existing = constraints.get(name).cloned().unwrap_or_default();
+                tables.insert(name.clone(), (columns.clone(), existing));
+            }
+            for (name, existing) in constraints {
+                if let Some(table) = tables.get_mut(name) {
+                    table.1 = existing.clone();
+                }
+            }
+            anyhow::Ok(tables)
+        };
+        for command in commands {
+            let lookup = |table: &str| match created.get(table) {
+                Some(columns) => Ok(columns.clone()),
+                None if dropped.contains(table) => anyhow::bail!("No table {} exists", table),
+                None => self.table_metadata(table),
+            };
+            match command {
+                Command::CreateTable(opts) => {
+                    let columns = check_create_table(opts, &self.config, lookup)?;
+                    created.insert(opts.name.clone(), columns);
+                    constraints.insert(opts.name.clone(), opts.constraints.clone());
+                }
+                Command::CloneTable(opts) => {
+                    let definition = clone_definition(opts, &lookup)?;
+                    let columns = check_create_table(&definition, &self.config, lookup)?;
+                    created.insert(opts.name.clone(), columns);
+                }
+                Command::Insert(opts) => {
+                    check_insert(opts, &lookup(&opts.table)?, &self.functions)?
+                }
+                Command::AlterTable(opts) => {
+                    let metadata = lookup(&opts.name)?;
+                    let existing = match constraints.get(&opts.name) {
+                        Some(existing) => existing.clone(),
+                        None => self.constraints(&opts.name)?,
+                    };
+                    check_alter_table(
+                        opts,
+                        &metadata,
+                        &existing,
+                        &self.config,
+                        &self.functions,
+                        lookup,
+                    )?;
+                    let mut existing = existing;
+                    match &opts.operation {
+                        AlterOperation::AddConstraint(constraint) => {
+                            existing.push(constraint.clone())
+                        }
+                        AlterOperation::DropConstraint { name, .. } => {
+                            existing.retain(|x| x.name != *name)
+                        }
+                        AlterOperation::AddColumn {
+                            column,
+                            descriptor,
+                            constraints: added,
+                            ..
+                        } => {
+                            if !metadata.contains_key(column) {
+                                let mut metadata = metadata;
+                                metadata.insert(column.clone(), descriptor.clone());
+                                created.insert(opts.name.clone(), metadata);
+                                existing.extend(added.iter().cloned());
+                            }
+                        }
+                        AlterOperation::DropColumn { column, .. } => {
+                            if metadata.contains_key(column) {
+                                let tables = definitions(&created, &constraints, &dropped)?;
+                                let ttl_column = self.ttl_column(&opts.name).ok().flatten();
+                                check_drop_column(
+                                    &opts.name,
+                                    column,
+                                    &tables,
+                                    ttl_column.as_deref(),
+                                )?;
+                                let mut metadata = metadata;
+                                metadata.remove(column);
+                                created.insert(opts.name.clone(), metadata);
+                                existing.retain(|x| !x.kind.refers_to(column));
+                            }
+                        }
+                        AlterOperation::RenameTable { .. }
+                        | AlterOperation::RenameColumn { .. } => {
+                            // These can change other tables' foreign keys, so every table whose
+                            // definition changes goes in the overlay
+                            let before = definitions(&created, &constraints, &dropped)?;
+                            let mut tables = before.clone();
+                            match &opts.operation {
+                                AlterOperation::RenameTable { to } => {
+                                    rename_table_definitions(&mut tables, &opts.name, to);
+                                    created.remove(&opts.name);
+                                    constraints.remove(&opts.name);
+                                    dropped.insert(opts.name.clone());
+                                }
+                                AlterOperation::RenameColumn { column, to } => {
+                                    rename_column_definitions(&mut tables, &opts.name, column, to)
+                                }
+                                _ => unreachable!(),
+                            }
+                            for (name, (columns, existing)) in tables {
+                                if before.get(&name).map(|(x, _)| x) != Some(&columns) {
+                                    created.insert(name.clone(), columns);
+                                }
+                                constraints.insert(name, existing);
+                            }
+                            continue;
+                        }
+                    }
+                    constraints.insert(opts.name.clone(), existing);
+                }
+                Command::DropTable(opts) => {
+                    let tables = definitions(&created, &constraints, &dropped)?;
+                    for name in check_drop_table(opts, &tables)? {
+                        created.remove(&name);
+                        constraints.remove(&name);
+                        dropped.insert(name);
+                    }
+                }
+                Command::CreateIndex(opts) => {
+                    if !indexes.insert(opts.index.name.clone()) && !opts.if_not_exists {
+                        anyhow::bail!("Index {} already exists", opts.index.name);
+                    }
+                    check_create_index(opts, &lookup(&opts.table)?)?;
+                }
+                Command::DropIndex(opts) => {
+                    for name in &opts.names {
+                        if !indexes.remove(name) && !opts.if_exists {
+                            anyhow::bail!("No index {} exists", name);
+                        }
+                    }
+                }
+                Command::Reindex(Reindex::Index(name)) => {
+                    if !indexes.contains(name) {
+                        anyhow::bail!("No index {} exists", name);
+                    }
+                }
+                Command::Reindex(Reindex::Table(table)) => {
+                    lookup(table)?;
+                }
+                Command::Update(opts) => {
+                    check_update(opts, &lookup(&opts.table)?, &self.functions)?
+                }
+                Command::Delete(opts) => {
+                    let metadata = lookup(&opts.table)?;
+                    if let Some(filter) = &opts.filter {
+                        expr::check(filter, &metadata)?;
+                    }
+                    check_returning(&opts.returning, &metadata)?;
+                }
+                Command::Select(opts) => {
+                    let metadata = lookup(&opts.table)?;
+                    if let Some(filter) = &opts.filter {
+                        expr::check(filter, &metadata)?;
+                    }
+                }
+                Command::Set(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a key from one of the engine's own column families, these aren't visible as tables.
+    pub(crate) fn system_get(&self, cf: &str, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        debug_assert!(cf.starts_with(SYSTEM_PREFIX));
+        match self.db.cf_handle(cf) {
+            Some(handle) => Ok(self.db.get_cf(handle, key)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Every entry in a system column family starting with `prefix`, in key order.
+    pub(crate) fn system_scan(
+        &self,
+        cf: &str,
+        prefix: &[u8],
+    ) -> anyhow::Result<Vec<(Box<[u8]>, Box<[u8]>)>> {
+        debug_assert!(cf.starts_with(SYSTEM_PREFIX));
+        let Some(handle) = self.db.cf_handle(cf) else {
+            return Ok(vec![]);
+        };
+        let mut res = vec![];
+        let start = IteratorMode::From(prefix, Direction::Forward);
+        for entry in self.db.iterator_cf(handle, start) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            res.push((key, value));
+        }
+        Ok(res)
+    }
+
+    /// Writes to a system column family, creating it the first time it's used.
+    pub(crate) fn system_put(&mut self, cf: &str, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        debug_assert!(cf.starts_with(SYSTEM_PREFIX));
+        if self.db.cf_handle(cf).is_none() {
+            self.db.create_cf(cf, &self.config.db_options())?;
+        }
+        let mut batch = WriteBatch::default();
+        batch.put_cf(self.db.cf_handle(cf).unwrap(), key, value);
+        self.write(batch)
+    }
+
+    pub(crate) fn system_delete(&mut self, cf: &str, key: &[u8]) -> anyhow::Result<()> {
+        debug_assert!(cf.starts_with(SYSTEM_PREFIX));
+        let Some(handle) = self.db.cf_handle(cf) else {
+            return Ok(());
+        };
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(handle, key);
+        self.write(batch)
+    }
+
+    /// Every table in the database along with its metadata.
+    pub fn tables(&self) -> anyhow::Result<BTreeMap<String, ColumnDescriptors>> {
+        let mut tables = BTreeMap::new();
+        for entry in self.db.iterator_cf(self.catalog(), IteratorMode::Start) {
+            let (name, metadata) = entry?;
+            if name.starts_with(SYSTEM_PREFIX.as_bytes()) {
+                continue;
+            }
+            tables.insert(String::from_utf8(name.to_vec())?, from_bytes(&metadata)?);
+        }
+        Ok(tables)
+    }
+
+    /// Every live row of a table in primary key order along with its key, keeping the engine's
+    /// hidden columns. Expired rows are left out.
+    fn scan_rows(&self, table: &str) -> anyhow::Result<Vec<(Box<[u8]>, Record)>> {
+        self.scan_rows_hinted(table, ScanHint::Auto)
+    }
+
+    fn scan_rows_hinted(
+        &self,
+        table: &str,
+        hint: ScanHint,
+    ) -> anyhow::Result<Vec<(Box<[u8]>, Record)>> {
+        let metadata = self.table_metadata(table)?;
+        let dictionary = self.dictionary(table, &metadata)?;
+        let handle = self.db.cf_handle(table).unwrap();
+        let now = unix_now();
+        let mut rows = vec![];
+        let start = IteratorMode::From(keys::DATA_PREFIX, Direction::Forward);
+        let opts = self.scan_options(hint);
+        for entry in self.db.iterator_cf_opt(handle, opts, start) {
+            let (key, value) = entry?;
+            if keys::strip_data_prefix(&key).is_none() {
+                break;
+            }
+            self.count_row()?;
+            let mut record: Record = from_bytes(&value)?;
+            if ttl::is_expired(&record, now) {
+                continue;
+            }
+            dictionary.decode(&mut record)?;
+            rows.push((key, record));
+        }
+        Ok(rows)
+    }
+
+    /// Every live row of a table in primary key order. Expired rows and the engine's hidden columns
+    /// are left out.
+    pub fn scan_table(&self, table: &str) -> anyhow::Result<Vec<Record>> {
+        self.scan_table_hinted(table, ScanHint::Auto)
+    }
+
+    /// Like [`Self::scan_table`] with the read options tuned for how the scan is expected to go.
+    #[instrument(skip(self), fields(rows))]
+    pub fn scan_table_hinted(&self, table: &str, hint: ScanHint) -> anyhow::Result<Vec<Record>> {
+        let rows = self
+            .scan_rows_hinted(table, hint)?
+            .into_iter()
+            .map(|(_, mut record)| {
+                record
+                    .columns
+                    .retain(|column, _| !column.starts_with(SYSTEM_PREFIX));
+                record
+            })
+            .collect::<Vec<_>>();
+        Span::current().record("rows", rows.len());
+        Ok(rows)
+    }
+
+    /// Fetches rows by primary key with a single `multi_get`. The result lines up with `keys`,
+    /// `None` where there's no live row with that key. Tables without a primary key are looked up
+    /// by rowid, tables with a composite key one row at a time with [`Self::get_row`].
+    #[instrument(skip(self, keys), fields(keys = keys.len()))]
+    pub fn get_rows_by_pk(
+        &self,
+        table: &str,
+        keys: &[Rc<Value>],
+    ) -> anyhow::Result<Vec<Option<Record>>> {
+        let metadata = self.table_metadata(table)?;
+        let pk = primary_key_column(&metadata)?;
+        let handle = self.db.cf_handle(table).unwrap();
+        // Looked up the way they're stored, one that isn't a valid timestamp can't match anyway
+        let pk_values = keys
+            .iter()
+            .map(|x| timezone::stored_value(&metadata[pk], x.clone()).unwrap_or_else(|_| x.clone()))
+            .collect::<Vec<_>>();
+        let mut row_keys = vec![];
+        for key in &pk_values {
+            let record = Record {
+                columns: BTreeMap::from([(pk.to_string(), key.clone())]),
+            };
+            let key = generate_pk_name(&record, std::slice::from_ref(&pk.to_string()))?;
+            row_keys.push(keys::data_key(key));
+        }
+
+        let dictionary = self.dictionary(table, &metadata)?;
+        let now = unix_now();
+        let mut rows = vec![];
+        let values = self.db.multi_get_cf(row_keys.iter().map(|x| (handle, x)));
+        for (key, value) in pk_values.iter().zip(values) {
+            let Some(bytes) = value? else {
+                rows.push(None);
+                continue;
+            };
+            let mut record: Record = from_bytes(&bytes)?;
+            // The row has to actually hold the key, not just be stored where it would be
+            if ttl::is_expired(&record, now) || record.columns.get(pk) != Some(key) {
+                rows.push(None);
+                continue;
+            }
+            dictionary.decode(&mut record)?;
+            record
+                .columns
+                .retain(|column, _| !column.starts_with(SYSTEM_PREFIX));
+            rows.push(Some(record));
+        }
+        Ok(rows)
+    }
+
+    /// Fetches a row by its primary key values, given in key order, with a single `get`. Works
+    /// with composite keys, and with the rowid of a table without a primary key. `None` when
+    /// there's no live row with that key.
+    #[instrument(skip(self, pk_values))]
+    pub fn get_row(&self, table: &str, pk_values: &[Value]) -> anyhow::Result<Option<Record>> {
+        let metadata = self.table_metadata(table)?;
+        let primary_key = self.key_columns(table, &metadata)?;
+        if pk_values.len() != primary_key.len() {
+            anyhow::bail!(
+                "Primary key of {} has {} columns, got {} values",
+                table,
+                primary_key.len(),
+                pk_values.len()
+            );
+        }
+        let key = Record {
+            columns: primary_key
+                .iter()
+                .zip(pk_values)
+                .map(|(column, value)| {
+                    let value = Rc::new(value.clone());
+                    let stored = timezone::stored_value(&metadata[column], value.clone());
+                    (column.clone(), stored.unwrap_or(value))
+                })
+                .collect(),
+        };
+        let row_key = keys::data_key(generate_pk_name(&key, &primary_key)?);
+        let handle = self.db.cf_handle(table).unwrap();
+        let Some(bytes) = self.db.get_cf(handle, row_key)? else {
+            return Ok(None);
+        };
+        let mut record: Record = from_bytes(&bytes)?;
+        let holds_key = key
+            .columns
+            .iter()
+            .all(|(column, value)| record.columns.get(column) == Some(value));
+        if ttl::is_expired(&record, unix_now()) || !holds_key {
+            return Ok(None);
+        }
+        self.dictionary(table, &metadata)?.decode(&mut record)?;
+        record
+            .columns
+            .retain(|column, _| !column.starts_with(SYSTEM_PREFIX));
+        Ok(Some(record))
+    }
+
+    /// Rows with an entry in a secondary index from `start` up to `end`, in row key order like a
+    /// scan of the table rather than index order. Entries aren't removed when rows expire or a
+    /// bulk load replaces them, so the rows still need checking against the filter the range came
+    /// from.
+    #[instrument(skip(self, start, end), fields(rows))]
+    pub fn index_scan(&self, table: &str, start: &[u8], end: &[u8]) -> anyhow::Result<Vec<Record>> {
+        let metadata = self.table_metadata(table)?;
+        let handle = self.db.cf_handle(table).unwrap();
+        let mut row_keys = vec![];
+        let mode = IteratorMode::From(start, Direction::Forward);
+        for entry in self.db.iterator_cf(handle, mode) {
+            let (key, pk) = entry?;
+            if key.as_ref() >= end {
+                break;
+            }
+            row_keys.push(keys::data_key(pk));
+        }
+        // An entry left behind by an old value can point at a row that's in range anyway
+        row_keys.sort();
+        row_keys.dedup();
+
+        let dictionary = self.dictionary(table, &metadata)?;
+        let now = unix_now();
+        let mut rows = vec![];
+        for chunk in row_keys.chunks(LOOKUP_BATCH) {
+            for value in self.db.multi_get_cf(chunk.iter().map(|x| (handle, x))) {
+                let Some(bytes) = value? else {
+                    continue;
+                };
+                self.count_row()?;
+                let mut record: Record = from_bytes(&bytes)?;
+                if ttl::is_expired(&record, now) {
+                    continue;
+                }
+                dictionary.decode(&mut record)?;
+                record
+                    .columns
+                    .retain(|column, _| !column.starts_with(SYSTEM_PREFIX));
+                rows.push(record);
+            }
+        }
+        Span::current().record("rows", rows.len());
+        Ok(rows)
+    }
+
+    /// Deletes the rows matching the filter and returns how many there were, along with the rows
+    /// its `RETURNING` clause asks for. Rows in other tables referring to them are handled by
+    /// their foreign key's `ON DELETE` action.
+    #[instrument(skip_all, fields(table = %delete_op.table, rows))]
+    pub fn delete_rows(
+        &mut self,
+        delete_op: &DeleteOptions,
+    ) -> anyhow::Result<(usize, Vec<Record>)> {
+        let metadata = self.table_metadata(&delete_op.table)?;
+        if let Some(filter) = &delete_op.filter {
+            expr::check(filter, &metadata)?;
+        }
+        check_returning(&delete_op.returning, &metadata)?;
+        let unique = self.unique_indexes(&delete_op.table, &metadata)?;
+        let indexes = self.indexes(&delete_op.table)?;
+        let referenced = !self.references_to(&delete_op.table)?.is_empty();
+        let filter = delete_op
+            .filter
+            .as_ref()
+            .map(expr::compile_predicate)
+            .transpose()?;
+        // Every row is checked before anything is written so a bad comparison deletes nothing
+        let mut keys = vec![];
+        let mut entries = vec![];
+        let mut changes = vec![];
+        let mut returned = vec![];
+        for (key, record) in self.scan_rows(&delete_op.table)? {
+            match &filter {
+                Some(filter) if !filter(&record)? => {}
+                _ => {
+                    for column in unique.keys() {
+                        if let Some(value) = indexed_value(&record, column) {
+                            entries.push(keys::unique_key(column, value));
+                        }
+                    }
+                    for index in &indexes {
+                        entries.extend(secondary_entry(index, &record, &key)?);
+                    }
+                    if referenced {
+                        changes.push((key.clone(), record.clone(), None));
+                    }
+                    keys.push(key);
+                    if let Some(returning) = &delete_op.returning {
+                        returned.push(returned_row(returning, record));
+                    }
+                }
+            }
+        }
+
+        let handle = self.db.cf_handle(&delete_op.table).unwrap();
+        let mut batch = WriteBatch::default();
+        self.referential_actions(&delete_op.table, changes, &mut batch)?;
+        // Rows changed by foreign key actions have to go in the same batch as the delete
+        let split = batch.is_empty();
+        for key in keys
+            .iter()
+            .map(|x| &x[..])
+            .chain(entries.iter().map(|x| &x[..]))
+        {
+            batch.delete_cf(handle, key);
+            if split {
+                self.write_if_full(&mut batch)?;
+            }
+        }
+        self.write(batch)?;
+        Span::current().record("rows", keys.len());
+        Ok((keys.len(), returned))
+    }
+
+    fn check_row_size(&self, table: &str, key: &[u8], row: &[u8]) -> anyhow::Result<()> {
+        if key.len() > self.config.max_key_bytes {
+            anyhow::bail!(
+                "Primary key for {} is {} bytes, more than the limit of {}",
+                table,
+                key.len(),
+                self.config.max_key_bytes
+            );
+        }
+        if row.len() > self.config.max_row_bytes {
+            anyhow::bail!(
+                "Row for {} is {} bytes, more than the limit of {}",
+                table,
+                row.len(),
+                self.config.max_row_bytes
+            );
+        }
+        Ok(())
+    }
+
+    /// Applies the assignments to every row matching the filter and returns how many rows were
+    /// updated, along with the rows its `RETURNING` clause asks for. Columns with an `ON UPDATE`
+    /// expression are regenerated unless they're assigned. Every row is written in a single batch
+    /// so either all of them change or none do.
+    #[instrument(skip_all, fields(table = %update_op.table, rows, bytes))]
+    pub fn update_rows(
+        &mut self,
+        update_op: &UpdateOptions,
+    ) -> anyhow::Result<(usize, Vec<Record>)> {
+        let metadata = self.table_metadata(&update_op.table)?;
+        check_update(update_op, &metadata, &self.functions)?;
+        let ttl_column = self.ttl_column(&update_op.table)?;
+        let primary_key = self.key_columns(&update_op.table, &metadata)?;
+
+        let mut providers = BTreeMap::new();
+        for (column, desc) in metadata.iter() {
+            let Some(on_update) = &desc.on_update else {
+                continue;
+            };
+            if !update_op.assignments.iter().any(|(x, _)| x == column) {
+                providers.insert(column, self.expr_provider(on_update)?);
+            }
+        }
+
+        let unique = self.unique_indexes(&update_op.table, &metadata)?;
+        let indexes = self.indexes(&update_op.table)?;
+        let mut dictionary = self.dictionary(&update_op.table, &metadata)?;
+        let foreign_keys = self.foreign_keys(&update_op.table, &metadata)?;
+        let checks = self.checks(&update_op.table, &metadata)?;
+        let references = self.references_to(&update_op.table)?;
+        let filter = update_op
+            .filter
+            .as_ref()
+            .map(expr::compile_predicate)
+            .transpose()?;
+        let assignments = update_op
+            .assignments
+            .iter()
+            .map(|(column, value)| Ok((column, expr::compile(value)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // Rows whose foreign key values changed
+        let mut referring = vec![];
+        // Rows whose values other rows refer to changed
+        let mut referred = vec![];
+        let mut updated = vec![];
+        let mut changed = vec![];
+        let mut returned = vec![];
+        for (key, record) in self.scan_rows(&update_op.table)? {
+            if let Some(filter) = &filter {
+                if !filter(&record)? {
+                    continue;
+                }
+            }
+            let mut new = record.clone();
+            for &(column, ref value) in &assignments {
+                let value = value(&record)?;
+                metadata[column].check_value(column, &value)?;
+                let value = timezone::stored_value(&metadata[column], value)?;
+                new.columns.insert(column.clone(), value);
+            }
+            for (column, provider) in &providers {
+                let value = provider.generate()?;
+                if !metadata[*column].value_matches_type(&value) {
+                    anyhow::bail!("ON UPDATE value for {} doesn't match column type", column);
+                }
+                let value = timezone::stored_value(&metadata[*column], value)?;
+                new.columns.insert(column.to_string(), value);
+            }
+            if let Some(expires) = ttl_column.as_ref().and_then(|x| new.columns.get(x)) {
+                let expires = expires.clone();
+                new.columns.insert(EXPIRES_COLUMN.to_string(), expires);
+            }
+            for check in &checks {
+                self.check_rows(&update_op.table, std::slice::from_ref(&new), check)?;
+            }
+            let new_key = keys::data_key(generate_pk_name(&new, &primary_key)?);
+            let row = self.encode_row(&update_op.table, &mut dictionary, &new)?;
+            self.check_row_size(&update_op.table, &new_key, &row)?;
+            let moved = |x: &Constraint| {
+                let column = x.kind.column();
+                record.columns.get(column) != new.columns.get(column)
+            };
+            if foreign_keys.iter().any(moved) {
+                referring.push(new.clone());
+            }
+            if references.iter().any(|x| {
+                record.columns.get(&x.referred_column) != new.columns.get(&x.referred_column)
+            }) {
+                referred.push((key.clone(), record.clone(), Some(new.clone())));
+            }
+            if !unique.is_empty() || !indexes.is_empty() {
+                changed.push((record, new.clone()));
+            }
+            updated.push((key, new_key, row));
+            if let Some(returning) = &update_op.returning {
+                returned.push(returned_row(returning, new));
+            }
+        }
+
+        self.check_foreign_keys(&update_op.table, &foreign_keys, &referring)?;
+
+        let handle = self.db.cf_handle(&update_op.table).unwrap();
+        let mut batch = WriteBatch::default();
+        self.referential_actions(&update_op.table, referred, &mut batch)?;
+        if !unique.is_empty() {
+            self.update_unique_indexes(&update_op.table, &unique, &updated, &changed, &mut batch)?;
+        }
+        self.update_secondary_indexes(&update_op.table, &indexes, &updated, &changed, &mut batch)?;
+        let mut bytes = 0;
+        for (key, new_key, row) in &updated {
+            // Changing the primary key moves the row
+            if key.as_ref() != new_key.as_slice() {
+                batch.delete_cf(handle, key);
+            }
+            bytes += row.len();
+            batch.put_cf(handle, new_key, row);
+        }
+        self.write(batch)?;
+        Span::current().record("rows", updated.len());
+        Span::current().record("bytes", bytes);
+        Ok((updated.len(), returned))
+    }
+
+    /// Moves the unique index entries of updated rows to their new values and keys. Values are
+    /// checked once every old entry is known to be going, so rows can swap values in one
+    /// statement.
+    fn update_unique_indexes(
+        &self,
+        table: &str,
+        unique: &BTreeMap<String, String>,
+        updated: &[(Box<[u8]>, Vec<u8>, Vec<u8>)],
+        changed: &[(Record, Record)],
+        batch: &mut WriteBatch,
+    ) -> anyhow::Result<()> {
+        let handle = self.db.cf_handle(table).unwrap();
+        let moved = |column: &str, i: usize| {
+            let (key, new_key, _) = &updated[i];
+            let (old, new) = &changed[i];
+            let (before, after) = (indexed_value(old, column), indexed_value(new, column));
+            (before != after || key.as_ref() != new_key.as_slice()).then_some((before, after))
+        };
+
+        let mut removed = HashSet::new();
+        for column in unique.keys() {
+            for i in 0..updated.len() {
+                if let Some((Some(before), _)) = moved(column, i) {
+                    removed.insert(keys::unique_key(column, before));
+                }
+            }
+        }
+        let now = unix_now();
+        let mut added = BTreeMap::new();
+        for (column, name) in unique {
+            for (i, (key, new_key, _)) in updated.iter().enumerate() {
+                let Some((_, Some(after))) = moved(column, i) else {
+                    continue;
+                };
+                let entry = keys::unique_key(column, after);
+                let pk = keys::strip_data_prefix(new_key).unwrap().to_vec();
+                if added.insert(entry.clone(), pk).is_some()
+                    || (!removed.contains(&entry)
+                        && self.unique_taken(handle, column, after, key, now)?)
+                {
+                    return Err(unique_violation(table, column, name, after));
+                }
+            }
+        }
+
+        for entry in removed.iter().filter(|x| !added.contains_key(*x)) {
+            batch.delete_cf(handle, entry);
+        }
+        for (entry, pk) in added {
+            batch.put_cf(handle, entry, pk);
+        }
+        Ok(())
+    }
+
+    /// Moves the secondary index entries of updated rows whose indexed values or keys changed.
+    /// Old entries are deleted before new ones are written as a row can move to where another
+    /// row's entry was.
+    fn update_secondary_indexes(
+        &self,
+        table: &str,
+        indexes: &[Index],
+        updated: &[(Box<[u8]>, Vec<u8>, Vec<u8>)],
+        changed: &[(Record, Record)],
+        batch: &mut WriteBatch,
+    ) -> anyhow::Result<()> {
+        let handle = self.db.cf_handle(table).unwrap();
+        let mut added = vec![];
+        for index in indexes {
+            for ((key, new_key, _), (old, new)) in updated.iter().zip(changed) {
+                let before = secondary_entry(index, old, key)?;
+                let after = secondary_entry(index, new, new_key)?;
+                if before == after {
+                    continue;
+                }
+                if let Some(before) = before {
+                    batch.delete_cf(handle, before);
+                }
+                if let Some(after) = after {
+                    added.push((after, keys::strip_data_prefix(new_key).unwrap()));
+                }
+            }
+        }
+        for (entry, pk) in added {
+            batch.put_cf(handle, entry, pk);
+        }
+        Ok(())
+    }
+
+    /// Creates the sequences used by the table's defaults the first time they're needed and
+    /// returns their names, their values need reserving before rows use them.
+    fn register_sequences(&mut self, metadata: &ColumnDescriptors) -> anyhow::Result<Vec<String>> {
+        let mut sequences = vec![];
+        for desc in metadata.values() {
+            if let Some((name, args)) = desc
+                .default
+                .as_ref()
+                .map(default_call)
+                .transpose()?
+                .flatten()
+            {
+                if name == NEXTVAL {
+                    let sequence = sequence_name(&args)?.to_string();
+                    self.sequences
+                        .entry(sequence.clone())
+                        .or_insert_with(|| Counter::new(1));
+                    sequences.push(sequence);
+                }
+            }
+        }
+        Ok(sequences)
+    }
+
+    /// The next value of every sequence a `nextval` default has used.
+    pub fn sequences(&self) -> BTreeMap<String, u64> {
+        self.sequences
+            .iter()
+            .map(|(name, counter)| (name.clone(), counter.next.load(Ordering::SeqCst) as u64))
+            .collect()
+    }
+
+    /// Carries a sequence on from `next`, as if the values before it had been handed out.
+    pub(crate) fn restore_sequence(&mut self, name: &str, next: u64) -> anyhow::Result<()> {
+        let mut batch = WriteBatch::default();
+        let sequences_cf = self.db.cf_handle(SEQUENCES_CF).unwrap();
+        batch.put_cf(sequences_cf, keys::data_key(name), next.to_be_bytes());
+        self.write(batch)?;
+        self.sequences
+            .insert(name.to_string(), Counter::restored(next as usize));
+        Ok(())
+    }
+
+    /// Makes sure the auto increment columns of `table` and the sequences have `count` more values
+    /// reserved in storage, reserving another block for any that don't. This is written before
+    /// the rows using the values so a crash part way through a statement can't hand them out
+    /// again.
+    fn reserve_counters(
+        &self,
+        table: &str,
+        sequences: &[String],
+        count: usize,
+    ) -> anyhow::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let block = self.config.counter_block_size;
+        let mut batch = WriteBatch::default();
+        let mut reservations = vec![];
+
+        let auto_incs = self
+            .auto_incs
+            .iter()
+            .filter(|(entry, _)| entry.table == table)
+            .map(|(entry, counter)| (&entry.column, counter))
+            .collect::<Vec<_>>();
+        if auto_incs
+            .iter()
+            .any(|(_, counter)| counter.reservation(count, block).is_some())
+        {
+            let mut reserved = BTreeMap::new();
+            for (column, counter) in auto_incs {
+                let limit = counter
+                    .reservation(count, block)
+                    .unwrap_or_else(|| counter.reserved.load(Ordering::SeqCst));
+                reserved.insert(column.clone(), limit as u64);
+                reservations.push((counter, limit));
+            }
+            let handle = self.db.cf_handle(table).unwrap();
+            batch.put_cf(
+                handle,
+                keys::metadata_key(keys::AUTO_INCREMENT_KEY),
+                to_allocvec(&reserved)?,
+            );
+        }
+
+        let sequences_cf = self.db.cf_handle(SEQUENCES_CF).unwrap();
+        for sequence in sequences {
+            let counter = &self.sequences[sequence];
+            if let Some(limit) = counter.reservation(count, block) {
+                let key = keys::data_key(sequence);
+                batch.put_cf(sequences_cf, key, (limit as u64).to_be_bytes());
+                reservations.push((counter, limit));
+            }
+        }
+
+        if !batch.is_empty() {
+            self.write(batch)?;
+        }
+        for (counter, limit) in reservations {
+            counter.reserved.store(limit, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// The key, encoded row and record for every row of an insert, with missing columns
+    /// generated.
+    fn encode_rows<'a>(
+        &'a self,
+        insert_op: &'a InsertOptions,
+        metadata: &'a ColumnDescriptors,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<(Vec<u8>, Vec<u8>, Record)>> + 'a> {
+        let ttl_column = self.ttl_column(&insert_op.table)?;
+        let primary_key = self.key_columns(&insert_op.table, metadata)?;
+        let mut dictionary = self.dictionary(&insert_op.table, metadata)?;
+
+        let mut providers = BTreeMap::new();
+        for (column, desc) in metadata.iter() {
+            if insert_op.columns.contains(column) || !desc.should_generate() {
+                continue;
+            }
+            providers.insert(
+                column,
+                self.default_provider(&insert_op.table, column, desc)?,
+            );
+        }
+
+        Ok(insert_op.records().map(move |mut record| {
+            self.count_row()?;
+            // Add things like missing default fields
+            for (column, provider) in &providers {
+                let value = provider.generate()?;
+                if !metadata[*column].value_matches_type(&value) {
+                    anyhow::bail!("Default for {} doesn't match column type", column);
+                }
+                record.columns.insert(column.to_string(), value);
+            }
+            for (column, value) in record.columns.iter_mut() {
+                *value = timezone::stored_value(&metadata[column], value.clone())?;
+            }
+            if let Some(expires) = ttl_column.as_ref().and_then(|x| record.columns.get(x)) {
+                let expires = expires.clone();
+                record.columns.insert(EXPIRES_COLUMN.to_string(), expires);
+            }
+
+            let key = keys::data_key(generate_pk_name(&record, &primary_key)?);
+            let row = self.encode_row(&insert_op.table, &mut dictionary, &record)?;
+            self.check_row_size(&insert_op.table, &key, &row)?;
+            Ok((key, row, record))
+        }))
+    }
+
+    /// Inserts the rows and returns how many there were, along with the rows its `RETURNING`
+    /// clause asks for. Every row is checked before any is written, so one that breaks a
+    /// constraint fails the statement without leaving the rows before it behind.
+    #[instrument(skip_all, fields(table = %insert_op.table, rows = insert_op.values.len(), bytes))]
+    pub fn insert_rows(
+        &mut self,
+        insert_op: &InsertOptions,
+    ) -> anyhow::Result<(usize, Vec<Record>)> {
+        // We should validate our metadata against our column data types!
+        let metadata = self.table_metadata(&insert_op.table)?;
+
+        check_insert(insert_op, &metadata, &self.functions)?;
+        let sequences = self.register_sequences(&metadata)?;
+        self.reserve_counters(&insert_op.table, &sequences, insert_op.values.len())?;
 
         // handle must exist if we got metadata
         let mut transaction = WriteBatch::default();
         let handle = self.db.cf_handle(&insert_op.table).unwrap();
+        let mut bytes = 0;
+        let mut returned = vec![];
+        // Rowids are never reused so only declared keys can collide
+        let check_keys = !insert_op.upsert && !metadata.contains_key(ROWID_COLUMN);
+        let mut inserted = HashSet::new();
+        let now = unix_now();
+        let unique = self.unique_indexes(&insert_op.table, &metadata)?;
+        let indexes = self.indexes(&insert_op.table)?;
+        let mut indexed = HashSet::new();
+        let foreign_keys = self.foreign_keys(&insert_op.table, &metadata)?;
+        let checks = self.checks(&insert_op.table, &metadata)?;
+        let quota = self.quota_usage(&insert_op.table)?;
+        // Rows checked against their foreign keys before anything is written
+        let mut pending = vec![];
+        // Every row is checked before the first batch is written so a bad row writes nothing
+        let mut writes = vec![];
+
+        for row in self.encode_rows(insert_op, &metadata)? {
+            let (key, row, record) = row?;
+            for check in &checks {
+                self.check_rows(&insert_op.table, std::slice::from_ref(&record), check)?;
+            }
+            if check_keys && (!inserted.insert(key.clone()) || self.live_row(handle, &key, now)?) {
+                let primary_key = self.key_columns(&insert_op.table, &metadata)?;
+                let values = primary_key
+                    .iter()
+                    .map(|x| record.columns[x].to_string())
+                    .collect::<Vec<_>>();
+                anyhow::bail!(
+                    "Duplicate key ({}) = ({}) in {}",
+                    primary_key.join(", "),
+                    values.join(", "),
+                    insert_op.table
+                );
+            }
+            bytes += row.len();
+            if let Some(quota) = &quota {
+                quota.check(&insert_op.table, bytes)?;
+            }
+            for (column, name) in &unique {
+                let Some(value) = indexed_value(&record, column) else {
+                    continue;
+                };
+                let entry = keys::unique_key(column, value);
+                if !indexed.insert(entry.clone())
+                    || self.unique_taken(handle, column, value, &key, now)?
+                {
+                    return Err(unique_violation(&insert_op.table, column, name, value));
+                }
+                writes.push((entry, keys::strip_data_prefix(&key).unwrap().to_vec()));
+            }
+            for index in &indexes {
+                if let Some(entry) = secondary_entry(index, &record, &key)? {
+                    writes.push((entry, keys::strip_data_prefix(&key).unwrap().to_vec()));
+                }
+            }
+            // The row goes last so batches are only split between rows
+            writes.push((key, row));
+            if !foreign_keys.is_empty() {
+                pending.push(record.clone());
+            }
+            if let Some(returning) = &insert_op.returning {
+                returned.push(returned_row(returning, record));
+            }
+        }
+        self.check_foreign_keys(&insert_op.table, &foreign_keys, &pending)?;
+
+        for (key, value) in writes {
+            let is_row = keys::strip_data_prefix(&key).is_some();
+            transaction.put_cf(handle, key, value);
+            if is_row {
+                self.write_if_full(&mut transaction)?;
+            }
+        }
+        Span::current().record("bytes", bytes);
+        self.write(transaction)?;
+        self.report_quota(&insert_op.table, quota.as_ref())?;
+        Ok((insert_op.values.len(), returned))
+    }
+
+    /// Foreign keys of a table, whether declared on a column or as a named constraint.
+    fn foreign_keys(
+        &self,
+        table: &str,
+        metadata: &ColumnDescriptors,
+    ) -> anyhow::Result<Vec<Constraint>> {
+        if !self.foreign_key_checks {
+            return Ok(vec![]);
+        }
+        let mut res = vec![];
+        for (column, desc) in metadata {
+            if let Some((target, referred)) = &desc.foreign_key {
+                let kind = ConstraintKind::ForeignKey {
+                    column: column.clone(),
+                    table: target.clone(),
+                    referred: referred.clone(),
+                };
+                res.push(Constraint {
+                    name: kind.default_name(table),
+                    kind,
+                    validated: true,
+                });
+            }
+        }
+        let constraints = self.constraints(table)?;
+        res.extend(
+            constraints
+                .into_iter()
+                .filter(|x| matches!(x.kind, ConstraintKind::ForeignKey { .. })),
+        );
+        Ok(res)
+    }
+
+    /// Check constraints of a table, whether declared on a column or as a named constraint.
+    fn checks(&self, table: &str, metadata: &ColumnDescriptors) -> anyhow::Result<Vec<Constraint>> {
+        let mut res = vec![];
+        for (column, desc) in metadata {
+            if let Some(expr) = &desc.check {
+                let kind = ConstraintKind::Check {
+                    column: column.clone(),
+                    expr: expr.clone(),
+                };
+                res.push(Constraint {
+                    name: kind.default_name(table),
+                    kind,
+                    validated: true,
+                });
+            }
+        }
+        let constraints = self.constraints(table)?;
+        res.extend(
+            constraints
+                .into_iter()
+                .filter(|x| matches!(x.kind, ConstraintKind::Check { .. })),
+        );
+        Ok(res)
+    }
+
+    /// Checks rows about to be written to `table` refer to rows that exist. A row can refer to
+    /// another row written along with it.
+    fn check_foreign_keys(
+        &self,
+        table: &str,
+        foreign_keys: &[Constraint],
+        rows: &[Record],
+    ) -> anyhow::Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        for constraint in foreign_keys {
+            match &constraint.kind {
+                ConstraintKind::ForeignKey {
+                    column,
+                    table: target,
+                    referred,
+                } if target == table => {
+                    let written = non_null_values(rows, referred).collect::<HashSet<_>>();
+                    let rest = rows
+                        .iter()
+                        .filter(|x| !x.columns.get(column).is_some_and(|x| written.contains(x)))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    self.check_rows(table, &rest, constraint)?;
+                }
+                _ => self.check_rows(table, rows, constraint)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Foreign keys in any table that refer to `table`.
+    fn references_to(&self, table: &str) -> anyhow::Result<Vec<ForeignKeyReference>> {
+        if !self.foreign_key_checks {
+            return Ok(vec![]);
+        }
+        Ok(foreign_keys(&self.table_definitions()?)
+            .into_iter()
+            .filter(|x| x.referred == table)
+            .collect())
+    }
+
+    /// Applies the `ON DELETE` and `ON UPDATE` actions of foreign keys referring to rows of
+    /// `table` that are being deleted, when the new row is `None`, or updated. Referring rows are
+    /// deleted, updated or set to NULL in `batch`, and their own referring rows are acted on in
+    /// turn. A foreign key without an action fails the statement. Rows in `changes` are left
+    /// alone, the statement writes them itself.
+    fn referential_actions(
+        &self,
+        table: &str,
+        changes: Vec<(Box<[u8]>, Record, Option<Record>)>,
+        batch: &mut WriteBatch,
+    ) -> anyhow::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let definitions = self.table_definitions()?;
+        let references = foreign_keys(&definitions);
+        let skipped = changes
+            .iter()
+            .map(|(key, ..)| (table.to_string(), key.clone()))
+            .collect::<HashSet<_>>();
+        let mut work = changes
+            .into_iter()
+            .map(|(_, old, new)| (table.to_string(), old, new))
+            .collect::<Vec<_>>();
+        let mut scanned = BTreeMap::new();
+        let mut deleted = BTreeMap::new();
+        // Rows being updated, keyed by where they're stored now, with their row before and after
+        let mut updated: BTreeMap<(String, Box<[u8]>), (Record, Record)> = BTreeMap::new();
+
+        while let Some((table, old, new)) = work.pop() {
+            for reference in references.iter().filter(|x| x.referred == table) {
+                let Some(value) = indexed_value(&old, &reference.referred_column) else {
+                    continue;
+                };
+                let replacement = match &new {
+                    Some(new) => {
+                        let replacement = new.columns.get(&reference.referred_column);
+                        if replacement.is_some_and(|x| x.as_ref() == value) {
+                            continue;
+                        }
+                        Some(replacement.cloned())
+                    }
+                    None => None,
+                };
+                let action = match replacement {
+                    Some(_) => reference.actions.on_update,
+                    None => reference.actions.on_delete,
+                };
+                let child = &reference.table;
+                if !scanned.contains_key(child) {
+                    scanned.insert(child.clone(), self.scan_rows(child)?);
+                }
+                for (key, row) in &scanned[child] {
+                    let id = (child.clone(), key.clone());
+                    if skipped.contains(&id) || deleted.contains_key(&id) {
+                        continue;
+                    }
+                    let current = updated.get(&id).map(|(_, x)| x).unwrap_or(row);
+                    if indexed_value(current, &reference.column) != Some(value) {
+                        continue;
+                    }
+                    let current = current.clone();
+                    match (action, &replacement) {
+                        (ReferentialAction::NoAction | ReferentialAction::Restrict, _) => {
+                            anyhow::bail!(
+                                "Constraint {} on {} failed, {} = {} still refers to {}.{}",
+                                reference.name(),
+                                child,
+                                reference.column,
+                                value,
+                                table,
+                                reference.referred_column
+                            );
+                        }
+                        (ReferentialAction::Cascade, None) => {
+                            updated.remove(&id);
+                            deleted.insert(id, row.clone());
+                            work.push((child.clone(), current, None));
+                        }
+                        (ReferentialAction::Cascade, Some(_)) | (ReferentialAction::SetNull, _) => {
+                            let mut changed = current.clone();
+                            match replacement.clone().flatten() {
+                                Some(value) if action == ReferentialAction::Cascade => {
+                                    changed.columns.insert(reference.column.clone(), value);
+                                }
+                                _ => {
+                                    let (columns, _) = &definitions[child];
+                                    if columns[&reference.column].not_null {
+                                        anyhow::bail!(
+                                            "Can't set {}.{} to NULL, it's NOT NULL",
+                                            child,
+                                            reference.column
+                                        );
+                                    }
+                                    changed.columns.remove(&reference.column);
+                                }
+                            }
+                            updated.insert(id, (row.clone(), changed.clone()));
+                            work.push((child.clone(), current, Some(changed)));
+                        }
+                    }
+                }
+            }
+        }
+
+        let tables = deleted
+            .keys()
+            .chain(updated.keys())
+            .map(|(table, _)| table.clone())
+            .collect::<BTreeSet<_>>();
+        for table in tables {
+            let (metadata, _) = &definitions[&table];
+            let handle = self.db.cf_handle(&table).unwrap();
+            let unique = self.unique_indexes(&table, metadata)?;
+            let indexes = self.indexes(&table)?;
+            for ((child, key), row) in &deleted {
+                if *child != table {
+                    continue;
+                }
+                batch.delete_cf(handle, key);
+                for column in unique.keys() {
+                    if let Some(value) = indexed_value(row, column) {
+                        batch.delete_cf(handle, keys::unique_key(column, value));
+                    }
+                }
+                for index in &indexes {
+                    if let Some(entry) = secondary_entry(index, row, key)? {
+                        batch.delete_cf(handle, entry);
+                    }
+                }
+            }
+
+            let primary_key = self.key_columns(&table, metadata)?;
+            let mut dictionary = self.dictionary(&table, metadata)?;
+            let mut rows = vec![];
+            let mut changed = vec![];
+            for ((child, key), (old, new)) in &updated {
+                if *child != table {
+                    continue;
+                }
+                let new_key = keys::data_key(generate_pk_name(new, &primary_key)?);
+                let row = self.encode_row(&table, &mut dictionary, new)?;
+                self.check_row_size(&table, &new_key, &row)?;
+                rows.push((key.clone(), new_key, row));
+                changed.push((old.clone(), new.clone()));
+            }
+            if !unique.is_empty() {
+                self.update_unique_indexes(&table, &unique, &rows, &changed, batch)?;
+            }
+            self.update_secondary_indexes(&table, &indexes, &rows, &changed, batch)?;
+            for (key, new_key, row) in &rows {
+                if key.as_ref() != new_key.as_slice() {
+                    batch.delete_cf(handle, key);
+                }
+                batch.put_cf(handle, new_key, row);
+            }
+        }
+        Ok(())
+    }
+
+    /// The dictionary of a table's dictionary encoded columns.
+    fn dictionary(&self, table: &str, metadata: &ColumnDescriptors) -> anyhow::Result<Dictionary> {
+        let handle = self
+            .db
+            .cf_handle(table)
+            .with_context(|| format!("No table {} exists", table))?;
+        let stored = self.db.get_cf(handle, keys::metadata_key(DICTIONARY_KEY))?;
+        Dictionary::new(metadata, stored.as_deref())
+    }
+
+    /// Serializes a row, encoding its dictionary columns. Values new to the dictionary are
+    /// written straight away so they're stored before any row holding their codes.
+    fn encode_row(
+        &self,
+        table: &str,
+        dictionary: &mut Dictionary,
+        record: &Record,
+    ) -> anyhow::Result<Vec<u8>> {
+        if dictionary.is_empty() {
+            return Ok(to_allocvec(record)?);
+        }
+        let mut record = record.clone();
+        if dictionary.encode(&mut record) {
+            let handle = self.db.cf_handle(table).unwrap();
+            let mut batch = WriteBatch::default();
+            batch.put_cf(
+                handle,
+                keys::metadata_key(DICTIONARY_KEY),
+                dictionary.to_bytes()?,
+            );
+            self.write(batch)?;
+        }
+        Ok(to_allocvec(&record)?)
+    }
+
+    /// Columns of a table kept unique by an index, with the constraint each one reports.
+    fn unique_indexes(
+        &self,
+        table: &str,
+        metadata: &ColumnDescriptors,
+    ) -> anyhow::Result<BTreeMap<String, String>> {
+        let constraints = self.constraints(table)?;
+        let primary_key = self.key_columns(table, metadata)?;
+        Ok(unique_columns(table, metadata, &constraints, &primary_key))
+    }
+
+    /// Whether a live row other than the one under `row_key` has `value` in `column`. Entries
+    /// aren't removed when rows expire or a bulk load replaces them, so the row an entry points
+    /// at is checked too.
+    fn unique_taken(
+        &self,
+        handle: &ColumnFamily,
+        column: &str,
+        value: &Value,
+        row_key: &[u8],
+        now: u64,
+    ) -> anyhow::Result<bool> {
+        let Some(pk) = self.db.get_cf(handle, keys::unique_key(column, value))? else {
+            return Ok(false);
+        };
+        let key = keys::data_key(pk);
+        if key == row_key {
+            return Ok(false);
+        }
+        match self.db.get_cf(handle, &key)? {
+            Some(bytes) => {
+                let record: Record = from_bytes(&bytes)?;
+                Ok(!ttl::is_expired(&record, now) && indexed_value(&record, column) == Some(value))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Whether a row that hasn't expired is stored under `key`. `key_may_exist` can rule a key out
+    /// without reading any blocks, otherwise the row is read to check.
+    fn live_row(&self, handle: &ColumnFamily, key: &[u8], now: u64) -> anyhow::Result<bool> {
+        if !self.db.key_may_exist_cf(handle, key) {
+            return Ok(false);
+        }
+        match self.db.get_cf(handle, key)? {
+            Some(bytes) => Ok(!ttl::is_expired(&from_bytes(&bytes)?, now)),
+            None => Ok(false),
+        }
+    }
+
+    /// Loads rows by writing them to an SST file that's ingested straight into the table, skipping
+    /// the memtable and write ahead log, which is much faster than [`Self::insert_rows`] for a
+    /// large initial load. Rows are sorted by key first so they can come in any order. Unlike an
+    /// insert, rows already in the table aren't checked and if a key repeats the last row with it
+    /// wins. Unique columns are still checked, against the table and the other loaded rows, as
+    /// are check constraints, but foreign keys aren't. A load that would take the table over its
+    /// quota fails before anything is written.
+    #[instrument(skip_all, fields(table = %insert_op.table, rows = insert_op.values.len(), bytes))]
+    pub fn ingest_rows(&mut self, insert_op: &InsertOptions) -> anyhow::Result<()> {
+        let metadata = self.table_metadata(&insert_op.table)?;
+
+        check_insert(insert_op, &metadata, &self.functions)?;
+        let sequences = self.register_sequences(&metadata)?;
+        self.reserve_counters(&insert_op.table, &sequences, insert_op.values.len())?;
+
+        let checks = self.checks(&insert_op.table, &metadata)?;
+        let mut rows = self
+            .encode_rows(insert_op, &metadata)?
+            .map(|row| {
+                let (key, row, record) = row?;
+                for check in &checks {
+                    self.check_rows(&insert_op.table, std::slice::from_ref(&record), check)?;
+                }
+                Ok((key, row))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // The sort is stable, reversing first puts the last of any repeated keys first to be kept
+        rows.reverse();
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+        rows.dedup_by(|a, b| a.0 == b.0);
+        Span::current().record("bytes", rows.iter().map(|x| x.1.len()).sum::<usize>());
+        let entries = self.ingested_entries(&insert_op.table, &metadata, &rows)?;
+        // Index entries sort after the rows, the file has to be written in key order
+        rows.extend(entries);
+
+        let quota = self.quota_usage(&insert_op.table)?;
+        if let Some(quota) = &quota {
+            let bytes = rows.iter().map(|x| x.0.len() + x.1.len()).sum();
+            quota.check(&insert_op.table, bytes)?;
+        }
+
+        if !rows.is_empty() {
+            self.ingest_file(&insert_op.table, |writer| {
+                for (key, row) in &rows {
+                    writer.put(key, row)?;
+                }
+                Ok(())
+            })?;
+        }
+        self.report_quota(&insert_op.table, quota.as_ref())
+    }
+
+    /// Unique and secondary index entries for rows about to be ingested, sorted by key. Values
+    /// held by rows the load replaces are free to be taken.
+    fn ingested_entries(
+        &self,
+        table: &str,
+        metadata: &ColumnDescriptors,
+        rows: &[(Vec<u8>, Vec<u8>)],
+    ) -> anyhow::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+        let unique = self.unique_indexes(table, metadata)?;
+        let indexes = self.indexes(table)?;
+        let mut entries = BTreeMap::new();
+        if unique.is_empty() && indexes.is_empty() {
+            return Ok(entries);
+        }
+        let handle = self.db.cf_handle(table).unwrap();
+        let dictionary = self.dictionary(table, metadata)?;
+        let now = unix_now();
+        for (key, row) in rows {
+            let record: Record = from_bytes(row)?;
+            if !indexes.is_empty() {
+                let mut decoded = record.clone();
+                dictionary.decode(&mut decoded)?;
+                let pk = keys::strip_data_prefix(key).unwrap();
+                for index in &indexes {
+                    if let Some(entry) = secondary_entry(index, &decoded, key)? {
+                        entries.insert(entry, pk.to_vec());
+                    }
+                }
+            }
+            for (column, name) in &unique {
+                let Some(value) = indexed_value(&record, column) else {
+                    continue;
+                };
+                let entry = keys::unique_key(column, value);
+                let taken = match self.db.get_cf(handle, &entry)? {
+                    Some(pk) => {
+                        let holder = keys::data_key(&pk);
+                        rows.binary_search_by(|x| x.0.cmp(&holder)).is_err()
+                            && self.unique_taken(handle, column, value, key, now)?
+                    }
+                    None => false,
+                };
+                let pk = keys::strip_data_prefix(key).unwrap().to_vec();
+                if taken || entries.insert(entry, pk).is_some() {
+                    return Err(unique_violation(table, column, name, value));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Writes an SST file with `write` and ingests it straight into the table, keys have to be
+    /// written in order. The file is removed whether or not the ingest works.
+    fn ingest_file(
+        &self,
+        table: &str,
+        write: impl FnOnce(&mut SstFileWriter) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let path = self
+            .config
+            .path
+            .join(format!("ingest-{}.sst", Uuid::new_v4()));
+        let ingest = || -> anyhow::Result<()> {
+            let opts = self.config.db_options();
+            let mut writer = SstFileWriter::create(&opts);
+            writer.open(&path)?;
+            write(&mut writer)?;
+            writer.finish()?;
+            let mut opts = IngestExternalFileOptions::default();
+            opts.set_move_files(true);
+            let handle = self.db.cf_handle(table).unwrap();
+            self.db
+                .ingest_external_file_cf_opts(handle, &opts, vec![&path])?;
+            Ok(())
+        };
+        let res = ingest();
+        if path.exists() {
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!(path = %path.display(), "Failed to remove SST file: {}", e);
+            }
+        }
+        res
+    }
+}
+
+/// Checks the columns of a new index exist in its table and its predicate can be evaluated
+/// against its rows.
+fn check_create_index(
+    opts: &CreateIndexOptions,
+    metadata: &ColumnDescriptors,
+) -> anyhow::Result<()> {
+    if let Some(predicate) = &opts.index.predicate {
+        expr::check(predicate, metadata)?;
+    }
+    for (i, indexed) in opts.index.columns.iter().enumerate() {
+        for column in expr::columns(indexed) {
+            if !metadata.contains_key(&column) || column.starts_with(SYSTEM_PREFIX) {
+                anyhow::bail!("Column {} does not exist in {}", column, opts.table);
+            }
+        }
+        expr::check(indexed, metadata)?;
+        if opts.index.columns[..i].contains(indexed) {
+            anyhow::bail!(
+                "{} appears in index {} more than once",
+                indexed,
+                opts.index.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks a table definition can be created and returns the columns that will be stored for it.
+/// Other tables are resolved through `lookup` so this can run against a schema snapshot.
+fn check_create_table(
+    create_table: &CreateTableOptions,
+    config: &StorageConfig,
+    lookup: impl Fn(&str) -> anyhow::Result<ColumnDescriptors>,
+) -> anyhow::Result<ColumnDescriptors> {
+    let name = create_table.name.as_str();
+    if name.starts_with(SYSTEM_PREFIX) || name == DEFAULT_COLUMN_FAMILY_NAME {
+        anyhow::bail!("Table name {} is reserved", name);
+    }
+    if create_table.columns.len() > config.max_columns {
+        anyhow::bail!(
+            "Table {} has {} columns, more than the limit of {}",
+            name,
+            create_table.columns.len(),
+            config.max_columns
+        );
+    }
+    if lookup(name).is_ok() {
+        anyhow::bail!("Table {} already exists", name);
+    }
+    for (table, col) in create_table
+        .columns
+        .values()
+        .filter_map(|x| x.foreign_key.as_ref())
+    {
+        check_foreign_key(table, col, &lookup)?;
+    }
+    let mut names = HashSet::new();
+    for constraint in &create_table.constraints {
+        if !names.insert(&constraint.name) {
+            anyhow::bail!("Constraint {} is declared more than once", constraint.name);
+        }
+        check_constraint(constraint, &create_table.columns, &lookup)?;
+    }
+
+    let mut columns = create_table.columns.clone();
+    if let Some(column) = columns.keys().find(|x| x.starts_with(SYSTEM_PREFIX)) {
+        anyhow::bail!("Column name {} is reserved", column);
+    }
+    if let Some(column) = &create_table.ttl_column {
+        let desc = columns
+            .get(column)
+            .with_context(|| format!("TTL column {} does not exist", column))?;
+        let timestamp = matches!(
+            desc.datatype,
+            DataType::Timestamp(..) | DataType::Datetime(_)
+        );
+        if !timestamp && !is_numeric_type(&desc.datatype) {
+            anyhow::bail!("TTL column {} must be a timestamp or a number", column);
+        }
+    }
+    for (column, desc) in &columns {
+        check_column_check(column, desc)?;
+    }
+    for (column, desc) in columns.iter().filter(|(_, x)| x.dictionary) {
+        if !is_text_type(&desc.datatype) {
+            anyhow::bail!("Dictionary column {} must be text", column);
+        }
+        // A unique column has as many values as rows, a dictionary wouldn't save anything
+        if desc.primary_key || desc.unique {
+            anyhow::bail!("Dictionary column {} can't be unique", column);
+        }
+    }
+    for column in &create_table.primary_key {
+        if !columns.get(column).is_some_and(|x| x.primary_key) {
+            anyhow::bail!("Primary key column {} does not exist", column);
+        }
+    }
+    if !columns.values().any(|x| x.primary_key) {
+        // Every row needs a unique key, so tables without a primary key get a hidden one
+        columns.insert(ROWID_COLUMN.to_string(), ColumnDescriptor::rowid());
+    }
+    Ok(columns)
+}
+
+fn non_null_values<'a>(
+    rows: &'a [Record],
+    column: &'a str,
+) -> impl Iterator<Item = &'a Rc<Value>> + 'a {
+    rows.iter()
+        .filter_map(move |row| row.columns.get(column))
+        .filter(|value| ***value != Value::Null)
+}
+
+fn check_foreign_key(
+    table: &str,
+    col: &str,
+    lookup: impl Fn(&str) -> anyhow::Result<ColumnDescriptors>,
+) -> anyhow::Result<()> {
+    let table_metadata = lookup(table)?;
+    if let Some(desc) = table_metadata.get(col) {
+        if !desc.primary_key {
+            anyhow::bail!("Foreign key {}.{} must refer to a primary key", table, col);
+        }
+        // One column of a composite key isn't unique on its own, and foreign keys can't name
+        // several columns yet
+        if primary_key_column(&table_metadata).is_err() {
+            anyhow::bail!(
+                "Foreign key {}.{} must refer to the whole primary key of {}",
+                table,
+                col,
+                table
+            );
+        }
+    } else {
+        anyhow::bail!("Column {} does not exist in {}", col, table);
+    }
+    Ok(())
+}
+
+/// A column's own `CHECK` can only refer to the column, a check on several columns has to be a
+/// table constraint.
+fn check_column_check(column: &str, desc: &ColumnDescriptor) -> anyhow::Result<()> {
+    let Some(check) = &desc.check else {
+        return Ok(());
+    };
+    if expr::columns(check).iter().any(|x| x != column) {
+        anyhow::bail!("CHECK on column {} can only refer to {}", column, column);
+    }
+    expr::check(
+        check,
+        &ColumnDescriptors::from([(column.to_string(), desc.clone())]),
+    )
+}
+
+/// Checks a named constraint refers to columns that exist.
+fn check_constraint(
+    constraint: &Constraint,
+    columns: &ColumnDescriptors,
+    lookup: impl Fn(&str) -> anyhow::Result<ColumnDescriptors>,
+) -> anyhow::Result<()> {
+    let column = constraint.kind.column();
+    if !columns.contains_key(column) || column.starts_with(SYSTEM_PREFIX) {
+        anyhow::bail!(
+            "Column {} of constraint {} does not exist",
+            column,
+            constraint.name
+        );
+    }
+    match &constraint.kind {
+        ConstraintKind::Unique { column } if columns[column].dictionary => {
+            anyhow::bail!("Dictionary column {} can't be unique", column);
+        }
+        ConstraintKind::ForeignKey {
+            table, referred, ..
+        } => check_foreign_key(table, referred, lookup)?,
+        ConstraintKind::Check { expr, .. } => expr::check(expr, columns)?,
+        ConstraintKind::Unique { .. } => {}
+    }
+    Ok(())
+}
+
+fn check_alter_table(
+    opts: &AlterTableOptions,
+    metadata: &ColumnDescriptors,
+    constraints: &[Constraint],
+    config: &StorageConfig,
+    functions: &FunctionRegistry,
+    lookup: impl Fn(&str) -> anyhow::Result<ColumnDescriptors>,
+) -> anyhow::Result<()> {
+    match &opts.operation {
+        AlterOperation::AddConstraint(constraint) => {
+            if constraints.iter().any(|x| x.name == constraint.name) {
+                anyhow::bail!(
+                    "Constraint {} already exists on {}",
+                    constraint.name,
+                    opts.name
+                );
+            }
+            check_constraint(constraint, metadata, lookup)
+        }
+        AlterOperation::DropConstraint { name, if_exists } => {
+            if !if_exists && !constraints.iter().any(|x| x.name == *name) {
+                anyhow::bail!("Constraint {} does not exist on {}", name, opts.name);
+            }
+            Ok(())
+        }
+        AlterOperation::AddColumn {
+            column,
+            descriptor,
+            constraints: added,
+            if_not_exists,
+        } => {
+            if column.starts_with(SYSTEM_PREFIX) {
+                anyhow::bail!("Column name {} is reserved", column);
+            }
+            if metadata.contains_key(column) {
+                if *if_not_exists {
+                    return Ok(());
+                }
+                anyhow::bail!("Column {} already exists in {}", column, opts.name);
+            }
+            if descriptor.primary_key {
+                anyhow::bail!(
+                    "Can't add primary key column {} to an existing table",
+                    column
+                );
+            }
+            let visible = metadata
+                .keys()
+                .filter(|x| !x.starts_with(SYSTEM_PREFIX))
+                .count();
+            if visible + 1 > config.max_columns {
+                anyhow::bail!(
+                    "Table {} would have {} columns, more than the limit of {}",
+                    opts.name,
+                    visible + 1,
+                    config.max_columns
+                );
+            }
+            if let Some((table, col)) = &descriptor.foreign_key {
+                check_foreign_key(table, col, &lookup)?;
+            }
+            check_column_check(column, descriptor)?;
+            for expr in descriptor.default.iter().chain(&descriptor.on_update) {
+                check_default(expr, functions)?;
+            }
+            if let Some(Expr::Value(val)) = &descriptor.default {
+                if !descriptor.value_matches_type(&Value::try_from(val.clone())?) {
+                    anyhow::bail!("Default for {} doesn't match column type", column);
+                }
+            }
+            let mut columns = metadata.clone();
+            columns.insert(column.clone(), descriptor.clone());
+            let mut names = constraints.iter().map(|x| &x.name).collect::<HashSet<_>>();
+            for constraint in added {
+                if !names.insert(&constraint.name) {
+                    anyhow::bail!(
+                        "Constraint {} already exists on {}",
+                        constraint.name,
+                        opts.name
+                    );
+                }
+                check_constraint(constraint, &columns, &lookup)?;
+            }
+            Ok(())
+        }
+        AlterOperation::DropColumn { column, if_exists } => {
+            let exists = metadata.contains_key(column) && !column.starts_with(SYSTEM_PREFIX);
+            if !exists && !if_exists {
+                anyhow::bail!("Column {} does not exist in {}", column, opts.name);
+            }
+            Ok(())
+        }
+        AlterOperation::RenameTable { to } => {
+            if to.starts_with(SYSTEM_PREFIX) || to == DEFAULT_COLUMN_FAMILY_NAME {
+                anyhow::bail!("Table name {} is reserved", to);
+            }
+            if lookup(to).is_ok() {
+                anyhow::bail!("Table {} already exists", to);
+            }
+            Ok(())
+        }
+        AlterOperation::RenameColumn { column, to } => {
+            if column.starts_with(SYSTEM_PREFIX) || !metadata.contains_key(column) {
+                anyhow::bail!("Column {} does not exist in {}", column, opts.name);
+            }
+            if to.starts_with(SYSTEM_PREFIX) {
+                anyhow::bail!("Column name {} is reserved", to);
+            }
+            if metadata.contains_key(to) {
+                anyhow::bail!("Column {} already exists in {}", to, opts.name);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The definition of a clone, the source's columns without the rowid which gets added back if
+/// the source had it.
+fn clone_definition(
+    opts: &CloneTableOptions,
+    lookup: impl Fn(&str) -> anyhow::Result<ColumnDescriptors>,
+) -> anyhow::Result<CreateTableOptions> {
+    let mut columns = lookup(&opts.source)?;
+    columns.remove(ROWID_COLUMN);
+    // The expiry column, quota, constraints and primary key order are read from the source's
+    // column family by the clone itself
+    Ok(CreateTableOptions {
+        name: opts.name.clone(),
+        columns,
+        ttl_column: None,
+        max_bytes: None,
+        constraints: vec![],
+        primary_key: vec![],
+    })
+}
+
+/// Checks every row of an insert against the table's columns without writing anything.
+fn check_insert(
+    insert_op: &InsertOptions,
+    metadata: &ColumnDescriptors,
+    functions: &FunctionRegistry,
+) -> anyhow::Result<()> {
+    if insert_op.columns.iter().any(|x| x == ROWID_COLUMN) {
+        anyhow::bail!("Column {} can't be set", ROWID_COLUMN);
+    }
+
+    // First lets just go over and make sure column names match etc
+    if let Some(bad_column) = insert_op
+        .columns
+        .iter()
+        .find(|x| !metadata.contains_key(x.as_str()))
+    {
+        anyhow::bail!("Column {} not present in table", bad_column);
+    }
+
+    for (column, desc) in metadata.iter() {
+        if desc.needs_value() {
+            // Now find missing columns that we need!
+            if !insert_op.columns.contains(column) {
+                anyhow::bail!("Required column {} is missing", column)
+            }
+        } else if !insert_op.columns.contains(column) && desc.should_generate() {
+            if let Some(default) = &desc.default {
+                check_default(default, functions)?;
+            } else if !desc.auto_increment {
+                anyhow::bail!("Unsure how to generate value for {}", column);
+            }
+        }
+    }
+
+    for record in insert_op.records() {
+        for (name, value) in record.columns.iter() {
+            metadata[name].check_value(name, value)?;
+        }
+    }
+    check_returning(&insert_op.returning, metadata)
+}
+
+/// Checks the columns a `RETURNING` clause asks for exist.
+fn check_returning(
+    returning: &Option<Returning>,
+    metadata: &ColumnDescriptors,
+) -> anyhow::Result<()> {
+    if let Some(Returning::Columns(columns)) = returning {
+        for column in columns {
+            if column.starts_with(SYSTEM_PREFIX) || !metadata.contains_key(column) {
+                anyhow::bail!("Column {} does not exist", column);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The columns of a row a `RETURNING` clause asked for, NULL for those the row doesn't store.
+fn returned_row(returning: &Returning, mut record: Record) -> Record {
+    match returning {
+        Returning::All => record
+            .columns
+            .retain(|column, _| !column.starts_with(SYSTEM_PREFIX)),
+        Returning::Columns(columns) => {
+            record.columns = columns
+                .iter()
+                .map(|column| {
+                    let value = record.columns.get(column).cloned();
+                    (
+                        column.clone(),
+                        value.unwrap_or_else(|| Rc::new(Value::Null)),
+                    )
+                })
+                .collect()
+        }
+    }
+    record
+}
+
+/// Tables by name with their columns and named constraints.
+type TableDefinitions = BTreeMap<String, (ColumnDescriptors, Vec<Constraint>)>;
+
+/// A foreign key on `table.column` into `referred.referred_column`, `constraint` is its name if it
+/// was declared as a named constraint rather than on the column.
+struct ForeignKeyReference {
+    referred: String,
+    referred_column: String,
+    table: String,
+    column: String,
+    constraint: Option<String>,
+    actions: ForeignKeyActions,
+}
+
+impl ForeignKeyReference {
+    fn name(&self) -> String {
+        self.constraint
+            .clone()
+            .unwrap_or_else(|| format!("{}_{}_fkey", self.table, self.column))
+    }
+}
+
+/// Every foreign key declared by the tables.
+fn foreign_keys(tables: &TableDefinitions) -> Vec<ForeignKeyReference> {
+    let mut references = vec![];
+    for (table, (columns, constraints)) in tables {
+        for (column, desc) in columns {
+            if let Some((referred, referred_column)) = &desc.foreign_key {
+                references.push(ForeignKeyReference {
+                    referred: referred.clone(),
+                    referred_column: referred_column.clone(),
+                    table: table.clone(),
+                    column: column.clone(),
+                    constraint: None,
+                    actions: desc.foreign_key_actions,
+                })
+            }
+        }
+        for constraint in constraints {
+            if let ConstraintKind::ForeignKey {
+                column,
+                table: referred,
+                referred: referred_column,
+            } = &constraint.kind
+            {
+                references.push(ForeignKeyReference {
+                    referred: referred.clone(),
+                    referred_column: referred_column.clone(),
+                    table: table.clone(),
+                    column: column.clone(),
+                    constraint: Some(constraint.name.clone()),
+                    actions: columns
+                        .get(column)
+                        .map(|x| x.foreign_key_actions)
+                        .unwrap_or_default(),
+                })
+            }
+        }
+    }
+    references
+}
+
+/// Foreign keys in tables that aren't being dropped that refer to one that is.
+fn referencing_foreign_keys(
+    dropped: &[String],
+    tables: &TableDefinitions,
+) -> Vec<ForeignKeyReference> {
+    foreign_keys(tables)
+        .into_iter()
+        .filter(|x| !dropped.contains(&x.table) && dropped.contains(&x.referred))
+        .collect()
+}
+
+/// Checks a column can be dropped, it can't be part of the primary key, the table's TTL column
+/// or referred to by a foreign key.
+fn check_drop_column(
+    table: &str,
+    column: &str,
+    tables: &TableDefinitions,
+    ttl_column: Option<&str>,
+) -> anyhow::Result<()> {
+    let (columns, _) = tables
+        .get(table)
+        .with_context(|| format!("No table {} exists", table))?;
+    let desc = columns
+        .get(column)
+        .filter(|_| !column.starts_with(SYSTEM_PREFIX))
+        .with_context(|| format!("Column {} does not exist in {}", column, table))?;
+    if desc.primary_key {
+        anyhow::bail!(
+            "Can't drop {}.{}, it's part of the primary key",
+            table,
+            column
+        );
+    }
+    if ttl_column == Some(column) {
+        anyhow::bail!(
+            "Can't drop {}.{}, it's the table's TTL column",
+            table,
+            column
+        );
+    }
+    let referenced = foreign_keys(tables).into_iter().find(|x| {
+        x.referred == table
+            && x.referred_column == column
+            && (x.table != table || x.column != column)
+    });
+    if let Some(reference) = referenced {
+        anyhow::bail!(
+            "Can't drop {}.{} because {}.{} refers to it",
+            table,
+            column,
+            reference.table,
+            reference.column
+        );
+    }
+    Ok(())
+}
+
+/// Returns the tables a `DROP TABLE` removes, checking nothing else refers to them unless the
+/// drop cascades.
+fn check_drop_table(
+    drop_op: &DropTableOptions,
+    tables: &TableDefinitions,
+) -> anyhow::Result<Vec<String>> {
+    let mut dropped = vec![];
+    for name in &drop_op.names {
+        if !tables.contains_key(name) {
+            if drop_op.if_exists {
+                continue;
+            }
+            anyhow::bail!("No table {} exists", name);
+        }
+        if !dropped.contains(name) {
+            dropped.push(name.clone());
+        }
+    }
+    if !drop_op.cascade {
+        if let Some(reference) = referencing_foreign_keys(&dropped, tables).first() {
+            anyhow::bail!(
+                "Can't drop {} because {}.{} refers to it, use CASCADE to drop the foreign key",
+                reference.referred,
+                reference.table,
+                reference.column
+            );
+        }
+    }
+    Ok(dropped)
+}
+
+/// Renames a table in `tables`, pointing foreign keys that referred to it at the new name.
+fn rename_table_definitions(tables: &mut TableDefinitions, table: &str, to: &str) {
+    if let Some(definition) = tables.remove(table) {
+        tables.insert(to.to_string(), definition);
+    }
+    for (columns, constraints) in tables.values_mut() {
+        for (referred, _) in columns.values_mut().filter_map(|x| x.foreign_key.as_mut()) {
+            if referred == table {
+                *referred = to.to_string();
+            }
+        }
+        for constraint in constraints.iter_mut() {
+            if let ConstraintKind::ForeignKey {
+                table: referred, ..
+            } = &mut constraint.kind
+            {
+                if referred == table {
+                    *referred = to.to_string();
+                }
+            }
+        }
+    }
+}
 
-        for mut record in insert_op.records() {
-            // validate record
-            for (name, value) in record.columns.iter() {
-                if !metadata[name].value_matches_type(value) {
-                    anyhow::bail!("Value for {} doesn't match column type", name);
+/// Renames a column of `table` in `tables` along with the table's constraints on it and foreign
+/// keys that refer to it.
+fn rename_column_definitions(tables: &mut TableDefinitions, table: &str, column: &str, to: &str) {
+    if let Some((columns, constraints)) = tables.get_mut(table) {
+        if let Some(mut desc) = columns.remove(column) {
+            if let Some(check) = &mut desc.check {
+                expr::rename_column(check, column, to);
+            }
+            columns.insert(to.to_string(), desc);
+        }
+        for constraint in constraints.iter_mut() {
+            let (ConstraintKind::Unique {
+                column: constrained,
+            }
+            | ConstraintKind::ForeignKey {
+                column: constrained,
+                ..
+            }
+            | ConstraintKind::Check {
+                column: constrained,
+                ..
+            }) = &mut constraint.kind;
+            if constrained == column {
+                *constrained = to.to_string();
+            }
+            if let ConstraintKind::Check { expr, .. } = &mut constraint.kind {
+                expr::rename_column(expr, column, to);
+            }
+        }
+    }
+    for (columns, constraints) in tables.values_mut() {
+        for (referred, referred_column) in
+            columns.values_mut().filter_map(|x| x.foreign_key.as_mut())
+        {
+            if referred == table && referred_column == column {
+                *referred_column = to.to_string();
+            }
+        }
+        for constraint in constraints.iter_mut() {
+            if let ConstraintKind::ForeignKey {
+                table: referred,
+                referred: referred_column,
+                ..
+            } = &mut constraint.kind
+            {
+                if referred == table && referred_column == column {
+                    *referred_column = to.to_string();
                 }
             }
+        }
+    }
+}
 
-            // Add things like missing default fields
-            for (column, action) in &value_actions {
-                let value = match action {
-                    Action::Increment(val) => {
-                        let value = val.fetch_add(1, Ordering::SeqCst);
-                        Rc::new(Value::Number(BigDecimal::from_usize(value).unwrap()))
-                    }
-                    Action::ApplyConstant(con) => con.clone(),
-                };
-                record.columns.insert(column.to_string(), value);
+/// Checks the assignments and filter of an update against the table's columns.
+fn check_update(
+    update_op: &UpdateOptions,
+    metadata: &ColumnDescriptors,
+    functions: &FunctionRegistry,
+) -> anyhow::Result<()> {
+    let mut assigned = HashSet::new();
+    for (column, value) in &update_op.assignments {
+        if column.starts_with(SYSTEM_PREFIX) {
+            anyhow::bail!("Column {} can't be set", column);
+        }
+        let desc = metadata
+            .get(column)
+            .with_context(|| format!("Column {} not present in table", column))?;
+        if !assigned.insert(column) {
+            anyhow::bail!("Column {} is assigned more than once", column);
+        }
+        expr::check(value, metadata)?;
+        // Constants can be checked up front, anything else depends on the row
+        if let Expr::Value(val) = value {
+            if !desc.value_matches_type(&Value::try_from(val.clone())?) {
+                anyhow::bail!("Value for {} doesn't match column type", column);
+            }
+        }
+    }
+    if let Some(filter) = &update_op.filter {
+        expr::check(filter, metadata)?;
+    }
+    check_returning(&update_op.returning, metadata)?;
+    for (column, desc) in metadata.iter() {
+        if let Some(on_update) = &desc.on_update {
+            if !assigned.contains(column) {
+                check_default(on_update, functions)?;
             }
+        }
+    }
+    Ok(())
+}
 
-            let pk = generate_pk_name(&record, &metadata);
+/// Files in the directory a repair moves what it can't salvage into.
+fn lost_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        files.push(entry?.path());
+    }
+    files.sort();
+    Ok(files)
+}
 
-            // If valid insert
-            let record = to_allocvec(&record)?;
-            transaction.put_cf(&handle, &pk, &record);
+/// Moves metadata stored by older versions inside the table column families into the catalog.
+fn migrate_legacy_metadata(db: &DB, opts: &rocksdb::Options, path: &Path) -> anyhow::Result<()> {
+    let catalog = db.cf_handle(CATALOG_CF).context("No catalog")?;
+    let mut batch = WriteBatch::default();
+    for name in DB::list_cf(opts, path)? {
+        if name == DEFAULT_COLUMN_FAMILY_NAME || name.starts_with(SYSTEM_PREFIX) {
+            continue;
         }
-        self.db.write(transaction)?;
-        Ok(())
+        let Some(handle) = db.cf_handle(&name) else {
+            continue;
+        };
+        if let Some(metadata) = db.get_cf(handle, LEGACY_METADATA_KEY)? {
+            debug!("Migrating metadata for {} into the catalog", name);
+            batch.put_cf(catalog, &name, metadata);
+            batch.delete_cf(handle, LEGACY_METADATA_KEY);
+        }
+    }
+    db.write(batch)?;
+    Ok(())
+}
+
+/// Column metadata as it was stored before the catalog was versioned.
+#[derive(Serialize, Deserialize)]
+struct ColumnDescriptorV0 {
+    datatype: DataType,
+    not_null: bool,
+    unique: bool,
+    primary_key: bool,
+    auto_increment: bool,
+    foreign_key: Option<(String, String)>,
+    default: Option<Expr>,
+}
+
+impl From<ColumnDescriptorV0> for ColumnDescriptor {
+    fn from(old: ColumnDescriptorV0) -> Self {
+        Self {
+            datatype: old.datatype,
+            not_null: old.not_null,
+            unique: old.unique,
+            primary_key: old.primary_key,
+            auto_increment: old.auto_increment,
+            foreign_key: old.foreign_key,
+            default: old.default,
+            ..Default::default()
+        }
+    }
+}
+
+/// Column metadata in catalog version 1, before dictionary encoding.
+#[derive(Serialize, Deserialize)]
+struct ColumnDescriptorV1 {
+    datatype: DataType,
+    not_null: bool,
+    unique: bool,
+    primary_key: bool,
+    auto_increment: bool,
+    foreign_key: Option<(String, String)>,
+    default: Option<Expr>,
+    on_update: Option<Expr>,
+}
+
+impl From<ColumnDescriptorV1> for ColumnDescriptor {
+    fn from(old: ColumnDescriptorV1) -> Self {
+        Self {
+            datatype: old.datatype,
+            not_null: old.not_null,
+            unique: old.unique,
+            primary_key: old.primary_key,
+            auto_increment: old.auto_increment,
+            foreign_key: old.foreign_key,
+            default: old.default,
+            on_update: old.on_update,
+            ..Default::default()
+        }
+    }
+}
+
+/// Column metadata in catalog version 2, before foreign key actions.
+#[derive(Serialize, Deserialize)]
+struct ColumnDescriptorV2 {
+    datatype: DataType,
+    not_null: bool,
+    unique: bool,
+    primary_key: bool,
+    auto_increment: bool,
+    foreign_key: Option<(String, String)>,
+    default: Option<Expr>,
+    on_update: Option<Expr>,
+    dictionary: bool,
+}
+
+impl From<ColumnDescriptorV2> for ColumnDescriptor {
+    fn from(old: ColumnDescriptorV2) -> Self {
+        Self {
+            datatype: old.datatype,
+            not_null: old.not_null,
+            unique: old.unique,
+            primary_key: old.primary_key,
+            auto_increment: old.auto_increment,
+            foreign_key: old.foreign_key,
+            default: old.default,
+            on_update: old.on_update,
+            dictionary: old.dictionary,
+            ..Default::default()
+        }
+    }
+}
+
+/// Column metadata in catalog version 3, before column checks.
+#[derive(Serialize, Deserialize)]
+struct ColumnDescriptorV3 {
+    datatype: DataType,
+    not_null: bool,
+    unique: bool,
+    primary_key: bool,
+    auto_increment: bool,
+    foreign_key: Option<(String, String)>,
+    default: Option<Expr>,
+    on_update: Option<Expr>,
+    dictionary: bool,
+    foreign_key_actions: ForeignKeyActions,
+}
+
+impl From<ColumnDescriptorV3> for ColumnDescriptor {
+    fn from(old: ColumnDescriptorV3) -> Self {
+        Self {
+            datatype: old.datatype,
+            not_null: old.not_null,
+            unique: old.unique,
+            primary_key: old.primary_key,
+            auto_increment: old.auto_increment,
+            foreign_key: old.foreign_key,
+            default: old.default,
+            on_update: old.on_update,
+            dictionary: old.dictionary,
+            foreign_key_actions: old.foreign_key_actions,
+            ..Default::default()
+        }
+    }
+}
+
+/// Column metadata in catalog version 4, before identity options.
+#[derive(Serialize, Deserialize)]
+struct ColumnDescriptorV4 {
+    datatype: DataType,
+    not_null: bool,
+    unique: bool,
+    primary_key: bool,
+    auto_increment: bool,
+    foreign_key: Option<(String, String)>,
+    default: Option<Expr>,
+    on_update: Option<Expr>,
+    dictionary: bool,
+    foreign_key_actions: ForeignKeyActions,
+    check: Option<Expr>,
+}
+
+impl From<ColumnDescriptorV4> for ColumnDescriptor {
+    fn from(old: ColumnDescriptorV4) -> Self {
+        Self {
+            datatype: old.datatype,
+            not_null: old.not_null,
+            unique: old.unique,
+            primary_key: old.primary_key,
+            auto_increment: old.auto_increment,
+            foreign_key: old.foreign_key,
+            default: old.default,
+            on_update: old.on_update,
+            dictionary: old.dictionary,
+            foreign_key_actions: old.foreign_key_actions,
+            check: old.check,
+            ..Default::default()
+        }
+    }
+}
+
+fn upgrade_columns<T: Into<ColumnDescriptor> + DeserializeOwned>(
+    metadata: &[u8],
+) -> anyhow::Result<ColumnDescriptors> {
+    let old: BTreeMap<String, T> = from_bytes(metadata)?;
+    Ok(old
+        .into_iter()
+        .map(|(column, desc)| (column, desc.into()))
+        .collect())
+}
+
+/// Postcard isn't self describing so adding to `ColumnDescriptor` changes the format of every
+/// catalog entry. Rewrites entries from older formats into the current one and returns the
+/// version the catalog was at.
+fn migrate_catalog(db: &DB) -> anyhow::Result<u8> {
+    let catalog = db.cf_handle(CATALOG_CF).context("No catalog")?;
+    let version = match db.get_cf(catalog, CATALOG_VERSION_KEY)? {
+        Some(version) => *version.first().context("Invalid catalog version")?,
+        None => 0,
+    };
+    if version > CATALOG_VERSION {
+        anyhow::bail!("Catalog version {} is newer than this build", version);
+    }
+    let mut batch = WriteBatch::default();
+    if version < COLUMNS_VERSION {
+        for entry in db.iterator_cf(catalog, IteratorMode::Start) {
+            let (name, metadata) = entry?;
+            if name.starts_with(SYSTEM_PREFIX.as_bytes()) {
+                continue;
+            }
+            debug!(
+                "Migrating catalog entry for {}",
+                String::from_utf8_lossy(&name)
+            );
+            let columns = match version {
+                0 => upgrade_columns::<ColumnDescriptorV0>(&metadata)?,
+                1 => upgrade_columns::<ColumnDescriptorV1>(&metadata)?,
+                2 => upgrade_columns::<ColumnDescriptorV2>(&metadata)?,
+                3 => upgrade_columns::<ColumnDescriptorV3>(&metadata)?,
+                _ => upgrade_columns::<ColumnDescriptorV4>(&metadata)?,
+            };
+            batch.put_cf(catalog, &name, to_allocvec(&columns)?);
+        }
+    }
+    // Stored timestamps are rewritten once the engine is open, see `migrate_timestamps`
+    batch.put_cf(catalog, CATALOG_VERSION_KEY, [version.max(COLUMNS_VERSION)]);
+    db.write(batch)?;
+    Ok(version)
+}
+
+/// Tables written before the key layout existed stored rows under their bare primary key. Prefix
+/// them as rows and stamp the table with the layout version.
+fn migrate_key_layout(db: &DB, opts: &rocksdb::Options, path: &Path) -> anyhow::Result<()> {
+    let layout_key = keys::metadata_key(keys::LAYOUT_KEY);
+    for name in DB::list_cf(opts, path)? {
+        if name == DEFAULT_COLUMN_FAMILY_NAME || name.starts_with(SYSTEM_PREFIX) {
+            continue;
+        }
+        let Some(handle) = db.cf_handle(&name) else {
+            continue;
+        };
+        let version = match db.get_cf(handle, &layout_key)? {
+            Some(version) => version.first().copied().unwrap_or_default(),
+            None => 0,
+        };
+        if version >= keys::LAYOUT_VERSION {
+            continue;
+        }
+        debug!(
+            "Migrating {} from key layout {} to {}",
+            name,
+            version,
+            keys::LAYOUT_VERSION
+        );
+        if version < 1 {
+            // Rows were stored without a namespace
+            let mut batch = WriteBatch::default();
+            for entry in db.iterator_cf(handle, IteratorMode::Start) {
+                let (key, value) = entry?;
+                batch.delete_cf(handle, &key);
+                batch.put_cf(handle, keys::data_key(&key), value);
+            }
+            batch.put_cf(handle, &layout_key, [1]);
+            db.write(batch)?;
+        }
+        if version < 3 {
+            let mut batch = WriteBatch::default();
+            rekey_rows(db, handle, &name, &mut batch)?;
+            batch.put_cf(handle, &layout_key, [3]);
+            db.write(batch)?;
+        }
+        let mut batch = WriteBatch::default();
+        index_unique_columns(db, handle, &name, &mut batch)?;
+        batch.put_cf(handle, &layout_key, [keys::LAYOUT_VERSION]);
+        db.write(batch)?;
+    }
+    Ok(())
+}
+
+/// Builds the unique indexes of a table, which layout 4 added. Before then unique columns were
+/// only checked by `ALTER TABLE`.
+fn index_unique_columns(
+    db: &DB,
+    handle: &ColumnFamily,
+    table: &str,
+    batch: &mut WriteBatch,
+) -> anyhow::Result<()> {
+    let catalog = db.cf_handle(CATALOG_CF).context("No catalog")?;
+    let Some(metadata) = db.get_cf(catalog, table)? else {
+        return Ok(());
+    };
+    let metadata: ColumnDescriptors = from_bytes(&metadata)?;
+    let constraints: Vec<Constraint> =
+        match db.get_cf(handle, keys::metadata_key(keys::CONSTRAINTS_KEY))? {
+            Some(bytes) => from_bytes(&bytes)?,
+            None => vec![],
+        };
+    let primary_key = read_primary_key(db, handle, &metadata)?;
+    for column in unique_columns(table, &metadata, &constraints, &primary_key).keys() {
+        index_rows(db, handle, column, batch)?;
+    }
+    Ok(())
+}
+
+/// Moves every row whose key isn't the one its primary key values give. Before layout 2 rows of
+/// a table with a declared primary key were keyed by the table's column names, so each write
+/// replaced the last row, and layout 2 encoded the values with postcard which doesn't sort.
+fn rekey_rows(
+    db: &DB,
+    handle: &ColumnFamily,
+    table: &str,
+    batch: &mut WriteBatch,
+) -> anyhow::Result<()> {
+    let catalog = db.cf_handle(CATALOG_CF).context("No catalog")?;
+    let Some(metadata) = db.get_cf(catalog, table)? else {
+        // A column family without a catalog entry isn't a table
+        return Ok(());
+    };
+    let metadata: ColumnDescriptors = from_bytes(&metadata)?;
+    let primary_key = read_primary_key(db, handle, &metadata)?;
+    let mut moved = vec![];
+    let start = IteratorMode::From(keys::DATA_PREFIX, Direction::Forward);
+    for entry in db.iterator_cf(handle, start) {
+        let (key, value) = entry?;
+        if keys::strip_data_prefix(&key).is_none() {
+            break;
+        }
+        let record: Record = from_bytes(&value)?;
+        let new_key = match generate_pk_name(&record, &primary_key) {
+            Ok(pk) => keys::data_key(pk),
+            Err(e) => {
+                warn!(%table, "Leaving a row where it is, it has no usable key: {}", e);
+                continue;
+            }
+        };
+        if *key != *new_key {
+            batch.delete_cf(handle, &key);
+            moved.push((new_key, value));
+        }
+    }
+    // Puts go after every delete so a row moving onto another row's old key isn't deleted with it
+    for (key, value) in moved {
+        batch.put_cf(handle, key, value);
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use sqlparser::ast::{self, DataType, Expr};
+    use sqlparser::ast;
     use std::collections::BTreeMap;
     use tracing_test::traced_test;
-    use uuid::Uuid;
 
     struct TableHandle {
         path: String,
     }
 
-    impl TableHandle {
-        fn new() -> Self {
-            Self {
-                path: format!("./target/{}", Uuid::new_v4()),
-            }
-        }
+    impl TableHandle {
+        fn new() -> Self {
+            Self {
+                path: format!("./target/{}", Uuid::new_v4()),
+            }
+        }
+    }
+
+    impl Drop for TableHandle {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    /// Metadata in the format written before the catalog was versioned.
+    fn legacy_columns(columns: &ColumnDescriptors) -> Vec<u8> {
+        let old = columns
+            .iter()
+            .map(|(column, desc)| {
+                let desc = ColumnDescriptorV0 {
+                    datatype: desc.datatype.clone(),
+                    not_null: desc.not_null,
+                    unique: desc.unique,
+                    primary_key: desc.primary_key,
+                    auto_increment: desc.auto_increment,
+                    foreign_key: desc.foreign_key.clone(),
+                    default: desc.default.clone(),
+                };
+                (column.clone(), desc)
+            })
+            .collect::<BTreeMap<_, _>>();
+        to_allocvec(&old).unwrap()
+    }
+
+    fn default_fixture() -> CreateTableOptions {
+        let mut columns = BTreeMap::new();
+        columns.insert(
+            "id".to_string(),
+            ColumnDescriptor {
+                datatype: DataType::UnsignedInteger(None),
+                not_null: true,
+                unique: true,
+                primary_key: true,
+                auto_increment: true,
+                ..Default::default()
+            },
+        );
+        columns.insert(
+            "name".to_string(),
+            ColumnDescriptor {
+                datatype: DataType::Text,
+                not_null: true,
+                ..Default::default()
+            },
+        );
+
+        let expr = Expr::Value(ast::Value::SingleQuotedString("London".to_string()));
+
+        columns.insert(
+            "city".to_string(),
+            ColumnDescriptor {
+                datatype: DataType::Text,
+                not_null: true,
+                default: Some(expr),
+                ..Default::default()
+            },
+        );
+
+        CreateTableOptions {
+            name: "users".to_string(),
+            columns,
+            ttl_column: None,
+            max_bytes: None,
+            constraints: vec![],
+            primary_key: vec![],
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn create_table() {
+        let handle = TableHandle::new();
+        let mut engine = StorageEngine::new_with_path(&handle.path);
+
+        let opt = default_fixture();
+
+        engine.create_table(&opt).unwrap();
+
+        let metadata = engine.table_metadata("users").unwrap();
+
+        assert_eq!(metadata, opt.columns);
+
+        std::mem::drop(engine);
+
+        // Can we open it again and have it work?
+
+        let _engine = StorageEngine::new_with_path(&handle.path);
+    }
+
+    #[test]
+    #[traced_test]
+    fn legacy_metadata_migrated() {
+        let handle = TableHandle::new();
+        let opt = default_fixture();
+        {
+            let mut opts = rocksdb::Options::default();
+            opts.create_if_missing(true);
+            let mut db = DB::open(&opts, &handle.path).unwrap();
+            db.create_cf("users", &opts).unwrap();
+            let cf = db.cf_handle("users").unwrap();
+            db.put_cf(cf, LEGACY_METADATA_KEY, legacy_columns(&opt.columns))
+                .unwrap();
+        }
+
+        let engine = StorageEngine::new_with_path(&handle.path);
+        assert_eq!(engine.table_metadata("users").unwrap(), opt.columns);
+        let cf = engine.db.cf_handle("users").unwrap();
+        assert!(engine.db.get_cf(cf, LEGACY_METADATA_KEY).unwrap().is_none());
+    }
+
+    #[test]
+    #[traced_test]
+    fn catalog_v1_migrated() {
+        let handle = TableHandle::new();
+        let opt = default_fixture();
+        {
+            let mut engine = StorageEngine::new_with_path(&handle.path);
+            engine.create_table(&opt).unwrap();
+            let columns = engine
+                .table_metadata("users")
+                .unwrap()
+                .into_iter()
+                .map(|(column, desc)| {
+                    let desc = ColumnDescriptorV1 {
+                        datatype: desc.datatype,
+                        not_null: desc.not_null,
+                        unique: desc.unique,
+                        primary_key: desc.primary_key,
+                        auto_increment: desc.auto_increment,
+                        foreign_key: desc.foreign_key,
+                        default: desc.default,
+                        on_update: desc.on_update,
+                    };
+                    (column, desc)
+                })
+                .collect::<BTreeMap<_, _>>();
+            let catalog = engine.catalog();
+            engine
+                .db
+                .put_cf(catalog, "users", to_allocvec(&columns).unwrap())
+                .unwrap();
+            engine.db.put_cf(catalog, CATALOG_VERSION_KEY, [1]).unwrap();
+        }
+
+        let engine = StorageEngine::new_with_path(&handle.path);
+        assert_eq!(engine.table_metadata("users").unwrap(), opt.columns);
+    }
+
+    #[test]
+    #[traced_test]
+    fn catalog_v2_migrated() {
+        let handle = TableHandle::new();
+        let opt = default_fixture();
+        {
+            let mut engine = StorageEngine::new_with_path(&handle.path);
+            engine.create_table(&opt).unwrap();
+            let columns = engine
+                .table_metadata("users")
+                .unwrap()
+                .into_iter()
+                .map(|(column, desc)| {
+                    let desc = ColumnDescriptorV2 {
+                        datatype: desc.datatype,
+                        not_null: desc.not_null,
+                        unique: desc.unique,
+                        primary_key: desc.primary_key,
+                        auto_increment: desc.auto_increment,
+                        foreign_key: desc.foreign_key,
+                        default: desc.default,
+                        on_update: desc.on_update,
+                        dictionary: desc.dictionary,
+                    };
+                    (column, desc)
+                })
+                .collect::<BTreeMap<_, _>>();
+            let catalog = engine.catalog();
+            engine
+                .db
+                .put_cf(catalog, "users", to_allocvec(&columns).unwrap())
+                .unwrap();
+            engine.db.put_cf(catalog, CATALOG_VERSION_KEY, [2]).unwrap();
+        }
+
+        let engine = StorageEngine::new_with_path(&handle.path);
+        assert_eq!(engine.table_metadata("users").unwrap(), opt.columns);
+    }
+
+    #[test]
+    #[traced_test]
+    fn catalog_v3_migrated() {
+        let handle = TableHandle::new();
+        let opt = default_fixture();
+        {
+            let mut engine = StorageEngine::new_with_path(&handle.path);
+            engine.create_table(&opt).unwrap();
+            let columns = engine
+                .table_metadata("users")
+                .unwrap()
+                .into_iter()
+                .map(|(column, desc)| {
+                    let desc = ColumnDescriptorV3 {
+                        datatype: desc.datatype,
+                        not_null: desc.not_null,
+                        unique: desc.unique,
+                        primary_key: desc.primary_key,
+                        auto_increment: desc.auto_increment,
+                        foreign_key: desc.foreign_key,
+                        default: desc.default,
+                        on_update: desc.on_update,
+                        dictionary: desc.dictionary,
+                        foreign_key_actions: desc.foreign_key_actions,
+                    };
+                    (column, desc)
+                })
+                .collect::<BTreeMap<_, _>>();
+            let catalog = engine.catalog();
+            engine
+                .db
+                .put_cf(catalog, "users", to_allocvec(&columns).unwrap())
+                .unwrap();
+            engine.db.put_cf(catalog, CATALOG_VERSION_KEY, [3]).unwrap();
+        }
+
+        let engine = StorageEngine::new_with_path(&handle.path);
+        assert_eq!(engine.table_metadata("users").unwrap(), opt.columns);
+    }
+
+    #[test]
+    #[traced_test]
+    fn catalog_v4_migrated() {
+        let handle = TableHandle::new();
+        let opt = default_fixture();
+        {
+            let mut engine = StorageEngine::new_with_path(&handle.path);
+            engine.create_table(&opt).unwrap();
+            let columns = engine
+                .table_metadata("users")
+                .unwrap()
+                .into_iter()
+                .map(|(column, desc)| {
+                    let desc = ColumnDescriptorV4 {
+                        datatype: desc.datatype,
+                        not_null: desc.not_null,
+                        unique: desc.unique,
+                        primary_key: desc.primary_key,
+                        auto_increment: desc.auto_increment,
+                        foreign_key: desc.foreign_key,
+                        default: desc.default,
+                        on_update: desc.on_update,
+                        dictionary: desc.dictionary,
+                        foreign_key_actions: desc.foreign_key_actions,
+                        check: desc.check,
+                    };
+                    (column, desc)
+                })
+                .collect::<BTreeMap<_, _>>();
+            let catalog = engine.catalog();
+            engine
+                .db
+                .put_cf(catalog, "users", to_allocvec(&columns).unwrap())
+                .unwrap();
+            engine.db.put_cf(catalog, CATALOG_VERSION_KEY, [4]).unwrap();
+        }
+
+        let engine = StorageEngine::new_with_path(&handle.path);
+        assert_eq!(engine.table_metadata("users").unwrap(), opt.columns);
+    }
+
+    #[test]
+    #[traced_test]
+    fn legacy_rows_migrated() {
+        let handle = TableHandle::new();
+        let opt = default_fixture();
+        let record = Record {
+            columns: BTreeMap::from([(
+                "name".to_string(),
+                Rc::new(Value::Text("Daniel".to_string())),
+            )]),
+        };
+        {
+            let mut opts = rocksdb::Options::default();
+            opts.create_if_missing(true);
+            let mut db = DB::open(&opts, &handle.path).unwrap();
+            db.create_cf("users", &opts).unwrap();
+            let cf = db.cf_handle("users").unwrap();
+            db.put_cf(cf, LEGACY_METADATA_KEY, legacy_columns(&opt.columns))
+                .unwrap();
+            db.put_cf(cf, "m/layout", to_allocvec(&record).unwrap())
+                .unwrap();
+        }
+
+        let engine = StorageEngine::new_with_path(&handle.path);
+        let cf = engine.db.cf_handle("users").unwrap();
+        let row = engine
+            .db
+            .get_cf(cf, keys::data_key("m/layout"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(from_bytes::<Record>(&row).unwrap(), record);
+        assert_eq!(
+            engine
+                .db
+                .get_cf(cf, keys::metadata_key(keys::LAYOUT_KEY))
+                .unwrap(),
+            Some(vec![keys::LAYOUT_VERSION])
+        );
+        assert!(engine
+            .db
+            .get_cf(cf, keys::data_key(LEGACY_METADATA_KEY))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    #[traced_test]
+    fn composite_primary_key() {
+        let handle = TableHandle::new();
+        let mut engine = StorageEngine::new_with_path(&handle.path);
+        let mut opt = default_fixture();
+        opt.columns.remove("id");
+        for column in ["name", "city"] {
+            opt.columns.get_mut(column).unwrap().primary_key = true;
+        }
+        opt.primary_key = vec!["name".to_string(), "city".to_string()];
+        engine.create_table(&opt).unwrap();
+        assert_eq!(engine.primary_key("users").unwrap(), opt.primary_key);
+
+        let text = |x: &str| Rc::new(Value::Text(x.to_string()));
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string(), "city".to_string()],
+            values: vec![
+                vec![text("Daniel"), text("London")],
+                vec![text("Daniel"), text("Paris")],
+            ],
+            returning: None,
+            upsert: false,
+        };
+        engine.insert_rows(&insert).unwrap();
+        assert_eq!(row_count(&engine, "users"), 2);
+
+        let record = Record {
+            columns: BTreeMap::from([
+                ("city".to_string(), text("Paris")),
+                ("name".to_string(), text("Daniel")),
+            ]),
+        };
+        let mut key = vec![];
+        keys::encode_value(&Value::Text("Daniel".to_string()), &mut key);
+        keys::encode_value(&Value::Text("Paris".to_string()), &mut key);
+        assert_eq!(generate_pk_name(&record, &opt.primary_key).unwrap(), key);
+        let cf = engine.db.cf_handle("users").unwrap();
+        assert!(engine
+            .db
+            .get_cf(cf, keys::data_key(&key))
+            .unwrap()
+            .is_some());
+        let missing = Record {
+            columns: BTreeMap::from([("name".to_string(), text("Daniel"))]),
+        };
+        assert!(generate_pk_name(&missing, &opt.primary_key).is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn name_keyed_rows_migrated() {
+        let handle = TableHandle::new();
+        let record = {
+            let mut engine = StorageEngine::new_with_path(&handle.path);
+            engine.create_table(&default_fixture()).unwrap();
+            let insert = InsertOptions {
+                table: "users".to_string(),
+                columns: vec!["name".to_string()],
+                values: vec![vec![Value::Text("Daniel".to_string()).into()]],
+                returning: None,
+                upsert: false,
+            };
+            engine.insert_rows(&insert).unwrap();
+            // Before layout 2 rows were keyed by the names of the other columns
+            let cf = engine.db.cf_handle("users").unwrap();
+            let (key, value) = engine
+                .db
+                .iterator_cf(
+                    cf,
+                    IteratorMode::From(keys::DATA_PREFIX, Direction::Forward),
+                )
+                .next()
+                .unwrap()
+                .unwrap();
+            engine.db.delete_cf(cf, &key).unwrap();
+            engine
+                .db
+                .put_cf(cf, keys::data_key("city/name"), &value)
+                .unwrap();
+            engine
+                .db
+                .put_cf(cf, keys::metadata_key(keys::LAYOUT_KEY), [1])
+                .unwrap();
+            from_bytes::<Record>(&value).unwrap()
+        };
+
+        let engine = StorageEngine::new_with_path(&handle.path);
+        let cf = engine.db.cf_handle("users").unwrap();
+        let key = generate_pk_name(&record, &["id".to_string()]).unwrap();
+        assert!(engine.db.get_cf(cf, keys::data_key(key)).unwrap().is_some());
+        assert!(engine
+            .db
+            .get_cf(cf, keys::data_key("city/name"))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            engine
+                .db
+                .get_cf(cf, keys::metadata_key(keys::LAYOUT_KEY))
+                .unwrap(),
+            Some(vec![keys::LAYOUT_VERSION])
+        );
     }
 
-    impl Drop for TableHandle {
-        fn drop(&mut self) {
-            let _ = std::fs::remove_dir_all(&self.path);
+    #[test]
+    #[traced_test]
+    fn unique_index_built_on_migration() {
+        let handle = TableHandle::new();
+        let mut opt = default_fixture();
+        opt.columns.get_mut("name").unwrap().unique = true;
+        let name = Value::Text("Daniel".to_string());
+        {
+            let mut engine = StorageEngine::new_with_path(&handle.path);
+            engine.create_table(&opt).unwrap();
+            let insert = InsertOptions {
+                table: "users".to_string(),
+                columns: vec!["name".to_string()],
+                values: vec![vec![name.clone().into()]],
+                returning: None,
+                upsert: false,
+            };
+            engine.insert_rows(&insert).unwrap();
+            // Layout 3 tables have no index entries
+            let cf = engine.db.cf_handle("users").unwrap();
+            let prefix = keys::unique_prefix("name");
+            engine
+                .db
+                .delete_range_cf(cf, &prefix, keys::prefix_end(&prefix))
+                .unwrap();
+            engine
+                .db
+                .put_cf(cf, keys::metadata_key(keys::LAYOUT_KEY), [3])
+                .unwrap();
         }
+
+        let engine = StorageEngine::new_with_path(&handle.path);
+        let cf = engine.db.cf_handle("users").unwrap();
+        let pk = engine
+            .db
+            .get_cf(cf, keys::unique_key("name", &name))
+            .unwrap()
+            .unwrap();
+        assert!(engine.db.get_cf(cf, keys::data_key(pk)).unwrap().is_some());
+        // The primary key is unique through the row keys alone
+        assert!(engine
+            .db
+            .iterator_cf(
+                cf,
+                IteratorMode::From(&keys::unique_prefix("id"), Direction::Forward)
+            )
+            .next()
+            .unwrap()
+            .unwrap()
+            .0
+            .starts_with(&keys::unique_prefix("name")));
     }
 
-    fn default_fixture() -> CreateTableOptions {
-        let mut columns = BTreeMap::new();
-        columns.insert(
-            "id".to_string(),
+    /// `users` keyed by a timestamp, with a unique name.
+    fn timestamp_fixture() -> CreateTableOptions {
+        let mut opt = default_fixture();
+        opt.columns.remove("id");
+        opt.columns.get_mut("name").unwrap().unique = true;
+        opt.columns.insert(
+            "at".to_string(),
             ColumnDescriptor {
-                datatype: DataType::UnsignedInteger(None),
+                datatype: DataType::Timestamp(None, ast::TimezoneInfo::WithTimeZone),
                 not_null: true,
-                unique: true,
                 primary_key: true,
-                auto_increment: true,
                 ..Default::default()
             },
         );
-        columns.insert(
-            "name".to_string(),
-            ColumnDescriptor {
-                datatype: DataType::Text,
-                not_null: true,
-                ..Default::default()
-            },
-        );
-
-        let expr = Expr::Value(ast::Value::SingleQuotedString("London".to_string()));
-
-        columns.insert(
-            "city".to_string(),
-            ColumnDescriptor {
-                datatype: DataType::Text,
-                not_null: true,
-                default: Some(expr),
-                ..Default::default()
-            },
-        );
-
-        CreateTableOptions {
-            name: "users".to_string(),
-            columns,
-        }
+        opt.primary_key = vec!["at".to_string()];
+        opt
     }
 
     #[test]
     #[traced_test]
-    fn create_table() {
+    fn timestamps_stored_in_full() {
         let handle = TableHandle::new();
         let mut engine = StorageEngine::new_with_path(&handle.path);
+        engine.create_table(&timestamp_fixture()).unwrap();
+        let text = |x: &str| Rc::new(Value::Text(x.to_string()));
+        // Rows that don't come through `Instance`, like imported ones, aren't normalized before
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["at".to_string(), "name".to_string()],
+            values: vec![vec![text("2024-03-01T12:00+02:00"), text("Daniel")]],
+            returning: None,
+            upsert: false,
+        };
+        engine.insert_rows(&insert).unwrap();
 
-        let opt = default_fixture();
-
-        engine.create_table(&opt).unwrap();
-
-        let metadata = engine.table_metadata("users").unwrap();
+        for key in [
+            "2024-03-01 10:00:00",
+            "2024-03-01T10:00",
+            "2024-03-01 12:00:00+02",
+        ] {
+            let row = engine
+                .get_row("users", &[Value::Text(key.to_string())])
+                .unwrap()
+                .unwrap();
+            assert_eq!(row.columns["at"], text("2024-03-01 10:00:00"));
+        }
+        assert_eq!(
+            engine
+                .get_rows_by_pk("users", &[text("2024-03-01 10:00 UTC")])
+                .unwrap(),
+            vec![engine
+                .get_row("users", &[Value::Text("2024-03-01 10:00:00".to_string())])
+                .unwrap()]
+        );
+        assert!(engine
+            .get_row("users", &[Value::Text("yesterday".to_string())])
+            .unwrap()
+            .is_none());
+    }
 
-        assert_eq!(metadata, opt.columns);
+    #[test]
+    #[traced_test]
+    fn timestamps_migrated() {
+        let handle = TableHandle::new();
+        let text = |x: &str| Rc::new(Value::Text(x.to_string()));
+        let stored = Record {
+            columns: BTreeMap::from([
+                ("at".to_string(), text("2024-03-01 10:00:00")),
+                ("city".to_string(), text("London")),
+                ("name".to_string(), text("Daniel")),
+            ]),
+        };
+        let pk = generate_pk_name(&stored, &["at".to_string()]).unwrap();
+        let old_pk = {
+            let mut engine = StorageEngine::new_with_path(&handle.path);
+            engine.create_table(&timestamp_fixture()).unwrap();
+            // Before catalog version 6 rows loaded straight into storage kept the text they had
+            let mut record = stored.clone();
+            record
+                .columns
+                .insert("at".to_string(), text("2024-03-01T12:00+02:00"));
+            let old_pk = generate_pk_name(&record, &["at".to_string()]).unwrap();
+            let cf = engine.db.cf_handle("users").unwrap();
+            engine
+                .db
+                .put_cf(cf, keys::data_key(&old_pk), to_allocvec(&record).unwrap())
+                .unwrap();
+            engine
+                .db
+                .put_cf(
+                    cf,
+                    keys::unique_key("name", &stored.columns["name"]),
+                    &old_pk,
+                )
+                .unwrap();
+            let catalog = engine.catalog();
+            engine.db.put_cf(catalog, CATALOG_VERSION_KEY, [5]).unwrap();
+            old_pk
+        };
 
-        std::mem::drop(engine);
+        let engine = StorageEngine::new_with_path(&handle.path);
+        let cf = engine.db.cf_handle("users").unwrap();
+        assert!(engine
+            .db
+            .get_cf(cf, keys::data_key(&old_pk))
+            .unwrap()
+            .is_none());
+        let row = engine.db.get_cf(cf, keys::data_key(&pk)).unwrap().unwrap();
+        assert_eq!(from_bytes::<Record>(&row).unwrap(), stored);
+        assert_eq!(
+            engine
+                .db
+                .get_cf(cf, keys::unique_key("name", &stored.columns["name"]))
+                .unwrap(),
+            Some(pk)
+        );
+        assert_eq!(
+            engine
+                .db
+                .get_cf(engine.catalog(), CATALOG_VERSION_KEY)
+                .unwrap(),
+            Some(vec![CATALOG_VERSION])
+        );
+    }
 
-        // Can we open it again and have it work?
+    #[test]
+    #[traced_test]
+    fn reserved_table_names() {
+        let handle = TableHandle::new();
+        let mut engine = StorageEngine::new_with_path(&handle.path);
 
-        let _engine = StorageEngine::new_with_path(&handle.path);
+        let mut opt = default_fixture();
+        opt.name = CATALOG_CF.to_string();
+        assert!(engine.create_table(&opt).is_err());
+        assert!(engine.table_metadata(CATALOG_CF).is_err());
     }
 
     #[test]
@@ -339,6 +5076,8 @@ mod tests {
             table: "doesnt_exist".to_string(),
             columns: vec!["name".to_string()],
             values: vec![vec![Value::Text("Daniel".to_string()).into()]],
+            returning: None,
+            upsert: false,
         };
         // Table doesn't exist should fail
         assert!(engine.insert_rows(&insert).is_err());
@@ -347,6 +5086,8 @@ mod tests {
             table: "users".to_string(),
             columns: vec!["city".to_string()],
             values: vec![vec![Value::Text("London".to_string()).into()]],
+            returning: None,
+            upsert: false,
         };
 
         // Missing name column should fail as it's not-null
@@ -356,6 +5097,8 @@ mod tests {
             table: "users".to_string(),
             columns: vec!["toshi".to_string()],
             values: vec![vec![Value::Text("London".to_string()).into()]],
+            returning: None,
+            upsert: false,
         };
 
         // Missing name column should fail as it's not-null
@@ -365,6 +5108,8 @@ mod tests {
             table: "users".to_string(),
             columns: vec!["name".to_string()],
             values: vec![vec![Value::Boolean(false).into()]],
+            returning: None,
+            upsert: false,
         };
 
         // Incorrect type should fail checking
@@ -373,6 +5118,67 @@ mod tests {
         // TODO foreign key violations, setting columns that shouldn't be set?
     }
 
+    fn row_count(engine: &StorageEngine, table: &str) -> usize {
+        let handle = engine.db.cf_handle(table).unwrap();
+        engine
+            .db
+            .iterator_cf(handle, IteratorMode::Start)
+            .filter(|x| keys::strip_data_prefix(&x.as_ref().unwrap().0).is_some())
+            .count()
+    }
+
+    #[test]
+    #[traced_test]
+    fn implicit_rowid() {
+        let handle = TableHandle::new();
+        let mut engine = StorageEngine::new_with_path(&handle.path);
+
+        let mut opt = default_fixture();
+        opt.columns.remove("id");
+        engine.create_table(&opt).unwrap();
+
+        let metadata = engine.table_metadata("users").unwrap();
+        assert_eq!(metadata[ROWID_COLUMN], ColumnDescriptor::rowid());
+
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string()],
+            values: vec![
+                vec![Value::Text("Daniel".to_string()).into()],
+                vec![Value::Text("Daniel".to_string()).into()],
+            ],
+            returning: None,
+            upsert: false,
+        };
+        engine.insert_rows(&insert).unwrap();
+        assert_eq!(row_count(&engine, "users"), 2);
+
+        // The counter has to carry on after a restart or we'd overwrite rows
+        std::mem::drop(engine);
+        let mut engine = StorageEngine::new_with_path(&handle.path);
+        engine.insert_rows(&insert).unwrap();
+        assert_eq!(row_count(&engine, "users"), 4);
+
+        let set_rowid = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string(), ROWID_COLUMN.to_string()],
+            values: vec![vec![
+                Value::Text("Daniel".to_string()).into(),
+                Value::Number(1u32.into()).into(),
+            ]],
+            returning: None,
+            upsert: false,
+        };
+        assert!(engine.insert_rows(&set_rowid).is_err());
+
+        let mut reserved = default_fixture();
+        reserved.name = "reserved".to_string();
+        reserved
+            .columns
+            .insert(ROWID_COLUMN.to_string(), ColumnDescriptor::default());
+        assert!(engine.create_table(&reserved).is_err());
+    }
+
     #[test]
     #[traced_test]
     fn primary_key_increments() {
@@ -387,6 +5193,8 @@ mod tests {
             table: "users".to_string(),
             columns: vec!["name".to_string()],
             values: vec![vec![Value::Text("Daniel".to_string()).into()]],
+            returning: None,
+            upsert: false,
         };
 
         engine.insert_rows(&insert).unwrap();
@@ -394,9 +5202,390 @@ mod tests {
             table: "users".to_string(),
             column: "id".to_string(),
         };
-        assert_eq!(engine.auto_incs[&pk].load(Ordering::Relaxed), 2);
+        assert_eq!(engine.auto_incs[&pk].next.load(Ordering::Relaxed), 2);
+
+        engine.insert_rows(&insert).unwrap();
+        assert_eq!(engine.auto_incs[&pk].next.load(Ordering::Relaxed), 3);
+
+        // The first insert reserved a block, a restart carries on from the end of it
+        std::mem::drop(engine);
+        let engine = StorageEngine::new_with_path(&handle.path);
+        let block = StorageConfig::default().counter_block_size;
+        assert_eq!(
+            engine.auto_incs[&pk].next.load(Ordering::Relaxed),
+            2 + block
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn clone_table() {
+        let handle = TableHandle::new();
+        let mut engine = StorageEngine::new_with_path(&handle.path);
+        let mut opt = default_fixture();
+        opt.columns.remove("id");
+        engine.create_table(&opt).unwrap();
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string()],
+            values: vec![
+                vec![Value::Text("Daniel".to_string()).into()],
+                vec![Value::Text("Daniel".to_string()).into()],
+            ],
+            returning: None,
+            upsert: false,
+        };
+        // An empty table has nothing to ingest
+        let empty = CloneTableOptions {
+            name: "users_empty".to_string(),
+            source: "users".to_string(),
+        };
+        engine.clone_table(&empty).unwrap();
+        assert_eq!(row_count(&engine, "users_empty"), 0);
+
+        engine.insert_rows(&insert).unwrap();
+        engine.set_quota("users", Some(1 << 20)).unwrap();
+
+        let clone = CloneTableOptions {
+            name: "users_copy".to_string(),
+            source: "users".to_string(),
+        };
+        engine
+            .validate(&[Command::CloneTable(clone.clone())])
+            .unwrap();
+        engine.clone_table(&clone).unwrap();
+        assert_eq!(
+            engine.table_metadata("users_copy").unwrap(),
+            engine.table_metadata("users").unwrap()
+        );
+        assert_eq!(row_count(&engine, "users_copy"), 2);
+        assert_eq!(engine.quota("users_copy").unwrap(), Some(1 << 20));
+
+        // New rows in the clone carry on from the source's rowids instead of overwriting them
+        let insert = InsertOptions {
+            table: "users_copy".to_string(),
+            ..insert
+        };
+        engine.insert_rows(&insert).unwrap();
+        assert_eq!(row_count(&engine, "users_copy"), 4);
+        assert_eq!(row_count(&engine, "users"), 2);
+
+        assert!(engine.clone_table(&clone).is_err());
+        let missing = CloneTableOptions {
+            name: "other".to_string(),
+            source: "missing".to_string(),
+        };
+        assert!(engine.clone_table(&missing).is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn expired_rows_compacted() {
+        let handle = TableHandle::new();
+        let mut engine = StorageEngine::new_with_path(&handle.path);
+        let mut opt = default_fixture();
+        opt.columns.remove("id");
+        opt.ttl_column = Some("name".to_string());
+        assert!(engine.create_table(&opt).is_err());
+
+        opt.columns.insert(
+            "expires".to_string(),
+            ColumnDescriptor {
+                datatype: DataType::BigInt(None),
+                ..Default::default()
+            },
+        );
+        opt.ttl_column = Some("expires".to_string());
+        engine.create_table(&opt).unwrap();
+        assert_eq!(
+            engine.ttl_column("users").unwrap(),
+            Some("expires".to_string())
+        );
+
+        let row =
+            |expires: Value| vec![Rc::new(Value::Text("Daniel".to_string())), Rc::new(expires)];
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string(), "expires".to_string()],
+            values: vec![
+                row(Value::Number(1u32.into())),
+                row(Value::Number(u32::MAX.into())),
+                row(Value::Null),
+            ],
+            returning: None,
+            upsert: false,
+        };
+        engine.insert_rows(&insert).unwrap();
+        assert_eq!(row_count(&engine, "users"), 3);
+
+        let cf = engine.db.cf_handle("users").unwrap();
+        engine.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+        assert_eq!(row_count(&engine, "users"), 2);
+    }
+
+    #[test]
+    #[traced_test]
+    fn limits() {
+        let handle = TableHandle::new();
+        let config = StorageConfig {
+            max_columns: 3,
+            max_row_bytes: 64,
+            ..StorageConfig::with_path(&handle.path)
+        };
+        let mut engine = StorageEngine::new_with_config(config);
+
+        let mut wide = default_fixture();
+        wide.columns
+            .insert("age".to_string(), ColumnDescriptor::default());
+        let err = engine.create_table(&wide).unwrap_err();
+        assert!(err.to_string().contains("limit of 3"), "{}", err);
+
+        engine.create_table(&default_fixture()).unwrap();
+        let insert = |name: &str| InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string()],
+            values: vec![vec![Value::Text(name.to_string()).into()]],
+            returning: None,
+            upsert: false,
+        };
+        engine.insert_rows(&insert("Daniel")).unwrap();
+        let err = engine.insert_rows(&insert(&"a".repeat(100))).unwrap_err();
+        assert!(err.to_string().contains("limit of 64"), "{}", err);
+    }
+
+    #[test]
+    #[traced_test]
+    fn large_inserts_split() {
+        let handle = TableHandle::new();
+        let config = StorageConfig {
+            max_batch_bytes: 256,
+            ..StorageConfig::with_path(&handle.path)
+        };
+        let mut engine = StorageEngine::new_with_config(config);
+        let mut opt = default_fixture();
+        opt.columns.remove("id");
+        engine.create_table(&opt).unwrap();
+        let before = engine.write_stats();
+
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string()],
+            values: vec![vec![Value::Text("a".repeat(100)).into()]; 10],
+            returning: None,
+            upsert: false,
+        };
+        engine.insert_rows(&insert).unwrap();
+        assert_eq!(row_count(&engine, "users"), 10);
+
+        let after = engine.write_stats();
+        assert!(after.batches - before.batches > 1, "{:?}", after);
+        assert!(after.bytes > before.bytes);
+        assert!(after.largest_batch < 512, "{:?}", after);
+    }
+
+    #[test]
+    #[traced_test]
+    fn split_inserts_checked_first() {
+        let handle = TableHandle::new();
+        let config = StorageConfig {
+            max_batch_bytes: 256,
+            ..StorageConfig::with_path(&handle.path)
+        };
+        let mut engine = StorageEngine::new_with_config(config);
+        let mut opt = default_fixture();
+        opt.columns.remove("id");
+        opt.columns.get_mut("name").unwrap().unique = true;
+        engine.create_table(&opt).unwrap();
+
+        // The rows are over several batches and only the last one breaks the constraint
+        let mut values = (0..10)
+            .map(|x| vec![Value::Text(format!("{}{}", x, "a".repeat(100))).into()])
+            .collect::<Vec<_>>();
+        values.push(values[0].clone());
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string()],
+            values,
+            returning: None,
+            upsert: false,
+        };
+        let err = engine.insert_rows(&insert).unwrap_err();
+        assert!(err.to_string().contains("Duplicate value"), "{}", err);
+        assert_eq!(row_count(&engine, "users"), 0);
+        let cf = engine.db.cf_handle("users").unwrap();
+        let prefix = keys::unique_prefix("name");
+        assert!(engine
+            .db
+            .iterator_cf(cf, IteratorMode::From(&prefix, Direction::Forward))
+            .all(|x| !x.unwrap().0.starts_with(&prefix)));
+    }
+
+    #[test]
+    #[traced_test]
+    fn ingested_rows() {
+        let handle = TableHandle::new();
+        let mut engine = StorageEngine::new_with_path(&handle.path);
+        let mut opt = default_fixture();
+        opt.columns.remove("id");
+        engine.create_table(&opt).unwrap();
+
+        let names = ["c", "a", "b"];
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string()],
+            values: names
+                .iter()
+                .map(|x| vec![Value::Text(x.to_string()).into()])
+                .collect(),
+            returning: None,
+            upsert: false,
+        };
+        engine.ingest_rows(&insert).unwrap();
+        // Rowids keep the rows in the order they were given and carry on for later inserts
+        engine.insert_rows(&insert).unwrap();
+        let rows = engine.scan_table("users").unwrap();
+        let got = rows
+            .iter()
+            .map(|x| x.columns["name"].to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(got, vec!["'c'", "'a'", "'b'", "'c'", "'a'", "'b'"]);
+        assert_eq!(*rows[0].columns["city"], Value::Text("London".to_string()));
+
+        // Nothing is left behind in the database directory
+        let leftover = std::fs::read_dir(&handle.path)
+            .unwrap()
+            .filter(|x| {
+                let name = x.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("ingest-")
+            })
+            .count();
+        assert_eq!(leftover, 0);
+
+        let mut missing = insert.clone();
+        missing.table = "missing".to_string();
+        assert!(engine.ingest_rows(&missing).is_err());
+        let mut empty = insert;
+        empty.values.clear();
+        engine.ingest_rows(&empty).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn function_defaults() {
+        let handle = TableHandle::new();
+        let mut engine = StorageEngine::new_with_path(&handle.path);
+        engine.register_function("answer", |_| Ok(Value::Number(42u32.into())));
+
+        let parse = |sql: &str| {
+            sqlparser::parser::Parser::new(&sqlparser::dialect::GenericDialect {})
+                .try_with_sql(sql)
+                .unwrap()
+                .parse_expr()
+                .unwrap()
+        };
+        let mut opt = default_fixture();
+        for (column, datatype, default) in [
+            (
+                "created",
+                DataType::Timestamp(None, ast::TimezoneInfo::None),
+                "now()",
+            ),
+            ("token", DataType::Uuid, "gen_random_uuid()"),
+            ("number", DataType::Int(None), "nextval('numbers')"),
+            ("answer", DataType::Int(None), "ANSWER()"),
+        ] {
+            opt.columns.insert(
+                column.to_string(),
+                ColumnDescriptor {
+                    datatype,
+                    default: Some(parse(default)),
+                    ..Default::default()
+                },
+            );
+        }
+        engine.create_table(&opt).unwrap();
+
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string()],
+            values: vec![
+                vec![Value::Text("Daniel".to_string()).into()],
+                vec![Value::Text("Daniel".to_string()).into()],
+            ],
+            returning: None,
+            upsert: false,
+        };
+        engine.insert_rows(&insert).unwrap();
+        assert_eq!(engine.sequences["numbers"].next.load(Ordering::Relaxed), 3);
+
+        // Sequences are stored so they carry on after a restart from the end of the block they
+        // reserved, functions have to be registered again
+        std::mem::drop(engine);
+        let mut engine = StorageEngine::new_with_path(&handle.path);
+        let block = StorageConfig::default().counter_block_size;
+        let next =
+            |engine: &StorageEngine| engine.sequences["numbers"].next.load(Ordering::Relaxed);
+        assert_eq!(next(&engine), 3 + block);
+        assert!(engine.insert_rows(&insert).is_err());
+        engine.register_function("answer", |_| Ok(Value::Number(42u32.into())));
+        engine.insert_rows(&insert).unwrap();
+        assert_eq!(next(&engine), 5 + block);
+
+        // Results are checked against the column type
+        engine.register_function("answer", |_| Ok(Value::Boolean(true)));
+        assert!(engine.insert_rows(&insert).is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn recovery() {
+        let handle = TableHandle::new();
+        let config = StorageConfig::with_path(&handle.path);
+        {
+            let mut engine = StorageEngine::new_with_config(config.clone());
+            let mut opt = default_fixture();
+            opt.columns.remove("id");
+            engine.create_table(&opt).unwrap();
+            let insert = InsertOptions {
+                table: "users".to_string(),
+                columns: vec!["name".to_string()],
+                values: vec![vec![Value::Text("Daniel".to_string()).into()]; 3],
+                returning: None,
+                upsert: false,
+            };
+            engine.insert_rows(&insert).unwrap();
+            let cf = engine.db.cf_handle("users").unwrap();
+            engine
+                .db
+                .put_cf(cf, keys::data_key("garbage"), [0xff; 3])
+                .unwrap();
+        }
 
+        let recovery = RecoveryOptions {
+            repair: true,
+            ..Default::default()
+        };
+        let (mut engine, report) =
+            StorageEngine::open_with_recovery(config.clone(), &recovery).unwrap();
+        assert!(report.lost_files.is_empty());
+        assert_eq!(
+            report.dropped_rows,
+            BTreeMap::from([("users".to_string(), 1)])
+        );
+        assert_eq!(row_count(&engine, "users"), 3);
+        // Counters are restored so new rows don't overwrite old ones
+        let insert = InsertOptions {
+            table: "users".to_string(),
+            columns: vec!["name".to_string()],
+            values: vec![vec![Value::Text("Ben".to_string()).into()]],
+            returning: None,
+            upsert: false,
+        };
         engine.insert_rows(&insert).unwrap();
-        assert_eq!(engine.auto_incs[&pk].load(Ordering::Relaxed), 3);
+        assert_eq!(row_count(&engine, "users"), 4);
+
+        // Recovery never creates a database
+        let missing = StorageConfig::with_path(format!("{}-missing", handle.path));
+        assert!(StorageEngine::open_with_recovery(missing, &RecoveryOptions::default()).is_err());
     }
 }
@@ -0,0 +1,280 @@
+//! Helpers for applications testing against dechib. A [`TestHarness`] owns an engine in its own
+//! temporary directory, loads fixtures and checks table contents. Functions that would make runs
+//! differ are replaced, `now()` always returns [`FIXED_NOW`] and `gen_random_uuid()` counts up
+//! from 1.
+use crate::types::*;
+use crate::Instance;
+use anyhow::Context;
+use sqlparser::ast::DataType;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tempfile::TempDir;
+use uuid::Uuid;
+
+/// What `now()` and `current_timestamp()` return inside a harness.
+pub const FIXED_NOW: &str = "2000-01-01 00:00:00";
+
+pub struct TestHarness {
+    instance: Instance,
+    // Declared after the instance so the database is closed before the directory is removed
+    dir: TempDir,
+}
+
+impl Default for TestHarness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestHarness {
+    pub fn new() -> Self {
+        let dir = TempDir::new().expect("Failed to create a temporary directory");
+        let mut instance = Instance::new_with_path(dir.path());
+        for name in ["now", "current_timestamp"] {
+            instance.register_function(name, |_| Ok(Value::Text(FIXED_NOW.to_string())));
+        }
+        let next_uuid = AtomicU64::new(1);
+        instance.register_function("gen_random_uuid", move |_| {
+            let n = next_uuid.fetch_add(1, Ordering::SeqCst);
+            Ok(Value::Text(Uuid::from_u128(n.into()).to_string()))
+        });
+        Self { instance, dir }
+    }
+
+    pub fn instance(&mut self) -> &mut Instance {
+        &mut self.instance
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Runs every statement in `sql`.
+    pub fn load_sql(&mut self, sql: &str) -> anyhow::Result<()> {
+        self.instance.execute(sql)?;
+        Ok(())
+    }
+
+    pub fn load_sql_file(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let sql = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        self.load_sql(&sql)
+    }
+
+    /// Inserts CSV rows into an existing table, returning how many were inserted. The first line
+    /// names the columns. An empty unquoted field is NULL while `""` is an empty string, booleans
+    /// are `true`/`false`, `t`/`f` or `1`/`0` and bytes are hex, optionally prefixed with `\x`.
+    pub fn load_csv(&mut self, table: &str, csv: &str) -> anyhow::Result<usize> {
+        let metadata = self.instance.storage.table_metadata(table)?;
+        let mut lines = parse_csv(csv)?.into_iter();
+        let columns = lines
+            .next()
+            .context("CSV has no header")?
+            .into_iter()
+            .map(|(name, _)| name.trim().to_string())
+            .collect::<Vec<_>>();
+        let mut descs = vec![];
+        for column in &columns {
+            descs.push(
+                metadata
+                    .get(column)
+                    .with_context(|| format!("{} has no column {}", table, column))?,
+            );
+        }
+
+        let mut values = vec![];
+        for (i, line) in lines.enumerate() {
+            if line.len() != columns.len() {
+                anyhow::bail!(
+                    "CSV row {} has {} fields but the header has {}",
+                    i + 1,
+                    line.len(),
+                    columns.len()
+                );
+            }
+            let row = line
+                .into_iter()
+                .zip(&descs)
+                .map(|((field, quoted), desc)| csv_value(field, quoted, desc).map(Rc::new))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .with_context(|| format!("CSV row {}", i + 1))?;
+            values.push(row);
+        }
+        let count = values.len();
+        self.instance.storage.insert_rows(&InsertOptions {
+            table: table.to_string(),
+            columns,
+            values,
+            returning: None,
+            upsert: false,
+        })?;
+        Ok(count)
+    }
+
+    pub fn load_csv_file(&mut self, table: &str, path: impl AsRef<Path>) -> anyhow::Result<usize> {
+        let path = path.as_ref();
+        let csv = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        self.load_csv(table, &csv)
+    }
+
+    /// The rows of a table in primary key order.
+    pub fn rows(&self, table: &str) -> anyhow::Result<Vec<Record>> {
+        self.instance.storage.scan_table(table)
+    }
+
+    #[track_caller]
+    pub fn assert_row_count(&self, table: &str, expected: usize) {
+        let rows = self.rows(table).unwrap();
+        assert_eq!(rows.len(), expected, "Row count of {}", table);
+    }
+
+    /// Checks both tables hold the same rows, ignoring their order.
+    #[track_caller]
+    pub fn assert_tables_equal(&self, left: &str, right: &str) {
+        let left_rows = self.rows(left).unwrap();
+        let mut right_rows = self.rows(right).unwrap();
+        for row in &left_rows {
+            match right_rows.iter().position(|x| x == row) {
+                Some(i) => {
+                    right_rows.swap_remove(i);
+                }
+                None => panic!("{:?} is in {} but not {}", row, left, right),
+            }
+        }
+        if let Some(row) = right_rows.first() {
+            panic!("{:?} is in {} but not {}", row, right, left);
+        }
+    }
+}
+
+/// Splits CSV text into lines of fields, each field paired with whether it was quoted. Blank lines
+/// are skipped.
+fn parse_csv(csv: &str) -> anyhow::Result<Vec<Vec<(String, bool)>>> {
+    let mut lines = vec![];
+    let mut line = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut in_quotes = false;
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() && !quoted => {
+                in_quotes = true;
+                quoted = true;
+            }
+            ',' => line.push((std::mem::take(&mut field), std::mem::take(&mut quoted))),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                if line.is_empty() && field.is_empty() && !quoted {
+                    continue;
+                }
+                line.push((std::mem::take(&mut field), std::mem::take(&mut quoted)));
+                lines.push(std::mem::take(&mut line));
+            }
+            c => field.push(c),
+        }
+    }
+    if in_quotes {
+        anyhow::bail!("Unterminated quoted field in CSV");
+    }
+    if !line.is_empty() || !field.is_empty() || quoted {
+        line.push((field, quoted));
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+fn csv_value(field: String, quoted: bool, desc: &ColumnDescriptor) -> anyhow::Result<Value> {
+    if field.is_empty() && !quoted {
+        return Ok(Value::Null);
+    }
+    let value = match &desc.datatype {
+        DataType::Bool | DataType::Boolean => match field.to_lowercase().as_str() {
+            "true" | "t" | "1" => Value::Boolean(true),
+            "false" | "f" | "0" => Value::Boolean(false),
+            _ => anyhow::bail!("Invalid boolean {}", field),
+        },
+        DataType::Bytea => {
+            let hex = field.strip_prefix("\\x").unwrap_or(&field);
+            Value::Bytes(hex::decode(hex).with_context(|| format!("Invalid hex {}", field))?)
+        }
+        ty if is_numeric_type(ty) => Value::Number(
+            field
+                .parse()
+                .with_context(|| format!("Invalid number {}", field))?,
+        ),
+        _ => Value::Text(field),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_parsing() {
+        let lines = parse_csv("a,b\n\"x, \"\"y\"\"\",\r\n\n\"\",2").unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                vec![("a".to_string(), false), ("b".to_string(), false)],
+                vec![("x, \"y\"".to_string(), true), (String::new(), false)],
+                vec![(String::new(), true), ("2".to_string(), false)],
+            ]
+        );
+        assert!(parse_csv("\"open").is_err());
+    }
+
+    #[test]
+    fn fixtures() {
+        let mut harness = TestHarness::new();
+        harness
+            .load_sql(
+                "CREATE TABLE users (id INT, name TEXT NOT NULL, admin BOOLEAN, \
+                 created TEXT DEFAULT now(), token TEXT DEFAULT gen_random_uuid());
+                 CREATE TABLE users_copy (id INT, name TEXT NOT NULL, admin BOOLEAN, \
+                 created TEXT DEFAULT now(), token TEXT DEFAULT gen_random_uuid());",
+            )
+            .unwrap();
+        let csv = "id,name,admin\n1,Daniel,true\n2,\"Ben\",\n";
+        assert_eq!(harness.load_csv("users", csv).unwrap(), 2);
+        harness.assert_row_count("users", 2);
+
+        let rows = harness.rows("users").unwrap();
+        assert_eq!(
+            *rows[0].columns["created"],
+            Value::Text(FIXED_NOW.to_string())
+        );
+        assert_eq!(
+            *rows[0].columns["token"],
+            Value::Text(Uuid::from_u128(1).to_string())
+        );
+        assert_eq!(*rows[1].columns["admin"], Value::Null);
+
+        // Same values, the generated uuids carry on counting so they're given explicitly
+        harness
+            .load_sql(
+                "INSERT INTO users_copy (id, name, admin, token) VALUES \
+                 (2, 'Ben', NULL, '00000000-0000-0000-0000-000000000002'), \
+                 (1, 'Daniel', true, '00000000-0000-0000-0000-000000000001')",
+            )
+            .unwrap();
+        harness.assert_tables_equal("users", "users_copy");
+        assert!(harness.load_csv("users", "id,missing\n3,x").is_err());
+    }
+}
@@ -0,0 +1,363 @@
+//! `TIMESTAMP WITH TIME ZONE` columns. Their values are stored as `YYYY-MM-DD HH:MM:SS` in UTC,
+//! the same format `now()` returns, so they sort and compare by the instant they represent.
+//! Timestamps written to them or compared against them are converted to UTC first, using the
+//! session's `timezone` when they don't carry an offset of their own, and rows read from them are
+//! rendered in the session's time zone.
+//!
+//! There's no time zone database to look names up in, so a time zone is UTC or a fixed offset.
+use crate::expr;
+use crate::functions::{days_from_civil, days_in_month, format_timestamp};
+use crate::types::*;
+use sqlparser::ast::{self, BinaryOperator, DataType, Expr};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::rc::Rc;
+
+/// Offsets further from UTC than this aren't accepted, like Postgres.
+const MAX_OFFSET: i32 = 16 * 3600;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeZone {
+    /// Seconds east of UTC
+    offset: i32,
+}
+
+impl TimeZone {
+    pub const UTC: Self = Self { offset: 0 };
+
+    pub fn from_offset(seconds: i32) -> anyhow::Result<Self> {
+        if seconds.abs() >= MAX_OFFSET {
+            anyhow::bail!("Time zone offset {}s is out of range", seconds);
+        }
+        Ok(Self { offset: seconds })
+    }
+
+    /// Parses `UTC` or an ISO 8601 offset, `+HH`, `+HHMM` or `+HH:MM`.
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        let name = name.trim();
+        if ["utc", "z", "gmt"]
+            .iter()
+            .any(|x| name.eq_ignore_ascii_case(x))
+        {
+            return Ok(Self::UTC);
+        }
+        let offset = name
+            .strip_prefix('+')
+            .map(|x| (1, x))
+            .or_else(|| name.strip_prefix('-').map(|x| (-1, x)))
+            .and_then(|(sign, x)| {
+                let (hours, minutes) = match (x.len(), x.split_once(':')) {
+                    (_, Some((hours, minutes))) => (hours, minutes),
+                    (1 | 2, None) => (x, "0"),
+                    (4, None) => x.split_at(2),
+                    _ => return None,
+                };
+                let (hours, minutes) = (digits(hours)?, digits(minutes)?);
+                (minutes < 60).then(|| sign * (hours * 3600 + minutes * 60))
+            });
+        match offset {
+            Some(offset) => Self::from_offset(i32::try_from(offset)?),
+            None => anyhow::bail!(
+                "Unknown time zone {}, only UTC and offsets like +02:00 are supported",
+                name
+            ),
+        }
+    }
+
+    /// Formats a time as `YYYY-MM-DD HH:MM:SS+HH:MM` in this time zone.
+    pub fn render(&self, secs: i64) -> String {
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        let offset = self.offset.abs();
+        format!(
+            "{}{}{:02}:{:02}",
+            format_timestamp(secs + i64::from(self.offset)),
+            sign,
+            offset / 3600,
+            offset % 3600 / 60
+        )
+    }
+}
+
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.offset == 0 {
+            return write!(f, "UTC");
+        }
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        let offset = self.offset.abs();
+        write!(f, "{}{:02}:{:02}", sign, offset / 3600, offset % 3600 / 60)
+    }
+}
+
+fn digits(s: &str) -> Option<i64> {
+    if s.is_empty() || !s.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Parses `YYYY-MM-DD[ HH:MM[:SS]]` followed by an optional offset to seconds since the unix
+/// epoch. A timestamp without an offset is in `zone`.
+pub fn parse_timestamp(text: &str, zone: TimeZone) -> anyhow::Result<i64> {
+    parse_parts(text.trim(), zone).ok_or_else(|| anyhow::anyhow!("Invalid timestamp {}", text))?
+}
+
+fn parse_parts(text: &str, zone: TimeZone) -> Option<anyhow::Result<i64>> {
+    let date = text.get(..10)?;
+    if date.as_bytes()[4] != b'-' || date.as_bytes()[7] != b'-' {
+        return None;
+    }
+    let mut rest = &text[10..];
+    let [year, month, day] = {
+        let mut parts = date.split('-').map(digits);
+        [parts.next()??, parts.next()??, parts.next()??]
+    };
+    if !(1..=12).contains(&month) {
+        return None;
+    }
+    if day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    let mut secs = days_from_civil(year, month, day) * 86400;
+    let time = rest
+        .strip_prefix([' ', 'T'])
+        .filter(|x| x.starts_with(|c: char| c.is_ascii_digit()));
+    if let Some(time) = time {
+        let len = time
+            .find(|c: char| !c.is_ascii_digit() && c != ':')
+            .unwrap_or(time.len());
+        let mut parts = time[..len].split(':').map(digits);
+        let hours = parts.next()??;
+        let minutes = parts.next()??;
+        let seconds = parts.next().unwrap_or(Some(0))?;
+        if parts.next().is_some() || hours > 23 || minutes > 59 || seconds > 59 {
+            return None;
+        }
+        secs += hours * 3600 + minutes * 60 + seconds;
+        rest = &time[len..];
+    }
+    if rest.starts_with('.') {
+        return Some(Err(anyhow::anyhow!(
+            "Fractional seconds aren't supported in {}",
+            text
+        )));
+    }
+    let zone = match rest.trim() {
+        "" => zone,
+        offset => match TimeZone::parse(offset) {
+            Ok(zone) => zone,
+            Err(e) => return Some(Err(e)),
+        },
+    };
+    Some(Ok(secs - i64::from(zone.offset)))
+}
+
+/// Converts a timestamp to how it's stored.
+pub fn to_utc(text: &str, zone: TimeZone) -> anyhow::Result<String> {
+    Ok(format_timestamp(parse_timestamp(text, zone)?))
+}
+
+pub fn is_zoned(datatype: &DataType) -> bool {
+    matches!(
+        datatype,
+        DataType::Timestamp(_, ast::TimezoneInfo::WithTimeZone | ast::TimezoneInfo::Tz)
+    )
+}
+
+/// Columns of `TIMESTAMP WITH TIME ZONE` type.
+pub fn zoned_columns(columns: &ColumnDescriptors) -> BTreeSet<String> {
+    columns
+        .iter()
+        .filter(|(_, desc)| is_zoned(&desc.datatype))
+        .map(|(column, _)| column.clone())
+        .collect()
+}
+
+/// Columns of `TIMESTAMP` or `DATETIME` type without a time zone. Their values are stored in the
+/// same format as zoned ones, but aren't converted from or rendered in the session's time zone.
+pub fn plain_timestamp_columns(columns: &ColumnDescriptors) -> BTreeSet<String> {
+    columns
+        .iter()
+        .filter(|(_, desc)| match &desc.datatype {
+            DataType::Timestamp(..) => !is_zoned(&desc.datatype),
+            DataType::Datetime(_) => true,
+            _ => false,
+        })
+        .map(|(column, _)| column.clone())
+        .collect()
+}
+
+/// Writes a value of a timestamp column out the way it's stored, converting it to UTC when it
+/// carries an offset. Rows that reach storage without going through [`command_to_utc`], like
+/// imported or copied ones, end up in the same format as the rest. Stored values are left as they
+/// are.
+pub fn stored_value(desc: &ColumnDescriptor, value: Rc<Value>) -> anyhow::Result<Rc<Value>> {
+    match (&desc.datatype, value.as_ref()) {
+        (DataType::Timestamp(..) | DataType::Datetime(_), Value::Text(text)) => {
+            Ok(Rc::new(Value::Text(to_utc(text, TimeZone::UTC)?)))
+        }
+        _ => Ok(value),
+    }
+}
+
+fn literal_to_utc(expr: &mut Expr, zone: TimeZone) -> anyhow::Result<()> {
+    match expr {
+        Expr::Value(ast::Value::SingleQuotedString(text)) => *text = to_utc(text, zone)?,
+        Expr::Nested(inner) => literal_to_utc(inner, zone)?,
+        // The timestamp an interval is added to
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Plus | BinaryOperator::Minus,
+            right,
+        } => {
+            literal_to_utc(left, zone)?;
+            literal_to_utc(right, zone)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Whether an expression is a zoned column, or one with an interval added to it.
+fn is_zoned_expr(expr: &Expr, columns: &BTreeSet<String>) -> bool {
+    match expr {
+        Expr::Nested(inner) => is_zoned_expr(inner, columns),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Plus | BinaryOperator::Minus,
+            right,
+        } => is_zoned_expr(left, columns) || is_zoned_expr(right, columns),
+        _ => expr::column_name(expr).is_some_and(|x| columns.contains(&x)),
+    }
+}
+
+/// Converts the literals a filter compares zoned columns against.
+fn filter_to_utc(
+    filter: &mut Expr,
+    columns: &BTreeSet<String>,
+    zone: TimeZone,
+) -> anyhow::Result<()> {
+    let zoned = |x: &Expr| is_zoned_expr(x, columns);
+    match filter {
+        Expr::Nested(inner) | Expr::UnaryOp { expr: inner, .. } => {
+            filter_to_utc(inner, columns, zone)
+        }
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And | BinaryOperator::Or,
+            right,
+        } => {
+            filter_to_utc(left, columns, zone)?;
+            filter_to_utc(right, columns, zone)
+        }
+        Expr::BinaryOp { left, right, .. } if zoned(left.as_ref()) => literal_to_utc(right, zone),
+        Expr::BinaryOp { left, right, .. } if zoned(right.as_ref()) => literal_to_utc(left, zone),
+        Expr::InList { expr, list, .. } if zoned(expr.as_ref()) => {
+            list.iter_mut().try_for_each(|x| literal_to_utc(x, zone))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Converts the timestamps a command writes to or compares against zoned columns to UTC.
+pub fn command_to_utc(
+    command: &mut Command,
+    columns: &BTreeSet<String>,
+    zone: TimeZone,
+) -> anyhow::Result<()> {
+    let filter = match command {
+        Command::Insert(insert) => {
+            for (i, column) in insert.columns.iter().enumerate() {
+                if !columns.contains(column) {
+                    continue;
+                }
+                for row in insert.values.iter_mut() {
+                    let Value::Text(text) = row[i].as_ref() else {
+                        continue;
+                    };
+                    row[i] = Rc::new(Value::Text(to_utc(text, zone)?));
+                }
+            }
+            None
+        }
+        Command::Update(update) => {
+            for (column, value) in update.assignments.iter_mut() {
+                if columns.contains(column) {
+                    literal_to_utc(value, zone)?;
+                }
+            }
+            update.filter.as_mut()
+        }
+        Command::Delete(delete) => delete.filter.as_mut(),
+        Command::Select(query) => query.filter.as_mut(),
+        _ => None,
+    };
+    match filter {
+        Some(filter) => filter_to_utc(filter, columns, zone),
+        None => Ok(()),
+    }
+}
+
+/// Renders the zoned columns of rows read back in `zone`. Values that were written before the
+/// column was zoned are left as they are.
+pub fn render_rows(rows: &mut [Record], columns: &BTreeSet<String>, zone: TimeZone) {
+    for row in rows {
+        for column in columns {
+            let Some(Value::Text(text)) = row.columns.get(column).map(|x| x.as_ref()) else {
+                continue;
+            };
+            if let Ok(secs) = parse_timestamp(text, TimeZone::UTC) {
+                let rendered = zone.render(secs);
+                row.columns
+                    .insert(column.clone(), Rc::new(Value::Text(rendered)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_zones() {
+        assert_eq!(TimeZone::parse("utc").unwrap(), TimeZone::UTC);
+        assert_eq!(TimeZone::parse("+02:00").unwrap().offset, 7200);
+        assert_eq!(TimeZone::parse("-0530").unwrap().offset, -19800);
+        assert_eq!(TimeZone::parse("+5").unwrap().offset, 18000);
+        assert!(TimeZone::parse("Europe/Berlin").is_err());
+        assert!(TimeZone::parse("+16").is_err());
+        assert!(TimeZone::parse("+02:60").is_err());
+        assert_eq!(TimeZone::parse("-05:30").unwrap().to_string(), "-05:30");
+        assert_eq!(TimeZone::UTC.to_string(), "UTC");
+    }
+
+    #[test]
+    fn timestamps() {
+        let berlin = TimeZone::parse("+01:00").unwrap();
+        assert_eq!(
+            parse_timestamp("2000-02-29 00:00:00", TimeZone::UTC).unwrap(),
+            951782400
+        );
+        assert_eq!(
+            to_utc("2000-02-29 01:00:00", berlin).unwrap(),
+            "2000-02-29 00:00:00"
+        );
+        assert_eq!(
+            to_utc("2000-02-29T05:30-04:30", berlin).unwrap(),
+            "2000-02-29 10:00:00"
+        );
+        assert_eq!(
+            to_utc("2000-03-01 00:00:00 UTC", berlin).unwrap(),
+            "2000-03-01 00:00:00"
+        );
+        assert_eq!(to_utc("2000-01-01", berlin).unwrap(), "1999-12-31 23:00:00");
+        assert!(parse_timestamp("1999-02-29 00:00:00", berlin).is_err());
+        assert!(parse_timestamp("2000-01-01 24:00:00", berlin).is_err());
+        assert!(parse_timestamp("2000-01-01 10:00:00.5", berlin).is_err());
+        assert!(parse_timestamp("yesterday", berlin).is_err());
+        assert_eq!(berlin.render(951782400), "2000-02-29 01:00:00+01:00");
+        assert_eq!(TimeZone::UTC.render(-1), "1969-12-31 23:59:59+00:00");
+    }
+}
@@ -0,0 +1,72 @@
+//! Row expiry. A table can name a column holding the time each of its rows expires, either as
+//! seconds since the unix epoch or as a `YYYY-MM-DD HH:MM:SS` UTC timestamp like `now()` returns.
+//!
+//! The compaction filter only sees keys and values, not which table they belong to, so when a row
+//! is written its expiry is copied into a hidden column the filter can find. Compaction only gets
+//! to rows eventually, so anything reading rows has to skip expired ones itself with
+//! [`is_expired`].
+use crate::functions::{format_timestamp, unix_now};
+use crate::keys;
+use crate::types::{Record, Value};
+use bigdecimal::ToPrimitive;
+use postcard::from_bytes;
+use rocksdb::compaction_filter::Decision;
+
+/// Hidden copy of the expiry column's value.
+pub const EXPIRES_COLUMN: &str = "__dechib_expires_at";
+/// Per table system state naming the expiry column.
+pub const TTL_KEY: &str = "ttl";
+
+/// Whether a row expired at or before `now`, in seconds since the unix epoch. Rows without an
+/// expiry never expire.
+pub fn is_expired(record: &Record, now: u64) -> bool {
+    match record.columns.get(EXPIRES_COLUMN).map(|x| x.as_ref()) {
+        Some(Value::Number(n)) => n.to_u64().is_some_and(|x| x <= now),
+        // The timestamp format sorts the same as the time it represents
+        Some(Value::Text(timestamp)) => *timestamp <= format_timestamp(now as i64),
+        _ => false,
+    }
+}
+
+pub(crate) fn compaction_filter(_level: u32, key: &[u8], value: &[u8]) -> Decision {
+    if keys::strip_data_prefix(key).is_none() {
+        return Decision::Keep;
+    }
+    match from_bytes::<Record>(value) {
+        Ok(record) if is_expired(&record, unix_now()) => Decision::Remove,
+        _ => Decision::Keep,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    fn record(expires: Value) -> Record {
+        Record {
+            columns: BTreeMap::from([(EXPIRES_COLUMN.to_string(), Rc::new(expires))]),
+        }
+    }
+
+    #[test]
+    fn expiry() {
+        let now = 951782400;
+        assert!(is_expired(&record(Value::Number(now.into())), now));
+        assert!(!is_expired(&record(Value::Number((now + 1).into())), now));
+        assert!(is_expired(
+            &record(Value::Text("2000-02-28 23:59:59".to_string())),
+            now
+        ));
+        assert!(!is_expired(
+            &record(Value::Text("2000-03-01 00:00:00".to_string())),
+            now
+        ));
+        assert!(!is_expired(&record(Value::Null), now));
+        let empty = Record {
+            columns: BTreeMap::new(),
+        };
+        assert!(!is_expired(&empty, now));
+    }
+}
@@ -1,17 +1,73 @@
+use crate::expr;
+use crate::timezone::TimeZone;
 use anyhow::Context;
-use bigdecimal::BigDecimal;
+use bigdecimal::{BigDecimal, ToPrimitive};
 use serde::{Deserialize, Serialize};
 use sqlparser::ast::{
-    self, ColumnOption, DataType, Expr, Insert, Query, SetExpr, Statement, TableConstraint,
+    self, AlterTableOperation, ColumnDef, ColumnOption, DataType, Delete, Expr, FromTable,
+    GroupByExpr, Ident, Insert, ObjectName, ObjectType, Query, SelectItem, SetExpr,
+    SqliteOnConflict, Statement, TableConstraint, TableFactor, TableWithJoins, UnaryOperator,
 };
 use std::collections::{BTreeMap, HashSet};
 use std::convert::TryFrom;
+use std::fmt;
 use std::rc::Rc;
+use std::time::Duration;
 use tracing::{debug, error, warn};
 
 pub type ColumnDescriptors = BTreeMap<String, ColumnDescriptor>;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Hidden primary key added to tables that don't declare one.
+pub const ROWID_COLUMN: &str = "__dechib_rowid";
+
+/// Unquoted identifiers are case insensitive and folded to lowercase like Postgres does, quoted
+/// identifiers keep their case exactly.
+pub fn normalize_ident(ident: &Ident) -> String {
+    match ident.quote_style {
+        Some(_) => ident.value.clone(),
+        None => ident.value.to_lowercase(),
+    }
+}
+
+pub fn normalize_object_name(name: &ObjectName) -> String {
+    name.0
+        .iter()
+        .map(normalize_ident)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+pub fn is_text_type(datatype: &DataType) -> bool {
+    matches!(
+        datatype,
+        DataType::Text
+            | DataType::Character(_)
+            | DataType::Char(_)
+            | DataType::CharacterVarying(_)
+            | DataType::Varchar(_)
+            | DataType::Nvarchar(_)
+    )
+}
+
+pub fn is_numeric_type(datatype: &DataType) -> bool {
+    matches!(
+        datatype,
+        DataType::Numeric(_)
+            | DataType::Decimal(_)
+            | DataType::Dec(_)
+            | DataType::Float(_)
+            | DataType::Int(_)
+            | DataType::UnsignedInt(_)
+            | DataType::Integer(_)
+            | DataType::UnsignedInteger(_)
+            | DataType::BigInt(_)
+            | DataType::UnsignedBigInt(_)
+            | DataType::Real
+            | DataType::Double
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Value {
     Text(String),
     Boolean(bool),
@@ -50,6 +106,25 @@ impl TryFrom<ast::Value> for Value {
     }
 }
 
+impl From<&Value> for ast::Value {
+    fn from(val: &Value) -> Self {
+        match val {
+            Value::Text(s) => ast::Value::SingleQuotedString(s.clone()),
+            Value::Boolean(b) => ast::Value::Boolean(*b),
+            Value::Number(n) => ast::Value::Number(n.clone(), false),
+            Value::Bytes(b) => ast::Value::HexStringLiteral(hex::encode(b)),
+            Value::Null => ast::Value::Null,
+        }
+    }
+}
+
+/// Formats the value as a SQL literal.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", ast::Value::from(self))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Record {
     pub columns: BTreeMap<String, Rc<Value>>,
@@ -64,10 +139,31 @@ pub struct ColumnDescriptor {
     pub auto_increment: bool,
     pub foreign_key: Option<(String, String)>,
     pub default: Option<Expr>,
-    // skipping check and create index as things I shalln't support (yet)
+    /// Value the column is set to whenever its row is updated, MySQL's `ON UPDATE`
+    pub on_update: Option<Expr>,
+    /// Values are stored as codes into the table's dictionary, see [`crate::dictionary`]
+    pub dictionary: bool,
+    /// What happens to rows referring through the column's foreign keys when the row they
+    /// refer to is deleted or its key changes
+    pub foreign_key_actions: ForeignKeyActions,
+    /// `CHECK` every value of the column has to pass, it can only refer to the column itself
+    pub check: Option<Expr>,
+    /// Values an auto increment column counts through
+    pub identity: Identity,
 }
 
 impl ColumnDescriptor {
+    pub fn rowid() -> Self {
+        Self {
+            datatype: DataType::UnsignedBigInt(None),
+            not_null: true,
+            unique: true,
+            primary_key: true,
+            auto_increment: true,
+            ..Default::default()
+        }
+    }
+
     pub fn needs_value(&self) -> bool {
         self.not_null && !(self.primary_key || self.auto_increment || self.default.is_some())
     }
@@ -76,31 +172,26 @@ impl ColumnDescriptor {
         self.auto_increment || self.default.is_some() || self.not_null
     }
 
+    /// Checks a value can be written to the column. NULL is only allowed in nullable columns,
+    /// which a primary key column never is.
+    pub fn check_value(&self, column: &str, value: &Value) -> anyhow::Result<()> {
+        if *value == Value::Null && (self.not_null || self.primary_key) {
+            anyhow::bail!("Column {} is NOT NULL", column);
+        }
+        if !self.value_matches_type(value) {
+            anyhow::bail!("Value for {} doesn't match column type", column);
+        }
+        Ok(())
+    }
+
     pub fn value_matches_type(&self, value: &Value) -> bool {
         match (value, &self.datatype) {
-            (
-                Value::Text(_),
-                DataType::Text
-                | DataType::Character(_)
-                | DataType::Char(_)
-                | DataType::CharacterVarying(_)
-                | DataType::Varchar(_)
-                | DataType::Nvarchar(_),
-            ) => true,
+            (Value::Text(_), ty) if is_text_type(ty) => true,
+            (Value::Text(_), DataType::Uuid | DataType::Timestamp(..) | DataType::Datetime(_)) => {
+                true
+            }
             (Value::Boolean(_), DataType::Bool | DataType::Boolean) => true,
-            (
-                Value::Number(_),
-                DataType::Numeric(_)
-                | DataType::Decimal(_)
-                | DataType::Dec(_)
-                | DataType::Float(_)
-                | DataType::Int(_)
-                | DataType::UnsignedInt(_)
-                | DataType::Integer(_)
-                | DataType::UnsignedInteger(_)
-                | DataType::Real
-                | DataType::Double,
-            ) => true,
+            (Value::Number(_), ty) if is_numeric_type(ty) => true,
             (Value::Bytes(_), DataType::Bytea | DataType::Blob(_) | DataType::Bytes(_)) => true,
             (Value::Null, _) if !self.not_null => true,
             (val, ty) => {
@@ -121,21 +212,589 @@ impl Default for ColumnDescriptor {
             primary_key: false,
             foreign_key: None,
             default: None,
+            on_update: None,
+            dictionary: false,
+            foreign_key_actions: ForeignKeyActions::default(),
+            check: None,
+            identity: Identity::default(),
+        }
+    }
+}
+
+/// The values of an auto increment column, set with
+/// `GENERATED BY DEFAULT AS IDENTITY (START WITH 10 INCREMENT BY 5 MAXVALUE 100)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub start: i64,
+    /// Negative to count down
+    pub increment: i64,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self {
+            start: 1,
+            increment: 1,
+            min: None,
+            max: None,
+        }
+    }
+}
+
+impl Identity {
+    fn parse(options: &[ast::SequenceOptions]) -> anyhow::Result<Self> {
+        let bound = |expr: &Expr| -> anyhow::Result<i64> {
+            let empty = Record {
+                columns: BTreeMap::new(),
+            };
+            match expr::evaluate(expr, &empty)?.as_ref() {
+                Value::Number(n) if n.is_integer() => n
+                    .to_i64()
+                    .with_context(|| format!("{} is out of range for an identity", n)),
+                v => anyhow::bail!("Identity options must be integers, got {}", v),
+            }
+        };
+        let mut res = Self::default();
+        for option in options {
+            match option {
+                ast::SequenceOptions::StartWith(expr, _) => res.start = bound(expr)?,
+                ast::SequenceOptions::IncrementBy(expr, _) => res.increment = bound(expr)?,
+                ast::SequenceOptions::MinValue(ast::MinMaxValue::Some(expr)) => {
+                    res.min = Some(bound(expr)?)
+                }
+                ast::SequenceOptions::MaxValue(ast::MinMaxValue::Some(expr)) => {
+                    res.max = Some(bound(expr)?)
+                }
+                ast::SequenceOptions::MinValue(_) | ast::SequenceOptions::MaxValue(_) => {}
+                option => {
+                    anyhow::bail!("Unsupported identity option {}", option.to_string().trim())
+                }
+            }
+        }
+        if res.increment == 0 {
+            anyhow::bail!("INCREMENT BY can't be 0");
+        }
+        if !res.contains(res.start) {
+            anyhow::bail!("START WITH {} is outside MINVALUE and MAXVALUE", res.start);
+        }
+        Ok(res)
+    }
+
+    fn contains(&self, value: i64) -> bool {
+        !(self.min.is_some_and(|min| value < min) || self.max.is_some_and(|max| value > max))
+    }
+
+    /// The `n`th value, counting from 1. Errors once the values run past MINVALUE or MAXVALUE,
+    /// or out of the range of a 64 bit integer.
+    pub fn value(&self, column: &str, n: usize) -> anyhow::Result<i64> {
+        let value = n
+            .checked_sub(1)
+            .and_then(|x| i64::try_from(x).ok())
+            .and_then(|x| x.checked_mul(self.increment))
+            .and_then(|x| x.checked_add(self.start))
+            .filter(|x| self.contains(*x));
+        value.with_context(|| format!("Identity column {} has run out of values", column))
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GENERATED BY DEFAULT AS IDENTITY (START WITH {} INCREMENT BY {}",
+            self.start, self.increment
+        )?;
+        if let Some(min) = self.min {
+            write!(f, " MINVALUE {}", min)?;
+        }
+        if let Some(max) = self.max {
+            write!(f, " MAXVALUE {}", max)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// A foreign key's `ON DELETE` or `ON UPDATE` action.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReferentialAction {
+    /// Fail the statement, the default
+    #[default]
+    NoAction,
+    /// Fail the statement, which without deferred constraints is the same as `NoAction`
+    Restrict,
+    /// Delete the referring rows, or update them to the new key
+    Cascade,
+    /// Set the referring column to NULL
+    SetNull,
+}
+
+impl TryFrom<&ast::ReferentialAction> for ReferentialAction {
+    type Error = anyhow::Error;
+
+    fn try_from(action: &ast::ReferentialAction) -> Result<Self, Self::Error> {
+        Ok(match action {
+            ast::ReferentialAction::NoAction => Self::NoAction,
+            ast::ReferentialAction::Restrict => Self::Restrict,
+            ast::ReferentialAction::Cascade => Self::Cascade,
+            ast::ReferentialAction::SetNull => Self::SetNull,
+            ast::ReferentialAction::SetDefault => anyhow::bail!("SET DEFAULT is not supported"),
+        })
+    }
+}
+
+impl fmt::Display for ReferentialAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Self::NoAction => "NO ACTION",
+            Self::Restrict => "RESTRICT",
+            Self::Cascade => "CASCADE",
+            Self::SetNull => "SET NULL",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForeignKeyActions {
+    pub on_delete: ReferentialAction,
+    pub on_update: ReferentialAction,
+}
+
+impl ForeignKeyActions {
+    fn parse(
+        on_delete: &Option<ast::ReferentialAction>,
+        on_update: &Option<ast::ReferentialAction>,
+    ) -> anyhow::Result<Self> {
+        let action = |action: &Option<ast::ReferentialAction>| {
+            action
+                .as_ref()
+                .map(ReferentialAction::try_from)
+                .transpose()
+                .map(Option::unwrap_or_default)
+        };
+        Ok(Self {
+            on_delete: action(on_delete)?,
+            on_update: action(on_update)?,
+        })
+    }
+}
+
+/// A non fatal issue found while running a statement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The table has no primary key so its rows are keyed by a hidden rowid
+    ImplicitRowid { table: String },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ImplicitRowid { table } => write!(
+                f,
+                "Table {} has no primary key, rows are keyed by a hidden rowid",
+                table
+            ),
+        }
+    }
+}
+
+/// Error for a statement that ran longer than `statement_timeout`, along with how far it got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementTimeout {
+    pub timeout: Duration,
+    pub elapsed: Duration,
+    /// Rows the statement had read or written when it was stopped
+    pub rows: usize,
+}
+
+impl fmt::Display for StatementTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Statement timed out after {:?} (limit {:?}) having processed {} rows",
+            self.elapsed, self.timeout, self.rows
+        )
+    }
+}
+
+impl std::error::Error for StatementTimeout {}
+
+/// Error for an `UPDATE` or `DELETE` stopped by `sql_safe_updates` or `safe_updates_limit`, along
+/// with how many rows it would have changed. [`crate::Instance::execute_forced`] runs it anyway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsafeWrite {
+    pub table: String,
+    pub rows: usize,
+    /// The limit the statement went over, `None` when it was stopped for having no `WHERE` clause
+    pub limit: Option<usize>,
+}
+
+impl fmt::Display for UnsafeWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.limit {
+            None => write!(
+                f,
+                "Statement would change {} rows of {} without a WHERE clause while \
+                 sql_safe_updates is on",
+                self.rows, self.table
+            ),
+            Some(limit) => write!(
+                f,
+                "Statement would change {} rows of {}, more than safe_updates_limit ({})",
+                self.rows, self.table, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UnsafeWrite {}
+
+/// Error for an insert that would take a table over its quota.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub table: String,
+    /// Estimated bytes the table took up before the insert
+    pub used: u64,
+    /// Bytes the insert would have added
+    pub adding: u64,
+    pub quota: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Insert would take {} to {} bytes, more than its quota of {}",
+            self.table,
+            self.used + self.adding,
+            self.quota
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Passed to the hook set with [`crate::Instance::set_quota_hook`] when a write takes a table
+/// past one of [`crate::config::StorageConfig::quota_thresholds`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub table: String,
+    /// Estimated bytes the table takes up
+    pub used: u64,
+    pub quota: u64,
+    /// The percentage of the quota that was crossed
+    pub threshold: u8,
+}
+
+/// A session setting changed with `SET`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Variable {
+    /// Statements running longer than this fail, `None` for no limit. Like Postgres a bare number
+    /// is milliseconds and `0` turns the limit off.
+    StatementTimeout(Option<Duration>),
+    /// Time zone timestamps without an offset are in and zoned timestamps are rendered in, see
+    /// [`crate::timezone`]. `SET TIME ZONE` is the same setting.
+    TimeZone(TimeZone),
+    /// `UPDATE` and `DELETE` without a `WHERE` clause fail with [`UnsafeWrite`], like MySQL.
+    SafeUpdates(bool),
+    /// `UPDATE` and `DELETE` that would change more rows than this fail with [`UnsafeWrite`],
+    /// `None` for no limit. `0` turns the limit off.
+    SafeUpdatesLimit(Option<usize>),
+}
+
+impl Variable {
+    fn parse(name: &str, value: &Expr) -> anyhow::Result<Self> {
+        match name {
+            "statement_timeout" => Ok(Self::StatementTimeout(parse_timeout(value)?)),
+            "timezone" => Ok(Self::TimeZone(parse_time_zone(value)?)),
+            "sql_safe_updates" => Ok(Self::SafeUpdates(parse_flag(value)?)),
+            "safe_updates_limit" => Ok(Self::SafeUpdatesLimit(parse_limit(value)?)),
+            _ => anyhow::bail!("Unknown setting {}", name),
+        }
+    }
+}
+
+/// `ON`, `1` and `TRUE` or `OFF`, `0` and `FALSE`, quoted or not.
+fn parse_flag(value: &Expr) -> anyhow::Result<bool> {
+    let text = match value {
+        Expr::Value(ast::Value::SingleQuotedString(s)) => s.clone(),
+        e => e.to_string(),
+    };
+    match text.to_lowercase().as_str() {
+        "on" | "1" | "true" => Ok(true),
+        "off" | "0" | "false" | "default" => Ok(false),
+        _ => anyhow::bail!("Invalid value {}, expected ON or OFF", text),
+    }
+}
+
+fn parse_limit(value: &Expr) -> anyhow::Result<Option<usize>> {
+    match value {
+        Expr::Value(ast::Value::Number(n, _)) => {
+            let limit = n
+                .to_usize()
+                .with_context(|| format!("Invalid limit {}", n))?;
+            Ok(Some(limit).filter(|x| *x > 0))
         }
+        Expr::Identifier(ident) if ident.value.eq_ignore_ascii_case("default") => Ok(None),
+        e => anyhow::bail!("Invalid limit {}", e),
     }
 }
 
+/// Like Postgres a bare number is an offset in hours.
+fn parse_time_zone(value: &Expr) -> anyhow::Result<TimeZone> {
+    match value {
+        Expr::Value(ast::Value::SingleQuotedString(s)) => TimeZone::parse(s),
+        Expr::Identifier(ident)
+            if ident.value.eq_ignore_ascii_case("default")
+                || ident.value.eq_ignore_ascii_case("local") =>
+        {
+            Ok(TimeZone::UTC)
+        }
+        Expr::Identifier(ident) => TimeZone::parse(&ident.value),
+        Expr::Value(ast::Value::Number(..)) | Expr::UnaryOp { .. } => {
+            let hours = value
+                .to_string()
+                .parse::<BigDecimal>()
+                .with_context(|| format!("Invalid time zone {}", value))?;
+            let seconds = (hours * BigDecimal::from(3600))
+                .to_i32()
+                .context("Time zone offset out of range")?;
+            TimeZone::from_offset(seconds)
+        }
+        e => anyhow::bail!("Invalid time zone {}", e),
+    }
+}
+
+fn parse_timeout(value: &Expr) -> anyhow::Result<Option<Duration>> {
+    let (number, unit) = match value {
+        Expr::Value(ast::Value::Number(n, _)) => (n.clone(), "ms"),
+        Expr::Value(ast::Value::SingleQuotedString(s)) => {
+            let s = s.trim();
+            let split = s
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(s.len());
+            let number = s[..split]
+                .parse::<BigDecimal>()
+                .with_context(|| format!("Invalid timeout {}", s))?;
+            (number, s[split..].trim())
+        }
+        Expr::Identifier(ident) if ident.value.eq_ignore_ascii_case("default") => return Ok(None),
+        e => anyhow::bail!("Invalid timeout {}", e),
+    };
+    let micros_per_unit: u64 = match unit {
+        "us" => 1,
+        "" | "ms" => 1_000,
+        "s" => 1_000_000,
+        "min" => 60_000_000,
+        "h" => 3_600_000_000,
+        "d" => 86_400_000_000,
+        _ => anyhow::bail!("Invalid timeout unit {}", unit),
+    };
+    let micros = (number * BigDecimal::from(micros_per_unit))
+        .to_u64()
+        .context("Timeout out of range")?;
+    Ok(Some(Duration::from_micros(micros)).filter(|x| !x.is_zero()))
+}
+
+/// The outcome of a successful query.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryResult {
+    pub warnings: Vec<Warning>,
+    /// Rows returned by the last `SELECT` or `RETURNING` clause
+    pub rows: Vec<Record>,
+    /// Rows inserted, updated or deleted by the query
+    pub rows_affected: usize,
+}
+
 #[derive(Clone, Debug)]
 pub enum Command {
     CreateTable(CreateTableOptions),
+    CloneTable(CloneTableOptions),
+    AlterTable(AlterTableOptions),
+    DropTable(DropTableOptions),
+    CreateIndex(CreateIndexOptions),
+    DropIndex(DropIndexOptions),
+    Reindex(Reindex),
     Insert(InsertOptions),
+    Update(UpdateOptions),
+    Delete(DeleteOptions),
     Select(QueryOptions),
+    Set(Variable),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CreateTableOptions {
     pub name: String,
     pub columns: ColumnDescriptors,
+    /// Column holding the time each row expires, `WITH (ttl_column = '<column>')`
+    pub ttl_column: Option<String>,
+    /// Most bytes the table can take up, `WITH (max_bytes = <bytes>)`. See
+    /// [`crate::storage_engine::StorageEngine::set_quota`].
+    pub max_bytes: Option<u64>,
+    /// Constraints declared with a name, unnamed ones are kept on their column
+    pub constraints: Vec<Constraint>,
+    /// Primary key columns in the order they were declared, which is the order their values
+    /// make up row keys in. When empty the columns marked as the primary key are used.
+    pub primary_key: Vec<String>,
+}
+
+/// A named table constraint. Like column constraints these are checked against the rows already
+/// in the table when added, and against every row written after.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Constraint {
+    pub name: String,
+    pub kind: ConstraintKind,
+    /// `false` for a constraint added `NOT VALID`, the rows that were already in the table
+    /// haven't been checked against it
+    pub validated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConstraintKind {
+    Unique {
+        column: String,
+    },
+    /// One column referring to a single column primary key. Foreign keys made of several columns,
+    /// which a composite primary key would need, aren't supported yet.
+    ForeignKey {
+        column: String,
+        table: String,
+        referred: String,
+    },
+    /// `column` is the first column the expression refers to
+    Check {
+        column: String,
+        expr: Expr,
+    },
+}
+
+impl ConstraintKind {
+    pub fn column(&self) -> &str {
+        match self {
+            Self::Unique { column }
+            | Self::ForeignKey { column, .. }
+            | Self::Check { column, .. } => column,
+        }
+    }
+
+    /// Whether the constraint involves the column, a check can refer to several.
+    pub fn refers_to(&self, column: &str) -> bool {
+        match self {
+            Self::Check { expr, .. } => expr::columns(expr).iter().any(|x| x == column),
+            kind => kind.column() == column,
+        }
+    }
+
+    /// The name Postgres would give the constraint if it wasn't named.
+    pub fn default_name(&self, table: &str) -> String {
+        match self {
+            Self::Unique { column } => format!("{}_{}_key", table, column),
+            Self::ForeignKey { column, .. } => format!("{}_{}_fkey", table, column),
+            Self::Check { column, .. } => format!("{}_{}_check", table, column),
+        }
+    }
+
+    fn check(expr: &Expr) -> anyhow::Result<Self> {
+        let Some(column) = expr::columns(expr).into_iter().next() else {
+            anyhow::bail!("CHECK ({}) doesn't refer to any column", expr);
+        };
+        Ok(Self::Check {
+            column,
+            expr: expr.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlterTableOptions {
+    pub name: String,
+    pub operation: AlterOperation,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AlterOperation {
+    AddConstraint(Constraint),
+    DropConstraint {
+        name: String,
+        if_exists: bool,
+    },
+    /// Existing rows are given the column's default, so a `NOT NULL` column without one can only
+    /// be added to an empty table
+    AddColumn {
+        column: String,
+        descriptor: ColumnDescriptor,
+        /// Named constraints declared on the column
+        constraints: Vec<Constraint>,
+        if_not_exists: bool,
+    },
+    /// Named constraints and indexes on the column are dropped along with it
+    DropColumn {
+        column: String,
+        if_exists: bool,
+    },
+    /// Foreign keys in other tables that refer to the table follow it to the new name
+    RenameTable {
+        to: String,
+    },
+    /// Constraints, indexes and foreign keys on the column follow it to the new name
+    RenameColumn {
+        column: String,
+        to: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DropTableOptions {
+    pub names: Vec<String>,
+    pub if_exists: bool,
+    /// Drop foreign keys in other tables that refer to the dropped tables rather than failing
+    pub cascade: bool,
+}
+
+/// A secondary index over one or more columns. Entries are kept in the order of the columns'
+/// values, so rows matching equalities on the leading columns and a range on the next one are
+/// next to each other.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Index {
+    pub name: String,
+    /// Columns, or expressions of them like `lower(email)`, evaluated for every row and
+    /// [`expr::normalized`] so they compare equal to the same expression in a query
+    pub columns: Vec<Expr>,
+    /// Only rows matching this are indexed, `CREATE INDEX ... WHERE <predicate>`
+    pub predicate: Option<Expr>,
+}
+
+/// `CREATE INDEX [<name>] ON <table> (<columns>) [WHERE <predicate>]`. Index names are unique
+/// across the database.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreateIndexOptions {
+    pub table: String,
+    pub index: Index,
+    pub if_not_exists: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DropIndexOptions {
+    pub names: Vec<String>,
+    pub if_exists: bool,
+}
+
+/// `REINDEX INDEX <name>` or `REINDEX TABLE <name>`, rebuilding indexes from the rows they
+/// index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reindex {
+    Index(String),
+    /// Every index of the table, unique indexes included
+    Table(String),
+}
+
+/// `CREATE TABLE <name> CLONE <source>`, a new table with the same columns and rows.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CloneTableOptions {
+    pub name: String,
+    pub source: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -143,11 +802,56 @@ pub struct InsertOptions {
     pub table: String,
     pub columns: Vec<String>,
     pub values: Vec<Vec<Rc<Value>>>,
+    pub returning: Option<Returning>,
+    /// Replace rows whose primary key is already taken instead of failing, from
+    /// `INSERT OR REPLACE` or `REPLACE INTO`
+    pub upsert: bool,
+}
+
+/// Columns of the rows a write changed to return, `RETURNING *` or a list of columns.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Returning {
+    All,
+    Columns(Vec<String>),
+}
+
+/// How a query expects to read its table, given as a table hint like
+/// `SELECT * FROM t WITH (SEQUENTIAL)`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScanHint {
+    /// Let rocksdb size its readahead from how the scan goes
+    #[default]
+    Auto,
+    /// The whole table is read once, so read far ahead and keep its blocks out of the block cache
+    Sequential,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct QueryOptions {
-    //TODO maybe I just want to keep the query type
+    /// Only `SELECT * FROM <table> [WHERE ...]` is supported so far
+    pub table: String,
+    pub filter: Option<Expr>,
+    pub scan: ScanHint,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateOptions {
+    pub table: String,
+    /// Columns and the expressions they're set to, evaluated against the row before the update
+    pub assignments: Vec<(String, Expr)>,
+    /// Rows matching the `WHERE` clause, every row without one
+    pub filter: Option<Expr>,
+    /// Returns the rows as they are after the update
+    pub returning: Option<Returning>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeleteOptions {
+    pub table: String,
+    /// Rows matching the `WHERE` clause, every row without one
+    pub filter: Option<Expr>,
+    /// Returns the rows as they were before they were deleted
+    pub returning: Option<Returning>,
 }
 
 impl InsertOptions {
@@ -175,103 +879,87 @@ impl TryFrom<&Statement> for Command {
         match statement {
             Statement::CreateTable {
                 name,
+                clone: Some(source),
                 columns,
                 constraints,
                 ..
             } => {
+                if !columns.is_empty() || !constraints.is_empty() {
+                    anyhow::bail!("A cloned table can't declare columns");
+                }
+                Ok(Command::CloneTable(CloneTableOptions {
+                    name: normalize_object_name(name),
+                    source: normalize_object_name(source),
+                }))
+            }
+            Statement::CreateTable {
+                name,
+                columns,
+                constraints,
+                with_options,
+                ..
+            } => {
+                let mut ttl_column = None;
+                let mut max_bytes = None;
+                let mut dictionary = vec![];
+                for option in with_options {
+                    match (option.name.value.to_lowercase().as_str(), &option.value) {
+                        ("ttl_column", Expr::Value(ast::Value::SingleQuotedString(column))) => {
+                            ttl_column = Some(column.clone());
+                        }
+                        ("max_bytes", Expr::Value(ast::Value::Number(n, _))) => {
+                            let bytes = n
+                                .to_u64()
+                                .with_context(|| format!("Invalid max_bytes {}", n))?;
+                            max_bytes = Some(bytes);
+                        }
+                        ("dictionary", Expr::Value(ast::Value::SingleQuotedString(columns))) => {
+                            dictionary.extend(columns.split(',').map(|x| x.trim().to_string()));
+                        }
+                        _ => anyhow::bail!("Unsupported table option {}", option),
+                    }
+                }
+
+                let table = normalize_object_name(name);
                 let mut descriptor = BTreeMap::new();
+                let mut named = vec![];
+                let mut primary_key = vec![];
                 for col in columns {
-                    let entry = descriptor.entry(col.name.to_string()).or_insert_with(|| {
-                        ColumnDescriptor {
-                            datatype: col.data_type.clone(),
-                            ..Default::default()
-                        }
-                    });
+                    let column = normalize_ident(&col.name);
+                    let entry =
+                        descriptor
+                            .entry(column.clone())
+                            .or_insert_with(|| ColumnDescriptor {
+                                datatype: col.data_type.clone(),
+                                ..Default::default()
+                            });
 
-                    for opt in &col.options {
-                        if opt.name.is_some() {
-                            // Of course we want a database to do the wrong thing if it gets
-                            // something unexpected :clown_face:
-                            warn!("Unhandled named constraint: {:?}", opt.name);
-                        }
-                        match &opt.option {
-                            ColumnOption::NotNull => {
-                                entry.not_null = true;
-                            }
-                            ColumnOption::Default(e) => {
-                                entry.default = Some(e.clone());
-                            }
-                            ColumnOption::Unique { is_primary, .. } => {
-                                entry.primary_key = *is_primary;
-                                entry.unique = true;
-                            }
-                            ColumnOption::ForeignKey {
-                                foreign_table,
-                                referred_columns,
-                                ..
-                            } => {
-                                if referred_columns.len() != 1 {
-                                    anyhow::bail!(
-                                        "Exactly one column must be specified for a foreign key"
-                                    );
-                                }
-                                entry.foreign_key = Some((
-                                    foreign_table.to_string(),
-                                    referred_columns[0].to_string(),
-                                ));
-                            }
-                            ColumnOption::Check(_) => anyhow::bail!("CHECK not yet supported"),
-                            ColumnOption::OnUpdate(_) => {
-                                anyhow::bail!("ON UPDATE not yet supported")
-                            }
-                            ColumnOption::Generated { .. } => {
-                                anyhow::bail!("GENERATED not yet supported")
-                            }
-                            ColumnOption::Null
-                            | ColumnOption::DialectSpecific(_)
-                            | ColumnOption::CharacterSet(_)
-                            | ColumnOption::Comment(_)
-                            | ColumnOption::Options(_) => {}
+                    apply_column_options(col, entry, &mut named)?;
+                    if entry.primary_key {
+                        if !primary_key.is_empty() {
+                            anyhow::bail!(
+                                "Multiple primary keys for table {} are not allowed",
+                                table
+                            );
                         }
+                        primary_key.push(column);
                     }
                 }
 
                 for constraint in constraints {
                     match constraint {
-                        TableConstraint::ForeignKey {
-                            name,
-                            columns,
-                            foreign_table,
-                            referred_columns,
-                            ..
-                        } => {
-                            if columns.len() != 1 {
+                        TableConstraint::PrimaryKey { columns, .. } => {
+                            if !primary_key.is_empty() {
                                 anyhow::bail!(
-                                    "Exactly one column must be specified for a foreign key"
+                                    "Multiple primary keys for table {} are not allowed",
+                                    table
                                 );
                             }
-                            let name = columns[0].to_string();
-                            if let Some(column_def) = descriptor.get_mut(&name) {
-                                if referred_columns.len() != 1 {
-                                    anyhow::bail!(
-                                        "Exactly one column must be specified for a foreign key"
-                                    );
-                                }
-                                column_def.foreign_key = Some((
-                                    foreign_table.to_string(),
-                                    referred_columns[0].to_string(),
-                                ));
-                            } else {
-                                anyhow::bail!("Specified foreign key column does not exist");
-                            }
-                        }
-                        TableConstraint::Check { .. } => {
-                            anyhow::bail!("Check constraints not supported")
-                        }
-                        TableConstraint::PrimaryKey { columns, .. } => {
                             for col in columns {
-                                if let Some(entry) = descriptor.get_mut(&col.to_string()) {
+                                let column = normalize_ident(col);
+                                if let Some(entry) = descriptor.get_mut(&column) {
                                     entry.primary_key = true;
+                                    primary_key.push(column);
                                 } else {
                                     anyhow::bail!(
                                         "Primary key constraint applied to not existing column: {}",
@@ -280,17 +968,247 @@ impl TryFrom<&Statement> for Command {
                                 }
                             }
                         }
-                        e => anyhow::bail!("MySQL constraint: {} is not supported", e),
+                        constraint => {
+                            let (name, kind, actions) = table_constraint(constraint)?;
+                            let Some(entry) = descriptor.get_mut(kind.column()) else {
+                                anyhow::bail!("Constraint column {} does not exist", kind.column());
+                            };
+                            if let Some(actions) = actions {
+                                entry.foreign_key_actions = actions;
+                            }
+                            match (name, kind) {
+                                (Some(name), kind) => named.push(Constraint {
+                                    name,
+                                    kind,
+                                    validated: true,
+                                }),
+                                (None, ConstraintKind::Unique { .. }) => entry.unique = true,
+                                (None, kind @ ConstraintKind::Check { .. }) => {
+                                    named.push(Constraint {
+                                        name: kind.default_name(&table),
+                                        kind,
+                                        validated: true,
+                                    })
+                                }
+                                (
+                                    None,
+                                    ConstraintKind::ForeignKey {
+                                        table, referred, ..
+                                    },
+                                ) => {
+                                    entry.foreign_key = Some((table, referred));
+                                }
+                            }
+                        }
                     }
                 }
 
+                for column in dictionary {
+                    let Some(entry) = descriptor.get_mut(&column) else {
+                        anyhow::bail!("Dictionary column {} does not exist", column);
+                    };
+                    entry.dictionary = true;
+                }
+
                 Ok(Command::CreateTable(CreateTableOptions {
-                    name: name.to_string(),
+                    name: table,
                     columns: descriptor,
+                    ttl_column,
+                    max_bytes,
+                    constraints: named,
+                    primary_key,
+                }))
+            }
+            Statement::AlterTable {
+                name, operations, ..
+            } => {
+                let [operation] = operations.as_slice() else {
+                    anyhow::bail!("Only one ALTER TABLE operation per statement is supported");
+                };
+                let table = normalize_object_name(name);
+                let operation = match operation {
+                    AlterTableOperation::AddConstraint(constraint) => {
+                        let (name, kind, actions) = table_constraint(constraint)?;
+                        if actions.is_some_and(|actions| actions != ForeignKeyActions::default()) {
+                            anyhow::bail!(
+                                "ON DELETE and ON UPDATE can only be given when a column is created"
+                            );
+                        }
+                        AlterOperation::AddConstraint(Constraint {
+                            name: name.unwrap_or_else(|| kind.default_name(&table)),
+                            kind,
+                            validated: true,
+                        })
+                    }
+                    AlterTableOperation::DropConstraint {
+                        name, if_exists, ..
+                    } => AlterOperation::DropConstraint {
+                        name: normalize_ident(name),
+                        if_exists: *if_exists,
+                    },
+                    AlterTableOperation::AddColumn {
+                        if_not_exists,
+                        column_def,
+                        ..
+                    } => {
+                        let mut descriptor = ColumnDescriptor {
+                            datatype: column_def.data_type.clone(),
+                            ..Default::default()
+                        };
+                        let mut constraints = vec![];
+                        apply_column_options(column_def, &mut descriptor, &mut constraints)?;
+                        AlterOperation::AddColumn {
+                            column: normalize_ident(&column_def.name),
+                            descriptor,
+                            constraints,
+                            if_not_exists: *if_not_exists,
+                        }
+                    }
+                    AlterTableOperation::DropColumn {
+                        column_name,
+                        if_exists,
+                        ..
+                    } => AlterOperation::DropColumn {
+                        column: normalize_ident(column_name),
+                        if_exists: *if_exists,
+                    },
+                    AlterTableOperation::RenameTable { table_name } => {
+                        AlterOperation::RenameTable {
+                            to: normalize_object_name(table_name),
+                        }
+                    }
+                    AlterTableOperation::RenameColumn {
+                        old_column_name,
+                        new_column_name,
+                    } => AlterOperation::RenameColumn {
+                        column: normalize_ident(old_column_name),
+                        to: normalize_ident(new_column_name),
+                    },
+                    e => anyhow::bail!("Unsupported ALTER TABLE operation: {}", e),
+                };
+                Ok(Command::AlterTable(AlterTableOptions {
+                    name: table,
+                    operation,
+                }))
+            }
+            Statement::Drop {
+                object_type: ObjectType::Table,
+                if_exists,
+                names,
+                cascade,
+                ..
+            } => Ok(Command::DropTable(DropTableOptions {
+                names: names.iter().map(normalize_object_name).collect(),
+                if_exists: *if_exists,
+                cascade: *cascade,
+            })),
+            Statement::Drop {
+                object_type: ObjectType::Index,
+                if_exists,
+                names,
+                ..
+            } => Ok(Command::DropIndex(DropIndexOptions {
+                names: names.iter().map(normalize_object_name).collect(),
+                if_exists: *if_exists,
+            })),
+            Statement::CreateIndex {
+                name,
+                table_name,
+                using,
+                columns,
+                unique,
+                if_not_exists,
+                include,
+                predicate,
+                ..
+            } => {
+                if *unique {
+                    anyhow::bail!("CREATE UNIQUE INDEX is not supported, use a UNIQUE constraint");
+                }
+                if using.is_some() || !include.is_empty() {
+                    anyhow::bail!(
+                        "Only CREATE INDEX [<name>] ON <table> (<columns>) [WHERE ...] is supported"
+                    );
+                }
+                let table = normalize_object_name(table_name);
+                let columns = columns
+                    .iter()
+                    .map(|x| {
+                        if x.asc == Some(false) || x.nulls_first.is_some() {
+                            anyhow::bail!("Index columns can only be in ascending order");
+                        }
+                        Ok(expr::normalized(&x.expr))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                // Postgres names an unnamed index after its table and columns, or the functions
+                // it calls
+                let name = match name {
+                    Some(name) => normalize_object_name(name),
+                    None => {
+                        let parts = columns
+                            .iter()
+                            .map(|x| match x {
+                                Expr::Function(function) => normalize_object_name(&function.name),
+                                x => expr::column_name(x).unwrap_or_else(|| "expr".to_string()),
+                            })
+                            .collect::<Vec<_>>();
+                        format!("{}_{}_idx", table, parts.join("_"))
+                    }
+                };
+                Ok(Command::CreateIndex(CreateIndexOptions {
+                    table,
+                    index: Index {
+                        name,
+                        columns,
+                        predicate: predicate.clone(),
+                    },
+                    if_not_exists: *if_not_exists,
                 }))
             }
+            Statement::SetVariable {
+                variable, value, ..
+            } => {
+                let [value] = value.as_slice() else {
+                    anyhow::bail!("SET takes a single value");
+                };
+                Ok(Command::Set(Variable::parse(
+                    &normalize_object_name(variable),
+                    value,
+                )?))
+            }
+            Statement::SetTimeZone { value, .. } => {
+                Ok(Command::Set(Variable::TimeZone(parse_time_zone(value)?)))
+            }
             Statement::Insert(insert) => process_insert(insert),
             Statement::Query(query) => process_query(query),
+            Statement::Update {
+                table,
+                assignments,
+                from,
+                selection,
+                returning,
+                ..
+            } => {
+                if from.is_some() {
+                    anyhow::bail!("Only UPDATE <table> SET ... [WHERE ...] is supported");
+                }
+                let table = single_table(std::slice::from_ref(table))
+                    .context("Rows must be updated in exactly one table")?;
+                let assignments = assignments
+                    .iter()
+                    .map(|x| {
+                        let column = x.id.last().context("Assignment without a column")?;
+                        Ok((normalize_ident(column), x.value.clone()))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Command::Update(UpdateOptions {
+                    table,
+                    assignments,
+                    filter: selection.clone(),
+                    returning: process_returning(returning)?,
+                }))
+            }
+            Statement::Delete(delete) => process_delete(delete),
             e => {
                 anyhow::bail!("Unsupported Statement: {}", e);
             }
@@ -298,12 +1216,266 @@ impl TryFrom<&Statement> for Command {
     }
 }
 
+/// Applies a column definition's options to its descriptor. Options that can be named and are
+/// given a name become constraints on the table rather than living on the column.
+fn apply_column_options(
+    col: &ColumnDef,
+    entry: &mut ColumnDescriptor,
+    named: &mut Vec<Constraint>,
+) -> anyhow::Result<()> {
+    for opt in &col.options {
+        if let Some(name) = &opt.name {
+            if let Some(kind) = column_constraint(&col.name, &opt.option)? {
+                named.push(Constraint {
+                    name: normalize_ident(name),
+                    kind,
+                    validated: true,
+                });
+                if let ColumnOption::ForeignKey {
+                    on_delete,
+                    on_update,
+                    ..
+                } = &opt.option
+                {
+                    entry.foreign_key_actions = ForeignKeyActions::parse(on_delete, on_update)?;
+                }
+                continue;
+            }
+            // Of course we want a database to do the wrong thing if it gets
+            // something unexpected :clown_face:
+            warn!("Unhandled named constraint: {:?}", opt.name);
+        }
+        match &opt.option {
+            ColumnOption::NotNull => {
+                entry.not_null = true;
+            }
+            ColumnOption::Default(e) => {
+                entry.default = Some(e.clone());
+            }
+            ColumnOption::Unique { is_primary, .. } => {
+                entry.primary_key = *is_primary;
+                entry.unique = true;
+            }
+            ColumnOption::ForeignKey {
+                foreign_table,
+                referred_columns,
+                on_delete,
+                on_update,
+                ..
+            } => {
+                if referred_columns.len() != 1 {
+                    anyhow::bail!("Exactly one column must be specified for a foreign key");
+                }
+                entry.foreign_key = Some((
+                    normalize_object_name(foreign_table),
+                    normalize_ident(&referred_columns[0]),
+                ));
+                entry.foreign_key_actions = ForeignKeyActions::parse(on_delete, on_update)?;
+            }
+            ColumnOption::Check(e) => {
+                entry.check = Some(e.clone());
+            }
+            ColumnOption::OnUpdate(e) => {
+                entry.on_update = Some(e.clone());
+            }
+            ColumnOption::Generated {
+                sequence_options,
+                generation_expr: None,
+                ..
+            } => {
+                entry.auto_increment = true;
+                entry.identity = Identity::parse(sequence_options.as_deref().unwrap_or_default())?;
+            }
+            ColumnOption::Generated { .. } => {
+                anyhow::bail!("Generated columns are not yet supported")
+            }
+            ColumnOption::Null
+            | ColumnOption::DialectSpecific(_)
+            | ColumnOption::CharacterSet(_)
+            | ColumnOption::Comment(_)
+            | ColumnOption::Options(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// A column option that can be given a name, returned as a constraint on the column.
+fn column_constraint(
+    column: &Ident,
+    option: &ColumnOption,
+) -> anyhow::Result<Option<ConstraintKind>> {
+    let column = normalize_ident(column);
+    let kind = match option {
+        ColumnOption::Unique {
+            is_primary: false, ..
+        } => ConstraintKind::Unique { column },
+        ColumnOption::ForeignKey {
+            foreign_table,
+            referred_columns,
+            ..
+        } => {
+            let [referred] = referred_columns.as_slice() else {
+                anyhow::bail!("Exactly one column must be specified for a foreign key");
+            };
+            ConstraintKind::ForeignKey {
+                column,
+                table: normalize_object_name(foreign_table),
+                referred: normalize_ident(referred),
+            }
+        }
+        ColumnOption::Check(expr) => ConstraintKind::check(expr)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(kind))
+}
+
+/// A table level `UNIQUE`, `FOREIGN KEY` or `CHECK` constraint along with its name if it was given one,
+/// and a foreign key's actions.
+fn table_constraint(
+    constraint: &TableConstraint,
+) -> anyhow::Result<(Option<String>, ConstraintKind, Option<ForeignKeyActions>)> {
+    match constraint {
+        TableConstraint::Unique { name, columns, .. } => {
+            let [column] = columns.as_slice() else {
+                anyhow::bail!("Exactly one column must be specified for a unique constraint");
+            };
+            let kind = ConstraintKind::Unique {
+                column: normalize_ident(column),
+            };
+            Ok((name.as_ref().map(normalize_ident), kind, None))
+        }
+        TableConstraint::ForeignKey {
+            name,
+            columns,
+            foreign_table,
+            referred_columns,
+            on_delete,
+            on_update,
+            ..
+        } => {
+            let ([column], [referred]) = (columns.as_slice(), referred_columns.as_slice()) else {
+                anyhow::bail!("Foreign keys on several columns aren't supported yet");
+            };
+            let kind = ConstraintKind::ForeignKey {
+                column: normalize_ident(column),
+                table: normalize_object_name(foreign_table),
+                referred: normalize_ident(referred),
+            };
+            let actions = ForeignKeyActions::parse(on_delete, on_update)?;
+            Ok((name.as_ref().map(normalize_ident), kind, Some(actions)))
+        }
+        TableConstraint::Check { name, expr } => Ok((
+            name.as_ref().map(normalize_ident),
+            ConstraintKind::check(expr)?,
+            None,
+        )),
+        e => anyhow::bail!("MySQL constraint: {} is not supported", e),
+    }
+}
+
 fn process_query(query: &Query) -> anyhow::Result<Command> {
-    todo!()
+    if query.with.is_some()
+        || !query.order_by.is_empty()
+        || query.limit.is_some()
+        || query.offset.is_some()
+        || query.fetch.is_some()
+    {
+        anyhow::bail!("Unsupported query: {}", query);
+    }
+    let SetExpr::Select(select) = query.body.as_ref() else {
+        anyhow::bail!("Unsupported query: {}", query);
+    };
+    let grouped =
+        !matches!(&select.group_by, GroupByExpr::Expressions(exprs, ..) if exprs.is_empty());
+    if select.distinct.is_some() || select.having.is_some() || grouped {
+        anyhow::bail!("Unsupported query: {}", query);
+    }
+    if !matches!(select.projection.as_slice(), [SelectItem::Wildcard(_)]) {
+        anyhow::bail!("Only SELECT * is supported");
+    }
+    let table = single_table(&select.from).context("Queries must select from exactly one table")?;
+    Ok(Command::Select(QueryOptions {
+        table,
+        filter: select.selection.clone(),
+        scan: scan_hint(&select.from)?,
+    }))
+}
+
+fn scan_hint(from: &[TableWithJoins]) -> anyhow::Result<ScanHint> {
+    let [TableWithJoins {
+        relation: TableFactor::Table { with_hints, .. },
+        ..
+    }] = from
+    else {
+        return Ok(ScanHint::Auto);
+    };
+    match with_hints.as_slice() {
+        [] => Ok(ScanHint::Auto),
+        [Expr::Identifier(hint)] if hint.value.eq_ignore_ascii_case("sequential") => {
+            Ok(ScanHint::Sequential)
+        }
+        hints => {
+            let hints = hints.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+            anyhow::bail!("Unsupported table hint: {}", hints.join(", "))
+        }
+    }
+}
+
+fn process_delete(delete: &Delete) -> anyhow::Result<Command> {
+    if !delete.tables.is_empty()
+        || delete.using.is_some()
+        || !delete.order_by.is_empty()
+        || delete.limit.is_some()
+    {
+        anyhow::bail!("Only DELETE FROM <table> [WHERE ...] is supported");
+    }
+    let (FromTable::WithFromKeyword(from) | FromTable::WithoutKeyword(from)) = &delete.from;
+    let table = single_table(from).context("Rows must be deleted from exactly one table")?;
+    Ok(Command::Delete(DeleteOptions {
+        table,
+        filter: delete.selection.clone(),
+        returning: process_returning(&delete.returning)?,
+    }))
+}
+
+/// A `RETURNING` clause, like `SELECT` only columns or `*` can be returned.
+fn process_returning(items: &Option<Vec<SelectItem>>) -> anyhow::Result<Option<Returning>> {
+    let Some(items) = items else {
+        return Ok(None);
+    };
+    if let [SelectItem::Wildcard(_)] = items.as_slice() {
+        return Ok(Some(Returning::All));
+    }
+    let columns = items
+        .iter()
+        .map(|item| match item {
+            SelectItem::UnnamedExpr(expr) => {
+                expr::column_name(expr).with_context(|| format!("Can't return {}", expr))
+            }
+            e => anyhow::bail!("Only columns or * can be returned, not {}", e),
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Some(Returning::Columns(columns)))
+}
+
+fn single_table(from: &[TableWithJoins]) -> anyhow::Result<String> {
+    match from {
+        [from] if from.joins.is_empty() => match &from.relation {
+            TableFactor::Table { name, .. } => Ok(normalize_object_name(name)),
+            e => anyhow::bail!("Unsupported table expression: {}", e),
+        },
+        _ => anyhow::bail!("Expected a single table"),
+    }
 }
 
 fn process_insert(insert: &Insert) -> anyhow::Result<Command> {
-    let columns = insert.columns.iter().map(|x| x.to_string()).collect();
+    if insert.on.is_some() {
+        anyhow::bail!("Conflict clauses aren't supported, use INSERT OR REPLACE to upsert");
+    }
+    if insert.or.is_some_and(|x| x != SqliteOnConflict::Replace) {
+        anyhow::bail!("Only INSERT OR REPLACE is supported");
+    }
+    let columns = insert.columns.iter().map(normalize_ident).collect();
     let mut dup_check = HashSet::new();
     for col in &columns {
         if !dup_check.insert(col) {
@@ -321,6 +1493,15 @@ fn process_insert(insert: &Insert) -> anyhow::Result<Command> {
                             Expr::Value(v) => {
                                 my_row.push(Value::try_from(v.clone())?.into());
                             }
+                            Expr::UnaryOp {
+                                op: UnaryOperator::Minus,
+                                expr,
+                            } => match expr.as_ref() {
+                                Expr::Value(ast::Value::Number(n, _)) => {
+                                    my_row.push(Value::Number(-n).into());
+                                }
+                                e => anyhow::bail!("Can't negate {}", e),
+                            },
                             e => anyhow::bail!("Unhandled expression type: {}", e),
                         }
                     }
@@ -332,8 +1513,10 @@ fn process_insert(insert: &Insert) -> anyhow::Result<Command> {
     }
 
     Ok(Command::Insert(InsertOptions {
-        table: insert.table_name.to_string(),
+        table: normalize_object_name(&insert.table_name),
         columns,
         values,
+        returning: process_returning(&insert.returning)?,
+        upsert: insert.replace_into || insert.or == Some(SqliteOnConflict::Replace),
     }))
 }